@@ -0,0 +1,154 @@
+//! Matching a blocklist of a million names: `DomainBlocklist` against a
+//! `HashSet` of the names, looked up for each parent of a name.
+//!
+//! Run with `cargo bench --bench blocklist`. Without `--bench`, as under
+//! `cargo test --all-targets`, it runs on a small list only.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::collections::HashSet;
+use std::hint::black_box;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use socks5_rs::{BlocklistFormat, DomainBlocklist};
+
+/// Counts the bytes allocated, to size the lists.
+struct Counting;
+
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATED.fetch_add(layout.size(), Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        ALLOCATED.fetch_sub(layout.size(), Ordering::Relaxed);
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: Counting = Counting;
+
+const TLDS: [&str; 8] = ["com", "net", "org", "io", "de", "co.uk", "info", "example"];
+
+/// A pseudo-random generator, so that runs are alike.
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        // xorshift64*
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    fn label(&mut self) -> String {
+        let len = 4 + self.next() % 10;
+        (0..len)
+            .map(|_| char::from(b'a' + (self.next() % 26) as u8))
+            .collect()
+    }
+
+    fn domain(&mut self) -> String {
+        let tld = TLDS[(self.next() % TLDS.len() as u64) as usize];
+        match self.next() % 3 {
+            0 => format!("{}.{}.{}", self.label(), self.label(), tld),
+            _ => format!("{}.{}", self.label(), tld),
+        }
+    }
+}
+
+/// Whether `domain` or one of its parents is in `names`.
+fn naive_blocks(names: &HashSet<String>, domain: &str) -> bool {
+    let mut name = domain;
+    loop {
+        if names.contains(name) {
+            return true;
+        }
+        match name.find('.') {
+            Some(dot) => name = &name[dot + 1..],
+            None => return false,
+        }
+    }
+}
+
+fn per_lookup(elapsed: Duration, lookups: usize) -> Duration {
+    elapsed / lookups as u32
+}
+
+fn main() {
+    let full = std::env::args().any(|arg| arg == "--bench");
+    let (count, lookups) = if full {
+        (1_000_000, 1_000_000)
+    } else {
+        (10_000, 10_000)
+    };
+
+    let mut rng = Rng(0x5eed);
+    let names: Vec<String> = (0..count).map(|_| rng.domain()).collect();
+    let contents = names.join("\n");
+    // Half of the lookups are for subdomains of listed names.
+    let queries: Vec<String> = (0..lookups)
+        .map(|i| match i % 2 {
+            0 => format!("{}.{}", rng.label(), names[i % names.len()]),
+            _ => format!("{}.{}", rng.label(), rng.domain()),
+        })
+        .collect();
+
+    let before = ALLOCATED.load(Ordering::Relaxed);
+    let started = Instant::now();
+    let blocklist = DomainBlocklist::parse(&contents, BlocklistFormat::Domains);
+    let built = started.elapsed();
+    let trie_bytes = ALLOCATED.load(Ordering::Relaxed) - before;
+
+    let before = ALLOCATED.load(Ordering::Relaxed);
+    let started = Instant::now();
+    let set: HashSet<String> = names.iter().cloned().collect();
+    let set_built = started.elapsed();
+    let set_bytes = ALLOCATED.load(Ordering::Relaxed) - before;
+
+    println!("{} names, {} lookups", blocklist.len(), queries.len());
+    println!(
+        "build: DomainBlocklist {:?}, {} MB; HashSet {:?}, {} MB",
+        built,
+        trie_bytes / 1_000_000,
+        set_built,
+        set_bytes / 1_000_000
+    );
+
+    let mut results = Vec::new();
+    for prefilter in [true, false] {
+        blocklist.set_prefilter(prefilter);
+        let started = Instant::now();
+        let blocked = queries
+            .iter()
+            .filter(|query| blocklist.blocks(black_box(query)))
+            .count();
+        let elapsed = per_lookup(started.elapsed(), queries.len());
+        println!(
+            "DomainBlocklist::blocks, prefilter {}: {:?} a lookup",
+            if prefilter { "on" } else { "off" },
+            elapsed
+        );
+        results.push(blocked);
+    }
+
+    let started = Instant::now();
+    let blocked = queries
+        .iter()
+        .filter(|query| naive_blocks(&set, black_box(query)))
+        .count();
+    let elapsed = per_lookup(started.elapsed(), queries.len());
+    println!("HashSet parent walk: {:?} a lookup", elapsed);
+    results.push(blocked);
+
+    assert!(
+        results.iter().all(|&blocked| blocked == results[0]),
+        "the lists disagree: {:?}",
+        results
+    );
+}
@@ -0,0 +1,642 @@
+//! Access control: which clients are served, and which targets they may
+//! reach.
+
+use std::fmt;
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+use std::ops::RangeInclusive;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::error::Socks5Error;
+use crate::idna;
+use crate::protocol::{Address, Reply, TargetAddr};
+use crate::regex::Program;
+use crate::{DomainBlocklist, IpFeed, Schedule};
+
+/// An IP network in CIDR notation, e.g. `10.0.0.0/8` or `2001:db8::/32`.
+/// A bare address is a network of its own.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct IpNet {
+    addr: IpAddr,
+    prefix_len: u8,
+}
+
+impl IpNet {
+    /// The network of `addr` with a `prefix_len` bit prefix. Host bits of
+    /// `addr` are cleared. `None` if `prefix_len` is too long for the
+    /// address family.
+    pub fn new(addr: IpAddr, prefix_len: u8) -> Option<Self> {
+        let addr = match addr {
+            IpAddr::V4(addr) if prefix_len <= 32 => {
+                let mask = u32::MAX
+                    .checked_shl(32 - u32::from(prefix_len))
+                    .unwrap_or(0);
+                IpAddr::from((u32::from(addr) & mask).to_be_bytes())
+            }
+            IpAddr::V6(addr) if prefix_len <= 128 => {
+                let mask = u128::MAX
+                    .checked_shl(128 - u32::from(prefix_len))
+                    .unwrap_or(0);
+                IpAddr::from((u128::from(addr) & mask).to_be_bytes())
+            }
+            _ => return None,
+        };
+        Some(IpNet { addr, prefix_len })
+    }
+
+    pub fn addr(&self) -> IpAddr {
+        self.addr
+    }
+
+    pub fn prefix_len(&self) -> u8 {
+        self.prefix_len
+    }
+
+    /// Whether `ip` is in the network. IPv4-mapped IPv6 addresses are
+    /// matched as IPv4.
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        let ip = match ip {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+            ip => ip,
+        };
+        IpNet::new(ip, self.prefix_len).is_some_and(|net| net.addr == self.addr)
+    }
+}
+
+impl FromStr for IpNet {
+    type Err = IpNetParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix_len) = match s.split_once('/') {
+            Some((addr, prefix_len)) => (addr, Some(prefix_len)),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr.parse().map_err(|_| IpNetParseError(()))?;
+        let prefix_len = match prefix_len {
+            Some(prefix_len) => prefix_len.parse().map_err(|_| IpNetParseError(()))?,
+            None if addr.is_ipv4() => 32,
+            None => 128,
+        };
+        IpNet::new(addr, prefix_len).ok_or(IpNetParseError(()))
+    }
+}
+
+impl fmt::Display for IpNet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix_len)
+    }
+}
+
+/// Failure to parse an [`IpNet`].
+#[derive(thiserror::Error, Debug)]
+#[error("invalid IP network")]
+pub struct IpNetParseError(());
+
+/// A pattern of domain names, matched without regard to case or a trailing
+/// dot, with names outside ASCII in Punycode:
+///
+/// - `example.com` matches just that name,
+/// - `*.example.com` matches its subdomains, at any depth, but not
+///   `example.com` itself,
+/// - other patterns with `*` (any run of characters, dots included) or `?`
+///   (any one character) match like shell wildcards, e.g. `ads*.example.*`.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct DomainPattern(Pattern);
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+enum Pattern {
+    Exact(String),
+    /// Subdomains of the domain, stored with a leading dot.
+    Suffix(String),
+    Wildcard(String),
+}
+
+impl DomainPattern {
+    pub fn matches(&self, domain: &str) -> bool {
+        let domain = domain
+            .strip_suffix('.')
+            .unwrap_or(domain)
+            .to_ascii_lowercase();
+        match &self.0 {
+            Pattern::Exact(exact) => domain == *exact,
+            Pattern::Suffix(suffix) => domain.ends_with(suffix.as_str()),
+            Pattern::Wildcard(pattern) => wildcard_match(pattern.as_bytes(), domain.as_bytes()),
+        }
+    }
+}
+
+impl FromStr for DomainPattern {
+    type Err = DomainPatternParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.strip_suffix('.').unwrap_or(s).to_ascii_lowercase();
+        if s.is_empty() || s.contains(|c: char| c.is_whitespace() || c == '/') {
+            return Err(DomainPatternParseError(()));
+        }
+        let labels: Option<Vec<String>> = s
+            .split('.')
+            .map(|label| {
+                if label.contains(['*', '?']) {
+                    Some(label.to_owned())
+                } else {
+                    idna::label(label)
+                }
+            })
+            .collect();
+        let s = labels.ok_or(DomainPatternParseError(()))?.join(".");
+        let pattern = match s.strip_prefix("*.") {
+            Some(rest) if !rest.contains(['*', '?']) => Pattern::Suffix(format!(".{}", rest)),
+            _ if s.contains(['*', '?']) => Pattern::Wildcard(s),
+            _ => Pattern::Exact(s),
+        };
+        Ok(DomainPattern(pattern))
+    }
+}
+
+impl fmt::Display for DomainPattern {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.0 {
+            Pattern::Exact(s) | Pattern::Wildcard(s) => f.write_str(s),
+            Pattern::Suffix(suffix) => write!(f, "*{}", suffix),
+        }
+    }
+}
+
+/// Failure to parse a [`DomainPattern`].
+#[derive(thiserror::Error, Debug)]
+#[error("invalid domain pattern")]
+pub struct DomainPatternParseError(());
+
+/// A regular expression on domain names, compiled when created.
+///
+/// It matches names containing a match, without regard to ASCII case or a
+/// trailing dot; anchor it with `^` and `$` to match whole names. Besides
+/// literals, it supports `.`, classes such as `[a-z0-9-]` or `[^.]`, `\d`,
+/// `\w`, `\s`, groups, `|`, and the quantifiers `*`, `+`, `?` and `{m,n}`.
+/// Backreferences and lookaround aren't supported.
+///
+/// Matching takes time linear in the length of the name, and is further
+/// bounded by a step limit. Names that take more steps than that count as
+/// matching, so that denied names can't get through by being costly to
+/// check.
+///
+/// ```
+/// use socks5_rs::DomainRegex;
+///
+/// let trackers: DomainRegex = r"^(ads?|track(er|ing)?)\d*\.".parse().unwrap();
+/// assert!(trackers.matches("ads2.example.com"));
+/// assert!(!trackers.matches("news.example.com"));
+/// ```
+#[derive(Clone)]
+pub struct DomainRegex {
+    pattern: String,
+    program: Arc<Program>,
+    step_limit: usize,
+}
+
+impl DomainRegex {
+    /// Compile `pattern`.
+    pub fn new(pattern: &str) -> Result<Self, DomainRegexError> {
+        let program = Program::compile(pattern).map_err(|e| DomainRegexError {
+            position: e.position,
+            reason: e.reason,
+        })?;
+        Ok(DomainRegex {
+            pattern: pattern.to_owned(),
+            program: Arc::new(program),
+            step_limit: 100_000,
+        })
+    }
+
+    /// Give up matching a name after `steps`, 100000 by default. A step is
+    /// roughly one state of the pattern tried at one position of the name.
+    pub fn with_step_limit(mut self, steps: usize) -> Self {
+        self.step_limit = steps;
+        self
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.pattern
+    }
+
+    pub fn matches(&self, domain: &str) -> bool {
+        let domain = domain.strip_suffix('.').unwrap_or(domain);
+        match self.program.is_match(domain.as_bytes(), self.step_limit) {
+            Some(matches) => matches,
+            None => {
+                log::warn!(
+                    "matching {:?} against /{}/ ran out of steps",
+                    domain,
+                    self.pattern
+                );
+                true
+            }
+        }
+    }
+}
+
+impl FromStr for DomainRegex {
+    type Err = DomainRegexError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        DomainRegex::new(s)
+    }
+}
+
+impl fmt::Debug for DomainRegex {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DomainRegex")
+            .field("pattern", &self.pattern)
+            .field("step_limit", &self.step_limit)
+            .finish()
+    }
+}
+
+impl fmt::Display for DomainRegex {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.pattern)
+    }
+}
+
+impl PartialEq for DomainRegex {
+    fn eq(&self, other: &Self) -> bool {
+        self.pattern == other.pattern && self.step_limit == other.step_limit
+    }
+}
+
+impl Eq for DomainRegex {}
+
+/// Failure to compile a [`DomainRegex`].
+#[derive(thiserror::Error, Debug)]
+#[error("invalid regex at offset {position}: {reason}")]
+pub struct DomainRegexError {
+    position: usize,
+    reason: &'static str,
+}
+
+/// Whether `text` matches `pattern`, with `*` and `?` as wildcards.
+fn wildcard_match(pattern: &[u8], text: &[u8]) -> bool {
+    let (mut p, mut t) = (0, 0);
+    // Where the last `*` was, and the text position it is retried from.
+    let mut backtrack = None;
+    while t < text.len() {
+        match pattern.get(p) {
+            Some(b'*') => {
+                backtrack = Some((p, t));
+                p += 1;
+            }
+            Some(&c) if c == b'?' || c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match backtrack {
+                Some((star, from)) => {
+                    p = star + 1;
+                    t = from + 1;
+                    backtrack = Some((star, from + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == b'*')
+}
+
+/// Which targets clients may reach, see [`Server::with_destination_acl`].
+///
+/// Domain names matching `deny_domains` or `deny_domain_regexes`, or
+/// blocked by any of `deny_lists`, are refused before being resolved.
+/// The IPs targets resolve to, or are given as, are checked against the
+/// networks: denied networks, and those of `deny_feeds`, take precedence
+/// over allowed ones. When any networks are allowed, everything else is
+/// denied. Addresses a target
+/// resolves to that are denied are skipped, and targets left with none are
+/// refused with `reply`.
+///
+/// ```
+/// use socks5_rs::{DestinationAcl, Schedule, Weekday};
+///
+/// let acl = DestinationAcl {
+///     deny: vec!["10.0.0.0/8".parse().unwrap(), "fd00::/8".parse().unwrap()],
+///     deny_domains: vec!["*.internal.example.com".parse().unwrap()],
+///     ..DestinationAcl::default()
+/// };
+/// let work_hours = DestinationAcl {
+///     deny_domains: vec!["*.social.example".parse().unwrap()],
+///     active: Some(Schedule::new().with_window(Weekday::WORKDAYS, (9, 0), (17, 0))),
+///     ..DestinationAcl::default()
+/// };
+/// ```
+///
+/// [`Server::with_destination_acl`]: crate::Server::with_destination_acl
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DestinationAcl {
+    pub allow: Vec<IpNet>,
+    pub deny: Vec<IpNet>,
+    pub deny_domains: Vec<DomainPattern>,
+    pub deny_domain_regexes: Vec<DomainRegex>,
+    pub deny_lists: Vec<DomainBlocklist>,
+    pub deny_feeds: Vec<IpFeed>,
+    /// Refuses blocked targets, "connection not allowed by ruleset" by
+    /// default.
+    pub reply: Reply,
+    /// When set, the ACL only applies while the schedule is active.
+    pub active: Option<Schedule>,
+}
+
+impl Default for DestinationAcl {
+    fn default() -> Self {
+        DestinationAcl {
+            allow: Vec::new(),
+            deny: Vec::new(),
+            deny_domains: Vec::new(),
+            deny_domain_regexes: Vec::new(),
+            deny_lists: Vec::new(),
+            deny_feeds: Vec::new(),
+            reply: Reply::NotAllowed,
+            active: None,
+        }
+    }
+}
+
+impl DestinationAcl {
+    /// An ACL keeping clients off internal infrastructure: loopback,
+    /// private (RFC 1918, unique local), shared (RFC 6598), link-local,
+    /// benchmarking (RFC 2544), multicast, reserved, broadcast and
+    /// unspecified networks, and the internal IPv4 networks as reached
+    /// through NAT64 (`64:ff9b::/96`) or 6to4 (`2002::/16`). That includes
+    /// cloud metadata services like `169.254.169.254`, `100.100.100.200` and
+    /// `fd00:ec2::254`.
+    ///
+    /// As for any ACL, names are checked by the addresses they resolve to,
+    /// which are the addresses then connected to, so DNS can't smuggle
+    /// internal addresses past it.
+    ///
+    /// ```
+    /// use socks5_rs::{DestinationAcl, Server};
+    ///
+    /// # async fn run() -> std::io::Result<()> {
+    /// let server = Server::bind("0.0.0.0:1080")
+    ///     .await?
+    ///     .with_destination_acl(DestinationAcl::deny_internal());
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn deny_internal() -> Self {
+        const INTERNAL_V4: &[&str] = &[
+            "0.0.0.0/8",
+            "10.0.0.0/8",
+            "100.64.0.0/10",
+            "127.0.0.0/8",
+            "169.254.0.0/16",
+            "172.16.0.0/12",
+            "192.168.0.0/16",
+            "198.18.0.0/15",
+            "224.0.0.0/4",
+            "240.0.0.0/4",
+        ];
+        const INTERNAL_V6: &[&str] = &["::/128", "::1/128", "fc00::/7", "fe80::/10", "ff00::/8"];
+        const NAT64: u128 = 0x0064_ff9b << 96;
+        const SIX_TO_FOUR: u128 = 0x2002 << 112;
+
+        let v4: Vec<IpNet> = INTERNAL_V4.iter().map(|net| net.parse().unwrap()).collect();
+        let embedded = v4.iter().flat_map(|net| {
+            let bits = match net.addr() {
+                IpAddr::V4(addr) => u128::from(u32::from(addr)),
+                IpAddr::V6(_) => unreachable!(),
+            };
+            let within = |prefix: u128, shift: u32, prefix_len: u8| {
+                let addr = Ipv6Addr::from(prefix | bits << shift);
+                IpNet::new(addr.into(), prefix_len + net.prefix_len()).unwrap()
+            };
+            [within(NAT64, 0, 96), within(SIX_TO_FOUR, 80, 16)]
+        });
+        let v6 = INTERNAL_V6.iter().map(|net| net.parse().unwrap());
+        let deny = v4.iter().copied().chain(v6).chain(embedded).collect();
+        DestinationAcl {
+            deny,
+            ..DestinationAcl::default()
+        }
+    }
+
+    pub(crate) fn is_active(&self) -> bool {
+        self.active.as_ref().is_none_or(Schedule::is_active)
+    }
+
+    /// Refuse `target` if its domain name is denied.
+    pub(crate) fn check_domain(&self, target: &TargetAddr) -> Result<(), Socks5Error> {
+        if let Address::Domain(domain) = &target.address {
+            let denied = self
+                .deny_domains
+                .iter()
+                .any(|pattern| pattern.matches(domain))
+                || self
+                    .deny_domain_regexes
+                    .iter()
+                    .any(|regex| regex.matches(domain))
+                || self.deny_lists.iter().any(|list| list.blocks(domain));
+            if denied {
+                return Err(self.blocked(target));
+            }
+        }
+        Ok(())
+    }
+
+    pub(crate) fn allows(&self, ip: IpAddr) -> bool {
+        permits(&self.allow, &self.deny, ip)
+            && !self.deny_feeds.iter().any(|feed| feed.contains(ip))
+    }
+
+    /// The addresses `target` resolved to that may be reached.
+    pub(crate) fn filter(
+        &self,
+        target: &TargetAddr,
+        mut addrs: Vec<SocketAddr>,
+    ) -> Result<Vec<SocketAddr>, Socks5Error> {
+        let resolved = addrs.len();
+        addrs.retain(|addr| self.allows(addr.ip()));
+        if addrs.is_empty() && resolved > 0 {
+            return Err(self.blocked(target));
+        }
+        Ok(addrs)
+    }
+
+    fn blocked(&self, target: &TargetAddr) -> Socks5Error {
+        Socks5Error::Blocked {
+            target: target.clone(),
+            reply: self.reply,
+        }
+    }
+}
+
+/// Which IPs clients may connect from, see [`Server::with_client_acl`].
+///
+/// Denied networks take precedence over allowed ones. When any networks are
+/// allowed, everything else is denied. Connections from other IPs are
+/// closed right away, before reading anything from them.
+///
+/// ```
+/// use socks5_rs::ClientAcl;
+///
+/// let acl = ClientAcl {
+///     allow: vec!["192.0.2.0/24".parse().unwrap()],
+///     deny: vec!["192.0.2.66".parse().unwrap()],
+/// };
+/// ```
+///
+/// [`Server::with_client_acl`]: crate::Server::with_client_acl
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ClientAcl {
+    pub allow: Vec<IpNet>,
+    pub deny: Vec<IpNet>,
+}
+
+impl ClientAcl {
+    pub(crate) fn allows(&self, ip: IpAddr) -> bool {
+        permits(&self.allow, &self.deny, ip)
+    }
+}
+
+/// Whether `ip` is in none of `deny`, and in one of `allow` unless empty.
+fn permits(allow: &[IpNet], deny: &[IpNet], ip: IpAddr) -> bool {
+    !deny.iter().any(|net| net.contains(ip))
+        && (allow.is_empty() || allow.iter().any(|net| net.contains(ip)))
+}
+
+/// Which destination ports clients may reach, see [`Server::with_port_acl`]
+/// and [`UserPolicy::with_port_acl`].
+///
+/// Denied ports take precedence over allowed ones. When any ports are
+/// allowed, everything else is denied. Blocked targets are refused with
+/// `reply`, "connection refused" by default so that clients can tell them
+/// from targets refused by other rules.
+///
+/// ```
+/// use socks5_rs::PortAcl;
+///
+/// let web_only = PortAcl {
+///     allow: vec![80..=80, 443..=443],
+///     ..PortAcl::default()
+/// };
+/// let no_smtp = PortAcl {
+///     deny: vec![25..=25],
+///     ..PortAcl::default()
+/// };
+/// ```
+///
+/// [`Server::with_port_acl`]: crate::Server::with_port_acl
+/// [`UserPolicy::with_port_acl`]: crate::UserPolicy::with_port_acl
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PortAcl {
+    pub allow: Vec<RangeInclusive<u16>>,
+    pub deny: Vec<RangeInclusive<u16>>,
+    pub reply: Reply,
+}
+
+impl Default for PortAcl {
+    fn default() -> Self {
+        PortAcl {
+            allow: Vec::new(),
+            deny: Vec::new(),
+            reply: Reply::ConnectionRefused,
+        }
+    }
+}
+
+impl PortAcl {
+    pub(crate) fn allows(&self, port: u16) -> bool {
+        !self.deny.iter().any(|ports| ports.contains(&port))
+            && (self.allow.is_empty() || self.allow.iter().any(|ports| ports.contains(&port)))
+    }
+
+    /// Refuse `target` if its port is blocked.
+    pub(crate) fn check(&self, target: &TargetAddr) -> Result<(), Socks5Error> {
+        if self.allows(target.port) {
+            return Ok(());
+        }
+        Err(Socks5Error::Blocked {
+            target: target.clone(),
+            reply: self.reply,
+        })
+    }
+}
+
+/// How requests for blocked targets are refused, see
+/// [`Server::with_blocked_response`].
+///
+/// [`Server::with_blocked_response`]: crate::Server::with_blocked_response
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum BlockedResponse {
+    /// Reply with the code of the ACL or rule blocking the target.
+    #[default]
+    Reply,
+    /// Close the connection without replying.
+    Close,
+    /// Hold the connection open for the duration, then close it without
+    /// replying, slowing down clients probing the policy.
+    Tarpit(Duration),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn deny_internal() {
+        let acl = DestinationAcl::deny_internal();
+        for ip in [
+            "0.0.0.0",
+            "10.1.2.3",
+            "100.64.0.1",
+            "100.100.100.200",
+            "127.0.0.1",
+            "169.254.169.254",
+            "172.31.255.255",
+            "192.168.1.1",
+            "198.18.0.1",
+            "198.19.255.255",
+            "224.0.0.1",
+            "239.255.255.250",
+            "240.0.0.1",
+            "255.255.255.255",
+            "::",
+            "::1",
+            "::ffff:127.0.0.1",
+            "::ffff:10.0.0.1",
+            "fd00:ec2::254",
+            "fe80::1",
+            "ff02::1",
+            "ff05::1:3",
+            "64:ff9b::7f00:1",
+            "64:ff9b::a9fe:a9fe",
+            "64:ff9b::10.0.0.1",
+            "64:ff9b::255.255.255.255",
+            "2002:7f00:1::",
+            "2002:a9fe:a9fe::1",
+            "2002:c0a8:101::",
+            "2002:e000:1::",
+        ] {
+            assert!(!acl.allows(ip.parse().unwrap()), "{} allowed", ip);
+        }
+        for ip in [
+            "1.1.1.1",
+            "8.8.8.8",
+            "11.0.0.1",
+            "100.63.255.255",
+            "100.128.0.1",
+            "172.32.0.1",
+            "198.17.255.255",
+            "198.20.0.1",
+            "223.255.255.255",
+            "2001:4860:4860::8888",
+            "2606:4700::1111",
+            "::ffff:8.8.8.8",
+            "64:ff9b::808:808",
+            "64:ff9b::1.1.1.1",
+            "2002:808:808::",
+        ] {
+            assert!(acl.allows(ip.parse().unwrap()), "{} denied", ip);
+        }
+    }
+}
@@ -0,0 +1,198 @@
+//! The authentication audit log: one record per authentication attempt,
+//! handed to a pluggable sink, e.g. to feed a SIEM.
+
+use std::fmt::Write;
+use std::net::SocketAddr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::protocol::Method;
+use crate::ServerConfig;
+
+/// Receives the audit records, see [`Server::with_audit_sink`].
+///
+/// Records are handed over on the connection's task, so sinks that do I/O
+/// should queue them rather than block.
+///
+/// ```
+/// use std::sync::mpsc;
+/// use socks5_rs::{AuditSink, AuthEvent};
+///
+/// let (tx, rx) = mpsc::channel();
+/// let sink = move |event: &AuthEvent| drop(tx.send(event.to_json()));
+/// # let _: &dyn AuditSink = &sink;
+/// ```
+///
+/// [`Server::with_audit_sink`]: crate::Server::with_audit_sink
+pub trait AuditSink: Send + Sync {
+    fn record(&self, event: &AuthEvent);
+}
+
+impl<F: Fn(&AuthEvent) + Send + Sync> AuditSink for F {
+    fn record(&self, event: &AuthEvent) {
+        self(event)
+    }
+}
+
+/// An authentication attempt.
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub struct AuthEvent {
+    pub time: SystemTime,
+    /// Where the client connects from.
+    pub peer: SocketAddr,
+    /// The username the client gave, or was identified as, if any.
+    pub username: Option<String>,
+    /// The method the attempt used, if the client got as far as one.
+    pub method: Option<Method>,
+    pub outcome: AuthOutcome,
+}
+
+/// How an authentication attempt ended.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum AuthOutcome {
+    Success,
+    Failure(AuthFailure),
+}
+
+/// Why an authentication attempt failed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum AuthFailure {
+    /// The client offered none of the methods allowed.
+    NoAcceptableMethod,
+    /// Wrong username or password, or an unknown user.
+    BadCredentials,
+    /// The client IP or username is locked out.
+    LockedOut,
+    /// The user has as many sessions open as their policy allows.
+    TooManySessions,
+    /// The GSSAPI security context couldn't be established.
+    Gssapi,
+    /// The process on the other end of a Unix socket isn't allowed.
+    PeerCredentials,
+    /// No TLS client certificate identifying a user, while one is required.
+    ClientCertificate,
+    /// The client took too long.
+    Timeout,
+}
+
+impl AuthFailure {
+    fn as_str(self) -> &'static str {
+        match self {
+            AuthFailure::NoAcceptableMethod => "no_acceptable_method",
+            AuthFailure::BadCredentials => "bad_credentials",
+            AuthFailure::LockedOut => "locked_out",
+            AuthFailure::TooManySessions => "too_many_sessions",
+            AuthFailure::Gssapi => "gssapi",
+            AuthFailure::PeerCredentials => "peer_credentials",
+            AuthFailure::ClientCertificate => "client_certificate",
+            AuthFailure::Timeout => "timeout",
+        }
+    }
+}
+
+impl AuthEvent {
+    /// The record as a line of JSON, e.g.
+    ///
+    /// ```text
+    /// {"time":"2024-05-01T12:00:00.000Z","peer":"192.0.2.1:50000","username":"alice","method":"username_password","outcome":"failure","reason":"bad_credentials"}
+    /// ```
+    pub fn to_json(&self) -> String {
+        let mut json = format!(
+            "{{\"time\":\"{}\",\"peer\":\"{}\"",
+            rfc3339(self.time),
+            self.peer
+        );
+        if let Some(username) = &self.username {
+            json.push_str(",\"username\":");
+            push_json_string(&mut json, username);
+        }
+        if let Some(method) = self.method {
+            let _ = write!(json, ",\"method\":\"{}\"", method_name(method));
+        }
+        match self.outcome {
+            AuthOutcome::Success => json.push_str(",\"outcome\":\"success\"}"),
+            AuthOutcome::Failure(reason) => {
+                let _ = write!(
+                    json,
+                    ",\"outcome\":\"failure\",\"reason\":\"{}\"}}",
+                    reason.as_str()
+                );
+            }
+        }
+        json
+    }
+}
+
+/// Hand a record of an attempt to the configured sink, if any.
+pub(crate) fn record(
+    config: &ServerConfig,
+    peer: SocketAddr,
+    username: Option<&str>,
+    method: Option<Method>,
+    outcome: AuthOutcome,
+) {
+    if let Some(sink) = &config.audit_sink {
+        sink.record(&AuthEvent {
+            time: SystemTime::now(),
+            peer,
+            username: username.map(str::to_owned),
+            method,
+            outcome,
+        });
+    }
+}
+
+fn method_name(method: Method) -> String {
+    match method {
+        Method::NoAuth => "none".to_owned(),
+        Method::GssApi => "gssapi".to_owned(),
+        Method::UserPass => "username_password".to_owned(),
+        Method::Other(n) => format!("{:#04x}", n),
+        Method::NoAcceptable => "no_acceptable".to_owned(),
+    }
+}
+
+fn push_json_string(json: &mut String, s: &str) {
+    json.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(json, "\\u{:04x}", c as u32);
+            }
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+}
+
+/// `time` in UTC, as RFC 3339 with milliseconds.
+fn rfc3339(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since_epoch.as_secs();
+    let (days, secs_of_day) = (secs / 86400, secs % 86400);
+    // Civil date from days since the epoch (Howard Hinnant's algorithm).
+    let z = days as i64 + 719_468;
+    let era = z / 146_097;
+    let day_of_era = z - era * 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        secs_of_day / 3600,
+        secs_of_day / 60 % 60,
+        secs_of_day % 60,
+        since_epoch.subsec_millis()
+    )
+}
@@ -0,0 +1,124 @@
+//! Username/password authentication (RFC 1929) against pluggable user
+//! systems.
+//!
+//! By default credentials are checked against [`ServerConfig::users`]. An
+//! [`Authenticator`] replaces that, so users can come from anywhere, e.g. a
+//! database or a directory service.
+//!
+//! [`ServerConfig::users`]: crate::ServerConfig::users
+
+use std::collections::HashMap;
+use std::future::{self, Future};
+use std::net::SocketAddr;
+use std::pin::Pin;
+
+use crate::UserPolicy;
+
+/// The future returned by [`Authenticator::verify`].
+pub type AuthFuture<'a> = Pin<Box<dyn Future<Output = Decision> + Send + 'a>>;
+
+/// Checks the credentials of clients choosing username/password
+/// authentication.
+///
+/// ```
+/// use std::net::SocketAddr;
+/// use socks5_rs::{AuthFuture, Authenticator, Decision};
+///
+/// struct OnlyAlice;
+///
+/// impl Authenticator for OnlyAlice {
+///     fn verify<'a>(
+///         &'a self,
+///         username: &'a str,
+///         password: &'a [u8],
+///         _peer: SocketAddr,
+///     ) -> AuthFuture<'a> {
+///         Box::pin(async move {
+///             if username == "alice" && password == b"secret" {
+///                 Decision::Allow
+///             } else {
+///                 Decision::Deny
+///             }
+///         })
+///     }
+/// }
+/// ```
+pub trait Authenticator: Send + Sync {
+    /// Decide on `username` and `password`, sent by a client connecting
+    /// from `peer`. Usernames that aren't UTF-8 are denied without asking.
+    fn verify<'a>(
+        &'a self,
+        username: &'a str,
+        password: &'a [u8],
+        peer: SocketAddr,
+    ) -> AuthFuture<'a>;
+
+    /// The policy of `username`, once allowed. Without one, the user's
+    /// entry in [`ServerConfig::user_policies`] applies, if any.
+    ///
+    /// [`ServerConfig::user_policies`]: crate::ServerConfig::user_policies
+    fn policy(&self, _username: &str) -> Option<UserPolicy> {
+        None
+    }
+}
+
+/// Outcome of [`Authenticator::verify`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Decision {
+    Allow,
+    Deny,
+}
+
+/// An async closure deciding on username and password, see
+/// [`Server::with_user_pass`].
+///
+/// [`Server::with_user_pass`]: crate::Server::with_user_pass
+pub(crate) struct UserPassFn<F>(pub(crate) F);
+
+impl<F, Fut> Authenticator for UserPassFn<F>
+where
+    F: Fn(String, String) -> Fut + Send + Sync,
+    Fut: Future<Output = bool> + Send + 'static,
+{
+    fn verify<'a>(
+        &'a self,
+        username: &'a str,
+        password: &'a [u8],
+        _peer: SocketAddr,
+    ) -> AuthFuture<'a> {
+        let password = match std::str::from_utf8(password) {
+            Ok(password) => password.to_owned(),
+            Err(_) => return Box::pin(future::ready(Decision::Deny)),
+        };
+        let allowed = (self.0)(username.to_owned(), password);
+        Box::pin(async move {
+            if allowed.await {
+                Decision::Allow
+            } else {
+                Decision::Deny
+            }
+        })
+    }
+}
+
+/// Username -> password, as in [`ServerConfig::users`].
+///
+/// [`ServerConfig::users`]: crate::ServerConfig::users
+impl Authenticator for HashMap<String, String> {
+    fn verify<'a>(
+        &'a self,
+        username: &'a str,
+        password: &'a [u8],
+        _peer: SocketAddr,
+    ) -> AuthFuture<'a> {
+        let allowed = self
+            .get(username)
+            .is_some_and(|expected| expected.as_bytes() == password);
+        Box::pin(future::ready(if allowed {
+            Decision::Allow
+        } else {
+            Decision::Deny
+        }))
+    }
+}
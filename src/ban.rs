@@ -0,0 +1,191 @@
+//! Banning locked out clients in the kernel, by adding their IPs to an
+//! ipset or nftables set that the firewall drops packets from.
+
+use std::net::IpAddr;
+use std::time::Duration;
+
+use tokio::process::Command;
+
+/// A kernel set to add client IPs to while they are locked out, see
+/// [`Server::with_kernel_ban`].
+///
+/// The sets must exist, support timeouts, and be matched by a firewall
+/// rule dropping their members' packets. IPs are added with the lockout's
+/// duration as their timeout, so that the kernel lifts the ban when the
+/// lockout ends, and removed when the lockout is cleared early. IPv6
+/// clients are only banned if a set is given for them.
+///
+/// With ipset, by running `ipset`:
+///
+/// ```text
+/// ipset create socks5-ban hash:ip timeout 0
+/// ipset create socks5-ban6 hash:ip family inet6 timeout 0
+/// iptables -I INPUT -m set --match-set socks5-ban src -j DROP
+/// ip6tables -I INPUT -m set --match-set socks5-ban6 src -j DROP
+/// ```
+///
+/// With nftables, by running `nft`:
+///
+/// ```text
+/// table inet filter {
+///     set socks5-ban { type ipv4_addr; flags timeout; }
+///     set socks5-ban6 { type ipv6_addr; flags timeout; }
+///     chain input {
+///         type filter hook input priority 0;
+///         ip saddr @socks5-ban drop
+///         ip6 saddr @socks5-ban6 drop
+///     }
+/// }
+/// ```
+///
+/// ```no_run
+/// # async fn run() -> std::io::Result<()> {
+/// use socks5_rs::{KernelBan, LockoutPolicy, Server};
+///
+/// Server::bind("0.0.0.0:1080")
+///     .await?
+///     .with_auth_lockout(LockoutPolicy::default())
+///     .with_kernel_ban(
+///         KernelBan::nftables("inet", "filter", "socks5-ban").with_ipv6_set("socks5-ban6"),
+///     )
+///     .serve()
+///     .await?;
+/// # Ok(())
+/// # }
+/// ```
+///
+/// [`Server::with_kernel_ban`]: crate::Server::with_kernel_ban
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct KernelBan {
+    backend: Backend,
+    set: String,
+    set_v6: Option<String>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Backend {
+    Ipset,
+    Nftables { family: String, table: String },
+}
+
+impl KernelBan {
+    /// Ban IPv4 clients in the ipset `set`.
+    pub fn ipset(set: impl Into<String>) -> Self {
+        KernelBan {
+            backend: Backend::Ipset,
+            set: set.into(),
+            set_v6: None,
+        }
+    }
+
+    /// Ban IPv4 clients in the nftables set `set` of `table`, in `family`.
+    pub fn nftables(
+        family: impl Into<String>,
+        table: impl Into<String>,
+        set: impl Into<String>,
+    ) -> Self {
+        KernelBan {
+            backend: Backend::Nftables {
+                family: family.into(),
+                table: table.into(),
+            },
+            set: set.into(),
+            set_v6: None,
+        }
+    }
+
+    /// Ban IPv6 clients in `set`.
+    pub fn with_ipv6_set(mut self, set: impl Into<String>) -> Self {
+        self.set_v6 = Some(set.into());
+        self
+    }
+
+    /// Add `ip` to its set for `duration`, in the background.
+    pub(crate) fn ban(&self, ip: IpAddr, duration: Duration) {
+        let ip = ip.to_canonical();
+        // A timeout of 0 would ban for good.
+        let secs = duration.as_secs().max(1);
+        let args = match &self.backend {
+            Backend::Ipset => self.set_for(ip).map(|set| {
+                vec![
+                    "-exist".to_owned(),
+                    "add".to_owned(),
+                    set.to_owned(),
+                    ip.to_string(),
+                    "timeout".to_owned(),
+                    secs.to_string(),
+                ]
+            }),
+            Backend::Nftables { .. } => {
+                self.nft_element("add", ip, &format!("{{ {} timeout {}s }}", ip, secs))
+            }
+        };
+        self.run(args, ip, "banning");
+    }
+
+    /// Take `ip` out of its set, in the background.
+    pub(crate) fn unban(&self, ip: IpAddr) {
+        let ip = ip.to_canonical();
+        let args = match &self.backend {
+            Backend::Ipset => self.set_for(ip).map(|set| {
+                vec![
+                    "-exist".to_owned(),
+                    "del".to_owned(),
+                    set.to_owned(),
+                    ip.to_string(),
+                ]
+            }),
+            Backend::Nftables { .. } => self.nft_element("delete", ip, &format!("{{ {} }}", ip)),
+        };
+        self.run(args, ip, "unbanning");
+    }
+
+    fn set_for(&self, ip: IpAddr) -> Option<&str> {
+        match ip {
+            IpAddr::V4(_) => Some(&self.set),
+            IpAddr::V6(_) => self.set_v6.as_deref(),
+        }
+    }
+
+    /// `nft <verb> element <family> <table> <set> <elements>`.
+    fn nft_element(&self, verb: &str, ip: IpAddr, elements: &str) -> Option<Vec<String>> {
+        let (family, table) = match &self.backend {
+            Backend::Nftables { family, table } => (family, table),
+            Backend::Ipset => return None,
+        };
+        let set = self.set_for(ip)?;
+        Some(vec![
+            verb.to_owned(),
+            "element".to_owned(),
+            family.clone(),
+            table.clone(),
+            set.to_owned(),
+            elements.to_owned(),
+        ])
+    }
+
+    fn run(&self, args: Option<Vec<String>>, ip: IpAddr, doing: &'static str) {
+        let args = match args {
+            Some(args) => args,
+            None => return,
+        };
+        let program = match self.backend {
+            Backend::Ipset => "ipset",
+            Backend::Nftables { .. } => "nft",
+        };
+        tokio::spawn(async move {
+            let output = Command::new(program).args(&args).output().await;
+            match output {
+                Ok(output) if output.status.success() => log::info!("{} {}", doing, ip),
+                Ok(output) => log::warn!(
+                    "{} {} failed: {} {}",
+                    doing,
+                    ip,
+                    program,
+                    String::from_utf8_lossy(&output.stderr).trim()
+                ),
+                Err(e) => log::warn!("{} {} failed: running {}: {}", doing, ip, program, e),
+            }
+        });
+    }
+}
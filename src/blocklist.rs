@@ -0,0 +1,471 @@
+//! Domain blocklists in the formats they are commonly published in: hosts
+//! files, plain domain lists, and AdBlock filter lists.
+
+use std::borrow::Cow;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+
+use tokio::task::JoinHandle;
+
+use crate::bloom::BloomFilter;
+use crate::idna;
+use crate::trie::{self, DomainTrie};
+use crate::watch::{self, Modified, Reload};
+
+/// The format of a blocklist file.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BlocklistFormat {
+    /// A hosts file: lines of an address followed by the names to block,
+    /// e.g. `0.0.0.0 ads.example.com`. Names like `localhost` are skipped.
+    Hosts,
+    /// One name per line, blocked along with its subdomains.
+    Domains,
+    /// AdBlock filters: `||example.com^` blocks a name and its subdomains,
+    /// and `@@||example.com^` exempts them. Filters on anything but whole
+    /// domains, or with options other than `$important`, are skipped.
+    Adblock,
+}
+
+/// Blocked domain names, loaded from a blocklist file, for
+/// [`DestinationAcl::deny_lists`].
+///
+/// Lines starting with `#` (or `!` for AdBlock lists) and lines that can't
+/// be used are skipped. The file can be reloaded while serving, by
+/// [`DomainBlocklist::reload`] or [`DomainBlocklist::watch`]. Clones share
+/// the names, so reloading one reloads all.
+///
+/// Lookups first check a Bloom filter of the listed domains, so that names
+/// that aren't listed, most of them, are told so in a single hash check
+/// however long the list. It can be turned off with
+/// [`DomainBlocklist::set_prefilter`].
+///
+/// ```no_run
+/// # async fn run() -> std::io::Result<()> {
+/// use socks5_rs::{BlocklistFormat, DestinationAcl, DomainBlocklist, Server};
+/// use tokio::signal::unix::{signal, SignalKind};
+///
+/// let ads = DomainBlocklist::load("/etc/socks5/ads.txt", BlocklistFormat::Adblock)?;
+/// let server = Server::bind("0.0.0.0:1080")
+///     .await?
+///     .with_destination_acl(DestinationAcl {
+///         deny_lists: vec![ads.clone()],
+///         ..DestinationAcl::default()
+///     });
+///
+/// // Reload on SIGHUP.
+/// let mut hangups = signal(SignalKind::hangup())?;
+/// tokio::spawn(async move {
+///     while hangups.recv().await.is_some() {
+///         if let Err(e) = ads.reload() {
+///             log::warn!("keeping the previous blocklist: {}", e);
+///         }
+///     }
+/// });
+/// server.serve().await?;
+/// # Ok(())
+/// # }
+/// ```
+///
+/// [`DestinationAcl::deny_lists`]: crate::DestinationAcl::deny_lists
+#[derive(Clone)]
+pub struct DomainBlocklist {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    /// Where the names were loaded from, if from a file.
+    path: Option<PathBuf>,
+    format: BlocklistFormat,
+    names: RwLock<Arc<Names>>,
+    /// Whether to check [`Names::filter`] before the trie.
+    prefilter: AtomicBool,
+    modified: Modified,
+}
+
+struct Names {
+    /// Names blocked exactly ([`trie::EXACT`]), blocked along with their
+    /// subdomains ([`trie::SUBTREE`]) and exempted along with their
+    /// subdomains ([`trie::EXEMPT`]).
+    trie: DomainTrie,
+    /// The last two labels of the blocked names, so that most names that
+    /// aren't blocked are told so without walking the trie.
+    filter: BloomFilter,
+    /// Whether some blocked names have a single label, and so aren't in
+    /// the filter.
+    unfiltered: bool,
+    /// The number of rules.
+    len: usize,
+}
+
+impl Names {
+    fn blocks(&self, domain: &str, prefilter: bool) -> bool {
+        if prefilter && !self.unfiltered && !self.filter.may_contain(filter_key(domain).as_bytes())
+        {
+            return false;
+        }
+        let flags = self.trie.lookup(domain);
+        flags & trie::EXEMPT == 0 && flags & (trie::EXACT | trie::SUBTREE) != 0
+    }
+}
+
+/// The last two labels of `name`.
+fn filter_key(name: &str) -> &str {
+    match name.rmatch_indices('.').nth(1) {
+        Some((dot, _)) => &name[dot + 1..],
+        None => name,
+    }
+}
+
+impl DomainBlocklist {
+    /// Read the blocklist file at `path`.
+    pub fn load(path: impl AsRef<Path>, format: BlocklistFormat) -> io::Result<Self> {
+        let path = path.as_ref();
+        let modified = Modified::of(path)?;
+        let names = parse(&fs::read_to_string(path)?, format);
+        Ok(DomainBlocklist::new(
+            Some(path.to_owned()),
+            format,
+            names,
+            modified,
+        ))
+    }
+
+    /// Parse the contents of a blocklist file.
+    pub fn parse(contents: &str, format: BlocklistFormat) -> Self {
+        DomainBlocklist::new(None, format, parse(contents, format), None)
+    }
+
+    fn new(
+        path: Option<PathBuf>,
+        format: BlocklistFormat,
+        names: Names,
+        modified: Option<SystemTime>,
+    ) -> Self {
+        DomainBlocklist {
+            inner: Arc::new(Inner {
+                path,
+                format,
+                names: RwLock::new(Arc::new(names)),
+                prefilter: AtomicBool::new(true),
+                modified: Modified::new(modified),
+            }),
+        }
+    }
+
+    /// Read the file again, replacing the names. On failure the names are
+    /// kept as they were.
+    ///
+    /// Fails with [`io::ErrorKind::InvalidInput`] if the names weren't
+    /// loaded from a file.
+    pub fn reload(&self) -> io::Result<()> {
+        self.inner.reload()
+    }
+
+    /// Reload the file whenever it changes, checking every `interval`.
+    /// Failed reloads are logged, and leave the names as they were.
+    ///
+    /// Must be called from within a Tokio runtime. Watching stops once all
+    /// clones are dropped, or when the returned task is aborted.
+    pub fn watch(&self, interval: Duration) -> JoinHandle<()> {
+        watch::spawn(&self.inner, interval)
+    }
+
+    /// Whether `domain` is blocked, ignoring case and a trailing dot.
+    pub fn blocks(&self, domain: &str) -> bool {
+        let domain = domain.strip_suffix('.').unwrap_or(domain);
+        let domain = if domain.bytes().any(|b| b.is_ascii_uppercase()) {
+            Cow::Owned(domain.to_ascii_lowercase())
+        } else {
+            Cow::Borrowed(domain)
+        };
+        let prefilter = self.inner.prefilter.load(Ordering::Relaxed);
+        self.inner.names.read().unwrap().blocks(&domain, prefilter)
+    }
+
+    /// Whether to check the Bloom filter before the names, on by default.
+    /// The filter wrongly passes about one in a hundred unlisted names on to
+    /// the names, so it only costs time when most names looked up are
+    /// listed. Applies to all clones.
+    pub fn set_prefilter(&self, enabled: bool) {
+        self.inner.prefilter.store(enabled, Ordering::Relaxed);
+    }
+
+    /// The number of rules in the list.
+    pub fn len(&self) -> usize {
+        self.names().len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn names(&self) -> Arc<Names> {
+        self.inner.names.read().unwrap().clone()
+    }
+}
+
+impl fmt::Debug for DomainBlocklist {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DomainBlocklist")
+            .field("path", &self.inner.path)
+            .field("format", &self.inner.format)
+            .field("len", &self.len())
+            .finish()
+    }
+}
+
+/// Lists are equal when they are clones of each other.
+impl PartialEq for DomainBlocklist {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.inner, &other.inner)
+    }
+}
+
+impl Eq for DomainBlocklist {}
+
+impl Reload for Inner {
+    fn changed(&self) -> bool {
+        self.path
+            .as_ref()
+            .is_some_and(|path| self.modified.changed(path))
+    }
+
+    fn reload(&self) -> io::Result<()> {
+        let path = self.path.as_ref().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "blocklist not loaded from a file",
+            )
+        })?;
+        let modified = Modified::of(path)?;
+        let names = parse(&fs::read_to_string(path)?, self.format);
+        log::info!(
+            "reloaded {} blocklist rules from {}",
+            names.len,
+            path.display()
+        );
+        *self.names.write().unwrap() = Arc::new(names);
+        self.modified.set(modified);
+        Ok(())
+    }
+
+    fn kept(&self) -> String {
+        "the previous blocklist".to_owned()
+    }
+}
+
+/// Names hosts files map to addresses for the host itself.
+const HOSTS_BUILTIN: &[&str] = &[
+    "localhost",
+    "localhost.localdomain",
+    "local",
+    "broadcasthost",
+    "ip6-localhost",
+    "ip6-loopback",
+    "ip6-localnet",
+    "ip6-mcastprefix",
+    "ip6-allnodes",
+    "ip6-allrouters",
+    "ip6-allhosts",
+    "0.0.0.0",
+];
+
+fn parse(contents: &str, format: BlocklistFormat) -> Names {
+    let mut names = DomainTrie::default();
+    let mut filter = BloomFilter::new(contents.lines().count());
+    let mut unfiltered = false;
+    let mut len = 0;
+    let mut insert = |name: &str, flags| {
+        if names.insert(name, flags) {
+            len += 1;
+        }
+        if flags != trie::EXEMPT {
+            unfiltered |= !name.contains('.');
+            filter.insert(filter_key(name).as_bytes());
+        }
+    };
+    for line in contents.lines() {
+        let line = line.trim();
+        match format {
+            BlocklistFormat::Hosts => {
+                let line = line.split('#').next().unwrap_or_default();
+                let mut fields = line.split_whitespace();
+                if fields
+                    .next()
+                    .and_then(|ip| ip.parse::<IpAddr>().ok())
+                    .is_none()
+                {
+                    continue;
+                }
+                for name in fields.filter_map(domain) {
+                    if !HOSTS_BUILTIN.contains(&name.as_str()) {
+                        insert(&name, trie::EXACT);
+                    }
+                }
+            }
+            BlocklistFormat::Domains => {
+                let line = line.split('#').next().unwrap_or_default().trim();
+                if let Some(name) = domain(line) {
+                    insert(&name, trie::SUBTREE);
+                }
+            }
+            BlocklistFormat::Adblock => {
+                let (filter, exempt) = match line.strip_prefix("@@") {
+                    Some(filter) => (filter, true),
+                    None => (line, false),
+                };
+                let filter = match filter.split_once('$') {
+                    Some((filter, "important")) => filter,
+                    Some(_) => continue,
+                    None => filter,
+                };
+                let name = filter
+                    .strip_prefix("||")
+                    .and_then(|filter| filter.strip_suffix('^').or(Some(filter)))
+                    .and_then(domain);
+                match (name, exempt) {
+                    (Some(name), false) => insert(&name, trie::SUBTREE),
+                    (Some(name), true) => insert(&name, trie::EXEMPT),
+                    (None, _) => continue,
+                }
+            }
+        }
+    }
+    names.shrink_to_fit();
+    Names {
+        trie: names,
+        filter,
+        unfiltered,
+        len,
+    }
+}
+
+/// `name` as a normalized domain name, if it looks like one.
+fn domain(name: &str) -> Option<String> {
+    let name = idna::normalize(name)?;
+    let valid = !name.is_empty()
+        && name.len() <= 253
+        && name.split('.').all(|label| {
+            !label.is_empty()
+                && label.len() <= 63
+                && label
+                    .bytes()
+                    .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+        });
+    if valid {
+        Some(name)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exemptions_win() {
+        let list = DomainBlocklist::parse(
+            "||example.com^\n\
+             @@||cdn.example.com^\n\
+             ||ads.cdn.example.com^\n\
+             @@||example.org^\n\
+             ||ads.example.org^\n",
+            BlocklistFormat::Adblock,
+        );
+        assert!(list.blocks("example.com"));
+        assert!(list.blocks("www.example.com"));
+        // Over the block of a parent, and of a subdomain.
+        assert!(!list.blocks("cdn.example.com"));
+        assert!(!list.blocks("img.cdn.example.com"));
+        assert!(!list.blocks("ads.cdn.example.com"));
+        assert!(!list.blocks("ads.example.org"));
+        assert!(!list.blocks("x.ads.example.org"));
+    }
+
+    /// Whether `list` blocks `name`, checked the same with the Bloom filter
+    /// as without.
+    fn blocks(list: &DomainBlocklist, name: &str) -> bool {
+        list.set_prefilter(false);
+        let blocked = list.blocks(name);
+        list.set_prefilter(true);
+        assert_eq!(list.blocks(name), blocked, "{}", name);
+        blocked
+    }
+
+    #[test]
+    fn prefilter_agrees() {
+        for (contents, format) in [
+            (
+                "0.0.0.0 ads.example.com tracker.example.org\n\
+                 127.0.0.1 localhost\n\
+                 0.0.0.0 intranet\n",
+                BlocklistFormat::Hosts,
+            ),
+            (
+                "example.net\n\
+                 ads.cdn.example.com\n\
+                 co.uk\n",
+                BlocklistFormat::Domains,
+            ),
+            (
+                "||example.com^\n\
+                 @@||cdn.example.com^\n\
+                 ||ads.cdn.example.com^\n\
+                 ||tracker^\n",
+                BlocklistFormat::Adblock,
+            ),
+        ] {
+            let list = DomainBlocklist::parse(contents, format);
+            for name in [
+                "ads.example.com",
+                "ADS.Example.com.",
+                "x.ads.example.com",
+                "tracker.example.org",
+                "example.org",
+                "localhost",
+                "intranet",
+                "a.intranet",
+                "example.net",
+                "www.example.net",
+                "ads.cdn.example.com",
+                "img.cdn.example.com",
+                "cdn.example.com",
+                "example.com",
+                "bbc.co.uk",
+                "tracker",
+                "x.tracker",
+                "com",
+                "",
+            ] {
+                blocks(&list, name);
+            }
+            for i in 0..10_000 {
+                blocks(&list, &format!("host{}.example.com", i));
+                blocks(&list, &format!("host{}.example.edu", i));
+                blocks(&list, &format!("host{}", i));
+            }
+        }
+    }
+
+    #[test]
+    fn prefilter_agrees_on_a_long_list() {
+        let contents: String = (0..50_000)
+            .map(|i| format!("ads{}.example{}.com\n", i, i % 100))
+            .collect();
+        let list = DomainBlocklist::parse(&contents, BlocklistFormat::Domains);
+        assert_eq!(list.len(), 50_000);
+        for i in 0..100_000 {
+            let name = format!("ads{}.example{}.com", i, i % 100);
+            assert_eq!(blocks(&list, &name), i < 50_000, "{}", name);
+            assert_eq!(blocks(&list, &format!("x.{}", name)), i < 50_000);
+            assert!(!blocks(&list, &format!("ads{}.example{}.org", i, i % 100)));
+        }
+    }
+}
@@ -0,0 +1,114 @@
+//! A blocked Bloom filter: a set that may report keys it doesn't hold, but
+//! never misses one it does, in about ten bits a key.
+//!
+//! Each key sets bits in a single 512-bit block, so that checking a key
+//! touches one cache line.
+
+/// Bits set per key.
+const PROBES: usize = 6;
+/// Bits per key the filter is sized for, for about 1% false positives.
+const BITS_PER_KEY: usize = 10;
+
+#[derive(Clone, Debug)]
+pub(crate) struct BloomFilter {
+    blocks: Vec<[u64; 8]>,
+}
+
+impl BloomFilter {
+    /// A filter sized for `keys` keys.
+    pub(crate) fn new(keys: usize) -> Self {
+        let blocks = (keys * BITS_PER_KEY).div_ceil(512).max(1);
+        BloomFilter {
+            blocks: vec![[0; 8]; blocks],
+        }
+    }
+
+    pub(crate) fn insert(&mut self, key: &[u8]) {
+        let (block, bits) = self.locate(key);
+        let block = &mut self.blocks[block];
+        for bit in bits {
+            block[bit / 64] |= 1 << (bit % 64);
+        }
+    }
+
+    /// Whether `key` may have been inserted.
+    pub(crate) fn may_contain(&self, key: &[u8]) -> bool {
+        let (block, bits) = self.locate(key);
+        let block = &self.blocks[block];
+        bits.iter()
+            .all(|&bit| block[bit / 64] & (1 << (bit % 64)) != 0)
+    }
+
+    /// The block of `key`, and its bits in the block.
+    fn locate(&self, key: &[u8]) -> (usize, [usize; PROBES]) {
+        let hash = mix(fnv1a(key));
+        let block = ((hash >> 32) * self.blocks.len() as u64) >> 32;
+        let bit_hash = mix(hash);
+        let mut bits = [0; PROBES];
+        for (i, bit) in bits.iter_mut().enumerate() {
+            *bit = ((bit_hash >> (9 * i)) & 511) as usize;
+        }
+        (block as usize, bits)
+    }
+}
+
+fn fnv1a(key: &[u8]) -> u64 {
+    key.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+/// The SplitMix64 finalizer, spreading the entropy of `hash` over its bits.
+fn mix(mut hash: u64) -> u64 {
+    hash = (hash ^ (hash >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    hash = (hash ^ (hash >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    hash ^ (hash >> 31)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(i: usize) -> Vec<u8> {
+        format!("host{}.example", i).into_bytes()
+    }
+
+    #[test]
+    fn no_false_negatives() {
+        for keys in [0, 1, 10, 1000, 100_000] {
+            let mut filter = BloomFilter::new(keys);
+            for i in 0..keys {
+                filter.insert(&key(i));
+            }
+            filter.insert(b"");
+            for i in 0..keys {
+                assert!(filter.may_contain(&key(i)), "{} of {}", i, keys);
+            }
+            assert!(filter.may_contain(b""));
+        }
+    }
+
+    #[test]
+    fn overfilled() {
+        // Past its size, it only errs more towards keys it doesn't hold.
+        let mut filter = BloomFilter::new(10);
+        for i in 0..10_000 {
+            filter.insert(&key(i));
+        }
+        assert!((0..10_000).all(|i| filter.may_contain(&key(i))));
+    }
+
+    #[test]
+    fn false_positives() {
+        let keys = 100_000;
+        let mut filter = BloomFilter::new(keys);
+        for i in 0..keys {
+            filter.insert(&key(i));
+        }
+        let false_positives = (keys..2 * keys)
+            .filter(|&i| filter.may_contain(&key(i)))
+            .count();
+        // About 1%.
+        assert!(false_positives < keys * 2 / 100, "{}", false_positives);
+    }
+}
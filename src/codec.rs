@@ -0,0 +1,189 @@
+//! [`tokio_util::codec`] adapters for the messages in [`crate::protocol`].
+//!
+//! Every message has a decoder and an encoder, named after the side that
+//! uses them: a server decodes greetings and requests and encodes replies, a
+//! client does the opposite. A handshake runs through several of them on one
+//! connection, so switch codecs with [`Framed::map_codec`], which keeps any
+//! bytes already read:
+//!
+//! ```no_run
+//! # async fn example(stream: tokio::net::TcpStream) -> Result<(), socks5_rs::codec::CodecError> {
+//! use futures_util::{SinkExt, StreamExt};
+//! use socks5_rs::codec::*;
+//! use socks5_rs::protocol::{Method, MethodSelection};
+//! use tokio_util::codec::Framed;
+//!
+//! let mut framed = Framed::new(stream, Socks5GreetingDecoder);
+//! let greeting = framed.next().await.ok_or(CodecError::Closed)??;
+//!
+//! let mut framed = framed.map_codec(|_| Socks5MethodEncoder);
+//! framed.send(MethodSelection { method: Method::NoAuth }).await?;
+//!
+//! let mut framed = framed.map_codec(|_| Socks5RequestDecoder);
+//! let request = framed.next().await.ok_or(CodecError::Closed)??;
+//! # let _ = (greeting, request);
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! [`Framed::map_codec`]: tokio_util::codec::Framed::map_codec
+
+use std::io;
+
+use bytes::{Buf, Bytes, BytesMut};
+use tokio_util::codec::{Decoder, Encoder};
+
+use crate::protocol::{
+    Greeting, MethodSelection, Parse, ProtocolError, Request, Response, UdpHeader, UserPassRequest,
+    UserPassResponse,
+};
+
+/// Errors surfaced by the codecs.
+#[derive(thiserror::Error, Debug)]
+pub enum CodecError {
+    #[error("IO error: {0}")]
+    Io(#[from] io::Error),
+    #[error(transparent)]
+    Protocol(#[from] ProtocolError),
+    /// The stream ended before a message arrived.
+    #[error("Connection closed")]
+    Closed,
+}
+
+/// Take one message off the front of `src`, if it is all there.
+fn decode<T>(
+    src: &mut BytesMut,
+    parse: fn(&[u8]) -> Result<Parse<T>, ProtocolError>,
+) -> Result<Option<T>, CodecError> {
+    match parse(src)? {
+        Parse::Complete(message, len) => {
+            src.advance(len);
+            Ok(Some(message))
+        }
+        Parse::Incomplete(needed) => {
+            src.reserve(needed);
+            Ok(None)
+        }
+    }
+}
+
+macro_rules! decoder {
+    ($(#[$doc:meta])* $name:ident => $item:ident) => {
+        $(#[$doc])*
+        #[derive(Clone, Copy, Debug, Default)]
+        pub struct $name;
+
+        impl Decoder for $name {
+            type Item = $item;
+            type Error = CodecError;
+
+            fn decode(&mut self, src: &mut BytesMut) -> Result<Option<$item>, CodecError> {
+                decode(src, $item::parse)
+            }
+        }
+    };
+}
+
+macro_rules! encoder {
+    ($(#[$doc:meta])* $name:ident => $item:ident) => {
+        $(#[$doc])*
+        #[derive(Clone, Copy, Debug, Default)]
+        pub struct $name;
+
+        impl Encoder<$item> for $name {
+            type Error = CodecError;
+
+            fn encode(&mut self, item: $item, dst: &mut BytesMut) -> Result<(), CodecError> {
+                item.encode(dst);
+                Ok(())
+            }
+        }
+    };
+}
+
+decoder! {
+    /// Server side: reads the client's method negotiation.
+    Socks5GreetingDecoder => Greeting
+}
+encoder! {
+    /// Client side: offers authentication methods.
+    Socks5GreetingEncoder => Greeting
+}
+decoder! {
+    /// Client side: reads the method chosen by the server.
+    Socks5MethodDecoder => MethodSelection
+}
+encoder! {
+    /// Server side: answers the greeting with the chosen method.
+    Socks5MethodEncoder => MethodSelection
+}
+decoder! {
+    /// Server side: reads RFC 1929 credentials.
+    Socks5UserPassDecoder => UserPassRequest
+}
+encoder! {
+    /// Client side: sends RFC 1929 credentials.
+    Socks5UserPassEncoder => UserPassRequest
+}
+decoder! {
+    /// Client side: reads the RFC 1929 status.
+    Socks5UserPassReplyDecoder => UserPassResponse
+}
+encoder! {
+    /// Server side: sends the RFC 1929 status.
+    Socks5UserPassReplyEncoder => UserPassResponse
+}
+decoder! {
+    /// Server side: reads the client's request.
+    Socks5RequestDecoder => Request
+}
+encoder! {
+    /// Client side: sends a request.
+    Socks5RequestEncoder => Request
+}
+decoder! {
+    /// Client side: reads the server's reply.
+    Socks5ReplyDecoder => Response
+}
+encoder! {
+    /// Server side: replies to a request.
+    Socks5ReplyEncoder => Response
+}
+
+/// Splits relayed datagrams into their header and payload, for use with
+/// [`tokio_util::udp::UdpFramed`] on either side of a UDP association.
+/// Datagrams too short for their header are dropped.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Socks5UdpCodec;
+
+impl Decoder for Socks5UdpCodec {
+    type Item = (UdpHeader, BytesMut);
+    type Error = CodecError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, CodecError> {
+        match UdpHeader::parse(src)? {
+            Parse::Complete(header, len) => {
+                src.advance(len);
+                Ok(Some((header, src.split())))
+            }
+            Parse::Incomplete(_) => {
+                src.clear();
+                Ok(None)
+            }
+        }
+    }
+}
+
+impl Encoder<(UdpHeader, Bytes)> for Socks5UdpCodec {
+    type Error = CodecError;
+
+    fn encode(
+        &mut self,
+        (header, payload): (UdpHeader, Bytes),
+        dst: &mut BytesMut,
+    ) -> Result<(), CodecError> {
+        header.encode(dst);
+        dst.extend_from_slice(&payload);
+        Ok(())
+    }
+}
@@ -0,0 +1,773 @@
+//! Server settings, and [`ServerBuilder`] to build a [`Server`] from them.
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::pin::Pin;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+
+use tokio::net::TcpListener;
+#[cfg(unix)]
+use tokio::net::UnixListener;
+
+use crate::audit::AuditSink;
+use crate::auth::Authenticator;
+use crate::dialcache::DialFailures;
+use crate::dialer::{DialOptions, Dialer, Keepalive, TcpDialer};
+use crate::dns::{DnsCounters, Timed};
+use crate::dnsbl::Dnsbl;
+use crate::error::Socks5Error;
+use crate::listener::{bind_reuse_port, is_local_ip, Listener};
+use crate::protocol::{unmap_socket_addr, Address, Command, Method, Reply, TargetAddr};
+use crate::rules;
+#[cfg(feature = "geoip")]
+use crate::GeoIpFilter;
+#[cfg(feature = "gssapi")]
+use crate::GssapiProvider;
+#[cfg(target_os = "linux")]
+use crate::KernelBan;
+#[cfg(unix)]
+use crate::PeerCredPolicy;
+use crate::{
+    AccessRequest, AccessScript, AddressFamilyPolicy, BlockedResponse, ClientAcl, DestinationAcl,
+    FragPolicy, LockoutPolicy, Nat64, PortAcl, Resolver, RetryPolicy, Rule, RuleAction, Server,
+    StreamIsolation, SystemResolver, UserPolicy,
+};
+#[cfg(feature = "tls")]
+use crate::{ClientCertAuth, TlsAcceptor};
+use crate::{DnsCache, StaticHosts};
+
+/// Decides whether a client connecting from an IP may use a method.
+pub type MethodFilter = dyn Fn(Method, IpAddr) -> bool + Send + Sync;
+
+/// A task for a [`Spawner`] to run.
+pub type BoxTask = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+/// Runs the tasks of a [`Server`]: one per listener and one per connection.
+/// The tasks must be polled to completion, or dropped to abort them.
+pub type Spawner = dyn Fn(BoxTask) + Send + Sync;
+
+/// Who a request is of: the client, and the user it authenticated as.
+pub(crate) struct Requester<'a> {
+    pub(crate) peer: SocketAddr,
+    pub(crate) user: Option<&'a str>,
+    pub(crate) policy: Option<&'a UserPolicy>,
+}
+
+/// Where a request goes, as the access script and rules decide.
+pub(crate) struct Routed<'a> {
+    /// The target to reach, rewritten or not.
+    pub(crate) target: TargetAddr,
+    /// The upstream proxy to reach it through, if any.
+    pub(crate) upstream: Option<SocketAddr>,
+    /// The rule deciding, if any did.
+    pub(crate) rule: Option<&'a Rule>,
+}
+
+/// Default limit on each handshake phase.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Settings shared by every connection of a [`Server`].
+///
+/// Start from [`ServerConfig::default`] and change what you need; fields
+/// will be added as the server grows more options.
+#[derive(Clone)]
+#[non_exhaustive]
+pub struct ServerConfig {
+    /// Where [`ServerBuilder::build`] listens. Defaults to `127.0.0.1:1080`.
+    pub listen_addrs: Vec<SocketAddr>,
+    /// How many sockets [`ServerBuilder::build`] binds to each listen
+    /// address, with `SO_REUSEPORT` when more than one. Each is accepted on
+    /// by a task of its own, and the kernel spreads connections over them.
+    /// Defaults to 1; more are only supported on Unix.
+    pub reuse_port_listeners: usize,
+
+    /// Username -> password. When non-empty, clients must authenticate.
+    pub users: HashMap<String, String>,
+    /// Checks username/password credentials instead of `users`. When set,
+    /// clients must authenticate.
+    pub authenticator: Option<Arc<dyn Authenticator>>,
+    /// Username -> restrictions on that user, unless the authenticator has
+    /// a policy for them.
+    pub user_policies: HashMap<String, UserPolicy>,
+    /// Keeps CONNECTs of different users on different egress addresses.
+    /// Off by default.
+    pub stream_isolation: Option<StreamIsolation>,
+    /// Local address connections to IPv4 targets are made from. When
+    /// unset, the system picks one.
+    pub egress_v4: Option<Ipv4Addr>,
+    /// Local address connections to IPv6 targets are made from. When
+    /// unset, the system picks one.
+    pub egress_v6: Option<Ipv6Addr>,
+    /// Network interface connections to targets are made out of, on
+    /// Linux. Rules may choose another.
+    pub interface: Option<String>,
+    /// Firewall mark connections to targets are tagged with, on Linux.
+    /// Rules may choose another.
+    pub mark: Option<u32>,
+    /// DSCP the packets of sessions are marked with, both to targets and
+    /// to clients, on Unix. Users and rules may choose another.
+    pub dscp: Option<u8>,
+    /// TCP keepalive on connections to clients and targets, on Unix.
+    pub keepalive: Option<Keepalive>,
+    /// Whether connections to clients and targets send small writes at
+    /// once, without Nagle's algorithm. On by default.
+    pub nodelay: bool,
+    /// Whether connections to targets use TCP Fast Open, on Linux.
+    pub fast_open: bool,
+    /// Whether connections to targets use Multipath TCP where the kernel
+    /// supports it, on Linux.
+    pub mptcp: bool,
+    /// How connecting to targets is retried. Rules may choose otherwise.
+    pub retry: Option<RetryPolicy>,
+    /// Restricts the IPs TCP clients may connect from.
+    pub client_acl: Option<ClientAcl>,
+    /// How requests for blocked targets are refused.
+    pub blocked_response: BlockedResponse,
+    /// How long to sniff CONNECTs to IPs for the domain name they are for,
+    /// which is checked against the domain rules and ACLs. Off by default.
+    pub sniff_timeout: Option<Duration>,
+    /// Decides what becomes of requests, before the rules.
+    pub access_script: Option<Arc<dyn AccessScript>>,
+    /// Decide what becomes of requests: the first rule matching a request
+    /// does, before any other check on its target.
+    pub rules: Vec<Rule>,
+    /// Leave domain names unresolved when evaluating rules, for upstream
+    /// proxies to resolve.
+    pub remote_dns: bool,
+    /// Restrict the domain names and IPs of targets, which must pass all
+    /// of them.
+    pub destination_acls: Vec<DestinationAcl>,
+    /// Restricts the countries and autonomous systems of targets.
+    #[cfg(feature = "geoip")]
+    pub geoip_filter: Option<GeoIpFilter>,
+    /// DNS blocklists to look up the IPs of targets in.
+    pub dnsbl: Option<Dnsbl>,
+    /// Restricts the ports of targets, for all users.
+    pub port_acl: Option<PortAcl>,
+    /// Locks out client IPs and usernames failing username/password
+    /// authentication too often. Off by default.
+    pub auth_lockout: Option<LockoutPolicy>,
+    /// Kernel set client IPs are added to while locked out.
+    #[cfg(target_os = "linux")]
+    pub kernel_ban: Option<KernelBan>,
+    /// Receives a record of every authentication attempt.
+    pub audit_sink: Option<Arc<dyn AuditSink>>,
+    /// Offered to clients that support GSSAPI, in preference to other methods.
+    #[cfg(feature = "gssapi")]
+    pub gssapi: Option<Arc<dyn GssapiProvider>>,
+    /// Methods offered, most preferred first. When unset they follow from
+    /// what is configured.
+    pub methods: Option<Vec<Method>>,
+    /// Further restricts the methods by client IP.
+    pub method_filter: Option<Arc<MethodFilter>>,
+    /// Runs a TLS handshake on every TCP client before SOCKS.
+    #[cfg(feature = "tls")]
+    pub tls: Option<Arc<dyn TlsAcceptor>>,
+    /// Maps TLS client certificates to users.
+    #[cfg(feature = "tls")]
+    pub client_cert_auth: Option<ClientCertAuth>,
+    /// Which Unix socket clients are served, by their process's
+    /// credentials. When unset, all are.
+    #[cfg(unix)]
+    pub unix_peer_cred: Option<PeerCredPolicy>,
+    /// Also serve SOCKS4/SOCKS4a clients.
+    pub socks4: bool,
+
+    /// Time allowed for the client's greeting, from accepting the connection.
+    pub greeting_timeout: Duration,
+    /// Time allowed for the authentication sub-negotiation.
+    pub auth_timeout: Duration,
+    /// Time allowed for the request, once authenticated.
+    pub request_timeout: Duration,
+
+    /// How long shutting down waits for open connections to finish.
+    pub drain_timeout: Duration,
+    /// Runs the server's tasks. When unset they go to [`tokio::spawn`].
+    pub spawner: Option<Arc<Spawner>>,
+
+    /// Resolves the domain names of targets. When unset, the system's
+    /// resolver does.
+    pub resolver: Option<Arc<dyn Resolver>>,
+    /// Connects to targets and upstream proxies. When unset, over TCP.
+    pub dialer: Option<Arc<dyn Dialer>>,
+    /// Addresses of target names, consulted before the resolver.
+    pub static_hosts: Option<StaticHosts>,
+    /// Caches the addresses target names resolve to.
+    pub dns_cache: Option<DnsCache>,
+    /// Which addresses of target names are tried, in which order.
+    pub address_family_policy: AddressFamilyPolicy,
+    /// Delay between staggered connection attempts to the addresses of a
+    /// target. When unset, each address is tried after the previous fails.
+    pub happy_eyeballs_delay: Option<Duration>,
+    /// Gateway IPv4 targets are reached through, at IPv6 addresses.
+    pub nat64: Option<Nat64>,
+    /// Time allowed for each attempt to connect to an address of a target.
+    /// When unset, the system's connect timeout applies.
+    pub connect_timeout: Option<Duration>,
+    /// How long addresses that failed to connect are refused without
+    /// another attempt. When unset, every request makes its attempts.
+    pub dial_failure_ttl: Option<Duration>,
+    /// Time allowed for resolving the domain name of a target.
+    pub resolve_timeout: Duration,
+    /// Scope ID given to link-local IPv6 targets that don't carry one.
+    pub link_local_scope_id: Option<u32>,
+
+    pub udp_frag_policy: FragPolicy,
+    /// UDP associations are closed after relaying nothing for this long.
+    pub udp_idle_timeout: Duration,
+    pub udp_max_associations: Option<usize>,
+
+    /// Addresses the server's TCP listeners are bound to, set when serving.
+    pub(crate) bound_addrs: Vec<SocketAddr>,
+    /// Counts how target names are resolved, across clones.
+    pub(crate) dns_counters: Arc<DnsCounters>,
+    /// Addresses that failed to connect recently, across clones.
+    pub(crate) dial_failures: Arc<DialFailures>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            listen_addrs: vec![SocketAddr::from((Ipv4Addr::LOCALHOST, 1080))],
+            reuse_port_listeners: 1,
+            users: HashMap::new(),
+            authenticator: None,
+            user_policies: HashMap::new(),
+            stream_isolation: None,
+            egress_v4: None,
+            egress_v6: None,
+            interface: None,
+            mark: None,
+            dscp: None,
+            keepalive: None,
+            nodelay: true,
+            fast_open: false,
+            mptcp: false,
+            retry: None,
+            client_acl: None,
+            blocked_response: BlockedResponse::default(),
+            sniff_timeout: None,
+            access_script: None,
+            rules: Vec::new(),
+            remote_dns: false,
+            destination_acls: Vec::new(),
+            #[cfg(feature = "geoip")]
+            geoip_filter: None,
+            dnsbl: None,
+            port_acl: None,
+            auth_lockout: None,
+            #[cfg(target_os = "linux")]
+            kernel_ban: None,
+            audit_sink: None,
+            #[cfg(feature = "gssapi")]
+            gssapi: None,
+            methods: None,
+            method_filter: None,
+            #[cfg(feature = "tls")]
+            tls: None,
+            #[cfg(feature = "tls")]
+            client_cert_auth: None,
+            #[cfg(unix)]
+            unix_peer_cred: None,
+            socks4: false,
+            greeting_timeout: HANDSHAKE_TIMEOUT,
+            auth_timeout: HANDSHAKE_TIMEOUT,
+            request_timeout: HANDSHAKE_TIMEOUT,
+            drain_timeout: Duration::from_secs(30),
+            spawner: None,
+            resolver: None,
+            dialer: None,
+            static_hosts: None,
+            dns_cache: None,
+            address_family_policy: AddressFamilyPolicy::default(),
+            happy_eyeballs_delay: None,
+            nat64: None,
+            connect_timeout: None,
+            dial_failure_ttl: None,
+            resolve_timeout: Duration::from_secs(5),
+            link_local_scope_id: None,
+            udp_frag_policy: FragPolicy::default(),
+            udp_idle_timeout: Duration::from_secs(120),
+            udp_max_associations: None,
+            bound_addrs: Vec::new(),
+            dns_counters: Arc::default(),
+            dial_failures: Arc::default(),
+        }
+    }
+}
+
+impl ServerConfig {
+    /// Resolve `target` to the socket addresses to try, in order.
+    pub(crate) async fn resolve(&self, target: &TargetAddr) -> io::Result<Vec<SocketAddr>> {
+        let mut addrs = match &target.address {
+            Address::Domain(domain) => {
+                let listed = self
+                    .static_hosts
+                    .as_ref()
+                    .and_then(|hosts| hosts.lookup(domain));
+                let addrs = match listed {
+                    Some(ips) => {
+                        self.dns_counters
+                            .static_hits
+                            .fetch_add(1, Ordering::Relaxed);
+                        ips.into_iter()
+                            .map(|ip| SocketAddr::new(ip, target.port))
+                            .collect()
+                    }
+                    None => self.lookup(domain, target.port).await?,
+                };
+                self.address_family_policy.apply(addrs)?
+            }
+            Address::Ipv4(ip) => vec![SocketAddr::from((*ip, target.port))],
+            Address::Ipv6(ip) => vec![SocketAddr::from((*ip, target.port))],
+        };
+        if let Some(nat64) = &self.nat64 {
+            addrs = nat64.unmap(addrs);
+        }
+        if let Some(scope_id) = self.link_local_scope_id {
+            for addr in &mut addrs {
+                if let SocketAddr::V6(addr) = addr {
+                    if addr.ip().is_unicast_link_local() && addr.scope_id() == 0 {
+                        addr.set_scope_id(scope_id);
+                    }
+                }
+            }
+        }
+        Ok(addrs)
+    }
+
+    /// Resolve `domain` with the resolver, through the cache if any.
+    async fn lookup(&self, domain: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+        let resolver: &dyn Resolver = match &self.resolver {
+            Some(resolver) => &**resolver,
+            None => &SystemResolver,
+        };
+        let resolver = Timed {
+            resolver,
+            counters: &self.dns_counters,
+        };
+        let lookup = async {
+            match &self.dns_cache {
+                Some(cache) => cache.resolve(&resolver, domain, port).await,
+                None => resolver.resolve(domain, port).await,
+            }
+        };
+        tokio::time::timeout(self.resolve_timeout, lookup)
+            .await
+            .map_err(|_| {
+                self.dns_counters.timed_out();
+                io::Error::new(io::ErrorKind::TimedOut, "resolving timed out")
+            })?
+    }
+
+    /// The dialer to connect to targets and upstream proxies with.
+    pub(crate) fn dialer(&self) -> Arc<dyn Dialer> {
+        // One default for all servers, rather than one a connection.
+        static TCP: OnceLock<Arc<dyn Dialer>> = OnceLock::new();
+        match &self.dialer {
+            Some(dialer) => Arc::clone(dialer),
+            None => Arc::clone(TCP.get_or_init(|| Arc::new(TcpDialer))),
+        }
+    }
+
+    /// The options to dial the target of a request decided by `rule` with,
+    /// for the user with `policy`, but the local address.
+    pub(crate) fn dial_options(
+        &self,
+        rule: Option<&Rule>,
+        policy: Option<&UserPolicy>,
+    ) -> DialOptions {
+        let interface = rule.and_then(Rule::interface);
+        let dscp = rule
+            .and_then(Rule::dscp)
+            .or_else(|| policy.and_then(UserPolicy::dscp));
+        DialOptions {
+            local: None,
+            interface: interface.or(self.interface.as_deref()).map(str::to_owned),
+            mark: rule.and_then(Rule::mark).or(self.mark),
+            dscp: dscp.or(self.dscp),
+            keepalive: self.keepalive,
+            nodelay: self.nodelay,
+            fast_open: self.fast_open,
+            mptcp: self.mptcp,
+        }
+    }
+
+    /// Apply the access script and rules to a `command` request of
+    /// `requester` for `target`: which target to reach instead, and which
+    /// upstream proxy to reach it through, if any. Fails if the request is
+    /// blocked.
+    pub(crate) async fn route(
+        &self,
+        target: &TargetAddr,
+        command: Command,
+        requester: &Requester<'_>,
+    ) -> Result<Routed<'_>, Socks5Error> {
+        let scripted = self.access_script.as_ref().and_then(|script| {
+            script.decide(&AccessRequest {
+                client: requester.peer,
+                user: requester.user,
+                command,
+                target,
+            })
+        });
+        // Names the ACLs deny aren't looked up, so that rules matching
+        // networks don't leak them to the resolver.
+        let resolve = || async {
+            if self.remote_dns || self.check_unresolved(target, requester.policy).is_err() {
+                return Vec::new();
+            }
+            match self.resolve(target).await {
+                Ok(addrs) => addrs.iter().map(SocketAddr::ip).collect(),
+                Err(_) => Vec::new(),
+            }
+        };
+        let rule = match &scripted {
+            Some(_) => None,
+            None => rules::evaluate(&self.rules, target, requester.user, resolve).await,
+        };
+        let routed = |target: &TargetAddr, upstream| Routed {
+            target: target.clone(),
+            upstream,
+            rule,
+        };
+        match scripted.as_ref().or_else(|| rule.map(Rule::action)) {
+            None | Some(RuleAction::Allow) => Ok(routed(target, None)),
+            Some(RuleAction::Block(reply)) => Err(Socks5Error::Blocked {
+                target: target.clone(),
+                reply: *reply,
+            }),
+            Some(RuleAction::Route(proxy)) => Ok(routed(target, Some(*proxy))),
+            Some(RuleAction::Rewrite(rewritten)) => {
+                log::debug!("rewriting {} to {}", target, rewritten);
+                // The user's policy applies to the target reached, too.
+                if let Some(policy) = requester.policy {
+                    policy.check(rewritten)?;
+                }
+                Ok(routed(rewritten, None))
+            }
+        }
+    }
+
+    /// Carry out `blocked_response` for a request refused by policy:
+    /// wait out the tarpit, if any, and tell whether to reply.
+    pub(crate) async fn refuse_blocked(&self) -> bool {
+        match self.blocked_response {
+            BlockedResponse::Reply => true,
+            BlockedResponse::Close => false,
+            BlockedResponse::Tarpit(delay) => {
+                tokio::time::sleep(delay).await;
+                false
+            }
+        }
+    }
+
+    /// Refuse `target`, the domain name sniffed from a connection to
+    /// `connected`, if a rule blocks it or its name is denied. Rules
+    /// matching networks match it by `connected`.
+    pub(crate) async fn check_sniffed(
+        &self,
+        target: &TargetAddr,
+        connected: SocketAddr,
+        requester: &Requester<'_>,
+    ) -> Result<(), Socks5Error> {
+        let resolved = || async { vec![connected.ip()] };
+        let rule = rules::evaluate(&self.rules, target, requester.user, resolved).await;
+        if let Some(RuleAction::Block(reply)) = rule.map(Rule::action) {
+            return Err(Socks5Error::Blocked {
+                target: target.clone(),
+                reply: *reply,
+            });
+        }
+        self.check_unresolved(target, requester.policy)?;
+        Ok(())
+    }
+
+    /// The destination ACLs applying now, the server's and the user's with
+    /// `policy`, after checking the port of `target` and its domain name.
+    fn check_unresolved<'a>(
+        &'a self,
+        target: &TargetAddr,
+        policy: Option<&'a UserPolicy>,
+    ) -> Result<Vec<&'a DestinationAcl>, Socks5Error> {
+        if let Some(acl) = &self.port_acl {
+            acl.check(target)?;
+        }
+        let user_acls = policy.map_or(&[][..], UserPolicy::destination_acls);
+        let acls: Vec<_> = self
+            .destination_acls
+            .iter()
+            .chain(user_acls)
+            .filter(|acl| acl.is_active())
+            .collect();
+        for acl in &acls {
+            acl.check_domain(target)?;
+        }
+        Ok(acls)
+    }
+
+    /// Refuse `target`, to be reached through an upstream proxy, if clients,
+    /// or the user with `policy`, may not reach it. Domain names are left
+    /// for the proxy to resolve.
+    pub(crate) fn check_routed(
+        &self,
+        target: &TargetAddr,
+        policy: Option<&UserPolicy>,
+    ) -> Result<(), Socks5Error> {
+        let acls = self.check_unresolved(target, policy)?;
+        if let Some(addr) = target.socket_addr() {
+            let mut addrs = vec![addr];
+            for acl in &acls {
+                addrs = acl.filter(target, addrs)?;
+            }
+            #[cfg(feature = "geoip")]
+            if let Some(filter) = &self.geoip_filter {
+                filter.filter(target, addrs)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Resolve `target` to the socket addresses to try, leaving out those
+    /// clients, or the user with `policy`, may not reach. Fails if none are
+    /// left.
+    pub(crate) async fn resolve_permitted(
+        &self,
+        target: &TargetAddr,
+        policy: Option<&UserPolicy>,
+    ) -> Result<Vec<SocketAddr>, Socks5Error> {
+        let acls = self.check_unresolved(target, policy)?;
+        let addrs = self
+            .resolve(target)
+            .await
+            .map_err(|source| Socks5Error::Resolve {
+                target: target.clone(),
+                source,
+            })?;
+        let mut addrs = addrs;
+        for acl in &acls {
+            addrs = acl.filter(target, addrs)?;
+        }
+        #[cfg(feature = "geoip")]
+        if let Some(filter) = &self.geoip_filter {
+            addrs = filter.filter(target, addrs)?;
+        }
+        let resolved = addrs.len();
+        addrs.retain(|&addr| !self.is_bound_addr(addr));
+        if addrs.is_empty() && resolved > 0 {
+            log::warn!("refusing {}: the target is this server itself", target);
+            return Err(Socks5Error::Blocked {
+                target: target.clone(),
+                reply: Reply::NotAllowed,
+            });
+        }
+        Ok(addrs)
+    }
+
+    /// `addrs` of `target`, without those the DNSBLs deny. Fails if none
+    /// are left.
+    pub(crate) async fn check_dnsbl(
+        &self,
+        target: &TargetAddr,
+        addrs: Vec<SocketAddr>,
+    ) -> Result<Vec<SocketAddr>, Socks5Error> {
+        match &self.dnsbl {
+            Some(dnsbl) => dnsbl.filter(target, addrs).await,
+            None => Ok(addrs),
+        }
+    }
+
+    /// Whether connecting to `addr` would reach one of the server's own
+    /// listeners, relaying in a loop.
+    fn is_bound_addr(&self, addr: SocketAddr) -> bool {
+        let addr = unmap_socket_addr(addr);
+        let mut bound = self
+            .bound_addrs
+            .iter()
+            .map(|&bound| unmap_socket_addr(bound))
+            .filter(|bound| bound.port() == addr.port());
+        bound.any(|bound| {
+            bound.ip() == addr.ip() || (bound.ip().is_unspecified() && is_local_ip(addr.ip()))
+        })
+    }
+
+    /// The methods a client connecting from `peer` may use, most preferred
+    /// first.
+    pub(crate) fn methods(&self, peer: IpAddr) -> Vec<Method> {
+        let mut methods = match &self.methods {
+            Some(methods) => methods
+                .iter()
+                .copied()
+                .filter(|&method| self.supports(method))
+                .collect(),
+            None => {
+                let mut methods = Vec::new();
+                #[cfg(feature = "gssapi")]
+                if self.gssapi.is_some() {
+                    methods.push(Method::GssApi);
+                }
+                if self.user_pass() {
+                    methods.push(Method::UserPass);
+                }
+                if methods.is_empty() {
+                    methods.push(Method::NoAuth);
+                }
+                methods
+            }
+        };
+        if let Some(filter) = &self.method_filter {
+            methods.retain(|&method| filter(method, peer));
+        }
+        methods
+    }
+
+    pub(crate) fn spawn<F>(&self, task: F)
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        match &self.spawner {
+            Some(spawner) => spawner(Box::pin(task)),
+            None => drop(tokio::spawn(task)),
+        }
+    }
+
+    /// Whether there are credentials to check for username/password
+    /// authentication.
+    fn user_pass(&self) -> bool {
+        self.authenticator.is_some() || !self.users.is_empty()
+    }
+
+    /// Whether `method` is implemented and has what it needs configured.
+    fn supports(&self, method: Method) -> bool {
+        match method {
+            Method::NoAuth => true,
+            Method::UserPass => self.user_pass(),
+            #[cfg(feature = "gssapi")]
+            Method::GssApi => self.gssapi.is_some(),
+            _ => false,
+        }
+    }
+}
+
+/// Builds a [`Server`] from a [`ServerConfig`].
+///
+/// ```no_run
+/// # async fn example() -> std::io::Result<()> {
+/// use socks5_rs::{Server, ServerConfig};
+///
+/// let mut config = ServerConfig::default();
+/// config.listen_addrs = vec!["0.0.0.0:1080".parse().unwrap()];
+/// config.socks4 = true;
+/// let server = Server::builder()
+///     .config(config)
+///     .add_listen_addr("[::]:1080".parse().unwrap())
+///     .build()
+///     .await?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Default)]
+pub struct ServerBuilder {
+    config: ServerConfig,
+    listeners: Vec<Listener>,
+}
+
+impl ServerBuilder {
+    pub fn new() -> Self {
+        ServerBuilder::default()
+    }
+
+    /// Replace all settings with `config`.
+    pub fn config(mut self, config: ServerConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Listen on `addr` only, instead of [`ServerConfig::listen_addrs`].
+    pub fn listen_addr(mut self, addr: SocketAddr) -> Self {
+        self.config.listen_addrs = vec![addr];
+        self
+    }
+
+    /// Listen on `addr` as well.
+    pub fn add_listen_addr(mut self, addr: SocketAddr) -> Self {
+        self.config.listen_addrs.push(addr);
+        self
+    }
+
+    /// Bind each listen address `n` times with `SO_REUSEPORT`, to spread
+    /// accepting over `n` tasks. See [`ServerConfig::reuse_port_listeners`].
+    pub fn reuse_port_listeners(mut self, n: usize) -> Self {
+        self.config.reuse_port_listeners = n;
+        self
+    }
+
+    /// Run the server's tasks with `spawner`. See [`Server::with_spawner`].
+    pub fn spawner<F>(mut self, spawner: F) -> Self
+    where
+        F: Fn(BoxTask) + Send + Sync + 'static,
+    {
+        self.config.spawner = Some(Arc::new(spawner));
+        self
+    }
+
+    /// Accept connections on an already bound `listener` as well.
+    pub fn add_listener(mut self, listener: TcpListener) -> Self {
+        self.listeners.push(listener.into());
+        self
+    }
+
+    /// Accept connections on the Unix socket `listener` as well.
+    #[cfg(unix)]
+    pub fn add_unix_listener(mut self, listener: UnixListener) -> Self {
+        self.listeners.push(listener.into());
+        self
+    }
+
+    /// Bind the listen addresses and create the server.
+    pub async fn build(mut self) -> io::Result<Server> {
+        for &addr in &self.config.listen_addrs {
+            if self.config.reuse_port_listeners > 1 {
+                let listeners = bind_reuse_port(addr, self.config.reuse_port_listeners)?;
+                self.listeners
+                    .extend(listeners.into_iter().map(Listener::from));
+            } else {
+                self.listeners.push(TcpListener::bind(addr).await?.into());
+            }
+        }
+        Ok(Server::from_listeners(self.listeners).with_config(self.config))
+    }
+
+    /// Create the server on `listener` and any added ones, ignoring the
+    /// listen addresses.
+    pub fn build_with_listener(mut self, listener: TcpListener) -> Server {
+        self.listeners.push(listener.into());
+        Server::from_listeners(self.listeners).with_config(self.config)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_dialer_is_shared() {
+        let config = ServerConfig::default();
+        assert!(Arc::ptr_eq(&config.dialer(), &config.dialer()));
+        assert!(Arc::ptr_eq(
+            &config.dialer(),
+            &ServerConfig::default().dialer()
+        ));
+
+        let dialer: Arc<dyn Dialer> = Arc::new(TcpDialer);
+        let config = ServerConfig {
+            dialer: Some(Arc::clone(&dialer)),
+            ..ServerConfig::default()
+        };
+        assert!(Arc::ptr_eq(&config.dialer(), &dialer));
+    }
+}
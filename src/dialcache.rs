@@ -0,0 +1,68 @@
+//! Remembering targets that just failed to connect, so that clients retrying
+//! them are answered at once rather than each waiting out another attempt.
+
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+/// Recent connect failures by target address, see
+/// [`Server::with_dial_failure_cache`].
+///
+/// [`Server::with_dial_failure_cache`]: crate::Server::with_dial_failure_cache
+#[derive(Debug, Default)]
+pub(crate) struct DialFailures {
+    entries: Mutex<Entries>,
+}
+
+#[derive(Debug, Default)]
+struct Entries {
+    map: HashMap<SocketAddr, Failure>,
+    /// Size of the map at which expired entries are next swept out.
+    sweep_at: usize,
+}
+
+#[derive(Debug)]
+struct Failure {
+    kind: io::ErrorKind,
+    expires: Instant,
+}
+
+/// Entries kept before expired ones are first swept out.
+const MIN_SWEEP: usize = 1024;
+
+impl DialFailures {
+    /// How connecting to `addr` failed, if it did recently.
+    pub(crate) fn recent(&self, addr: SocketAddr) -> Option<io::ErrorKind> {
+        let entries = self.entries.lock().unwrap();
+        let failure = entries.map.get(&addr)?;
+        Some(failure.kind).filter(|_| failure.expires > Instant::now())
+    }
+
+    /// Remember for `ttl` that connecting to `addr` failed with `error`,
+    /// if the error says something about the target rather than about this
+    /// host: that it refused, was unreachable or didn't answer.
+    pub(crate) fn fail(&self, addr: SocketAddr, error: &io::Error, ttl: Duration) {
+        let kind = error.kind();
+        if !matches!(
+            kind,
+            io::ErrorKind::ConnectionRefused
+                | io::ErrorKind::HostUnreachable
+                | io::ErrorKind::NetworkUnreachable
+                | io::ErrorKind::TimedOut
+        ) {
+            return;
+        }
+        let now = Instant::now();
+        let mut entries = self.entries.lock().unwrap();
+        let expires = now + ttl;
+        entries.map.insert(addr, Failure { kind, expires });
+        if entries.map.len() >= entries.sweep_at {
+            entries.map.retain(|_, failure| failure.expires > now);
+            entries.sweep_at = (entries.map.len() * 2).max(MIN_SWEEP);
+        }
+    }
+}
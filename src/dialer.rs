@@ -0,0 +1,333 @@
+//! Making the server's outbound connections, over TCP or by a dialer the
+//! embedder supplies, e.g. through a VPN interface, with a custom socket
+//! factory, or to mocks in tests.
+
+use std::fmt;
+use std::future::Future;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+#[cfg(target_os = "linux")]
+use std::os::unix::io::FromRawFd;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::{TcpSocket, TcpStream};
+
+#[cfg(unix)]
+use crate::sockopt;
+
+/// The future returned by [`Dialer::dial`].
+pub type DialFuture<'a> = Pin<Box<dyn Future<Output = io::Result<DialedStream>> + Send + 'a>>;
+
+/// How a [`Dialer`] is to connect, as the server's configuration and rules
+/// say. Dialers may ignore what they can't honor.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct DialOptions {
+    /// Local address to connect from: the egress address of the user, of
+    /// their stream isolation or of the server.
+    pub local: Option<IpAddr>,
+    /// Name of the network interface to connect out of.
+    pub interface: Option<String>,
+    /// Firewall mark to tag the connection with, for policy routing and
+    /// packet filters.
+    pub mark: Option<u32>,
+    /// DSCP to mark the packets sent to the target with.
+    pub dscp: Option<u8>,
+    /// TCP keepalive to probe the target with.
+    pub keepalive: Option<Keepalive>,
+    /// Whether to send small writes at once (`TCP_NODELAY`) rather than
+    /// coalesce them with Nagle's algorithm.
+    pub nodelay: bool,
+    /// Whether to connect with TCP Fast Open, sending the first data in
+    /// the SYN to targets that handed out a cookie before.
+    pub fast_open: bool,
+    /// Whether to connect with Multipath TCP, if the system supports it.
+    pub mptcp: bool,
+}
+
+/// How to probe idle connections for peers that went away, see
+/// [`Server::with_keepalive`].
+///
+/// [`Server::with_keepalive`]: crate::Server::with_keepalive
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Keepalive {
+    /// Idle time before the first probe.
+    pub idle: Duration,
+    /// Time between unanswered probes.
+    pub interval: Duration,
+    /// Unanswered probes after which the connection is dropped.
+    pub retries: u32,
+}
+
+impl Default for Keepalive {
+    /// A first probe after a minute idle, then every 10 seconds, dropping
+    /// the connection after 6 unanswered: well within the minutes NATs
+    /// commonly keep idle TCP mappings for.
+    fn default() -> Self {
+        Keepalive {
+            idle: Duration::from_secs(60),
+            interval: Duration::from_secs(10),
+            retries: 6,
+        }
+    }
+}
+
+/// Makes the server's connections to targets and upstream proxies, see
+/// [`Server::with_dialer`].
+///
+/// Target names are resolved, and their addresses checked against the
+/// ACLs and rules, before they are dialed, so a dialer is only ever asked
+/// for addresses clients may reach. The server's connect timeout applies
+/// on top of any the dialer has.
+///
+/// ```
+/// use std::io;
+/// use std::net::SocketAddr;
+/// use socks5_rs::{DialFuture, DialOptions, Dialer, TcpDialer};
+///
+/// /// Refuses to connect to mail servers.
+/// struct NoSmtp;
+///
+/// impl Dialer for NoSmtp {
+///     fn dial<'a>(&'a self, addr: SocketAddr, options: &'a DialOptions) -> DialFuture<'a> {
+///         match addr.port() {
+///             25 => Box::pin(async { Err(io::ErrorKind::ConnectionRefused.into()) }),
+///             _ => TcpDialer.dial(addr, options),
+///         }
+///     }
+/// }
+/// ```
+///
+/// [`Server::with_dialer`]: crate::Server::with_dialer
+pub trait Dialer: Send + Sync {
+    /// Connect to `addr` as `options` say.
+    fn dial<'a>(&'a self, addr: SocketAddr, options: &'a DialOptions) -> DialFuture<'a>;
+}
+
+/// Connects over TCP, from a socket bound to the local address and
+/// interface when set. The default.
+///
+/// Binding to an interface, marking, Fast Open and Multipath TCP are only
+/// supported on Linux, and setting the DSCP and keepalive on Unix. Binding needs the
+/// `CAP_NET_RAW` capability before Linux 5.7, and marking needs
+/// `CAP_NET_ADMIN`.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TcpDialer;
+
+impl Dialer for TcpDialer {
+    fn dial<'a>(&'a self, addr: SocketAddr, options: &'a DialOptions) -> DialFuture<'a> {
+        Box::pin(async move {
+            let socket = new_socket(addr, options.mptcp)?;
+            if let Some(interface) = &options.interface {
+                bind_device(&socket, interface)?;
+            }
+            if let Some(mark) = options.mark {
+                set_mark(&socket, mark)?;
+            }
+            if let Some(dscp) = options.dscp {
+                set_dscp(&socket, addr.is_ipv6(), dscp)?;
+            }
+            if let Some(keepalive) = &options.keepalive {
+                set_keepalive(&socket, keepalive)?;
+            }
+            if options.fast_open {
+                set_fast_open(&socket)?;
+            }
+            if let Some(local) = options.local {
+                socket.bind(SocketAddr::new(local, 0))?;
+            }
+            let stream = socket.connect(addr).await?;
+            stream.set_nodelay(options.nodelay)?;
+            DialedStream::tcp(stream)
+        })
+    }
+}
+
+/// A socket to connect to `addr` with, over Multipath TCP if `mptcp` and
+/// the kernel supports it, and over TCP otherwise.
+fn new_socket(addr: SocketAddr, mptcp: bool) -> io::Result<TcpSocket> {
+    if mptcp {
+        match new_mptcp_socket(addr) {
+            Ok(socket) => return Ok(socket),
+            Err(e) => log::debug!("falling back to TCP to connect to {}: {}", addr, e),
+        }
+    }
+    if addr.is_ipv4() {
+        TcpSocket::new_v4()
+    } else {
+        TcpSocket::new_v6()
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn new_mptcp_socket(addr: SocketAddr) -> io::Result<TcpSocket> {
+    let domain = if addr.is_ipv4() {
+        libc::AF_INET
+    } else {
+        libc::AF_INET6
+    };
+    let kind = libc::SOCK_STREAM | libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC;
+    // SAFETY: `socket` takes no pointers.
+    let fd = unsafe { libc::socket(domain, kind, libc::IPPROTO_MPTCP) };
+    if fd == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: the descriptor was just opened, and nothing else owns it.
+    Ok(unsafe { TcpSocket::from_raw_fd(fd) })
+}
+
+#[cfg(not(target_os = "linux"))]
+fn new_mptcp_socket(_: SocketAddr) -> io::Result<TcpSocket> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "Multipath TCP is only supported on Linux",
+    ))
+}
+
+#[cfg(target_os = "linux")]
+fn bind_device(socket: &TcpSocket, interface: &str) -> io::Result<()> {
+    socket.bind_device(Some(interface.as_bytes()))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn bind_device(_: &TcpSocket, _: &str) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "binding to an interface is only supported on Linux",
+    ))
+}
+
+#[cfg(target_os = "linux")]
+fn set_mark(socket: &TcpSocket, mark: u32) -> io::Result<()> {
+    // The kernel takes the mark as an unsigned integer.
+    let mark = mark as libc::c_int;
+    sockopt::set_int(socket, libc::SOL_SOCKET, libc::SO_MARK, mark)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_mark(_: &TcpSocket, _: u32) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "marking connections is only supported on Linux",
+    ))
+}
+
+#[cfg(target_os = "linux")]
+fn set_fast_open(socket: &TcpSocket) -> io::Result<()> {
+    let name = libc::TCP_FASTOPEN_CONNECT;
+    sockopt::set_int(socket, libc::IPPROTO_TCP, name, 1)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_fast_open(_: &TcpSocket) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "TCP Fast Open is only supported on Linux",
+    ))
+}
+
+#[cfg(unix)]
+fn set_dscp(socket: &TcpSocket, ipv6: bool, dscp: u8) -> io::Result<()> {
+    sockopt::set_dscp(socket, ipv6, dscp)
+}
+
+#[cfg(not(unix))]
+fn set_dscp(_: &TcpSocket, _: bool, _: u8) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "setting the DSCP is only supported on Unix",
+    ))
+}
+
+#[cfg(unix)]
+fn set_keepalive(socket: &TcpSocket, keepalive: &Keepalive) -> io::Result<()> {
+    sockopt::set_keepalive(socket, keepalive)
+}
+
+#[cfg(not(unix))]
+fn set_keepalive(_: &TcpSocket, _: &Keepalive) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "TCP keepalive is only supported on Unix",
+    ))
+}
+
+/// A connection made by a [`Dialer`].
+pub struct DialedStream {
+    stream: Box<dyn Stream>,
+    local_addr: SocketAddr,
+    peer_addr: SocketAddr,
+}
+
+trait Stream: AsyncRead + AsyncWrite + Send + Unpin {}
+
+impl<S: AsyncRead + AsyncWrite + Send + Unpin> Stream for S {}
+
+impl DialedStream {
+    /// Wrap `stream`, connected from `local_addr` to `peer_addr`. The
+    /// local address is what clients are told the server connected from.
+    pub fn new<S>(stream: S, local_addr: SocketAddr, peer_addr: SocketAddr) -> Self
+    where
+        S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+    {
+        DialedStream {
+            stream: Box::new(stream),
+            local_addr,
+            peer_addr,
+        }
+    }
+
+    /// Wrap the TCP connection `stream`.
+    pub fn tcp(stream: TcpStream) -> io::Result<Self> {
+        let (local_addr, peer_addr) = (stream.local_addr()?, stream.peer_addr()?);
+        Ok(DialedStream::new(stream, local_addr, peer_addr))
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.peer_addr
+    }
+}
+
+impl fmt::Debug for DialedStream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DialedStream")
+            .field("local_addr", &self.local_addr)
+            .field("peer_addr", &self.peer_addr)
+            .finish_non_exhaustive()
+    }
+}
+
+impl AsyncRead for DialedStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for DialedStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_shutdown(cx)
+    }
+}
@@ -0,0 +1,347 @@
+//! Caching the addresses names resolve to, and the failures to resolve
+//! them.
+
+use std::collections::HashMap;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+use crate::resolver::Resolver;
+
+/// Caches what the resolver answers for target names, see
+/// [`Server::with_dns_cache`].
+///
+/// Addresses are kept for the TTL of the answer, clamped between the
+/// minimum and maximum TTLs. Resolvers that don't tell the TTL, such as
+/// the system's, are taken to answer with the minimum. Names that fail to
+/// resolve are remembered as failing for the negative TTL, so that clients
+/// retrying them don't each wait on the resolver. Lookups that time out
+/// aren't cached. Clones share the entries.
+///
+/// Once the cache holds its most entries, further names aren't cached until
+/// some expire.
+///
+/// ```
+/// use std::time::Duration;
+/// use socks5_rs::DnsCache;
+///
+/// let cache = DnsCache::new()
+///     .with_ttl_range(Duration::from_secs(10), Duration::from_secs(600))
+///     .with_negative_ttl(Duration::from_secs(2));
+/// ```
+///
+/// [`Server::with_dns_cache`]: crate::Server::with_dns_cache
+#[derive(Clone)]
+pub struct DnsCache {
+    min_ttl: Duration,
+    max_ttl: Duration,
+    negative_ttl: Duration,
+    max_entries: usize,
+    entries: Arc<Mutex<HashMap<String, Entry>>>,
+    hits: Arc<AtomicU64>,
+    misses: Arc<AtomicU64>,
+}
+
+struct Entry {
+    resolved: Result<Vec<IpAddr>, (io::ErrorKind, String)>,
+    expires: Instant,
+}
+
+impl DnsCache {
+    /// A cache keeping answers for 30 seconds to an hour, failures for 5
+    /// seconds, and up to 10,000 names.
+    pub fn new() -> Self {
+        DnsCache {
+            min_ttl: Duration::from_secs(30),
+            max_ttl: Duration::from_secs(60 * 60),
+            negative_ttl: Duration::from_secs(5),
+            max_entries: 10_000,
+            entries: Arc::default(),
+            hits: Arc::default(),
+            misses: Arc::default(),
+        }
+    }
+
+    /// Keep answers for at least `min` and at most `max`, whatever their
+    /// TTL.
+    pub fn with_ttl_range(mut self, min: Duration, max: Duration) -> Self {
+        self.min_ttl = min;
+        self.max_ttl = max.max(min);
+        self
+    }
+
+    /// Remember names failing to resolve for `ttl`. Zero doesn't.
+    pub fn with_negative_ttl(mut self, ttl: Duration) -> Self {
+        self.negative_ttl = ttl;
+        self
+    }
+
+    pub fn with_max_entries(mut self, max: usize) -> Self {
+        self.max_entries = max;
+        self
+    }
+
+    /// Forget all names.
+    pub fn clear(&self) {
+        self.entries.lock().unwrap().clear();
+    }
+
+    /// The number of names cached, some of which may have expired.
+    pub fn len(&self) -> usize {
+        self.entries.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The names answered from the cache, and those it asked the resolver
+    /// for, so far.
+    pub(crate) fn counts(&self) -> (u64, u64) {
+        (
+            self.hits.load(Ordering::Relaxed),
+            self.misses.load(Ordering::Relaxed),
+        )
+    }
+
+    /// The addresses of `domain` with `port`, from the cache or else from
+    /// `resolver`.
+    pub(crate) async fn resolve(
+        &self,
+        resolver: &dyn Resolver,
+        domain: &str,
+        port: u16,
+    ) -> io::Result<Vec<SocketAddr>> {
+        let key = domain.to_ascii_lowercase();
+        let with_port = |ips: &[IpAddr]| ips.iter().map(|&ip| SocketAddr::new(ip, port)).collect();
+        if let Some(entry) = self.entries.lock().unwrap().get(&key) {
+            if entry.expires > Instant::now() {
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                return match &entry.resolved {
+                    Ok(ips) => Ok(with_port(ips)),
+                    Err((kind, message)) => Err(io::Error::new(*kind, message.clone())),
+                };
+            }
+        }
+
+        self.misses.fetch_add(1, Ordering::Relaxed);
+        let resolved = resolver.resolve_with_ttl(domain, port).await;
+        let (entry, result) = match resolved {
+            Ok((addrs, ttl)) => {
+                let ttl = ttl
+                    .unwrap_or(self.min_ttl)
+                    .clamp(self.min_ttl, self.max_ttl);
+                let ips = addrs.iter().map(SocketAddr::ip).collect();
+                let entry = Entry {
+                    resolved: Ok(ips),
+                    expires: Instant::now() + ttl,
+                };
+                (entry, Ok(addrs))
+            }
+            Err(e) if e.kind() == io::ErrorKind::TimedOut || self.negative_ttl.is_zero() => {
+                return Err(e)
+            }
+            Err(e) => {
+                let entry = Entry {
+                    resolved: Err((e.kind(), e.to_string())),
+                    expires: Instant::now() + self.negative_ttl,
+                };
+                (entry, Err(e))
+            }
+        };
+        self.insert(key, entry);
+        result
+    }
+
+    fn insert(&self, key: String, entry: Entry) {
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() >= self.max_entries && !entries.contains_key(&key) {
+            let now = Instant::now();
+            entries.retain(|_, entry| entry.expires > now);
+            if entries.len() >= self.max_entries {
+                return;
+            }
+        }
+        entries.insert(key, entry);
+    }
+}
+
+impl Default for DnsCache {
+    fn default() -> Self {
+        DnsCache::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::resolver::{ResolveFuture, ResolveWithTtlFuture};
+    use std::sync::atomic::AtomicUsize;
+
+    /// Answers every name with 192.0.2.1 and `ttl`, or fails with `error`,
+    /// counting the lookups.
+    struct Answers {
+        ttl: Option<Duration>,
+        error: Option<io::ErrorKind>,
+        lookups: AtomicUsize,
+    }
+
+    fn answers(ttl: Option<Duration>) -> Answers {
+        Answers {
+            ttl,
+            error: None,
+            lookups: AtomicUsize::new(0),
+        }
+    }
+
+    fn failing(error: io::ErrorKind) -> Answers {
+        Answers {
+            error: Some(error),
+            ..answers(None)
+        }
+    }
+
+    impl Resolver for Answers {
+        fn resolve<'a>(&'a self, domain: &'a str, port: u16) -> ResolveFuture<'a> {
+            Box::pin(async move { Ok(self.resolve_with_ttl(domain, port).await?.0) })
+        }
+
+        fn resolve_with_ttl<'a>(&'a self, _domain: &'a str, port: u16) -> ResolveWithTtlFuture<'a> {
+            self.lookups.fetch_add(1, Ordering::SeqCst);
+            let resolved = match self.error {
+                Some(kind) => Err(io::Error::new(kind, "failed")),
+                None => Ok((vec![SocketAddr::from(([192, 0, 2, 1], port))], self.ttl)),
+            };
+            Box::pin(std::future::ready(resolved))
+        }
+    }
+
+    impl Answers {
+        fn lookups(&self) -> usize {
+            self.lookups.load(Ordering::SeqCst)
+        }
+    }
+
+    const MIN: Duration = Duration::from_millis(200);
+    const MAX: Duration = Duration::from_millis(600);
+
+    fn cache() -> DnsCache {
+        DnsCache::new()
+            .with_ttl_range(MIN, MAX)
+            .with_negative_ttl(MIN)
+    }
+
+    async fn sleep_past(ttl: Duration) {
+        tokio::time::sleep(ttl + Duration::from_millis(100)).await;
+    }
+
+    #[tokio::test]
+    async fn hits() {
+        let cache = cache();
+        let resolver = answers(Some(MIN));
+        let resolved = cache.resolve(&resolver, "example.com", 80).await.unwrap();
+        assert_eq!(resolved, [SocketAddr::from(([192, 0, 2, 1], 80))]);
+        // With the port asked for, and ignoring case.
+        let resolved = cache.resolve(&resolver, "Example.COM", 443).await.unwrap();
+        assert_eq!(resolved, [SocketAddr::from(([192, 0, 2, 1], 443))]);
+        assert_eq!(resolver.lookups(), 1);
+        assert_eq!(cache.counts(), (1, 1));
+        assert_eq!(cache.len(), 1);
+
+        cache.resolve(&resolver, "example.org", 80).await.unwrap();
+        assert_eq!(resolver.lookups(), 2);
+        cache.clear();
+        assert!(cache.is_empty());
+        cache.resolve(&resolver, "example.com", 80).await.unwrap();
+        assert_eq!(resolver.lookups(), 3);
+    }
+
+    #[tokio::test]
+    async fn clamps_ttls() {
+        for ttl in [None, Some(Duration::ZERO), Some(Duration::from_millis(1))] {
+            let cache = cache();
+            let resolver = answers(ttl);
+            cache.resolve(&resolver, "example.com", 80).await.unwrap();
+            cache.resolve(&resolver, "example.com", 80).await.unwrap();
+            assert_eq!(resolver.lookups(), 1, "{:?}", ttl);
+            sleep_past(MIN).await;
+            cache.resolve(&resolver, "example.com", 80).await.unwrap();
+            assert_eq!(resolver.lookups(), 2, "{:?}", ttl);
+        }
+
+        let cache = cache();
+        let resolver = answers(Some(Duration::from_secs(3600)));
+        cache.resolve(&resolver, "example.com", 80).await.unwrap();
+        sleep_past(MIN).await;
+        cache.resolve(&resolver, "example.com", 80).await.unwrap();
+        assert_eq!(resolver.lookups(), 1);
+        sleep_past(MAX - MIN).await;
+        cache.resolve(&resolver, "example.com", 80).await.unwrap();
+        assert_eq!(resolver.lookups(), 2);
+    }
+
+    #[tokio::test]
+    async fn caches_failures() {
+        let cache = cache();
+        let resolver = failing(io::ErrorKind::NotFound);
+        for _ in 0..3 {
+            let err = cache
+                .resolve(&resolver, "nx.example", 80)
+                .await
+                .unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::NotFound);
+            assert_eq!(err.to_string(), "failed");
+        }
+        assert_eq!(resolver.lookups(), 1);
+        sleep_past(MIN).await;
+        cache
+            .resolve(&resolver, "nx.example", 80)
+            .await
+            .unwrap_err();
+        assert_eq!(resolver.lookups(), 2);
+
+        // Unless timed out, or not to be.
+        let resolver = failing(io::ErrorKind::TimedOut);
+        for _ in 0..3 {
+            cache
+                .resolve(&resolver, "slow.example", 80)
+                .await
+                .unwrap_err();
+        }
+        assert_eq!(resolver.lookups(), 3);
+        let cache = cache.with_negative_ttl(Duration::ZERO);
+        let resolver = failing(io::ErrorKind::NotFound);
+        for _ in 0..3 {
+            cache
+                .resolve(&resolver, "other.example", 80)
+                .await
+                .unwrap_err();
+        }
+        assert_eq!(resolver.lookups(), 3);
+    }
+
+    #[tokio::test]
+    async fn max_entries() {
+        let cache = cache().with_max_entries(2);
+        let resolver = answers(None);
+        for name in ["a.example", "b.example", "c.example"] {
+            cache.resolve(&resolver, name, 80).await.unwrap();
+        }
+        assert_eq!(cache.len(), 2);
+        // The first two stay cached, the third isn't.
+        cache.resolve(&resolver, "a.example", 80).await.unwrap();
+        cache.resolve(&resolver, "c.example", 80).await.unwrap();
+        assert_eq!(resolver.lookups(), 4);
+
+        // Until the others expire.
+        sleep_past(MIN).await;
+        cache.resolve(&resolver, "c.example", 80).await.unwrap();
+        cache.resolve(&resolver, "c.example", 80).await.unwrap();
+        assert_eq!(resolver.lookups(), 5);
+        assert_eq!(cache.len(), 1);
+    }
+}
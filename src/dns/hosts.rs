@@ -0,0 +1,211 @@
+//! Static addresses for names, in the hosts file format, consulted before
+//! resolving them.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+
+use tokio::task::JoinHandle;
+
+use crate::watch::{self, Modified, Reload};
+
+/// Addresses target names resolve to without asking the resolver, see
+/// [`Server::with_static_hosts`]: to pin internal names, test, or work
+/// around broken records.
+///
+/// Entries are in the hosts file format, an address followed by the names
+/// it is for, e.g. `10.0.0.5 db.internal db`. Names listed on several lines
+/// resolve to all their addresses, in order. Names match exactly, ignoring
+/// case and a trailing dot. Text after `#` is a comment, and lines that
+/// can't be used are skipped.
+///
+/// The file can be reloaded while serving, by [`StaticHosts::reload`] or
+/// [`StaticHosts::watch`]. Clones share the entries, so reloading one
+/// reloads all.
+///
+/// ```no_run
+/// # async fn run() -> std::io::Result<()> {
+/// use std::time::Duration;
+/// use socks5_rs::{Server, StaticHosts};
+///
+/// let hosts = StaticHosts::load("/etc/socks5/hosts")?;
+/// hosts.watch(Duration::from_secs(10));
+/// Server::bind("0.0.0.0:1080")
+///     .await?
+///     .with_static_hosts(hosts)
+///     .serve()
+///     .await?;
+/// # Ok(())
+/// # }
+/// ```
+///
+/// [`Server::with_static_hosts`]: crate::Server::with_static_hosts
+#[derive(Clone)]
+pub struct StaticHosts {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    /// Where the entries were loaded from, if from a file.
+    path: Option<PathBuf>,
+    entries: RwLock<Arc<HashMap<String, Vec<IpAddr>>>>,
+    modified: Modified,
+}
+
+impl StaticHosts {
+    /// Read the hosts file at `path`.
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref();
+        let modified = Modified::of(path)?;
+        let entries = parse(&fs::read_to_string(path)?);
+        Ok(StaticHosts::new(Some(path.to_owned()), entries, modified))
+    }
+
+    /// Parse the contents of a hosts file.
+    ///
+    /// ```
+    /// use socks5_rs::StaticHosts;
+    ///
+    /// let hosts = StaticHosts::parse(
+    ///     "10.0.0.5 db.internal\n\
+    ///      fd00::5  db.internal\n\
+    ///      127.0.0.1 api.example.com # until the fix is deployed",
+    /// );
+    /// assert_eq!(hosts.lookup("DB.internal.").unwrap().len(), 2);
+    /// ```
+    pub fn parse(contents: &str) -> Self {
+        StaticHosts::new(None, parse(contents), None)
+    }
+
+    fn new(
+        path: Option<PathBuf>,
+        entries: HashMap<String, Vec<IpAddr>>,
+        modified: Option<SystemTime>,
+    ) -> Self {
+        StaticHosts {
+            inner: Arc::new(Inner {
+                path,
+                entries: RwLock::new(Arc::new(entries)),
+                modified: Modified::new(modified),
+            }),
+        }
+    }
+
+    /// Read the file again, replacing the entries. On failure the entries
+    /// are kept as they were.
+    ///
+    /// Fails with [`io::ErrorKind::InvalidInput`] if the entries weren't
+    /// loaded from a file.
+    pub fn reload(&self) -> io::Result<()> {
+        self.inner.reload()
+    }
+
+    /// Reload the file whenever it changes, checking every `interval`.
+    /// Failed reloads are logged, and leave the entries as they were.
+    ///
+    /// Must be called from within a Tokio runtime. Watching stops once all
+    /// clones are dropped, or when the returned task is aborted.
+    pub fn watch(&self, interval: Duration) -> JoinHandle<()> {
+        watch::spawn(&self.inner, interval)
+    }
+
+    /// The addresses listed for `domain`, if any.
+    pub fn lookup(&self, domain: &str) -> Option<Vec<IpAddr>> {
+        let domain = domain.strip_suffix('.').unwrap_or(domain);
+        let entries = self.entries();
+        match entries.get(domain) {
+            Some(ips) => Some(ips.clone()),
+            None => entries.get(&domain.to_ascii_lowercase()).cloned(),
+        }
+    }
+
+    /// The number of names listed.
+    pub fn len(&self) -> usize {
+        self.entries().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn entries(&self) -> Arc<HashMap<String, Vec<IpAddr>>> {
+        self.inner.entries.read().unwrap().clone()
+    }
+}
+
+impl fmt::Debug for StaticHosts {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("StaticHosts")
+            .field("path", &self.inner.path)
+            .field("len", &self.len())
+            .finish()
+    }
+}
+
+/// Entries are equal when they are clones of each other.
+impl PartialEq for StaticHosts {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.inner, &other.inner)
+    }
+}
+
+impl Eq for StaticHosts {}
+
+impl Reload for Inner {
+    fn changed(&self) -> bool {
+        self.path
+            .as_ref()
+            .is_some_and(|path| self.modified.changed(path))
+    }
+
+    fn reload(&self) -> io::Result<()> {
+        let path = self.path.as_ref().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "static hosts not loaded from a file",
+            )
+        })?;
+        let modified = Modified::of(path)?;
+        let entries = parse(&fs::read_to_string(path)?);
+        log::info!(
+            "reloaded {} static hosts from {}",
+            entries.len(),
+            path.display()
+        );
+        *self.entries.write().unwrap() = Arc::new(entries);
+        self.modified.set(modified);
+        Ok(())
+    }
+
+    fn kept(&self) -> String {
+        "the previous static hosts".to_owned()
+    }
+}
+
+fn parse(contents: &str) -> HashMap<String, Vec<IpAddr>> {
+    let mut entries: HashMap<String, Vec<IpAddr>> = HashMap::new();
+    for line in contents.lines() {
+        let line = line.split('#').next().unwrap_or_default();
+        let mut fields = line.split_whitespace();
+        let ip = match fields.next().and_then(|ip| ip.parse::<IpAddr>().ok()) {
+            Some(ip) => ip,
+            None => continue,
+        };
+        for name in fields {
+            let name = name.strip_suffix('.').unwrap_or(name);
+            if name.is_empty() || name.len() > 253 {
+                continue;
+            }
+            let ips = entries.entry(name.to_ascii_lowercase()).or_default();
+            if !ips.contains(&ip) {
+                ips.push(ip);
+            }
+        }
+    }
+    entries
+}
@@ -0,0 +1,137 @@
+//! DNS over HTTPS (RFC 8484): queries POSTed to a URL, over HTTP/1.1.
+
+use std::io;
+use std::net::{Ipv6Addr, SocketAddr};
+
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+
+use super::message::{self, Answer};
+use crate::tls::TlsConnector;
+
+/// Longest response accepted: the most a DNS message over TCP can be.
+const MAX_RESPONSE_LEN: usize = u16::MAX as usize;
+
+/// A parsed `https://` URL.
+#[derive(Clone, Debug)]
+pub(crate) struct HttpsUrl {
+    pub(crate) host: String,
+    pub(crate) port: u16,
+    path: String,
+}
+
+impl HttpsUrl {
+    pub(crate) fn parse(url: &str) -> io::Result<Self> {
+        let invalid = |msg| io::Error::new(io::ErrorKind::InvalidInput, msg);
+        let rest = url
+            .strip_prefix("https://")
+            .ok_or_else(|| invalid("DNS over HTTPS URL isn't an https:// URL"))?;
+        let (authority, path) = match rest.find('/') {
+            Some(slash) => rest.split_at(slash),
+            None => (rest, "/dns-query"),
+        };
+        let (host, port) = match authority.rsplit_once(':') {
+            Some((host, port)) if !port.contains(']') => (
+                host,
+                port.parse()
+                    .map_err(|_| invalid("invalid port in DNS over HTTPS URL"))?,
+            ),
+            _ => (authority, 443),
+        };
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        if host.is_empty() || host.contains('@') {
+            return Err(invalid("invalid host in DNS over HTTPS URL"));
+        }
+        Ok(HttpsUrl {
+            host: host.to_owned(),
+            port,
+            path: path.to_owned(),
+        })
+    }
+}
+
+/// POST `queries` to `url` at `server`, all at once on a single connection
+/// (HTTP/1.1 pipelining, which servers answer in order), and match the
+/// answers to `ids`.
+pub(crate) async fn exchange(
+    url: &HttpsUrl,
+    connector: &dyn TlsConnector,
+    server: SocketAddr,
+    queries: &[Vec<u8>],
+    ids: &[u16],
+) -> io::Result<Vec<Answer>> {
+    let stream = TcpStream::connect(server).await?;
+    let mut stream = BufReader::new(connector.connect(&url.host, stream).await?);
+    let host = match url.host.parse::<Ipv6Addr>() {
+        Ok(_) => format!("[{}]", url.host),
+        Err(_) => url.host.clone(),
+    };
+    let mut requests = Vec::new();
+    for (i, query) in queries.iter().enumerate() {
+        let connection = if i + 1 == queries.len() {
+            "close"
+        } else {
+            "keep-alive"
+        };
+        let request = format!(
+            "POST {} HTTP/1.1\r\nHost: {}\r\nUser-Agent: socks5_rs\r\n\
+             Accept: application/dns-message\r\nContent-Type: application/dns-message\r\n\
+             Content-Length: {}\r\nConnection: {}\r\n\r\n",
+            url.path,
+            host,
+            query.len(),
+            connection
+        );
+        requests.extend_from_slice(request.as_bytes());
+        requests.extend_from_slice(query);
+    }
+    stream.get_mut().write_all(&requests).await?;
+    let mut answers = Vec::with_capacity(queries.len());
+    for &id in ids {
+        let response = read_response(&mut stream).await?;
+        let answer = message::parse(&response, id)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "bad DNS response"))?;
+        answers.push(answer);
+    }
+    Ok(answers)
+}
+
+/// The body of a `200 OK` response with a `Content-Length`.
+async fn read_response<R: AsyncBufReadExt + Unpin>(stream: &mut R) -> io::Result<Vec<u8>> {
+    let invalid = |msg: String| io::Error::new(io::ErrorKind::InvalidData, msg);
+    let mut line = String::new();
+    stream.read_line(&mut line).await?;
+    let status = line
+        .split_whitespace()
+        .nth(1)
+        .unwrap_or_default()
+        .to_owned();
+    if status != "200" {
+        return Err(invalid(format!(
+            "DNS over HTTPS server answered: {}",
+            line.trim()
+        )));
+    }
+    let mut len = None;
+    loop {
+        line.clear();
+        if stream.read_line(&mut line).await? == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        let header = line.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            if name.eq_ignore_ascii_case("content-length") {
+                len = value.trim().parse::<usize>().ok();
+            }
+        }
+    }
+    let len = len
+        .filter(|&len| len <= MAX_RESPONSE_LEN)
+        .ok_or_else(|| invalid("DNS over HTTPS response without a valid length".to_owned()))?;
+    let mut body = vec![0; len];
+    stream.read_exact(&mut body).await?;
+    Ok(body)
+}
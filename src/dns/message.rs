@@ -0,0 +1,385 @@
+//! DNS messages (RFC 1035): queries for the addresses of a name, and the
+//! answers to them.
+
+use std::convert::TryFrom;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+pub(crate) const TYPE_A: u16 = 1;
+pub(crate) const TYPE_AAAA: u16 = 28;
+const TYPE_OPT: u16 = 41;
+const CLASS_IN: u16 = 1;
+
+const FLAG_QR: u16 = 0x8000;
+const FLAG_TC: u16 = 0x0200;
+const FLAG_RD: u16 = 0x0100;
+
+pub(crate) const RCODE_NOERROR: u8 = 0;
+pub(crate) const RCODE_NXDOMAIN: u8 = 3;
+
+/// Largest UDP response asked for, as DNS Flag Day 2020 recommends.
+pub(crate) const UDP_PAYLOAD_LEN: u16 = 1232;
+
+const HEADER_LEN: usize = 12;
+
+/// A recursive query `id` for the `qtype` records of `name`, advertising
+/// [`UDP_PAYLOAD_LEN`] with EDNS.
+pub(crate) fn query(id: u16, name: &str, qtype: u16) -> io::Result<Vec<u8>> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidInput, "invalid domain name");
+    let name = name.strip_suffix('.').unwrap_or(name);
+    if name.is_empty() || name.len() > 253 {
+        return Err(invalid());
+    }
+    let mut message = Vec::with_capacity(HEADER_LEN + name.len() + 2 + 4 + 11);
+    for field in [id, FLAG_RD, 1, 0, 0, 1] {
+        message.extend_from_slice(&field.to_be_bytes());
+    }
+    for label in name.split('.') {
+        if label.is_empty() || label.len() > 63 {
+            return Err(invalid());
+        }
+        message.push(label.len() as u8);
+        message.extend_from_slice(label.as_bytes());
+    }
+    message.push(0);
+    message.extend_from_slice(&qtype.to_be_bytes());
+    message.extend_from_slice(&CLASS_IN.to_be_bytes());
+    // OPT: root name, type, payload size as class, no extended flags and
+    // no options.
+    message.push(0);
+    message.extend_from_slice(&TYPE_OPT.to_be_bytes());
+    message.extend_from_slice(&UDP_PAYLOAD_LEN.to_be_bytes());
+    message.extend_from_slice(&[0, 0, 0, 0, 0, 0]);
+    Ok(message)
+}
+
+/// What a response said.
+#[derive(Debug)]
+pub(crate) struct Answer {
+    pub(crate) rcode: u8,
+    /// Cut short to fit in a UDP datagram, to be asked again over TCP.
+    pub(crate) truncated: bool,
+    pub(crate) addrs: Vec<IpAddr>,
+    /// The lowest TTL of the answer records, in seconds, if any.
+    pub(crate) ttl: Option<u32>,
+}
+
+/// `response`, if it is a well-formed response to query `id`.
+pub(crate) fn parse(response: &[u8], id: u16) -> Option<Answer> {
+    let mut reader = Reader(response);
+    if reader.u16()? != id {
+        return None;
+    }
+    let flags = reader.u16()?;
+    if flags & FLAG_QR == 0 {
+        return None;
+    }
+    let questions = reader.u16()?;
+    let answers = reader.u16()?;
+    reader.take(4)?; // authority and additional counts
+    for _ in 0..questions {
+        reader.skip_name()?;
+        reader.take(4)?; // type, class
+    }
+
+    let mut answer = Answer {
+        rcode: (flags & 0xf) as u8,
+        truncated: flags & FLAG_TC != 0,
+        addrs: Vec::new(),
+        ttl: None,
+    };
+    // A truncated response may end mid-record.
+    for _ in 0..answers {
+        let record = reader.record();
+        let (rtype, class, ttl, data) = match record {
+            Some(record) => record,
+            None if answer.truncated => break,
+            None => return None,
+        };
+        if class != CLASS_IN {
+            continue;
+        }
+        // The answers to a name behind aliases are the aliases (CNAME
+        // records), then the addresses, and expire with the first of them.
+        answer.ttl = Some(answer.ttl.map_or(ttl, |lowest| lowest.min(ttl)));
+        match (rtype, data.len()) {
+            (TYPE_A, 4) => {
+                let octets = <[u8; 4]>::try_from(data).ok()?;
+                answer.addrs.push(Ipv4Addr::from(octets).into());
+            }
+            (TYPE_AAAA, 16) => {
+                let octets = <[u8; 16]>::try_from(data).ok()?;
+                answer.addrs.push(Ipv6Addr::from(octets).into());
+            }
+            _ => {}
+        }
+    }
+    Some(answer)
+}
+
+/// Reads big-endian fields off the front of a message.
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Option<&'a [u8]> {
+        if self.0.len() < n {
+            return None;
+        }
+        let (taken, rest) = self.0.split_at(n);
+        self.0 = rest;
+        Some(taken)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        Some(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        let bytes = self.take(2)?;
+        Some(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        let bytes = self.take(4)?;
+        Some(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    /// Skip a name: labels ending with the root, or with a pointer to the
+    /// rest of the name elsewhere in the message.
+    fn skip_name(&mut self) -> Option<()> {
+        loop {
+            let len = self.u8()?;
+            match len & 0xc0 {
+                0xc0 => {
+                    self.take(1)?;
+                    return Some(());
+                }
+                0 if len == 0 => return Some(()),
+                0 => {
+                    self.take(usize::from(len))?;
+                }
+                _ => return None,
+            }
+        }
+    }
+
+    /// A resource record: its type, class, TTL and data.
+    fn record(&mut self) -> Option<(u16, u16, u32, &'a [u8])> {
+        self.skip_name()?;
+        let rtype = self.u16()?;
+        let class = self.u16()?;
+        let ttl = self.u32()?;
+        let len = usize::from(self.u16()?);
+        Some((rtype, class, ttl, self.take(len)?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ID: u16 = 0x1234;
+    /// `www.example.com` in a question, at the offset of the first name.
+    const QUESTION_NAME: &[u8] = b"\x03www\x07example\x03com\x00";
+    /// A pointer to the name of the question.
+    const TO_QUESTION: &[u8] = &[0xc0, 0x0c];
+
+    /// A response to a question for the A records of `www.example.com`,
+    /// with `records`, each an owner name, type, class, TTL and data.
+    fn response(flags: u16, records: &[(&[u8], u16, u16, u32, &[u8])]) -> Vec<u8> {
+        let mut message = Vec::new();
+        for field in [ID, FLAG_QR | FLAG_RD | flags, 1, records.len() as u16, 0, 0] {
+            message.extend_from_slice(&field.to_be_bytes());
+        }
+        message.extend_from_slice(QUESTION_NAME);
+        message.extend_from_slice(&TYPE_A.to_be_bytes());
+        message.extend_from_slice(&CLASS_IN.to_be_bytes());
+        for (name, rtype, class, ttl, data) in records {
+            message.extend_from_slice(name);
+            message.extend_from_slice(&rtype.to_be_bytes());
+            message.extend_from_slice(&class.to_be_bytes());
+            message.extend_from_slice(&ttl.to_be_bytes());
+            message.extend_from_slice(&(data.len() as u16).to_be_bytes());
+            message.extend_from_slice(data);
+        }
+        message
+    }
+
+    fn answer(response: &[u8]) -> Option<Answer> {
+        parse(response, ID)
+    }
+
+    #[test]
+    fn queries() {
+        let query = query(ID, "www.Example.com.", TYPE_AAAA).unwrap();
+        let mut expected = vec![0x12, 0x34, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 1];
+        expected.extend_from_slice(b"\x03www\x07Example\x03com\x00");
+        expected.extend_from_slice(&[0, 28, 0, 1]);
+        expected.extend_from_slice(&[0, 0, 41, 0x04, 0xd0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(query, expected);
+
+        let long_label = "a".repeat(64);
+        let long_name = vec!["a".repeat(63); 4].join(".");
+        for name in ["", ".", "a..b", ".a", &long_label, &long_name] {
+            let err = super::query(ID, name, TYPE_A).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidInput, "{:?}", name);
+        }
+        assert!(super::query(ID, &long_name[..253], TYPE_A).is_ok());
+    }
+
+    #[test]
+    fn addresses() {
+        let v6 = [0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1];
+        let message = response(
+            0,
+            &[
+                (QUESTION_NAME, TYPE_A, CLASS_IN, 300, &[192, 0, 2, 1]),
+                (TO_QUESTION, TYPE_AAAA, CLASS_IN, 60, &v6),
+                // Malformed, and of another class: skipped.
+                (TO_QUESTION, TYPE_A, CLASS_IN, 600, &[192, 0, 2, 2, 0]),
+                (TO_QUESTION, TYPE_A, 3, 1, &[192, 0, 2, 3]),
+            ],
+        );
+        let answer = answer(&message).unwrap();
+        assert_eq!(answer.rcode, RCODE_NOERROR);
+        assert!(!answer.truncated);
+        assert_eq!(
+            answer.addrs,
+            [
+                IpAddr::from([192, 0, 2, 1]),
+                IpAddr::from(Ipv6Addr::from(v6))
+            ]
+        );
+        assert_eq!(answer.ttl, Some(60));
+    }
+
+    #[test]
+    fn aliases() {
+        let message = response(
+            0,
+            &[
+                (
+                    TO_QUESTION,
+                    5,
+                    CLASS_IN,
+                    30,
+                    b"\x03cdn\x07example\x03net\x00",
+                ),
+                (
+                    b"\x03cdn\x07example\x03net\x00",
+                    TYPE_A,
+                    CLASS_IN,
+                    300,
+                    &[192, 0, 2, 1],
+                ),
+            ],
+        );
+        let answer = answer(&message).unwrap();
+        assert_eq!(answer.addrs, [IpAddr::from([192, 0, 2, 1])]);
+        // The alias expires first.
+        assert_eq!(answer.ttl, Some(30));
+    }
+
+    #[test]
+    fn compressed_names() {
+        // Labels then a pointer, and pointers back to the question and to
+        // the header, which are skipped without being followed.
+        let message = response(
+            0,
+            &[
+                (b"\x04mail\xc0\x0c", TYPE_A, CLASS_IN, 300, &[192, 0, 2, 1]),
+                (TO_QUESTION, TYPE_A, CLASS_IN, 300, &[192, 0, 2, 2]),
+                (&[0xc0, 0x00], TYPE_A, CLASS_IN, 300, &[192, 0, 2, 3]),
+            ],
+        );
+        assert_eq!(answer(&message).unwrap().addrs.len(), 3);
+
+        // A pointer to itself, so a loop if followed.
+        let at = (HEADER_LEN + QUESTION_NAME.len() + 4) as u8;
+        let looped = response(0, &[(&[0xc0, at], TYPE_A, CLASS_IN, 300, &[192, 0, 2, 4])]);
+        assert_eq!(
+            answer(&looped).unwrap().addrs,
+            [IpAddr::from([192, 0, 2, 4])]
+        );
+
+        // Cut short, and the label types that aren't lengths or pointers.
+        for name in [&[0xc0][..], &[0x40, 0x00], &[0x80, 0x00], b"\x04mail"] {
+            let mut message = response(0, &[]);
+            message[7] = 1;
+            message.extend_from_slice(name);
+            assert!(answer(&message).is_none(), "{:02x?}", name);
+        }
+    }
+
+    #[test]
+    fn truncated() {
+        let records = [
+            (QUESTION_NAME, TYPE_A, CLASS_IN, 300, &[192, 0, 2, 1][..]),
+            (TO_QUESTION, TYPE_A, CLASS_IN, 300, &[192, 0, 2, 2]),
+        ];
+        let complete = response(FLAG_TC, &records);
+        let first_end = complete.len() - (2 + 10 + 4);
+        // Up to where the second record starts, then anywhere in it.
+        for len in first_end..complete.len() {
+            let answer = answer(&complete[..len]).unwrap();
+            assert!(answer.truncated);
+            assert_eq!(answer.addrs, [IpAddr::from([192, 0, 2, 1])]);
+        }
+        let answer = answer(&complete).unwrap();
+        assert_eq!(answer.addrs.len(), 2);
+
+        // Responses not marked as truncated must be whole.
+        let complete = response(0, &records);
+        for len in 0..complete.len() {
+            assert!(
+                super::parse(&complete[..len], ID).is_none(),
+                "{} bytes",
+                len
+            );
+        }
+    }
+
+    #[test]
+    fn malformed() {
+        let message = response(
+            0,
+            &[(QUESTION_NAME, TYPE_A, CLASS_IN, 300, &[192, 0, 2, 1])],
+        );
+        assert!(parse(&message, ID + 1).is_none());
+        // A query, not a response.
+        let mut query = message.clone();
+        query[2] &= !0x80;
+        assert!(answer(&query).is_none());
+        // More questions or answers than there are.
+        let mut questions = message.clone();
+        questions[5] = 2;
+        assert!(answer(&questions).is_none());
+        let mut answers = message.clone();
+        answers[6] = 0xff;
+        assert!(answer(&answers).is_none());
+
+        let nxdomain = response(RCODE_NXDOMAIN.into(), &[]);
+        let answer = answer(&nxdomain).unwrap();
+        assert_eq!(answer.rcode, RCODE_NXDOMAIN);
+        assert!(answer.addrs.is_empty());
+        assert_eq!(answer.ttl, None);
+    }
+
+    #[test]
+    fn corrupt() {
+        let message = response(
+            FLAG_TC,
+            &[
+                (QUESTION_NAME, TYPE_A, CLASS_IN, 300, &[192, 0, 2, 1]),
+                (b"\x04mail\xc0\x0c", TYPE_AAAA, CLASS_IN, 300, &[1; 16]),
+            ],
+        );
+        for i in 0..message.len() {
+            for flip in [0x01, 0x40, 0x80, 0xff] {
+                let mut corrupt = message.clone();
+                corrupt[i] ^= flip;
+                parse(&corrupt, ID);
+            }
+        }
+    }
+}
@@ -0,0 +1,476 @@
+//! Resolving names by asking DNS servers directly, over plain DNS, DNS
+//! over TLS or DNS over HTTPS, which tells how long the answers may be
+//! cached, and caching them.
+
+mod cache;
+mod hosts;
+#[cfg(feature = "tls")]
+mod https;
+mod message;
+mod stats;
+
+use std::collections::hash_map::RandomState;
+use std::fmt;
+use std::fs;
+use std::hash::{BuildHasher, Hasher};
+use std::io;
+#[cfg(feature = "tls")]
+use std::net::ToSocketAddrs;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+#[cfg(feature = "tls")]
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpStream, UdpSocket};
+use tokio::time::{self, Instant};
+
+use crate::resolver::{ResolveFuture, ResolveWithTtlFuture, Resolver};
+#[cfg(feature = "tls")]
+use crate::tls::TlsConnector;
+pub use cache::DnsCache;
+pub use hosts::StaticHosts;
+use message::{Answer, RCODE_NOERROR, RCODE_NXDOMAIN, TYPE_A, TYPE_AAAA, UDP_PAYLOAD_LEN};
+pub use stats::DnsStats;
+pub(crate) use stats::{DnsCounters, Timed};
+
+const DNS_PORT: u16 = 53;
+#[cfg(feature = "tls")]
+const DNS_OVER_TLS_PORT: u16 = 853;
+
+/// A [`Resolver`] sending queries to DNS servers, over UDP, and over TCP
+/// for answers too long for UDP, or encrypted, over TLS or HTTPS.
+///
+/// Unlike the system's resolver it knows the TTLs of answers, which a
+/// [`DnsCache`] in front of it honors, but it doesn't read the hosts file,
+/// see [`StaticHosts`] for that. Its servers, search domains and `ndots`
+/// are given in code, or read from `/etc/resolv.conf` by
+/// [`DnsResolver::from_resolv_conf`], so that deployments need not depend
+/// on the host's. Servers are asked in order, the next one when one fails
+/// or doesn't answer in time; a server saying the name doesn't exist is
+/// taken at its word.
+///
+/// The IPv4 (A) and IPv6 (AAAA) addresses of a name are asked for at once,
+/// on every transport, so a lookup takes a single round trip rather than
+/// two. The addresses are returned IPv6 first, and ordered for dialing by
+/// the server's [`AddressFamilyPolicy`](crate::AddressFamilyPolicy).
+///
+/// DNS over TLS and DNS over HTTPS need the `tls` feature, and a
+/// `TlsConnector` to run the handshakes. Every lookup makes a new
+/// connection, so put a [`DnsCache`] in front.
+///
+/// ```no_run
+/// # async fn run() -> std::io::Result<()> {
+/// use std::time::Duration;
+/// use socks5_rs::{DnsCache, DnsResolver, Server};
+///
+/// let resolver = DnsResolver::new(["10.0.0.53:53".parse().unwrap()])
+///     .with_search(["svc.cluster.local", "cluster.local"])
+///     .with_ndots(2)
+///     .with_timeout(Duration::from_secs(1));
+/// Server::bind("0.0.0.0:1080")
+///     .await?
+///     .with_resolver(resolver)
+///     .with_dns_cache(DnsCache::new())
+///     .serve()
+///     .await?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone, Debug)]
+pub struct DnsResolver {
+    servers: Vec<SocketAddr>,
+    transport: Transport,
+    /// Domains appended to relative names, in order.
+    search: Vec<String>,
+    /// Names with fewer dots are tried with the search domains first.
+    ndots: usize,
+    timeout: Duration,
+}
+
+#[derive(Clone)]
+enum Transport {
+    Udp,
+    #[cfg(feature = "tls")]
+    Tls {
+        server_name: String,
+        connector: Arc<dyn TlsConnector>,
+    },
+    #[cfg(feature = "tls")]
+    Https {
+        url: https::HttpsUrl,
+        connector: Arc<dyn TlsConnector>,
+    },
+}
+
+impl fmt::Debug for Transport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Transport::Udp => f.write_str("Udp"),
+            #[cfg(feature = "tls")]
+            Transport::Tls { server_name, .. } => f
+                .debug_struct("Tls")
+                .field("server_name", server_name)
+                .finish(),
+            #[cfg(feature = "tls")]
+            Transport::Https { url, .. } => f.debug_struct("Https").field("url", url).finish(),
+        }
+    }
+}
+
+impl DnsResolver {
+    /// Ask `servers`, in order.
+    pub fn new(servers: impl IntoIterator<Item = SocketAddr>) -> Self {
+        DnsResolver {
+            servers: servers.into_iter().collect(),
+            transport: Transport::Udp,
+            search: Vec::new(),
+            ndots: 1,
+            timeout: Duration::from_secs(2),
+        }
+    }
+
+    /// Ask `servers` over TLS, on port 853, with
+    /// `connector` checking that they are `server_name`.
+    ///
+    /// ```
+    /// # fn run(connector: std::sync::Arc<dyn socks5_rs::TlsConnector>) {
+    /// use socks5_rs::DnsResolver;
+    ///
+    /// let resolver = DnsResolver::tls(
+    ///     ["9.9.9.9".parse().unwrap(), "149.112.112.112".parse().unwrap()],
+    ///     "dns.quad9.net",
+    ///     connector,
+    /// );
+    /// # }
+    /// ```
+    #[cfg(feature = "tls")]
+    pub fn tls(
+        servers: impl IntoIterator<Item = IpAddr>,
+        server_name: impl Into<String>,
+        connector: Arc<dyn TlsConnector>,
+    ) -> Self {
+        let servers = servers
+            .into_iter()
+            .map(|ip| SocketAddr::new(ip, DNS_OVER_TLS_PORT));
+        DnsResolver {
+            transport: Transport::Tls {
+                server_name: server_name.into(),
+                connector,
+            },
+            ..DnsResolver::new(servers)
+        }
+    }
+
+    /// Ask the DNS over HTTPS server at `url`, such as
+    /// `https://dns.google/dns-query`, with `connector`.
+    ///
+    /// The URL's host is resolved now, by the system's resolver, and its
+    /// addresses asked in order.
+    #[cfg(feature = "tls")]
+    pub fn https(url: &str, connector: Arc<dyn TlsConnector>) -> io::Result<Self> {
+        let url = https::HttpsUrl::parse(url)?;
+        let servers = (url.host.as_str(), url.port).to_socket_addrs()?;
+        Ok(DnsResolver {
+            transport: Transport::Https { url, connector },
+            ..DnsResolver::new(servers)
+        })
+    }
+
+    /// Ask the nameservers of `/etc/resolv.conf`, or the local one if it
+    /// lists none, with its search domains and `ndots` option.
+    pub fn from_resolv_conf() -> io::Result<Self> {
+        let conf = fs::read_to_string("/etc/resolv.conf")?;
+        let mut servers = Vec::new();
+        let mut search = Vec::new();
+        let mut ndots = None;
+        for line in conf.lines() {
+            let mut fields = line.split_whitespace();
+            match fields.next() {
+                Some("nameserver") => {
+                    let ip = fields.next().and_then(|ip| ip.parse::<IpAddr>().ok());
+                    servers.extend(ip.map(|ip| SocketAddr::new(ip, DNS_PORT)));
+                }
+                // The last of `search` and `domain` wins.
+                Some("search") | Some("domain") => search = fields.map(str::to_owned).collect(),
+                Some("options") => {
+                    let option = fields.find_map(|option| option.strip_prefix("ndots:"));
+                    ndots = option.and_then(|n| n.parse().ok()).or(ndots);
+                }
+                _ => {}
+            }
+        }
+        if servers.is_empty() {
+            servers.push(SocketAddr::from((Ipv4Addr::LOCALHOST, DNS_PORT)));
+        }
+        let resolver = DnsResolver::new(servers).with_search(search);
+        Ok(match ndots {
+            Some(ndots) => resolver.with_ndots(ndots),
+            None => resolver,
+        })
+    }
+
+    /// Try relative names under `domains` too, in order, like the `search`
+    /// line of `resolv.conf`. None by default.
+    pub fn with_search<I>(mut self, domains: I) -> Self
+    where
+        I: IntoIterator,
+        I::Item: Into<String>,
+    {
+        self.search = domains
+            .into_iter()
+            .map(Into::into)
+            .map(|domain| domain.trim_matches('.').to_owned())
+            .filter(|domain| !domain.is_empty())
+            .collect();
+        self
+    }
+
+    /// Try names with at least `ndots` dots as given before under the
+    /// search domains, and others under the search domains first, like the
+    /// `ndots` option of `resolv.conf`. 1 by default, capped at 15.
+    pub fn with_ndots(mut self, ndots: usize) -> Self {
+        self.ndots = ndots.min(15);
+        self
+    }
+
+    /// How long to wait for a server to answer before asking the next, 2
+    /// seconds by default.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// The addresses of `domain`, IPv6 first, and the TTL of the answers.
+    async fn lookup(&self, domain: &str) -> io::Result<(Vec<IpAddr>, Option<Duration>)> {
+        if let Ok(ip) = domain.parse::<IpAddr>() {
+            return Ok((vec![ip], None));
+        }
+        let mut error = None;
+        for name in self.candidates(domain) {
+            match self.lookup_name(&name).await {
+                Err(e) if e.kind() == io::ErrorKind::NotFound => error = Some(e),
+                looked_up => return looked_up,
+            }
+        }
+        Err(error.unwrap_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no such domain")))
+    }
+
+    /// The names to look up for `domain`, in order: as given, and under
+    /// each search domain unless it ends with a dot.
+    fn candidates(&self, domain: &str) -> Vec<String> {
+        if let Some(absolute) = domain.strip_suffix('.') {
+            return vec![absolute.to_owned()];
+        }
+        let searched = self
+            .search
+            .iter()
+            .map(|search| format!("{}.{}", domain, search));
+        if domain.matches('.').count() >= self.ndots {
+            std::iter::once(domain.to_owned()).chain(searched).collect()
+        } else {
+            searched.chain(std::iter::once(domain.to_owned())).collect()
+        }
+    }
+
+    /// The addresses of the fully qualified `domain`, from the first server
+    /// to answer.
+    async fn lookup_name(&self, domain: &str) -> io::Result<(Vec<IpAddr>, Option<Duration>)> {
+        let mut error = io::Error::new(io::ErrorKind::NotFound, "no DNS servers");
+        for &server in &self.servers {
+            match self.ask(server, domain).await {
+                Ok(found) => return Ok(found),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => return Err(e),
+                Err(e) => {
+                    log::debug!("DNS server {} failed for {}: {}", server, domain, e);
+                    error = e;
+                }
+            }
+        }
+        Err(error)
+    }
+
+    /// Ask `server` for the IPv6 and IPv4 addresses of `domain` at once.
+    async fn ask(
+        &self,
+        server: SocketAddr,
+        domain: &str,
+    ) -> io::Result<(Vec<IpAddr>, Option<Duration>)> {
+        let id = random_id();
+        let ids = [id, id.wrapping_add(1)];
+        let queries = [
+            message::query(ids[0], domain, TYPE_AAAA)?,
+            message::query(ids[1], domain, TYPE_A)?,
+        ];
+        let exchange = self.exchange(server, &queries, &ids);
+        let answers = time::timeout_at(Instant::now() + self.timeout, exchange)
+            .await
+            .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "DNS server timed out"))??;
+
+        let mut addrs = Vec::new();
+        let mut ttl: Option<u32> = None;
+        for mut answer in answers {
+            match answer.rcode {
+                RCODE_NOERROR => {}
+                RCODE_NXDOMAIN => {
+                    return Err(io::Error::new(io::ErrorKind::NotFound, "no such domain"))
+                }
+                rcode => {
+                    return Err(io::Error::other(format!(
+                        "DNS server answered with error {}",
+                        rcode
+                    )))
+                }
+            }
+            addrs.append(&mut answer.addrs);
+            ttl = match (ttl, answer.ttl) {
+                (Some(a), Some(b)) => Some(a.min(b)),
+                (a, b) => a.or(b),
+            };
+        }
+        if addrs.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::NotFound, "no addresses"));
+        }
+        Ok((addrs, ttl.map(|ttl| Duration::from_secs(ttl.into()))))
+    }
+
+    /// The answers of `server` to `queries`, with `ids`, in order.
+    async fn exchange(
+        &self,
+        server: SocketAddr,
+        queries: &[Vec<u8>],
+        ids: &[u16],
+    ) -> io::Result<Vec<Answer>> {
+        match &self.transport {
+            Transport::Udp => {
+                let mut answers = exchange_udp(server, queries, ids).await?;
+                // Ask again for all truncated answers at once, on a single
+                // connection.
+                let truncated: Vec<usize> = (0..answers.len())
+                    .filter(|&i| answers[i].truncated)
+                    .collect();
+                if !truncated.is_empty() {
+                    let queries: Vec<Vec<u8>> =
+                        truncated.iter().map(|&i| queries[i].clone()).collect();
+                    let ids: Vec<u16> = truncated.iter().map(|&i| ids[i]).collect();
+                    let stream = TcpStream::connect(server).await?;
+                    let retried = exchange_stream(stream, &queries, &ids).await?;
+                    for (i, answer) in truncated.into_iter().zip(retried) {
+                        answers[i] = answer;
+                    }
+                }
+                Ok(answers)
+            }
+            #[cfg(feature = "tls")]
+            Transport::Tls {
+                server_name,
+                connector,
+            } => {
+                let stream = TcpStream::connect(server).await?;
+                let stream = connector.connect(server_name, stream).await?;
+                exchange_stream(stream, queries, ids).await
+            }
+            #[cfg(feature = "tls")]
+            Transport::Https { url, connector } => {
+                https::exchange(url, &**connector, server, queries, ids).await
+            }
+        }
+    }
+}
+
+impl Resolver for DnsResolver {
+    fn resolve<'a>(&'a self, domain: &'a str, port: u16) -> ResolveFuture<'a> {
+        Box::pin(async move {
+            let (ips, _) = self.lookup(domain).await?;
+            Ok(ips
+                .into_iter()
+                .map(|ip| SocketAddr::new(ip, port))
+                .collect())
+        })
+    }
+
+    fn resolve_with_ttl<'a>(&'a self, domain: &'a str, port: u16) -> ResolveWithTtlFuture<'a> {
+        Box::pin(async move {
+            let (ips, ttl) = self.lookup(domain).await?;
+            let addrs = ips.into_iter().map(|ip| SocketAddr::new(ip, port));
+            Ok((addrs.collect(), ttl))
+        })
+    }
+}
+
+/// Send `queries` to `server` in datagrams, and match the answers to `ids`.
+async fn exchange_udp(
+    server: SocketAddr,
+    queries: &[Vec<u8>],
+    ids: &[u16],
+) -> io::Result<Vec<Answer>> {
+    let local: SocketAddr = if server.is_ipv4() {
+        (Ipv4Addr::UNSPECIFIED, 0).into()
+    } else {
+        (Ipv6Addr::UNSPECIFIED, 0).into()
+    };
+    let socket = UdpSocket::bind(local).await?;
+    socket.connect(server).await?;
+    for query in queries {
+        socket.send(query).await?;
+    }
+    let mut answers: Vec<Option<Answer>> = ids.iter().map(|_| None).collect();
+    let mut buf = vec![0; usize::from(UDP_PAYLOAD_LEN)];
+    while answers.iter().any(Option::is_none) {
+        let received = socket.recv(&mut buf).await?;
+        match_answer(&mut answers, ids, &buf[..received]);
+    }
+    Ok(answers.into_iter().flatten().collect())
+}
+
+/// Send `queries` over `stream`, each after its length as DNS over TCP and
+/// TLS do, and match the answers, which may come in any order, to `ids`.
+async fn exchange_stream<S: AsyncRead + AsyncWrite + Unpin>(
+    mut stream: S,
+    queries: &[Vec<u8>],
+    ids: &[u16],
+) -> io::Result<Vec<Answer>> {
+    let mut framed = Vec::new();
+    for query in queries {
+        framed.extend_from_slice(&(query.len() as u16).to_be_bytes());
+        framed.extend_from_slice(query);
+    }
+    stream.write_all(&framed).await?;
+    let mut answers: Vec<Option<Answer>> = ids.iter().map(|_| None).collect();
+    while answers.iter().any(Option::is_none) {
+        let len = stream.read_u16().await?;
+        let mut response = vec![0; usize::from(len)];
+        stream.read_exact(&mut response).await?;
+        if !match_answer(&mut answers, ids, &response) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "bad DNS response",
+            ));
+        }
+    }
+    Ok(answers.into_iter().flatten().collect())
+}
+
+/// Parse `response` as the answer of the first unanswered query of `ids`
+/// it answers. Returns whether it does.
+fn match_answer(answers: &mut [Option<Answer>], ids: &[u16], response: &[u8]) -> bool {
+    for (answer, &id) in answers.iter_mut().zip(ids) {
+        if answer.is_none() {
+            *answer = message::parse(response, id);
+            if answer.is_some() {
+                return true;
+            }
+        }
+    }
+    false
+}
+
+/// An unpredictable query ID, so that off-path attackers can't forge
+/// answers.
+fn random_id() -> u16 {
+    let mut hasher = RandomState::new().build_hasher();
+    if let Ok(now) = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH) {
+        hasher.write_u128(now.as_nanos());
+    }
+    hasher.finish() as u16
+}
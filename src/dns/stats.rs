@@ -0,0 +1,147 @@
+//! Counting how target names get resolved, and how long the resolver takes.
+
+use std::convert::TryFrom;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+use crate::resolver::{ResolveFuture, ResolveWithTtlFuture, Resolver};
+
+/// How target names were resolved, see [`Server::dns_stats`].
+///
+/// Latencies are those of the lookups the resolver made, cache hits
+/// excluded, since the server started. They are rounded up to the bounds
+/// of 1, 2 and 5 times powers of ten milliseconds, from 1 millisecond to
+/// 10 seconds; slower lookups are counted as taking 10 seconds.
+///
+/// [`Server::dns_stats`]: crate::Server::dns_stats
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct DnsStats {
+    /// Names found in the static hosts.
+    pub static_hits: u64,
+    /// Names answered from the DNS cache, failures included.
+    pub cache_hits: u64,
+    /// Names the DNS cache asked the resolver for.
+    pub cache_misses: u64,
+    /// Lookups made by the resolver.
+    pub queries: u64,
+    /// Lookups that failed, including those that timed out.
+    pub failures: u64,
+    /// Lookups given up after the resolve timeout.
+    pub timeouts: u64,
+    /// The median lookup latency, if any lookup was made.
+    pub latency_p50: Option<Duration>,
+    /// The 90th percentile lookup latency.
+    pub latency_p90: Option<Duration>,
+    /// The 99th percentile lookup latency.
+    pub latency_p99: Option<Duration>,
+}
+
+/// Upper bounds of the latency buckets, in milliseconds.
+const BUCKETS: [u64; 14] = [
+    1,
+    2,
+    5,
+    10,
+    20,
+    50,
+    100,
+    200,
+    500,
+    1_000,
+    2_000,
+    5_000,
+    10_000,
+    u64::MAX,
+];
+
+/// The counters behind [`DnsStats`], shared by clones of a config.
+#[derive(Debug, Default)]
+pub(crate) struct DnsCounters {
+    pub(crate) static_hits: AtomicU64,
+    queries: AtomicU64,
+    failures: AtomicU64,
+    timeouts: AtomicU64,
+    latencies: [AtomicU64; BUCKETS.len()],
+}
+
+impl DnsCounters {
+    pub(crate) fn timed_out(&self) {
+        self.timeouts.fetch_add(1, Ordering::Relaxed);
+        self.failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn record(&self, latency: Duration, failed: bool) {
+        self.queries.fetch_add(1, Ordering::Relaxed);
+        if failed {
+            self.failures.fetch_add(1, Ordering::Relaxed);
+        }
+        let millis = u64::try_from(latency.as_millis()).unwrap_or(u64::MAX);
+        let bucket = BUCKETS.iter().position(|&bound| millis < bound);
+        let bucket = bucket.unwrap_or(BUCKETS.len() - 1);
+        self.latencies[bucket].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// The stats, with the cache's hits and misses.
+    pub(crate) fn stats(&self, cache_hits: u64, cache_misses: u64) -> DnsStats {
+        let latencies: Vec<u64> = self
+            .latencies
+            .iter()
+            .map(|count| count.load(Ordering::Relaxed))
+            .collect();
+        let total: u64 = latencies.iter().sum();
+        let percentile = |percent: u64| {
+            if total == 0 {
+                return None;
+            }
+            // The rank of the percentile, rounded up.
+            let rank = (total * percent).div_ceil(100).max(1);
+            let mut seen = 0;
+            for (count, &bound) in latencies.iter().zip(&BUCKETS) {
+                seen += count;
+                if seen >= rank {
+                    return Some(Duration::from_millis(bound.min(10_000)));
+                }
+            }
+            None
+        };
+        DnsStats {
+            static_hits: self.static_hits.load(Ordering::Relaxed),
+            cache_hits,
+            cache_misses,
+            queries: self.queries.load(Ordering::Relaxed),
+            failures: self.failures.load(Ordering::Relaxed),
+            timeouts: self.timeouts.load(Ordering::Relaxed),
+            latency_p50: percentile(50),
+            latency_p90: percentile(90),
+            latency_p99: percentile(99),
+        }
+    }
+}
+
+/// A resolver counting the lookups of the one it wraps, and timing them.
+pub(crate) struct Timed<'a> {
+    pub(crate) resolver: &'a dyn Resolver,
+    pub(crate) counters: &'a DnsCounters,
+}
+
+impl Resolver for Timed<'_> {
+    fn resolve<'a>(&'a self, domain: &'a str, port: u16) -> ResolveFuture<'a> {
+        Box::pin(async move {
+            let started = Instant::now();
+            let resolved = self.resolver.resolve(domain, port).await;
+            self.counters.record(started.elapsed(), resolved.is_err());
+            resolved
+        })
+    }
+
+    fn resolve_with_ttl<'a>(&'a self, domain: &'a str, port: u16) -> ResolveWithTtlFuture<'a> {
+        Box::pin(async move {
+            let started = Instant::now();
+            let resolved = self.resolver.resolve_with_ttl(domain, port).await;
+            self.counters.record(started.elapsed(), resolved.is_err());
+            resolved
+        })
+    }
+}
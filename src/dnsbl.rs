@@ -0,0 +1,262 @@
+//! DNS blocklists (DNSBLs) of destination IPs: zones like
+//! `zen.spamhaus.org`, where an IP is listed if the name of its reversed
+//! address in the zone resolves.
+
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::time::{self, Instant};
+
+use crate::error::Socks5Error;
+use crate::protocol::{Reply, TargetAddr};
+
+/// Entries kept before stale ones are first swept out.
+const MIN_SWEEP: usize = 1024;
+
+/// What becomes of targets a [`Dnsbl`] lists.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DnsblAction {
+    /// Their addresses aren't connected to, and targets with only listed
+    /// addresses are refused.
+    #[default]
+    Deny,
+    /// They are logged, and connected to all the same.
+    Flag,
+}
+
+/// DNS blocklists to look the IPs of targets up in, see
+/// [`Server::with_dnsbl`].
+///
+/// Lookups go through the system's resolver. Those of the zones are made
+/// together, and given up on after the timeout, in which case the IP is
+/// taken as unlisted. Answers are cached, listed or not, and clones share
+/// the cache. Only public IPs are looked up.
+///
+/// Zones answer with an address in `127.0.0.0/8` for listed IPs, except
+/// for `127.255.255.0/24`, which some use to report errors, such as queries
+/// coming through public resolvers they refuse.
+///
+/// ```no_run
+/// # async fn run() -> std::io::Result<()> {
+/// use std::time::Duration;
+/// use socks5_rs::{Dnsbl, DnsblAction, Server};
+///
+/// let dnsbl = Dnsbl::new(["zen.spamhaus.org", "bl.spamcop.net"])
+///     .with_action(DnsblAction::Flag)
+///     .with_timeout(Duration::from_millis(500));
+/// Server::bind("0.0.0.0:1080")
+///     .await?
+///     .with_dnsbl(dnsbl)
+///     .serve()
+///     .await?;
+/// # Ok(())
+/// # }
+/// ```
+///
+/// [`Server::with_dnsbl`]: crate::Server::with_dnsbl
+#[derive(Clone)]
+pub struct Dnsbl {
+    zones: Vec<Arc<str>>,
+    action: DnsblAction,
+    timeout: Duration,
+    cache_ttl: Duration,
+    cache: Arc<Mutex<Cache>>,
+}
+
+#[derive(Default)]
+struct Cache {
+    /// IP -> the zone listing it, if any, and when that expires.
+    map: HashMap<IpAddr, (Option<Arc<str>>, Instant)>,
+    /// Size of the map at which stale entries are next swept out.
+    sweep_at: usize,
+}
+
+impl Dnsbl {
+    /// Look IPs up in `zones`, e.g. `zen.spamhaus.org`.
+    pub fn new(zones: impl IntoIterator<Item = impl Into<String>>) -> Self {
+        Dnsbl {
+            zones: zones
+                .into_iter()
+                .map(|zone| {
+                    let zone = zone.into();
+                    Arc::from(zone.trim_end_matches('.'))
+                })
+                .collect(),
+            action: DnsblAction::Deny,
+            timeout: Duration::from_secs(1),
+            cache_ttl: Duration::from_secs(10 * 60),
+            cache: Arc::default(),
+        }
+    }
+
+    /// What becomes of listed targets, denied by default.
+    pub fn with_action(mut self, action: DnsblAction) -> Self {
+        self.action = action;
+        self
+    }
+
+    /// How long a request may wait on lookups, 1 second by default.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// How long answers are cached, 10 minutes by default.
+    pub fn with_cache_ttl(mut self, ttl: Duration) -> Self {
+        self.cache_ttl = ttl;
+        self
+    }
+
+    /// `addrs` of `target`, without those listed when denying them. Fails
+    /// if all are.
+    pub(crate) async fn filter(
+        &self,
+        target: &TargetAddr,
+        addrs: Vec<SocketAddr>,
+    ) -> Result<Vec<SocketAddr>, Socks5Error> {
+        let deadline = Instant::now() + self.timeout;
+        let mut permitted = Vec::with_capacity(addrs.len());
+        for addr in &addrs {
+            let zone = match self.listing(addr.ip(), deadline).await {
+                Some(zone) => zone,
+                None => {
+                    permitted.push(*addr);
+                    continue;
+                }
+            };
+            match self.action {
+                DnsblAction::Deny => {
+                    log::info!("{} of {} is listed by {}", addr.ip(), target, zone)
+                }
+                DnsblAction::Flag => {
+                    log::warn!("{} of {} is listed by {}", addr.ip(), target, zone);
+                    permitted.push(*addr);
+                }
+            }
+        }
+        if permitted.is_empty() && !addrs.is_empty() {
+            return Err(Socks5Error::Blocked {
+                target: target.clone(),
+                reply: Reply::NotAllowed,
+            });
+        }
+        Ok(permitted)
+    }
+
+    /// The zone listing `ip`, if any, looked up until `deadline`.
+    async fn listing(&self, ip: IpAddr, deadline: Instant) -> Option<Arc<str>> {
+        if self.zones.is_empty() || !is_public(ip) {
+            return None;
+        }
+        let now = Instant::now();
+        if let Some((zone, expires)) = self.cache.lock().unwrap().map.get(&ip) {
+            if *expires > now {
+                return zone.clone();
+            }
+        }
+
+        let lookups: Vec<_> = self
+            .zones
+            .iter()
+            .map(|zone| tokio::spawn(is_listed(query_name(ip, zone))))
+            .collect();
+        let mut listing = None;
+        for (zone, lookup) in self.zones.iter().zip(lookups) {
+            match time::timeout_at(deadline, lookup).await {
+                Ok(Ok(true)) => {
+                    listing = Some(zone.clone());
+                    break;
+                }
+                Ok(_) => {}
+                // Out of time: take it as unlisted, without caching that.
+                Err(_) => {
+                    log::debug!("DNSBL lookups of {} timed out", ip);
+                    return None;
+                }
+            }
+        }
+
+        let mut cache = self.cache.lock().unwrap();
+        let now = Instant::now();
+        cache
+            .map
+            .insert(ip, (listing.clone(), now + self.cache_ttl));
+        if cache.map.len() >= cache.sweep_at {
+            cache.map.retain(|_, (_, expires)| *expires > now);
+            cache.sweep_at = (cache.map.len() * 2).max(MIN_SWEEP);
+        }
+        listing
+    }
+}
+
+/// Whether `name` resolves to a listing: an address in `127.0.0.0/8`
+/// outside `127.255.255.0/24`.
+async fn is_listed(name: String) -> bool {
+    let addrs = match tokio::net::lookup_host((name.as_str(), 0)).await {
+        Ok(addrs) => addrs,
+        // Mostly NXDOMAIN, for IPs that aren't listed.
+        Err(_) => return false,
+    };
+    let mut listed = false;
+    for addr in addrs {
+        if let IpAddr::V4(ip) = addr.ip() {
+            let [a, b, c, _] = ip.octets();
+            if (a, b, c) == (127, 255, 255) {
+                log::warn!("DNSBL lookup of {} failed with {}", name, ip);
+            } else if a == 127 {
+                listed = true;
+            }
+        }
+    }
+    listed
+}
+
+/// The name `ip` is listed under in `zone`: its octets, or the nibbles of
+/// an IPv6 address, in reverse.
+fn query_name(ip: IpAddr, zone: &str) -> String {
+    let mut name = String::new();
+    match ip {
+        IpAddr::V4(ip) => {
+            for octet in ip.octets().iter().rev() {
+                name.push_str(&format!("{}.", octet));
+            }
+        }
+        IpAddr::V6(ip) => {
+            for byte in ip.octets().iter().rev() {
+                name.push_str(&format!("{:x}.{:x}.", byte & 0xf, byte >> 4));
+            }
+        }
+    }
+    name.push_str(zone);
+    name
+}
+
+/// Whether `ip` is reachable on the internet, and so may be listed.
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_public_v4(ip),
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_public_v4(ip),
+            None => {
+                let first = ip.segments()[0];
+                !(ip.is_loopback()
+                    || ip.is_unspecified()
+                    || ip.is_multicast()
+                    // Unique local, fc00::/7, and link-local, fe80::/10.
+                    || first & 0xfe00 == 0xfc00
+                    || first & 0xffc0 == 0xfe80)
+            }
+        },
+    }
+}
+
+fn is_public_v4(ip: Ipv4Addr) -> bool {
+    !(ip.is_private()
+        || ip.is_loopback()
+        || ip.is_link_local()
+        || ip.is_unspecified()
+        || ip.is_broadcast()
+        || ip.is_multicast())
+}
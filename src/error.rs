@@ -0,0 +1,122 @@
+//! [`Socks5Error`], why serving a connection failed.
+
+use std::io;
+
+use crate::protocol::{Command, ProtocolError, Reply, TargetAddr};
+
+/// Why serving a client connection failed, as returned by
+/// [`handle_connection`] and [`handle_stream`].
+///
+/// [`Socks5Error::reply`] gives the SOCKS5 reply code reporting the failure
+/// to the client, where there is one.
+///
+/// [`handle_connection`]: crate::handle_connection
+/// [`handle_stream`]: crate::handle_stream
+#[derive(thiserror::Error, Debug)]
+#[non_exhaustive]
+pub enum Socks5Error {
+    /// Reading from or writing to the client failed, e.g. because it went
+    /// away during the handshake.
+    #[error("IO error: {0}")]
+    Io(#[from] io::Error),
+    /// The client sent a malformed message.
+    #[error(transparent)]
+    Protocol(#[from] ProtocolError),
+
+    /// A handshake phase wasn't over within its timeout.
+    #[error("handshake timed out")]
+    Timeout,
+    /// The client offered no method it may use.
+    #[error("no acceptable authentication method")]
+    NoAcceptableMethod,
+    /// The client failed to authenticate.
+    #[error("authentication failed")]
+    AuthFailed,
+    /// The request isn't allowed, e.g. SOCKS4 when SOCKS5 clients must
+    /// authenticate.
+    #[error("request not allowed")]
+    NotAllowed,
+    /// The request is valid but its command isn't served.
+    #[error("{0:?} is not supported")]
+    CommandNotSupported(Command),
+
+    /// The target is blocked by the destination or port ACL, and refused
+    /// with `reply`.
+    #[error("{target} is blocked")]
+    Blocked { target: TargetAddr, reply: Reply },
+    /// The target's domain name couldn't be resolved.
+    #[error("resolving {target} failed: {source}")]
+    Resolve {
+        target: TargetAddr,
+        source: io::Error,
+    },
+    /// Connecting to the target failed.
+    #[error("connecting to {target} failed: {source}")]
+    Connect {
+        target: TargetAddr,
+        source: io::Error,
+    },
+    /// The upstream proxy a rule routes the target through refused it with
+    /// `reply`.
+    #[error("upstream proxy refused {target}: {reply:?}")]
+    Upstream { target: TargetAddr, reply: Reply },
+    /// Relaying between client and target failed, after the request was
+    /// granted.
+    #[error("relay failed: {0}")]
+    Relay(io::Error),
+}
+
+impl Socks5Error {
+    /// The reply code reporting this failure to a client whose request it
+    /// fails, or `None` for failures outside of the request phase.
+    pub fn reply(&self) -> Option<Reply> {
+        match self {
+            Socks5Error::Protocol(ProtocolError::AddressTypeNotSupported(_)) => {
+                Some(Reply::AddressTypeNotSupported)
+            }
+            Socks5Error::Protocol(ProtocolError::CommandNotSupported(_))
+            | Socks5Error::CommandNotSupported(_) => Some(Reply::CommandNotSupported),
+            Socks5Error::Protocol(_) => Some(Reply::GeneralFailure),
+            Socks5Error::NotAllowed => Some(Reply::NotAllowed),
+            Socks5Error::Blocked { reply, .. } => Some(*reply),
+            Socks5Error::Resolve { .. } => Some(Reply::HostUnreachable),
+            Socks5Error::Connect { source, .. } => Some(Reply::from(source)),
+            Socks5Error::Upstream { reply, .. } => Some(*reply),
+            Socks5Error::Io(_)
+            | Socks5Error::Timeout
+            | Socks5Error::NoAcceptableMethod
+            | Socks5Error::AuthFailed
+            | Socks5Error::Relay(_) => None,
+        }
+    }
+
+    /// Whether the target was blocked, by an ACL or rule.
+    pub(crate) fn is_blocked(&self) -> bool {
+        matches!(self, Socks5Error::Blocked { .. })
+    }
+
+    fn kind(&self) -> io::ErrorKind {
+        match self {
+            Socks5Error::Io(e) | Socks5Error::Relay(e) => e.kind(),
+            Socks5Error::Protocol(_) => io::ErrorKind::InvalidData,
+            Socks5Error::Timeout => io::ErrorKind::TimedOut,
+            Socks5Error::NoAcceptableMethod
+            | Socks5Error::AuthFailed
+            | Socks5Error::NotAllowed
+            | Socks5Error::Blocked { .. } => io::ErrorKind::PermissionDenied,
+            Socks5Error::CommandNotSupported(_) => io::ErrorKind::Unsupported,
+            Socks5Error::Resolve { .. } => io::ErrorKind::NotFound,
+            Socks5Error::Connect { source, .. } => source.kind(),
+            Socks5Error::Upstream { .. } => io::ErrorKind::ConnectionRefused,
+        }
+    }
+}
+
+impl From<Socks5Error> for io::Error {
+    fn from(e: Socks5Error) -> io::Error {
+        match e {
+            Socks5Error::Io(e) | Socks5Error::Relay(e) => e,
+            e => io::Error::new(e.kind(), e),
+        }
+    }
+}
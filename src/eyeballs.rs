@@ -0,0 +1,72 @@
+//! Happy Eyeballs (RFC 8305): connecting to the addresses of a target in
+//! staggered, overlapping attempts, so that a broken address or family
+//! delays the connection by a moment rather than by a connect timeout.
+
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use tokio::task::JoinSet;
+use tokio::time;
+
+/// Connect to one of `addrs` with `connect`, starting the next attempt
+/// after `delay`, or as soon as the previous one fails. Addresses are tried
+/// alternating between families, starting with that of the first. The
+/// first attempt to connect wins and the others are aborted.
+pub(crate) async fn connect<F, Fut, S>(
+    addrs: &[SocketAddr],
+    delay: Duration,
+    connect: F,
+) -> io::Result<S>
+where
+    F: Fn(SocketAddr) -> Fut,
+    Fut: Future<Output = io::Result<S>> + Send + 'static,
+    S: Send + 'static,
+{
+    let mut pending = interleave(addrs).into_iter();
+    let mut next = pending.next();
+    let mut attempts = JoinSet::new();
+    let mut last_error = None;
+    loop {
+        if let Some(addr) = next.take() {
+            attempts.spawn(connect(addr));
+        }
+        if attempts.is_empty() {
+            return Err(last_error.unwrap_or_else(|| {
+                io::Error::new(io::ErrorKind::AddrNotAvailable, "no address to connect to")
+            }));
+        }
+        let more = pending.len() > 0;
+        tokio::select! {
+            Some(joined) = attempts.join_next() => match joined {
+                Ok(Ok(stream)) => return Ok(stream),
+                Ok(Err(e)) => {
+                    last_error = Some(e);
+                    next = pending.next();
+                }
+                Err(e) => {
+                    last_error = Some(io::Error::other(e));
+                    next = pending.next();
+                }
+            },
+            _ = time::sleep(delay), if more => next = pending.next(),
+        }
+    }
+}
+
+/// `addrs` alternating between families, starting with that of the first,
+/// each family in its original order.
+fn interleave(addrs: &[SocketAddr]) -> Vec<SocketAddr> {
+    let first_v6 = addrs.first().is_some_and(SocketAddr::is_ipv6);
+    let (first, second): (Vec<SocketAddr>, Vec<SocketAddr>) =
+        addrs.iter().partition(|addr| addr.is_ipv6() == first_v6);
+    let (mut first, mut second) = (first.into_iter(), second.into_iter());
+    let mut interleaved = Vec::with_capacity(addrs.len());
+    loop {
+        match (first.next(), second.next()) {
+            (None, None) => return interleaved,
+            (a, b) => interleaved.extend(a.into_iter().chain(b)),
+        }
+    }
+}
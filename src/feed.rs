@@ -0,0 +1,383 @@
+//! IP reputation feeds: lists of networks known for abuse, published as
+//! plain CIDR lists, such as Spamhaus DROP or FireHOL's level 1.
+
+use std::fmt;
+use std::fs;
+use std::io;
+use std::net::{IpAddr, Ipv6Addr};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::task::JoinHandle;
+
+use crate::acl::IpNet;
+use crate::watch::{self, Modified, Reload};
+
+/// Time allowed for fetching a feed over HTTP.
+const FETCH_TIMEOUT: Duration = Duration::from_secs(60);
+/// Largest feed fetched over HTTP.
+const MAX_FEED_LEN: u64 = 64 << 20;
+
+/// Networks to deny, from an IP reputation feed, for
+/// [`DestinationAcl::deny_feeds`].
+///
+/// Feeds are lists of networks in CIDR notation, or bare addresses, one a
+/// line. Anything after the first field, and lines starting with `#` or
+/// `;`, are ignored, so that lists like Spamhaus DROP (`1.2.3.0/24 ; SBL1`)
+/// can be used as published. Feeds are read from files, or fetched over
+/// plain HTTP; HTTPS feeds have to be downloaded by other means, and
+/// loaded from the file.
+///
+/// Feeds can be refreshed while serving, by [`IpFeed::refresh`] or every
+/// so often by [`IpFeed::watch`], and turned off and on again by
+/// [`IpFeed::set_enabled`]. Clones share the networks and the flag.
+///
+/// ```no_run
+/// # async fn run() -> std::io::Result<()> {
+/// use std::time::Duration;
+/// use socks5_rs::{DestinationAcl, IpFeed, Server};
+///
+/// let drop = IpFeed::fetch("http://feeds.example.com/drop.txt").await?;
+/// drop.watch(Duration::from_secs(12 * 60 * 60));
+/// let local = IpFeed::load("/etc/socks5/bad-ips.txt")?;
+/// local.watch(Duration::from_secs(30));
+/// Server::bind("0.0.0.0:1080")
+///     .await?
+///     .with_destination_acl(DestinationAcl {
+///         deny_feeds: vec![drop, local],
+///         ..DestinationAcl::default()
+///     })
+///     .serve()
+///     .await?;
+/// # Ok(())
+/// # }
+/// ```
+///
+/// [`DestinationAcl::deny_feeds`]: crate::DestinationAcl::deny_feeds
+#[derive(Clone)]
+pub struct IpFeed {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    source: Source,
+    nets: RwLock<Arc<IpRanges>>,
+    enabled: AtomicBool,
+    modified: Modified,
+}
+
+#[derive(Debug)]
+enum Source {
+    None,
+    File(PathBuf),
+    Http(HttpUrl),
+}
+
+impl IpFeed {
+    /// Read the feed file at `path`.
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref();
+        let modified = Modified::of(path)?;
+        let nets = parse(&fs::read_to_string(path)?);
+        Ok(IpFeed::new(Source::File(path.to_owned()), nets, modified))
+    }
+
+    /// Fetch the feed at `url`, an `http://` URL.
+    pub async fn fetch(url: &str) -> io::Result<Self> {
+        let url = HttpUrl::parse(url)?;
+        let nets = parse(&url.get().await?);
+        Ok(IpFeed::new(Source::Http(url), nets, None))
+    }
+
+    /// Parse the contents of a feed.
+    pub fn parse(contents: &str) -> Self {
+        IpFeed::new(Source::None, parse(contents), None)
+    }
+
+    fn new(source: Source, nets: IpRanges, modified: Option<SystemTime>) -> Self {
+        IpFeed {
+            inner: Arc::new(Inner {
+                source,
+                nets: RwLock::new(Arc::new(nets)),
+                enabled: AtomicBool::new(true),
+                modified: Modified::new(modified),
+            }),
+        }
+    }
+
+    /// Read the file, or fetch the URL, again, replacing the networks. On
+    /// failure the networks are kept as they were.
+    ///
+    /// Fails with [`io::ErrorKind::InvalidInput`] if the feed wasn't
+    /// loaded from a file or URL.
+    pub async fn refresh(&self) -> io::Result<()> {
+        self.inner.refresh().await
+    }
+
+    /// Refresh the feed every `interval`: fetch it again, or read the file
+    /// again if it changed. Failed refreshes are logged, and leave the
+    /// networks as they were.
+    ///
+    /// Must be called from within a Tokio runtime. Watching stops once all
+    /// clones are dropped, or when the returned task is aborted.
+    pub fn watch(&self, interval: Duration) -> JoinHandle<()> {
+        watch::spawn(&self.inner, interval)
+    }
+
+    /// Turn the feed off, so that it denies nothing, or on again. Feeds
+    /// are on to begin with, and keep being refreshed while off.
+    pub fn set_enabled(&self, enabled: bool) {
+        self.inner.enabled.store(enabled, Ordering::Relaxed);
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.inner.enabled.load(Ordering::Relaxed)
+    }
+
+    /// Whether the feed is on and lists `ip`. IPv4-mapped IPv6 addresses
+    /// are matched as IPv4.
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        self.is_enabled() && self.inner.nets.read().unwrap().contains(ip)
+    }
+
+    /// The number of networks in the feed.
+    pub fn len(&self) -> usize {
+        self.inner.nets.read().unwrap().len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl fmt::Debug for IpFeed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("IpFeed")
+            .field("source", &self.inner.source)
+            .field("enabled", &self.is_enabled())
+            .field("len", &self.len())
+            .finish()
+    }
+}
+
+/// Feeds are equal when they are clones of each other.
+impl PartialEq for IpFeed {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.inner, &other.inner)
+    }
+}
+
+impl Eq for IpFeed {}
+
+impl Inner {
+    async fn refresh(&self) -> io::Result<()> {
+        let (nets, modified) = match &self.source {
+            Source::None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "feed not loaded from a file or URL",
+                ))
+            }
+            Source::File(path) => {
+                let modified = Modified::of(path)?;
+                (parse(&fs::read_to_string(path)?), modified)
+            }
+            Source::Http(url) => (parse(&url.get().await?), None),
+        };
+        log::info!(
+            "refreshed {} networks from {}",
+            nets.len,
+            self.source.name()
+        );
+        *self.nets.write().unwrap() = Arc::new(nets);
+        self.modified.set(modified);
+        Ok(())
+    }
+}
+
+impl Reload for Inner {
+    /// Files that were modified, and all URLs.
+    fn changed(&self) -> bool {
+        match &self.source {
+            Source::None => false,
+            Source::File(path) => self.modified.changed(path),
+            Source::Http(_) => true,
+        }
+    }
+
+    fn reload(&self) -> io::Result<()> {
+        tokio::runtime::Handle::current().block_on(self.refresh())
+    }
+
+    fn kept(&self) -> String {
+        format!("the previous networks of {}", self.source.name())
+    }
+}
+
+impl Source {
+    fn name(&self) -> String {
+        match self {
+            Source::None => "feed".to_owned(),
+            Source::File(path) => path.display().to_string(),
+            Source::Http(url) => url.to_string(),
+        }
+    }
+}
+
+/// Disjoint address ranges, sorted, with IPv4 addresses mapped to IPv6.
+struct IpRanges {
+    ranges: Vec<(u128, u128)>,
+    /// The number of networks listed.
+    len: usize,
+}
+
+impl IpRanges {
+    fn contains(&self, ip: IpAddr) -> bool {
+        let ip = to_u128(ip);
+        // The last range starting at or before `ip`.
+        let i = self.ranges.partition_point(|&(start, _)| start <= ip);
+        i > 0 && self.ranges[i - 1].1 >= ip
+    }
+}
+
+fn to_u128(ip: IpAddr) -> u128 {
+    match ip {
+        IpAddr::V4(ip) => u128::from(ip.to_ipv6_mapped()),
+        IpAddr::V6(ip) => u128::from(ip),
+    }
+}
+
+fn parse(contents: &str) -> IpRanges {
+    let mut ranges = Vec::new();
+    for line in contents.lines() {
+        let net = line
+            .split(|c: char| c.is_whitespace() || c == '#' || c == ';')
+            .next()
+            .and_then(|net| net.parse::<IpNet>().ok());
+        if let Some(net) = net {
+            // Mapped addresses keep the prefix of their IPv4 network.
+            let (start, prefix_len) = match net.addr() {
+                IpAddr::V4(_) => (to_u128(net.addr()), net.prefix_len() + 96),
+                IpAddr::V6(addr) => (u128::from(addr), net.prefix_len()),
+            };
+            let host_bits = u128::MAX.checked_shr(u32::from(prefix_len)).unwrap_or(0);
+            ranges.push((start, start | host_bits));
+        }
+    }
+    let len = ranges.len();
+    ranges.sort_unstable();
+    let mut merged: Vec<(u128, u128)> = Vec::with_capacity(ranges.len());
+    for (start, end) in ranges {
+        match merged.last_mut() {
+            Some(last) if start <= last.1.saturating_add(1) => last.1 = last.1.max(end),
+            _ => merged.push((start, end)),
+        }
+    }
+    merged.shrink_to_fit();
+    IpRanges {
+        ranges: merged,
+        len,
+    }
+}
+
+/// An `http://` URL.
+#[derive(Debug)]
+struct HttpUrl {
+    host: String,
+    port: u16,
+    path: String,
+}
+
+impl HttpUrl {
+    fn parse(url: &str) -> io::Result<Self> {
+        let invalid = |msg| io::Error::new(io::ErrorKind::InvalidInput, msg);
+        let rest = match url.strip_prefix("http://") {
+            Some(rest) => rest,
+            None if url.starts_with("https://") => {
+                return Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    "HTTPS feeds aren't supported, download them to a file",
+                ))
+            }
+            None => return Err(invalid("feed URL isn't an http:// URL")),
+        };
+        let (authority, path) = match rest.find('/') {
+            Some(slash) => rest.split_at(slash),
+            None => (rest, "/"),
+        };
+        let (host, port) = match authority.rsplit_once(':') {
+            Some((host, port)) if !port.contains(']') => (
+                host,
+                port.parse()
+                    .map_err(|_| invalid("invalid port in feed URL"))?,
+            ),
+            _ => (authority, 80),
+        };
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        if host.is_empty() || host.contains('@') {
+            return Err(invalid("invalid host in feed URL"));
+        }
+        Ok(HttpUrl {
+            host: host.to_owned(),
+            port,
+            path: path.to_owned(),
+        })
+    }
+
+    /// GET the URL, over HTTP/1.0 so that the body comes whole.
+    async fn get(&self) -> io::Result<String> {
+        tokio::time::timeout(FETCH_TIMEOUT, self.get_inner())
+            .await
+            .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "fetching feed timed out"))?
+    }
+
+    async fn get_inner(&self) -> io::Result<String> {
+        let mut stream = TcpStream::connect((self.host.as_str(), self.port)).await?;
+        let host = match self.host.parse::<Ipv6Addr>() {
+            Ok(_) => format!("[{}]", self.host),
+            Err(_) => self.host.clone(),
+        };
+        let request = format!(
+            "GET {} HTTP/1.0\r\nHost: {}\r\nUser-Agent: socks5_rs\r\nConnection: close\r\n\r\n",
+            self.path, host
+        );
+        stream.write_all(request.as_bytes()).await?;
+        let mut response = Vec::new();
+        (&mut stream)
+            .take(MAX_FEED_LEN + 1)
+            .read_to_end(&mut response)
+            .await?;
+        if response.len() as u64 > MAX_FEED_LEN {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "feed too large"));
+        }
+        let head_end = response
+            .windows(4)
+            .position(|window| window == b"\r\n\r\n")
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "bad HTTP response"))?;
+        let head = String::from_utf8_lossy(&response[..head_end]);
+        let status = head.split(' ').nth(1).unwrap_or_default();
+        if status != "200" {
+            let status_line = head.lines().next().unwrap_or_default();
+            return Err(io::Error::other(format!(
+                "feed server answered {}",
+                status_line
+            )));
+        }
+        String::from_utf8(response.split_off(head_end + 4))
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "feed isn't UTF-8"))
+    }
+}
+
+impl fmt::Display for HttpUrl {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.host.contains(':') {
+            write!(f, "http://[{}]:{}{}", self.host, self.port, self.path)
+        } else {
+            write!(f, "http://{}:{}{}", self.host, self.port, self.path)
+        }
+    }
+}
@@ -0,0 +1,575 @@
+//! Just enough of the MaxMind DB format to look up the record of an IP, see
+//! <https://maxmind.github.io/MaxMind-DB/>.
+
+use std::convert::TryFrom;
+use std::io;
+use std::net::IpAddr;
+
+/// Starts the metadata section, at the end of the file.
+const METADATA_MARKER: &[u8] = b"\xab\xcd\xefMaxMind.com";
+/// Zeros between the search tree and the data section.
+const SEPARATOR_LEN: usize = 16;
+/// How deep maps and arrays may nest.
+const MAX_DEPTH: u32 = 32;
+
+const POINTER: u8 = 1;
+const STRING: u8 = 2;
+const DOUBLE: u8 = 3;
+const BYTES: u8 = 4;
+const UINT16: u8 = 5;
+const UINT32: u8 = 6;
+const MAP: u8 = 7;
+const INT32: u8 = 8;
+const UINT64: u8 = 9;
+const UINT128: u8 = 10;
+const ARRAY: u8 = 11;
+const BOOLEAN: u8 = 14;
+const FLOAT: u8 = 15;
+
+/// A value of a record.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) enum Value {
+    String(String),
+    Uint(u128),
+}
+
+/// A database read into memory.
+pub(crate) struct Mmdb {
+    file: Vec<u8>,
+    node_count: u32,
+    record_size: u16,
+    ip_version: u16,
+    /// Where the data section starts and ends in `file`.
+    data: (usize, usize),
+    /// The node IPv4 addresses start from in an IPv6 tree.
+    ipv4_start: u32,
+}
+
+impl Mmdb {
+    pub(crate) fn parse(file: Vec<u8>) -> io::Result<Self> {
+        let invalid = |reason: &str| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("invalid MaxMind DB: {}", reason),
+            )
+        };
+        let marker = file
+            .windows(METADATA_MARKER.len())
+            .rposition(|window| window == METADATA_MARKER)
+            .ok_or_else(|| invalid("no metadata"))?;
+        let metadata = &file[marker + METADATA_MARKER.len()..];
+        let field = |key| match get(metadata, 0, &[key]) {
+            Some(Value::Uint(n)) => Ok(n),
+            _ => Err(invalid(&format!("no {} in the metadata", key))),
+        };
+        let node_count = u32::try_from(field("node_count")?).map_err(|_| invalid("node_count"))?;
+        let record_size = match field("record_size")? {
+            24 => 24,
+            28 => 28,
+            32 => 32,
+            _ => return Err(invalid("unsupported record_size")),
+        };
+        let ip_version = match field("ip_version")? {
+            4 => 4,
+            6 => 6,
+            _ => return Err(invalid("unsupported ip_version")),
+        };
+        let tree_len = node_count as usize * usize::from(record_size) / 4;
+        let data_start = tree_len + SEPARATOR_LEN;
+        if data_start > marker {
+            return Err(invalid("search tree past the end of the file"));
+        }
+
+        let mut db = Mmdb {
+            file,
+            node_count,
+            record_size,
+            ip_version,
+            data: (data_start, marker),
+            ipv4_start: 0,
+        };
+        if ip_version == 6 {
+            let mut node = 0;
+            for _ in 0..96 {
+                if node >= node_count {
+                    break;
+                }
+                node = db.record(node, 0);
+            }
+            db.ipv4_start = node;
+        }
+        Ok(db)
+    }
+
+    /// The value at `path` in the record of `ip`, e.g. `["country",
+    /// "iso_code"]`. `None` if there is no record, or nothing there.
+    pub(crate) fn get(&self, ip: IpAddr, path: &[&str]) -> Option<Value> {
+        let offset = self.lookup(ip)?;
+        get(&self.file[self.data.0..self.data.1], offset, path)
+    }
+
+    /// Where the record of `ip` is in the data section.
+    fn lookup(&self, ip: IpAddr) -> Option<usize> {
+        let ip = match ip {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+            ip => ip,
+        };
+        let (octets, mut node) = match ip {
+            IpAddr::V4(v4) => (v4.octets().to_vec(), self.ipv4_start),
+            IpAddr::V6(v6) if self.ip_version == 6 => (v6.octets().to_vec(), 0),
+            IpAddr::V6(_) => return None,
+        };
+        for i in 0..octets.len() * 8 {
+            if node >= self.node_count {
+                break;
+            }
+            let bit = (octets[i / 8] >> (7 - i % 8)) & 1;
+            node = self.record(node, bit);
+        }
+        if node <= self.node_count {
+            // Either no record, or still in the tree, which a valid
+            // database doesn't end in.
+            return None;
+        }
+        ((node - self.node_count) as usize).checked_sub(SEPARATOR_LEN)
+    }
+
+    /// The left (0) or right (1) record of `node`. Records past the end of
+    /// the file read as "no record".
+    fn record(&self, node: u32, side: u8) -> u32 {
+        let node_len = usize::from(self.record_size) / 4;
+        let start = node as usize * node_len;
+        let bytes = match self.file.get(start..start + node_len) {
+            Some(bytes) => bytes,
+            None => return self.node_count,
+        };
+        let be = |bytes: &[u8]| bytes.iter().fold(0u32, |n, &b| (n << 8) | u32::from(b));
+        match (self.record_size, side) {
+            (24, 0) => be(&bytes[..3]),
+            (24, _) => be(&bytes[3..]),
+            (28, 0) => (u32::from(bytes[3] & 0xf0) << 20) | be(&bytes[..3]),
+            (28, _) => (u32::from(bytes[3] & 0x0f) << 24) | be(&bytes[4..]),
+            (_, 0) => be(&bytes[..4]),
+            (_, _) => be(&bytes[4..]),
+        }
+    }
+}
+
+/// The value at `path` in the value at `offset` of `section`.
+fn get(section: &[u8], offset: usize, path: &[&str]) -> Option<Value> {
+    let (mut decoder, mut ty, mut size) = Decoder {
+        section,
+        pos: offset,
+    }
+    .follow(0)?;
+    for key in path {
+        if ty != MAP {
+            return None;
+        }
+        let mut found = None;
+        for _ in 0..size {
+            let (mut name, name_ty, name_len) = decoder.follow(0)?;
+            if name_ty != STRING {
+                return None;
+            }
+            if name.take(name_len)? == key.as_bytes() {
+                found = Some(decoder.follow(0)?);
+                break;
+            }
+            decoder.skip(0)?;
+        }
+        (decoder, ty, size) = found?;
+    }
+    let payload = decoder.take(size)?;
+    match ty {
+        STRING => String::from_utf8(payload.to_vec()).ok().map(Value::String),
+        UINT16 | UINT32 | UINT64 | UINT128 if size <= 16 => Some(Value::Uint(
+            payload.iter().fold(0, |n, &b| (n << 8) | u128::from(b)),
+        )),
+        _ => None,
+    }
+}
+
+/// Reads values of the data section (or the metadata).
+#[derive(Clone, Copy)]
+struct Decoder<'a> {
+    section: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Decoder<'a> {
+    fn take(&mut self, n: usize) -> Option<&'a [u8]> {
+        let bytes = self.section.get(self.pos..self.pos.checked_add(n)?)?;
+        self.pos += n;
+        Some(bytes)
+    }
+
+    fn uint(&mut self, n: usize) -> Option<usize> {
+        let bytes = self.take(n)?;
+        Some(bytes.iter().fold(0, |n, &b| (n << 8) | usize::from(b)))
+    }
+
+    /// The next value's type and size, or for a pointer, where it points.
+    fn header(&mut self) -> Option<(u8, usize)> {
+        let control = self.take(1)?[0];
+        let mut ty = control >> 5;
+        if ty == POINTER {
+            let high = usize::from(control & 0x07);
+            let target = match (control >> 3) & 0x03 {
+                0 => (high << 8) | self.uint(1)?,
+                1 => ((high << 16) | self.uint(2)?) + 2048,
+                2 => ((high << 24) | self.uint(3)?) + 526_336,
+                _ => self.uint(4)?,
+            };
+            return Some((POINTER, target));
+        }
+        if ty == 0 {
+            ty = 7u8.checked_add(self.take(1)?[0])?;
+        }
+        let size = match control & 0x1f {
+            29 => 29 + self.uint(1)?,
+            30 => 285 + self.uint(2)?,
+            31 => 65_821 + self.uint(3)?,
+            size => usize::from(size),
+        };
+        Some((ty, size))
+    }
+
+    /// Move past the next value, returning its type and size, and a decoder
+    /// at its payload. Pointers are followed.
+    fn follow(&mut self, depth: u32) -> Option<(Decoder<'a>, u8, usize)> {
+        let (ty, size) = self.header()?;
+        if ty == POINTER {
+            let mut target = Decoder {
+                section: self.section,
+                pos: size,
+            };
+            let (ty, size) = target.header()?;
+            // Pointers to pointers aren't valid.
+            return if ty == POINTER {
+                None
+            } else {
+                Some((target, ty, size))
+            };
+        }
+        let payload = *self;
+        self.skip_payload(ty, size, depth)?;
+        Some((payload, ty, size))
+    }
+
+    /// Move past the next value.
+    fn skip(&mut self, depth: u32) -> Option<()> {
+        self.follow(depth).map(|_| ())
+    }
+
+    fn skip_payload(&mut self, ty: u8, size: usize, depth: u32) -> Option<()> {
+        if depth > MAX_DEPTH {
+            return None;
+        }
+        match ty {
+            MAP => {
+                for _ in 0..size {
+                    self.skip(depth + 1)?;
+                    self.skip(depth + 1)?;
+                }
+            }
+            ARRAY => {
+                for _ in 0..size {
+                    self.skip(depth + 1)?;
+                }
+            }
+            BOOLEAN => {}
+            DOUBLE => {
+                self.take(8)?;
+            }
+            FLOAT => {
+                self.take(4)?;
+            }
+            STRING | BYTES | UINT16 | UINT32 | INT32 | UINT64 | UINT128 => {
+                self.take(size)?;
+            }
+            _ => return None,
+        }
+        Some(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header(ty: u8, size: usize) -> Vec<u8> {
+        assert!(size < 29);
+        if ty <= 7 {
+            vec![ty << 5 | size as u8]
+        } else {
+            vec![size as u8, ty - 7]
+        }
+    }
+
+    fn string(s: &str) -> Vec<u8> {
+        let mut out = header(STRING, s.len());
+        out.extend_from_slice(s.as_bytes());
+        out
+    }
+
+    fn uint(ty: u8, n: u64) -> Vec<u8> {
+        let bytes = n.to_be_bytes();
+        let skip = bytes.iter().take_while(|&&b| b == 0).count();
+        let mut out = header(ty, 8 - skip);
+        out.extend_from_slice(&bytes[skip..]);
+        out
+    }
+
+    fn map(entries: &[(&str, Vec<u8>)]) -> Vec<u8> {
+        let mut out = header(MAP, entries.len());
+        for (key, value) in entries {
+            out.extend(string(key));
+            out.extend_from_slice(value);
+        }
+        out
+    }
+
+    fn array(items: &[Vec<u8>]) -> Vec<u8> {
+        let mut out = header(ARRAY, items.len());
+        for item in items {
+            out.extend_from_slice(item);
+        }
+        out
+    }
+
+    /// A pointer of the smallest form to `target`, below 2048.
+    fn pointer(target: usize) -> Vec<u8> {
+        vec![POINTER << 5 | (target >> 8) as u8, target as u8]
+    }
+
+    fn node(record_size: u16, left: u32, right: u32) -> Vec<u8> {
+        let be = |n: u32, len: usize| n.to_be_bytes()[4 - len..].to_vec();
+        match record_size {
+            24 => [be(left, 3), be(right, 3)].concat(),
+            28 => [
+                be(left, 3),
+                vec![((left >> 20) & 0xf0) as u8 | (right >> 24) as u8],
+                be(right, 3),
+            ]
+            .concat(),
+            _ => [be(left, 4), be(right, 4)].concat(),
+        }
+    }
+
+    /// Record of 0.0.0.0/1: DE and AS 64496, sharing the country by a
+    /// pointer. Of 128.0.0.0/2: FR, after an array. Nothing for the rest.
+    fn data() -> (Vec<u8>, usize, usize) {
+        let mut data = map(&[("iso_code", string("DE"))]);
+        let first = data.len();
+        data.extend(map(&[
+            ("tags", array(&[string("x"), uint(UINT16, 1), array(&[])])),
+            ("country", pointer(0)),
+            ("autonomous_system_number", uint(UINT32, 64496)),
+        ]));
+        let second = data.len();
+        data.extend(map(&[
+            (
+                "names",
+                array(&[map(&[("en", string("France"))]), string("y")]),
+            ),
+            ("country", map(&[("iso_code", string("FR"))])),
+            ("big", uint(UINT64, u64::MAX)),
+        ]));
+        (data, first, second)
+    }
+
+    fn metadata(node_count: u32, record_size: u16, ip_version: u16) -> Vec<u8> {
+        let mut out = METADATA_MARKER.to_vec();
+        out.extend(map(&[
+            ("node_count", uint(UINT32, node_count.into())),
+            ("record_size", uint(UINT16, record_size.into())),
+            ("ip_version", uint(UINT16, ip_version.into())),
+        ]));
+        out
+    }
+
+    fn v4_db(record_size: u16) -> Vec<u8> {
+        let (data, first, second) = data();
+        let node_count = 2;
+        let record = |offset: usize| node_count + SEPARATOR_LEN as u32 + offset as u32;
+        let mut file = node(record_size, record(first), 1);
+        file.extend(node(record_size, record(second), node_count));
+        file.extend([0; SEPARATOR_LEN]);
+        file.extend(data);
+        file.extend(metadata(node_count, record_size, 4));
+        file
+    }
+
+    /// An IPv6 tree, with the IPv4 records of `v4_db` under ::/96.
+    fn v6_db() -> Vec<u8> {
+        let (data, first, second) = data();
+        let node_count = 98;
+        let record = |offset: usize| node_count + SEPARATOR_LEN as u32 + offset as u32;
+        let mut file = Vec::new();
+        for i in 0..96 {
+            file.extend(node(24, i + 1, node_count));
+        }
+        file.extend(node(24, record(first), 97));
+        file.extend(node(24, record(second), node_count));
+        file.extend([0; SEPARATOR_LEN]);
+        file.extend(data);
+        file.extend(metadata(node_count, 24, 6));
+        file
+    }
+
+    fn country(db: &Mmdb, ip: &str) -> Option<Value> {
+        db.get(ip.parse().unwrap(), &["country", "iso_code"])
+    }
+
+    fn de() -> Option<Value> {
+        Some(Value::String("DE".to_owned()))
+    }
+
+    fn fr() -> Option<Value> {
+        Some(Value::String("FR".to_owned()))
+    }
+
+    #[test]
+    fn search_tree() {
+        for record_size in [24, 28, 32] {
+            let db = Mmdb::parse(v4_db(record_size)).unwrap();
+            assert_eq!(country(&db, "1.2.3.4"), de(), "{}", record_size);
+            assert_eq!(country(&db, "127.255.255.255"), de());
+            assert_eq!(country(&db, "128.0.0.0"), fr());
+            assert_eq!(country(&db, "191.1.1.1"), fr());
+            assert_eq!(country(&db, "192.0.0.1"), None);
+            assert_eq!(country(&db, "::ffff:1.2.3.4"), de());
+            // IPv6 addresses aren't in an IPv4 database.
+            assert_eq!(country(&db, "2001:db8::1"), None);
+        }
+    }
+
+    #[test]
+    fn ipv6_tree() {
+        let db = Mmdb::parse(v6_db()).unwrap();
+        assert_eq!(db.ipv4_start, 96);
+        assert_eq!(country(&db, "1.2.3.4"), de());
+        assert_eq!(country(&db, "::ffff:130.0.0.1"), fr());
+        assert_eq!(country(&db, "::130.0.0.1"), fr());
+        assert_eq!(country(&db, "2001:db8::1"), None);
+    }
+
+    #[test]
+    fn values() {
+        let db = Mmdb::parse(v4_db(24)).unwrap();
+        let asn = |ip: &str| db.get(ip.parse().unwrap(), &["autonomous_system_number"]);
+        assert_eq!(asn("1.2.3.4"), Some(Value::Uint(64496)));
+        assert_eq!(asn("128.0.0.1"), None);
+        let ip = "128.0.0.1".parse().unwrap();
+        assert_eq!(db.get(ip, &["big"]), Some(Value::Uint(u64::MAX.into())));
+        // Maps, arrays and missing keys aren't values.
+        assert_eq!(db.get(ip, &["country"]), None);
+        assert_eq!(db.get(ip, &["names"]), None);
+        assert_eq!(db.get(ip, &["names", "en"]), None);
+        assert_eq!(db.get(ip, &["country", "iso_code", "more"]), None);
+        assert_eq!(db.get(ip, &["missing"]), None);
+    }
+
+    #[test]
+    fn pointers() {
+        let pointed = || Some(Value::String("pointed".to_owned()));
+        // Pointers of each size, to a string that far into the section.
+        for (target, bytes) in [
+            (100, vec![POINTER << 5, 100]),
+            (
+                2048 + 0x1_0203,
+                vec![POINTER << 5 | 1 << 3 | 0x01, 0x02, 0x03],
+            ),
+            (
+                526_336 + 0x1_0203,
+                vec![POINTER << 5 | 2 << 3, 0x01, 0x02, 0x03],
+            ),
+            (
+                0x0009_0102,
+                vec![POINTER << 5 | 3 << 3, 0x00, 0x09, 0x01, 0x02],
+            ),
+        ] {
+            let mut section = bytes.clone();
+            section.resize(target, 0);
+            section.extend(string("pointed"));
+            assert_eq!(get(&section, 0, &[]), pointed(), "{:?}", bytes);
+            // Truncated pointers.
+            assert_eq!(get(&section[..bytes.len() - 1], 0, &[]), None);
+        }
+
+        let mut section = string("pointed");
+        let first = section.len();
+        section.extend(pointer(0));
+        let second = section.len();
+        section.extend(pointer(first));
+        let past_end = section.len();
+        section.extend(pointer(past_end + 100));
+        assert_eq!(get(&section, first, &[]), pointed());
+        // Pointers to pointers, and past the end, aren't followed.
+        assert_eq!(get(&section, second, &[]), None);
+        assert_eq!(get(&section, past_end, &[]), None);
+    }
+
+    #[test]
+    fn too_deep() {
+        let mut value = string("deep");
+        for _ in 0..MAX_DEPTH + 2 {
+            value = array(&[value]);
+        }
+        let section = map(&[("a", value), ("b", string("after"))]);
+        assert_eq!(get(&section, 0, &["b"]), None);
+
+        let mut value = string("deep");
+        for _ in 0..MAX_DEPTH - 2 {
+            value = array(&[value]);
+        }
+        let section = map(&[("a", value), ("b", string("after"))]);
+        assert_eq!(
+            get(&section, 0, &["b"]),
+            Some(Value::String("after".to_owned()))
+        );
+    }
+
+    #[test]
+    fn invalid_metadata() {
+        assert!(Mmdb::parse(Vec::new()).is_err());
+        assert!(Mmdb::parse(b"not a database".to_vec()).is_err());
+        for (node_count, record_size, ip_version) in [(2, 20, 4), (2, 24, 5), (1 << 30, 24, 4)] {
+            let mut file = v4_db(24);
+            let marker = file.len() - metadata(2, 24, 4).len();
+            file.truncate(marker);
+            file.extend(metadata(node_count, record_size, ip_version));
+            let err = Mmdb::parse(file).err().unwrap();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        }
+    }
+
+    #[test]
+    fn truncated() {
+        let file = v4_db(24);
+        for len in 0..file.len() {
+            assert!(Mmdb::parse(file[..len].to_vec()).is_err(), "{}", len);
+        }
+    }
+
+    #[test]
+    fn corrupt() {
+        let ips = ["1.2.3.4", "128.0.0.1", "192.0.0.1"];
+        for file in [v4_db(24), v4_db(28), v6_db()] {
+            for i in 0..file.len() {
+                for byte in [0x00, 0x01, 0x20, 0x3f, 0x7f, 0xe0, 0xff] {
+                    let mut corrupt = file.clone();
+                    corrupt[i] = byte;
+                    if let Ok(db) = Mmdb::parse(corrupt) {
+                        for ip in ips {
+                            let ip = ip.parse().unwrap();
+                            db.get(ip, &["country", "iso_code"]);
+                            db.get(ip, &["autonomous_system_number"]);
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
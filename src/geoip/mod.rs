@@ -0,0 +1,248 @@
+//! Destinations filtered by country and autonomous system, looked up in
+//! MaxMind DB (`.mmdb`) files such as GeoLite2 Country and GeoLite2 ASN.
+
+mod mmdb;
+
+use std::collections::HashSet;
+use std::convert::TryFrom;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use tokio::task::JoinHandle;
+
+use crate::error::Socks5Error;
+use crate::protocol::{Reply, TargetAddr};
+use crate::watch::{self, Modified, Reload};
+use mmdb::{Mmdb, Value};
+
+/// A MaxMind DB file, read into memory.
+///
+/// The file can be reloaded while serving, by [`GeoIpDb::reload`] or
+/// [`GeoIpDb::watch`], e.g. after a `geoipupdate` run. Clones share the
+/// database, so reloading one reloads all.
+#[derive(Clone)]
+pub struct GeoIpDb {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    path: PathBuf,
+    db: RwLock<Arc<Mmdb>>,
+    modified: Modified,
+}
+
+impl GeoIpDb {
+    /// Read the database at `path`.
+    ///
+    /// Fails with [`io::ErrorKind::InvalidData`] if it isn't a MaxMind DB.
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref();
+        let modified = Modified::of(path)?;
+        let db = Mmdb::parse(fs::read(path)?)?;
+        Ok(GeoIpDb {
+            inner: Arc::new(Inner {
+                path: path.to_owned(),
+                db: RwLock::new(Arc::new(db)),
+                modified: Modified::new(modified),
+            }),
+        })
+    }
+
+    /// Read the file again, replacing the database. On failure the
+    /// database is kept as it was.
+    pub fn reload(&self) -> io::Result<()> {
+        self.inner.reload()
+    }
+
+    /// Reload the file whenever it changes, checking every `interval`.
+    /// Failed reloads are logged, and leave the database as it was.
+    ///
+    /// Must be called from within a Tokio runtime. Watching stops once all
+    /// clones are dropped, or when the returned task is aborted.
+    pub fn watch(&self, interval: Duration) -> JoinHandle<()> {
+        watch::spawn(&self.inner, interval)
+    }
+
+    /// The ISO 3166-1 code of the country `ip` is in, e.g. `"DE"`, or else
+    /// of the country it is registered in.
+    pub fn country(&self, ip: IpAddr) -> Option<String> {
+        let db = self.db();
+        let code = db
+            .get(ip, &["country", "iso_code"])
+            .or_else(|| db.get(ip, &["registered_country", "iso_code"]));
+        match code {
+            Some(Value::String(code)) => Some(code),
+            _ => None,
+        }
+    }
+
+    /// The number of the autonomous system `ip` is in.
+    pub fn asn(&self, ip: IpAddr) -> Option<u32> {
+        match self.db().get(ip, &["autonomous_system_number"]) {
+            Some(Value::Uint(asn)) => u32::try_from(asn).ok(),
+            _ => None,
+        }
+    }
+
+    fn db(&self) -> Arc<Mmdb> {
+        self.inner.db.read().unwrap().clone()
+    }
+}
+
+impl fmt::Debug for GeoIpDb {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("GeoIpDb")
+            .field("path", &self.inner.path)
+            .finish()
+    }
+}
+
+impl Reload for Inner {
+    fn changed(&self) -> bool {
+        self.modified.changed(&self.path)
+    }
+
+    fn reload(&self) -> io::Result<()> {
+        let modified = Modified::of(&self.path)?;
+        let db = Mmdb::parse(fs::read(&self.path)?)?;
+        log::info!("reloaded GeoIP database {}", self.path.display());
+        *self.db.write().unwrap() = Arc::new(db);
+        self.modified.set(modified);
+        Ok(())
+    }
+
+    fn kept(&self) -> String {
+        "the previous GeoIP database".to_owned()
+    }
+}
+
+/// Which countries and autonomous systems targets may be in, see
+/// [`Server::with_geoip_filter`].
+///
+/// Like [`DestinationAcl`], it checks the IPs targets resolve to, and denied
+/// countries and ASNs take precedence over allowed ones. When any are
+/// allowed, everything else is denied, including IPs in none of the
+/// databases. Targets left with no address are refused with "connection not
+/// allowed by ruleset", or the reply set by [`GeoIpFilter::with_reply`].
+///
+/// ```no_run
+/// # fn run() -> std::io::Result<()> {
+/// use socks5_rs::{GeoIpDb, GeoIpFilter};
+///
+/// let countries = GeoIpDb::open("/var/lib/GeoIP/GeoLite2-Country.mmdb")?;
+/// let asns = GeoIpDb::open("/var/lib/GeoIP/GeoLite2-ASN.mmdb")?;
+/// let filter = GeoIpFilter::new()
+///     .with_database(countries.clone())
+///     .with_database(asns.clone())
+///     .deny_country("KP")
+///     .deny_asn(64496);
+/// countries.watch(std::time::Duration::from_secs(3600));
+/// asns.watch(std::time::Duration::from_secs(3600));
+/// # Ok(())
+/// # }
+/// ```
+///
+/// [`Server::with_geoip_filter`]: crate::Server::with_geoip_filter
+/// [`DestinationAcl`]: crate::DestinationAcl
+#[derive(Clone, Debug)]
+pub struct GeoIpFilter {
+    databases: Vec<GeoIpDb>,
+    allow_countries: HashSet<String>,
+    deny_countries: HashSet<String>,
+    allow_asns: HashSet<u32>,
+    deny_asns: HashSet<u32>,
+    reply: Reply,
+}
+
+impl Default for GeoIpFilter {
+    fn default() -> Self {
+        GeoIpFilter {
+            databases: Vec::new(),
+            allow_countries: HashSet::new(),
+            deny_countries: HashSet::new(),
+            allow_asns: HashSet::new(),
+            deny_asns: HashSet::new(),
+            reply: Reply::NotAllowed,
+        }
+    }
+}
+
+impl GeoIpFilter {
+    /// A filter with no databases, letting everything through.
+    pub fn new() -> Self {
+        GeoIpFilter::default()
+    }
+
+    /// Look IPs up in `db` too. Countries and ASNs come from the first
+    /// database having them.
+    pub fn with_database(mut self, db: GeoIpDb) -> Self {
+        self.databases.push(db);
+        self
+    }
+
+    /// Let targets in the country with ISO 3166-1 code `code` through.
+    pub fn allow_country(mut self, code: &str) -> Self {
+        self.allow_countries.insert(code.to_ascii_uppercase());
+        self
+    }
+
+    /// Refuse targets in the country with ISO 3166-1 code `code`.
+    pub fn deny_country(mut self, code: &str) -> Self {
+        self.deny_countries.insert(code.to_ascii_uppercase());
+        self
+    }
+
+    /// Let targets in autonomous system `asn` through.
+    pub fn allow_asn(mut self, asn: u32) -> Self {
+        self.allow_asns.insert(asn);
+        self
+    }
+
+    /// Refuse targets in autonomous system `asn`.
+    pub fn deny_asn(mut self, asn: u32) -> Self {
+        self.deny_asns.insert(asn);
+        self
+    }
+
+    /// Refuse blocked targets with `reply`.
+    pub fn with_reply(mut self, reply: Reply) -> Self {
+        self.reply = reply;
+        self
+    }
+
+    pub(crate) fn allows(&self, ip: IpAddr) -> bool {
+        let country = self.databases.iter().find_map(|db| db.country(ip));
+        let asn = self.databases.iter().find_map(|db| db.asn(ip));
+        let in_country =
+            |codes: &HashSet<String>| country.as_ref().is_some_and(|c| codes.contains(c));
+        let in_asn = |asns: &HashSet<u32>| asn.is_some_and(|asn| asns.contains(&asn));
+        if in_country(&self.deny_countries) || in_asn(&self.deny_asns) {
+            return false;
+        }
+        (self.allow_countries.is_empty() && self.allow_asns.is_empty())
+            || in_country(&self.allow_countries)
+            || in_asn(&self.allow_asns)
+    }
+
+    /// The addresses `target` resolved to that may be reached.
+    pub(crate) fn filter(
+        &self,
+        target: &TargetAddr,
+        mut addrs: Vec<SocketAddr>,
+    ) -> Result<Vec<SocketAddr>, Socks5Error> {
+        let resolved = addrs.len();
+        addrs.retain(|addr| self.allows(addr.ip()));
+        if addrs.is_empty() && resolved > 0 {
+            return Err(Socks5Error::Blocked {
+                target: target.clone(),
+                reply: self.reply,
+            });
+        }
+        Ok(addrs)
+    }
+}
@@ -0,0 +1,225 @@
+//! GSSAPI authentication (RFC 1961).
+//!
+//! The crate does not link against a GSSAPI implementation itself. Instead a
+//! [`GssapiProvider`] hands out one [`GssapiContext`] per connection, which
+//! lets deployments plug in Kerberos (or anything else speaking GSSAPI) while
+//! this module takes care of the SOCKS side of the exchange: context
+//! establishment, protection level negotiation and per-message encapsulation.
+
+use std::io;
+use std::sync::Mutex;
+
+use bytes::Buf;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Version byte of every RFC 1961 message.
+const GSSAPI_VERSION: u8 = 0x01;
+
+const MTYP_AUTH: u8 = 0x01;
+const MTYP_PROTECTION: u8 = 0x02;
+const MTYP_ENCAPSULATION: u8 = 0x03;
+const MTYP_ABORT: u8 = 0xff;
+
+/// Per-message integrity.
+const LEVEL_INTEGRITY: u8 = 0x01;
+/// Per-message integrity and confidentiality.
+const LEVEL_CONFIDENTIALITY: u8 = 0x02;
+
+/// Plaintext bytes relayed per encapsulated message, leaving room for the
+/// wrap overhead within the 16-bit token length.
+const RELAY_CHUNK: usize = 16 * 1024;
+
+/// Creates a fresh security context for every client choosing GSSAPI.
+pub trait GssapiProvider: Send + Sync {
+    fn new_context(&self) -> Box<dyn GssapiContext>;
+}
+
+/// Server side of one GSSAPI security context, i.e. the operations of
+/// `gss_accept_sec_context`, `gss_wrap` and `gss_unwrap`.
+pub trait GssapiContext: Send {
+    /// Process a token received from the client.
+    fn accept(&mut self, token: &[u8]) -> io::Result<GssapiStep>;
+
+    /// Protect `data` for sending to the client, encrypting it as well when
+    /// `confidential` is set.
+    fn wrap(&mut self, data: &[u8], confidential: bool) -> io::Result<Vec<u8>>;
+
+    /// Verify (and decrypt) a protected token received from the client.
+    fn unwrap(&mut self, token: &[u8]) -> io::Result<Vec<u8>>;
+}
+
+/// Outcome of [`GssapiContext::accept`].
+pub enum GssapiStep {
+    /// The context needs another round trip; send this token to the client.
+    Continue(Vec<u8>),
+    /// The context is established. A non-empty token is still sent to the
+    /// client.
+    Complete(Vec<u8>),
+}
+
+/// An established context and the negotiated protection level.
+pub(crate) struct Session {
+    context: Mutex<Box<dyn GssapiContext>>,
+    confidential: bool,
+}
+
+/// Run the context establishment and protection level sub-negotiation.
+pub(crate) async fn negotiate<S>(
+    stream: &mut S,
+    provider: &dyn GssapiProvider,
+) -> io::Result<Session>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut context = provider.new_context();
+
+    loop {
+        let token = expect_message(stream, MTYP_AUTH).await?;
+        match context.accept(&token) {
+            Ok(GssapiStep::Continue(token)) => write_message(stream, MTYP_AUTH, &token).await?,
+            Ok(GssapiStep::Complete(token)) => {
+                if !token.is_empty() {
+                    write_message(stream, MTYP_AUTH, &token).await?;
+                }
+                break;
+            }
+            Err(e) => {
+                let _ = stream.write_all(&[GSSAPI_VERSION, MTYP_ABORT]).await;
+                return Err(e);
+            }
+        }
+    }
+
+    let token = expect_message(stream, MTYP_PROTECTION).await?;
+    let requested = context.unwrap(&token)?;
+    let level = match requested.first() {
+        Some(&LEVEL_INTEGRITY) => LEVEL_INTEGRITY,
+        // Selective protection (0x03) is answered with full confidentiality.
+        Some(_) => LEVEL_CONFIDENTIALITY,
+        None => return Err(io::ErrorKind::InvalidData.into()),
+    };
+    let token = context.wrap(&[level], false)?;
+    write_message(stream, MTYP_PROTECTION, &token).await?;
+
+    Ok(Session {
+        context: Mutex::new(context),
+        confidential: level == LEVEL_CONFIDENTIALITY,
+    })
+}
+
+impl Session {
+    /// Read and unwrap one encapsulated message.
+    pub(crate) async fn read<R: AsyncRead + Unpin>(&self, stream: &mut R) -> io::Result<Vec<u8>> {
+        let token = expect_message(stream, MTYP_ENCAPSULATION).await?;
+        self.unwrap(&token)
+    }
+
+    /// Wrap `data` and send it as one encapsulated message.
+    pub(crate) async fn write<W: AsyncWrite + Unpin>(
+        &self,
+        stream: &mut W,
+        data: &[u8],
+    ) -> io::Result<()> {
+        let token = self.wrap(data)?;
+        write_message(stream, MTYP_ENCAPSULATION, &token).await
+    }
+
+    /// Relay between the encapsulated client stream and a plain target.
+    pub(crate) async fn relay<C, T>(&self, client: &mut C, target: &mut T) -> io::Result<()>
+    where
+        C: AsyncRead + AsyncWrite + Unpin,
+        T: AsyncRead + AsyncWrite + Unpin,
+    {
+        let (mut client_r, mut client_w) = tokio::io::split(client);
+        let (mut target_r, mut target_w) = tokio::io::split(target);
+
+        let upstream = async {
+            while let Some(token) = read_message(&mut client_r, MTYP_ENCAPSULATION).await? {
+                target_w.write_all(&self.unwrap(&token)?).await?;
+            }
+            target_w.shutdown().await
+        };
+        let downstream = async {
+            let mut buf = vec![0u8; RELAY_CHUNK];
+            loop {
+                let n = target_r.read(&mut buf).await?;
+                if n == 0 {
+                    break;
+                }
+                self.write(&mut client_w, &buf[..n]).await?;
+            }
+            client_w.shutdown().await
+        };
+
+        tokio::try_join!(upstream, downstream)?;
+        Ok(())
+    }
+
+    fn wrap(&self, data: &[u8]) -> io::Result<Vec<u8>> {
+        self.context.lock().unwrap().wrap(data, self.confidential)
+    }
+
+    fn unwrap(&self, token: &[u8]) -> io::Result<Vec<u8>> {
+        self.context.lock().unwrap().unwrap(token)
+    }
+}
+
+/// Read one `VER | MTYP | LEN | TOKEN` message of type `mtyp`, returning
+/// `None` on a clean end of stream.
+async fn read_message<R: AsyncRead + Unpin>(
+    stream: &mut R,
+    mtyp: u8,
+) -> io::Result<Option<Vec<u8>>> {
+    let mut header = [0u8; 2];
+    match stream.read_exact(&mut header[..1]).await {
+        Ok(_) => {}
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+    stream.read_exact(&mut header[1..]).await?;
+
+    if header[0] != GSSAPI_VERSION {
+        return Err(io::ErrorKind::InvalidData.into());
+    }
+    if header[1] == MTYP_ABORT {
+        return Err(io::Error::new(
+            io::ErrorKind::ConnectionAborted,
+            "GSSAPI negotiation aborted by client",
+        ));
+    }
+    if header[1] != mtyp {
+        return Err(io::ErrorKind::InvalidData.into());
+    }
+
+    let mut len = [0u8; 2];
+    stream.read_exact(&mut len).await?;
+    let mut token = vec![0u8; (&len[..]).get_u16() as usize];
+    stream.read_exact(&mut token).await?;
+
+    Ok(Some(token))
+}
+
+async fn expect_message<R: AsyncRead + Unpin>(stream: &mut R, mtyp: u8) -> io::Result<Vec<u8>> {
+    read_message(stream, mtyp)
+        .await?
+        .ok_or_else(|| io::ErrorKind::UnexpectedEof.into())
+}
+
+async fn write_message<W: AsyncWrite + Unpin>(
+    stream: &mut W,
+    mtyp: u8,
+    token: &[u8],
+) -> io::Result<()> {
+    if token.len() > u16::MAX as usize {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "GSSAPI token too large",
+        ));
+    }
+
+    let mut message = Vec::with_capacity(4 + token.len());
+    message.extend_from_slice(&[GSSAPI_VERSION, mtyp]);
+    message.extend_from_slice(&(token.len() as u16).to_be_bytes());
+    message.extend_from_slice(token);
+    stream.write_all(&message).await
+}
@@ -0,0 +1,247 @@
+//! Users from an htpasswd-style credentials file: one `username:hash` per
+//! line, with bcrypt (`$2b$`, `$2y$`, `$2a$`) or Argon2 (`$argon2id$`,
+//! `$argon2i$`, `$argon2d$`) hashes, as written by `htpasswd -B` or the
+//! `argon2` tool. Empty lines and lines starting with `#` are skipped.
+
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+
+use tokio::task::JoinHandle;
+
+use crate::auth::{AuthFuture, Authenticator, Decision};
+use crate::passwd::PasswordHash;
+use crate::watch::{self, Modified, Reload};
+
+/// An [`Authenticator`] checking passwords against the hashes of a
+/// credentials file, so that no password is kept in plaintext.
+///
+/// The file can be reloaded while serving, by [`HtpasswdFile::reload`] or
+/// [`HtpasswdFile::watch`]. Clones share the users, so reloading one reloads
+/// all. Clients already authenticated stay connected.
+///
+/// ```no_run
+/// # async fn run() -> std::io::Result<()> {
+/// use socks5_rs::{HtpasswdFile, Server};
+///
+/// let users = HtpasswdFile::load("/etc/socks5/users")?;
+/// Server::bind("0.0.0.0:1080")
+///     .await?
+///     .with_authenticator(users)
+///     .serve()
+///     .await?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct HtpasswdFile {
+    inner: Arc<Inner>,
+}
+
+type Users = HashMap<String, Arc<PasswordHash>>;
+
+struct Inner {
+    /// Where the users were loaded from, if from a file.
+    path: Option<PathBuf>,
+    users: RwLock<Arc<Users>>,
+    modified: Modified,
+}
+
+impl HtpasswdFile {
+    /// Read the credentials file at `path`.
+    ///
+    /// Fails with [`io::ErrorKind::InvalidData`] on a malformed line or a
+    /// hash scheme that isn't supported, such as MD5 or SHA-1 ones.
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref();
+        let modified = Modified::of(path)?;
+        let users = parse_users(&fs::read_to_string(path)?)?;
+        Ok(HtpasswdFile::new(Some(path.to_owned()), users, modified))
+    }
+
+    /// Parse the contents of a credentials file.
+    pub fn parse(contents: &str) -> io::Result<Self> {
+        Ok(HtpasswdFile::new(None, parse_users(contents)?, None))
+    }
+
+    fn new(path: Option<PathBuf>, users: Users, modified: Option<SystemTime>) -> Self {
+        HtpasswdFile {
+            inner: Arc::new(Inner {
+                path,
+                users: RwLock::new(Arc::new(users)),
+                modified: Modified::new(modified),
+            }),
+        }
+    }
+
+    /// Read the file again, replacing the users. On failure, including a
+    /// malformed line, the users are kept as they were.
+    ///
+    /// Fails with [`io::ErrorKind::InvalidInput`] if the users weren't
+    /// loaded from a file.
+    pub fn reload(&self) -> io::Result<()> {
+        self.inner.reload()
+    }
+
+    /// Reload the file whenever it changes, checking every `interval`.
+    /// Failed reloads are logged, and leave the users as they were.
+    ///
+    /// Must be called from within a Tokio runtime. Watching stops once all
+    /// clones are dropped, or when the returned task is aborted.
+    pub fn watch(&self, interval: Duration) -> JoinHandle<()> {
+        watch::spawn(&self.inner, interval)
+    }
+
+    /// The number of users in the file.
+    pub fn len(&self) -> usize {
+        self.users().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.users().is_empty()
+    }
+
+    fn users(&self) -> Arc<Users> {
+        self.inner.users.read().unwrap().clone()
+    }
+}
+
+impl Reload for Inner {
+    fn changed(&self) -> bool {
+        self.path
+            .as_ref()
+            .is_some_and(|path| self.modified.changed(path))
+    }
+
+    fn reload(&self) -> io::Result<()> {
+        let path = self.path.as_ref().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "users not loaded from a file")
+        })?;
+        let modified = Modified::of(path)?;
+        let users = parse_users(&fs::read_to_string(path)?)?;
+        log::info!("reloaded {} users from {}", users.len(), path.display());
+        *self.users.write().unwrap() = Arc::new(users);
+        self.modified.set(modified);
+        Ok(())
+    }
+
+    fn kept(&self) -> String {
+        "the previous users".to_owned()
+    }
+}
+
+/// The users in the contents of a credentials file.
+fn parse_users(contents: &str) -> io::Result<Users> {
+    let mut users = HashMap::new();
+    for (i, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let invalid = |reason: &str| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("line {}: {}", i + 1, reason),
+            )
+        };
+        let (username, hash) = line.split_once(':').ok_or_else(|| invalid("missing ':'"))?;
+        let hash = PasswordHash::parse(hash).map_err(invalid)?;
+        users.insert(username.to_owned(), Arc::new(hash));
+    }
+    Ok(users)
+}
+
+impl Authenticator for HtpasswdFile {
+    fn verify<'a>(
+        &'a self,
+        username: &'a str,
+        password: &'a [u8],
+        _peer: SocketAddr,
+    ) -> AuthFuture<'a> {
+        // Unknown users are checked against some other hash all the same,
+        // so as not to tell them apart by how quickly they are denied.
+        let users = self.users();
+        let (known, hash) = match users.get(username) {
+            Some(hash) => (true, hash.clone()),
+            None => match users.values().next() {
+                Some(hash) => (false, hash.clone()),
+                None => return Box::pin(std::future::ready(Decision::Deny)),
+            },
+        };
+        let password = password.to_vec();
+        Box::pin(async move {
+            // Hashing is slow on purpose, keep it off the runtime's threads.
+            let matches = tokio::task::spawn_blocking(move || hash.verify(&password))
+                .await
+                .unwrap_or(false);
+            if known && matches {
+                Decision::Allow
+            } else {
+                Decision::Deny
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ALICE: &str = "alice:$2y$05$CCCCCCCCCCCCCCCCCCCCC.E5YPO9kmyuRGyh0XouQYb4YMJKvyOeW";
+
+    fn peer() -> SocketAddr {
+        "127.0.0.1:1".parse().unwrap()
+    }
+
+    #[test]
+    fn skips_comments_and_empty_lines() {
+        let users = HtpasswdFile::parse(&format!("# users\n\n  {}  \n", ALICE)).unwrap();
+        assert_eq!(users.len(), 1);
+    }
+
+    #[test]
+    fn unknown_scheme() {
+        for line in [
+            "bob:$apr1$salt$hash",
+            "bob:{SHA}W6ph5Mm5Pz8GgiULbPgzG37mj9g=",
+            "bob:$1$salt$hash",
+            "bob:plaintext",
+            "bob:",
+        ] {
+            let contents = format!("{}\n{}\n", ALICE, line);
+            let err = HtpasswdFile::parse(&contents).err().expect(line);
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+            assert!(err.to_string().starts_with("line 2:"), "{}", err);
+        }
+    }
+
+    #[test]
+    fn missing_colon() {
+        let err = HtpasswdFile::parse("alice").err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn reload_needs_a_file() {
+        let users = HtpasswdFile::parse(ALICE).unwrap();
+        assert_eq!(
+            users.reload().unwrap_err().kind(),
+            io::ErrorKind::InvalidInput
+        );
+    }
+
+    #[tokio::test]
+    async fn verify() {
+        let users = HtpasswdFile::parse(ALICE).unwrap();
+        assert_eq!(users.verify("alice", b"U*U", peer()).await, Decision::Allow);
+        assert_eq!(users.verify("alice", b"U*U*", peer()).await, Decision::Deny);
+        // Bob isn't a user, though the password is Alice's.
+        assert_eq!(users.verify("bob", b"U*U", peer()).await, Decision::Deny);
+        let none = HtpasswdFile::parse("").unwrap();
+        assert_eq!(none.verify("alice", b"U*U", peer()).await, Decision::Deny);
+    }
+}
@@ -0,0 +1,316 @@
+//! Internationalized domain names (RFC 5891): the one ASCII form of a name
+//! however a client spells it, so that rules about a name can't be dodged
+//! with its Unicode form, its Punycode (RFC 3492) form or a change of case.
+
+use std::convert::TryFrom;
+
+const BASE: u32 = 36;
+const T_MIN: u32 = 1;
+const T_MAX: u32 = 26;
+const SKEW: u32 = 38;
+const DAMP: u32 = 700;
+const INITIAL_BIAS: u32 = 72;
+const INITIAL_N: u32 = 0x80;
+
+/// Prefix of the labels in Punycode.
+const ACE_PREFIX: &str = "xn--";
+
+/// `domain` lowercased, without a trailing dot, and with labels outside
+/// ASCII in Punycode, if it is a valid name. Labels already in Punycode are
+/// decoded and encoded again, to lowercase them too. Ideographic full stops
+/// separate labels like dots do.
+///
+/// Unicode is lowercased but not otherwise normalized.
+pub(crate) fn normalize(domain: &str) -> Option<String> {
+    let domain = domain.replace(['\u{3002}', '\u{ff0e}', '\u{ff61}'], ".");
+    let domain = domain.strip_suffix('.').unwrap_or(&domain);
+    if domain.is_empty() {
+        return None;
+    }
+    let labels: Option<Vec<String>> = domain.split('.').map(label).collect();
+    Some(labels?.join("."))
+}
+
+/// The ASCII form of one label of a name.
+pub(crate) fn label(label: &str) -> Option<String> {
+    if label.is_empty() {
+        return None;
+    }
+    if label.is_ascii() {
+        let label = label.to_ascii_lowercase();
+        return match label.strip_prefix(ACE_PREFIX) {
+            // A label in Punycode must stand for one outside ASCII.
+            Some(encoded) => decode(encoded)
+                .filter(|decoded| !decoded.is_ascii())
+                .and_then(|decoded| encode(&decoded.to_lowercase())),
+            None => Some(label),
+        };
+    }
+    // Nor can a label outside ASCII pass for one in Punycode.
+    if label
+        .get(..ACE_PREFIX.len())
+        .is_some_and(|prefix| prefix.eq_ignore_ascii_case(ACE_PREFIX))
+    {
+        return None;
+    }
+    encode(&label.to_lowercase())
+}
+
+/// `label`, outside ASCII, in Punycode with the ACE prefix.
+fn encode(label: &str) -> Option<String> {
+    let input: Vec<u32> = label.chars().map(u32::from).collect();
+    let mut output: String = label.chars().filter(char::is_ascii).collect();
+    let basic = output.len() as u32;
+    output.insert_str(0, ACE_PREFIX);
+    if basic > 0 {
+        output.push('-');
+    }
+
+    let (mut n, mut delta, mut bias) = (INITIAL_N, 0u32, INITIAL_BIAS);
+    let mut handled = basic;
+    while (handled as usize) < input.len() {
+        let m = input.iter().copied().filter(|&c| c >= n).min()?;
+        delta = delta.checked_add((m - n).checked_mul(handled + 1)?)?;
+        n = m;
+        for &c in &input {
+            if c < n {
+                delta = delta.checked_add(1)?;
+            }
+            if c == n {
+                let mut q = delta;
+                let mut k = BASE;
+                loop {
+                    let t = threshold(k, bias);
+                    if q < t {
+                        break;
+                    }
+                    output.push(digit(t + (q - t) % (BASE - t)));
+                    q = (q - t) / (BASE - t);
+                    k += BASE;
+                }
+                output.push(digit(q));
+                bias = adapt(delta, handled + 1, handled == basic);
+                delta = 0;
+                handled += 1;
+            }
+        }
+        delta = delta.checked_add(1)?;
+        n = n.checked_add(1)?;
+    }
+    Some(output)
+}
+
+/// The label `encoded` in Punycode stands for, without the ACE prefix.
+fn decode(encoded: &str) -> Option<String> {
+    let (basic, deltas) = match encoded.rfind('-') {
+        Some(delimiter) => (&encoded[..delimiter], &encoded[delimiter + 1..]),
+        None => ("", encoded),
+    };
+    let mut output: Vec<char> = basic.chars().collect();
+    let (mut n, mut i, mut bias) = (INITIAL_N, 0u32, INITIAL_BIAS);
+    let mut deltas = deltas.bytes();
+    while deltas.len() > 0 {
+        let old_i = i;
+        let mut w = 1u32;
+        let mut k = BASE;
+        loop {
+            let value = value(deltas.next()?)?;
+            i = i.checked_add(value.checked_mul(w)?)?;
+            let t = threshold(k, bias);
+            if value < t {
+                break;
+            }
+            w = w.checked_mul(BASE - t)?;
+            k += BASE;
+        }
+        let len = output.len() as u32 + 1;
+        bias = adapt(i - old_i, len, old_i == 0);
+        n = n.checked_add(i / len)?;
+        i %= len;
+        output.insert(i as usize, char::from_u32(n)?);
+        i += 1;
+    }
+    Some(output.into_iter().collect())
+}
+
+fn threshold(k: u32, bias: u32) -> u32 {
+    k.saturating_sub(bias).clamp(T_MIN, T_MAX)
+}
+
+fn adapt(delta: u32, points: u32, first: bool) -> u32 {
+    let mut delta = if first { delta / DAMP } else { delta / 2 };
+    delta += delta / points;
+    let mut k = 0;
+    while delta > ((BASE - T_MIN) * T_MAX) / 2 {
+        delta /= BASE - T_MIN;
+        k += BASE;
+    }
+    k + (BASE - T_MIN + 1) * delta / (delta + SKEW)
+}
+
+fn digit(value: u32) -> char {
+    let value = u8::try_from(value).unwrap_or(0);
+    match value {
+        0..=25 => char::from(b'a' + value),
+        _ => char::from(b'0' + value - 26),
+    }
+}
+
+fn value(digit: u8) -> Option<u32> {
+    match digit {
+        b'a'..=b'z' => Some(u32::from(digit - b'a')),
+        b'A'..=b'Z' => Some(u32::from(digit - b'A')),
+        b'0'..=b'9' => Some(u32::from(digit - b'0') + 26),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The samples of RFC 3492 section 7.1, without their case annotations.
+    const SAMPLES: &[(&str, &str)] = &[
+        // (A) Arabic (Egyptian)
+        ("\u{644}\u{64a}\u{647}\u{645}\u{627}\u{628}\u{62a}\u{643}\u{644}\u{645}\u{648}\u{634}\u{639}\u{631}\u{628}\u{64a}\u{61f}", "egbpdaj6bu4bxfgehfvwxn"),
+        // (B) Chinese (simplified)
+        ("\u{4ed6}\u{4eec}\u{4e3a}\u{4ec0}\u{4e48}\u{4e0d}\u{8bf4}\u{4e2d}\u{6587}", "ihqwcrb4cv8a8dqg056pqjye"),
+        // (C) Chinese (traditional)
+        ("\u{4ed6}\u{5011}\u{7232}\u{4ec0}\u{9ebd}\u{4e0d}\u{8aaa}\u{4e2d}\u{6587}", "ihqwctvzc91f659drss3x8bo0yb"),
+        // (D) Czech
+        ("Pro\u{10d}prost\u{11b}nemluv\u{ed}\u{10d}esky", "Proprostnemluvesky-uyb24dma41a"),
+        // (E) Hebrew
+        ("\u{5dc}\u{5de}\u{5d4}\u{5d4}\u{5dd}\u{5e4}\u{5e9}\u{5d5}\u{5d8}\u{5dc}\u{5d0}\u{5de}\u{5d3}\u{5d1}\u{5e8}\u{5d9}\u{5dd}\u{5e2}\u{5d1}\u{5e8}\u{5d9}\u{5ea}", "4dbcagdahymbxekheh6e0a7fei0b"),
+        // (F) Hindi (Devanagari)
+        ("\u{92f}\u{939}\u{932}\u{94b}\u{917}\u{939}\u{93f}\u{928}\u{94d}\u{926}\u{940}\u{915}\u{94d}\u{92f}\u{94b}\u{902}\u{928}\u{939}\u{940}\u{902}\u{92c}\u{94b}\u{932}\u{938}\u{915}\u{924}\u{947}\u{939}\u{948}\u{902}", "i1baa7eci9glrd9b2ae1bj0hfcgg6iyaf8o0a1dig0cd"),
+        // (G) Japanese (kanji and hiragana)
+        ("\u{306a}\u{305c}\u{307f}\u{3093}\u{306a}\u{65e5}\u{672c}\u{8a9e}\u{3092}\u{8a71}\u{3057}\u{3066}\u{304f}\u{308c}\u{306a}\u{3044}\u{306e}\u{304b}", "n8jok5ay5dzabd5bym9f0cm5685rrjetr6pdxa"),
+        // (H) Korean (Hangul syllables)
+        ("\u{c138}\u{acc4}\u{c758}\u{baa8}\u{b4e0}\u{c0ac}\u{b78c}\u{b4e4}\u{c774}\u{d55c}\u{ad6d}\u{c5b4}\u{b97c}\u{c774}\u{d574}\u{d55c}\u{b2e4}\u{ba74}\u{c5bc}\u{b9c8}\u{b098}\u{c88b}\u{c744}\u{ae4c}", "989aomsvi5e83db1d2a355cv1e0vak1dwrv93d5xbh15a0dt30a5jpsd879ccm6fea98c"),
+        // (I) Russian (Cyrillic)
+        ("\u{43f}\u{43e}\u{447}\u{435}\u{43c}\u{443}\u{436}\u{435}\u{43e}\u{43d}\u{438}\u{43d}\u{435}\u{433}\u{43e}\u{432}\u{43e}\u{440}\u{44f}\u{442}\u{43f}\u{43e}\u{440}\u{443}\u{441}\u{441}\u{43a}\u{438}", "b1abfaaepdrnnbgefbaDotcwatmq2g4l"),
+        // (J) Spanish
+        ("Porqu\u{e9}nopuedensimplementehablarenEspa\u{f1}ol", "PorqunopuedensimplementehablarenEspaol-fmd56a"),
+        // (K) Vietnamese
+        ("T\u{1ea1}isaoh\u{1ecd}kh\u{f4}ngth\u{1ec3}ch\u{1ec9}n\u{f3}iti\u{1ebf}ngVi\u{1ec7}t", "TisaohkhngthchnitingVit-kjcr8268qyxafd2f1b9g"),
+        // (L) 3<nen>B<gumi><kinpachi><sensei>
+        ("3\u{5e74}B\u{7d44}\u{91d1}\u{516b}\u{5148}\u{751f}", "3B-ww4c5e180e575a65lsy2b"),
+        // (M) <amuro><namie>-with-SUPER-MONKEYS
+        ("\u{5b89}\u{5ba4}\u{5948}\u{7f8e}\u{6075}-with-SUPER-MONKEYS", "-with-SUPER-MONKEYS-pc58ag80a8qai00g7n9n"),
+        // (N) Hello-Another-Way-<sorezore><no><basho>
+        ("Hello-Another-Way-\u{305d}\u{308c}\u{305e}\u{308c}\u{306e}\u{5834}\u{6240}", "Hello-Another-Way--fc4qua05auwb3674vfr0b"),
+        // (O) <hitotsu><yane><no><shita>2
+        ("\u{3072}\u{3068}\u{3064}\u{5c4b}\u{6839}\u{306e}\u{4e0b}2", "2-u9tlzr9756bt3uc0v"),
+        // (P) Maji<de>Koi<suru>5<byou><mae>
+        ("Maji\u{3067}Koi\u{3059}\u{308b}5\u{79d2}\u{524d}", "MajiKoi5-783gue6qz075azm5e"),
+        // (Q) <pafii>de<runba>
+        ("\u{30d1}\u{30d5}\u{30a3}\u{30fc}de\u{30eb}\u{30f3}\u{30d0}", "de-jg4avhby1noc0d"),
+        // (R) <sono><supiido><de>
+        ("\u{305d}\u{306e}\u{30b9}\u{30d4}\u{30fc}\u{30c9}\u{3067}", "d9juau41awczczp"),
+        // (S) -> $1.00 <-
+        ("-> $1.00 <-", "-> $1.00 <--"),
+    ];
+
+    #[test]
+    fn encodes_samples() {
+        for (decoded, encoded) in SAMPLES {
+            let got = encode(decoded).unwrap();
+            // The samples mark case in some digits, which encoding doesn't.
+            assert!(
+                got.eq_ignore_ascii_case(&format!("xn--{}", encoded)),
+                "{} for {}",
+                got,
+                encoded
+            );
+        }
+    }
+
+    #[test]
+    fn decodes_samples() {
+        for (decoded, encoded) in SAMPLES {
+            assert_eq!(decode(encoded).as_deref(), Some(*decoded), "{}", encoded);
+            let upper = encoded.to_ascii_uppercase();
+            let lower = encoded.to_ascii_lowercase();
+            // Digits mean the same in either case, unlike basic code points.
+            let deltas = |encoded: &str| {
+                let decoded = decode(encoded).unwrap();
+                decoded
+                    .chars()
+                    .filter(|c| !c.is_ascii())
+                    .collect::<String>()
+            };
+            assert_eq!(deltas(&upper), deltas(encoded));
+            assert_eq!(deltas(&lower), deltas(encoded));
+        }
+    }
+
+    #[test]
+    fn round_trips() {
+        for label in [
+            "bücher",
+            "münchen",
+            "ü",
+            "üü",
+            "aü-b",
+            "-ü-",
+            "日本語",
+            "\u{80}",
+            "\u{10ffff}",
+            "a\u{10ffff}\u{80}z",
+        ] {
+            let encoded = encode(label).unwrap();
+            assert!(encoded.is_ascii());
+            let decoded = decode(encoded.strip_prefix(ACE_PREFIX).unwrap());
+            assert_eq!(decoded.as_deref(), Some(label));
+            assert_eq!(self::label(label), Some(encoded.clone()));
+            assert_eq!(self::label(&encoded), Some(encoded));
+        }
+    }
+
+    #[test]
+    fn normalizes() {
+        for (domain, normalized) in [
+            ("example.com", "example.com"),
+            ("Example.COM.", "example.com"),
+            ("bücher.example", "xn--bcher-kva.example"),
+            ("Bücher.Example", "xn--bcher-kva.example"),
+            ("BÜCHER.example", "xn--bcher-kva.example"),
+            ("xn--bcher-kva.example", "xn--bcher-kva.example"),
+            ("XN--BCHER-KVA.EXAMPLE", "xn--bcher-kva.example"),
+            // Bücher, spelt with a capital.
+            ("xn--Bcher-kva.example", "xn--bcher-kva.example"),
+            ("münchen\u{3002}de", "xn--mnchen-3ya.de"),
+            ("münchen\u{ff0e}de\u{ff61}", "xn--mnchen-3ya.de"),
+            ("\u{4ed6}\u{4eec}.cn", "xn--8mqxb.cn"),
+        ] {
+            assert_eq!(normalize(domain).as_deref(), Some(normalized), "{}", domain);
+        }
+    }
+
+    #[test]
+    fn invalid() {
+        for domain in [
+            "",
+            ".",
+            "..",
+            "a..b",
+            ".example",
+            // Incomplete.
+            "xn--zz.example",
+            "xn--bcher-kv.example",
+            // Not a digit.
+            "xn--ab_c.example",
+            "xn--bcher-kv\u{e4}.example",
+            "XN--\u{e4}.example",
+            // Standing for ASCII.
+            "xn--abc-.example",
+            "xn--.example",
+            "xn---> $1.00 <--",
+            // Overflowing 32 bits, and past the last code point.
+            "xn--99999999999a.example",
+            "xn--99999a.example",
+        ] {
+            assert_eq!(normalize(domain), None, "{:?}", domain);
+        }
+    }
+}
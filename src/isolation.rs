@@ -0,0 +1,113 @@
+//! Stream isolation: keeping clients that authenticated as different users
+//! on different egress addresses, so that destinations can't correlate their
+//! traffic by source IP.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+
+/// A pool of egress addresses handed out one per user, see
+/// [`Server::with_stream_isolation`].
+///
+/// A user keeps their address while they have connections open, and all of
+/// their connections use it. No two users hold the same address at once, so
+/// connections are refused while every address is held by other users.
+/// Clients that didn't authenticate with username/password count as one user.
+///
+/// Clones share the assignments.
+///
+/// [`Server::with_stream_isolation`]: crate::Server::with_stream_isolation
+#[derive(Clone)]
+pub struct StreamIsolation {
+    addrs: Arc<[IpAddr]>,
+    assigned: Arc<Mutex<Assigned>>,
+}
+
+#[derive(Default)]
+struct Assigned {
+    /// User -> index into the addresses, and connections holding it.
+    by_user: HashMap<Option<String>, (usize, usize)>,
+    /// Whether each address is held.
+    held: Vec<bool>,
+    /// Where to start looking for a free address, so that released
+    /// addresses aren't reused right away.
+    next: usize,
+}
+
+impl StreamIsolation {
+    /// Isolate users on `addrs`, which must be addresses of this host.
+    pub fn new(addrs: impl IntoIterator<Item = IpAddr>) -> Self {
+        let addrs: Arc<[IpAddr]> = addrs.into_iter().collect();
+        StreamIsolation {
+            assigned: Arc::new(Mutex::new(Assigned {
+                held: vec![false; addrs.len()],
+                ..Assigned::default()
+            })),
+            addrs,
+        }
+    }
+
+    /// Hold the address of `user` for a connection, assigning one if they
+    /// have none. Fails if all addresses are held by other users.
+    pub(crate) fn acquire(&self, user: Option<&str>) -> Option<IsolationGuard> {
+        let key = user.map(str::to_owned);
+        let mut assigned = self.assigned.lock().unwrap();
+        let index = match assigned.by_user.get_mut(&key) {
+            Some((index, connections)) => {
+                *connections += 1;
+                *index
+            }
+            None => {
+                let len = self.addrs.len();
+                let index = (0..len)
+                    .map(|i| (assigned.next + i) % len)
+                    .find(|&i| !assigned.held[i])?;
+                assigned.held[index] = true;
+                assigned.next = (index + 1) % len;
+                assigned.by_user.insert(key.clone(), (index, 1));
+                index
+            }
+        };
+        Some(IsolationGuard {
+            isolation: self.clone(),
+            user: key,
+            addr: self.addrs[index],
+        })
+    }
+}
+
+impl fmt::Debug for StreamIsolation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("StreamIsolation")
+            .field("addrs", &self.addrs)
+            .finish()
+    }
+}
+
+/// Holds a user's egress address while alive.
+pub(crate) struct IsolationGuard {
+    isolation: StreamIsolation,
+    user: Option<String>,
+    addr: IpAddr,
+}
+
+impl IsolationGuard {
+    pub(crate) fn addr(&self) -> IpAddr {
+        self.addr
+    }
+}
+
+impl Drop for IsolationGuard {
+    fn drop(&mut self) {
+        let mut assigned = self.isolation.assigned.lock().unwrap();
+        if let Some((index, connections)) = assigned.by_user.get_mut(&self.user) {
+            *connections -= 1;
+            if *connections == 0 {
+                let index = *index;
+                assigned.held[index] = false;
+                assigned.by_user.remove(&self.user);
+            }
+        }
+    }
+}
@@ -0,0 +1,478 @@
+//! Username/password authentication against an LDAP directory (RFC 4511),
+//! with simple binds over plain TCP.
+//!
+//! Either the user's DN follows from the username through a template, and
+//! binding as it checks the password, or a service account first searches
+//! for the entry whose attribute matches the username, and the password is
+//! checked by binding as the DN found.
+
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time;
+
+use crate::auth::{AuthFuture, Authenticator, Decision};
+
+const LDAP_VERSION: i64 = 3;
+
+const TAG_INTEGER: u8 = 0x02;
+const TAG_OCTET_STRING: u8 = 0x04;
+const TAG_ENUMERATED: u8 = 0x0a;
+const TAG_BOOLEAN: u8 = 0x01;
+const TAG_SEQUENCE: u8 = 0x30;
+const TAG_BIND_REQUEST: u8 = 0x60;
+const TAG_BIND_RESPONSE: u8 = 0x61;
+const TAG_SEARCH_REQUEST: u8 = 0x63;
+const TAG_SEARCH_ENTRY: u8 = 0x64;
+const TAG_SEARCH_DONE: u8 = 0x65;
+const TAG_SEARCH_REFERENCE: u8 = 0x73;
+/// `simple` choice of the bind authentication.
+const TAG_AUTH_SIMPLE: u8 = 0x80;
+/// `equalityMatch` choice of a search filter.
+const TAG_FILTER_EQUALITY: u8 = 0xa3;
+
+const RESULT_SUCCESS: i64 = 0;
+const RESULT_INVALID_CREDENTIALS: i64 = 49;
+
+const SCOPE_SUBTREE: i64 = 2;
+const DEREF_NEVER: i64 = 0;
+
+/// Largest response accepted from the server.
+const MAX_MESSAGE: usize = 1 << 20;
+
+/// Connections kept open for reuse by default.
+const MAX_IDLE: usize = 4;
+
+/// An [`Authenticator`] checking credentials against an LDAP directory.
+///
+/// ```no_run
+/// # async fn run() -> std::io::Result<()> {
+/// use socks5_rs::{LdapAuthenticator, Server};
+///
+/// let ldap = LdapAuthenticator::search_and_bind(
+///     "ldap.example.com:389",
+///     "cn=socks,ou=services,dc=example,dc=com",
+///     "service password",
+///     "ou=people,dc=example,dc=com",
+///     "uid",
+/// );
+/// Server::bind("0.0.0.0:1080")
+///     .await?
+///     .with_authenticator(ldap)
+///     .serve()
+///     .await?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct LdapAuthenticator {
+    server: String,
+    mode: Mode,
+    timeout: Duration,
+    max_idle: usize,
+    idle: Mutex<Vec<Connection>>,
+}
+
+enum Mode {
+    BindAsUser {
+        dn_template: String,
+    },
+    SearchAndBind {
+        service_dn: String,
+        service_password: String,
+        base_dn: String,
+        attribute: String,
+    },
+}
+
+impl LdapAuthenticator {
+    /// Bind to `server` (`host:port`) as the DN made from `dn_template` by
+    /// replacing `{}` with the escaped username, e.g.
+    /// `uid={},ou=people,dc=example,dc=com`.
+    pub fn bind_as_user(server: impl Into<String>, dn_template: impl Into<String>) -> Self {
+        LdapAuthenticator::new(
+            server.into(),
+            Mode::BindAsUser {
+                dn_template: dn_template.into(),
+            },
+        )
+    }
+
+    /// Bind to `server` (`host:port`) as `service_dn` to search below
+    /// `base_dn` for the one entry whose `attribute` equals the username,
+    /// then bind as that entry.
+    pub fn search_and_bind(
+        server: impl Into<String>,
+        service_dn: impl Into<String>,
+        service_password: impl Into<String>,
+        base_dn: impl Into<String>,
+        attribute: impl Into<String>,
+    ) -> Self {
+        LdapAuthenticator::new(
+            server.into(),
+            Mode::SearchAndBind {
+                service_dn: service_dn.into(),
+                service_password: service_password.into(),
+                base_dn: base_dn.into(),
+                attribute: attribute.into(),
+            },
+        )
+    }
+
+    fn new(server: String, mode: Mode) -> Self {
+        LdapAuthenticator {
+            server,
+            mode,
+            timeout: Duration::from_secs(5),
+            max_idle: MAX_IDLE,
+            idle: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Limit on connecting to the server and on each operation, 5 seconds by
+    /// default. Credentials are denied when it runs out.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// How many connections are kept open for later checks, 4 by default.
+    pub fn with_max_idle(mut self, max_idle: usize) -> Self {
+        self.max_idle = max_idle;
+        self
+    }
+
+    async fn check(&self, username: &str, password: &[u8]) -> io::Result<bool> {
+        // Servers take a bind without a password as an anonymous one, and
+        // report success.
+        if password.is_empty() {
+            return Ok(false);
+        }
+        let idle = self.idle.lock().unwrap().pop();
+        if let Some(mut connection) = idle {
+            // The server may have closed it since; then start afresh.
+            if let Ok(allowed) = self.attempt(&mut connection, username, password).await {
+                self.release(connection);
+                return Ok(allowed);
+            }
+        }
+        let mut connection = time::timeout(self.timeout, Connection::connect(&self.server))
+            .await
+            .map_err(|_| io::ErrorKind::TimedOut)??;
+        let allowed = self.attempt(&mut connection, username, password).await?;
+        self.release(connection);
+        Ok(allowed)
+    }
+
+    async fn attempt(
+        &self,
+        connection: &mut Connection,
+        username: &str,
+        password: &[u8],
+    ) -> io::Result<bool> {
+        time::timeout(self.timeout, self.run(connection, username, password))
+            .await
+            .map_err(|_| io::ErrorKind::TimedOut)?
+    }
+
+    /// Keep `connection` for later checks.
+    fn release(&self, connection: Connection) {
+        let mut idle = self.idle.lock().unwrap();
+        if idle.len() < self.max_idle {
+            idle.push(connection);
+        }
+    }
+
+    async fn run(
+        &self,
+        connection: &mut Connection,
+        username: &str,
+        password: &[u8],
+    ) -> io::Result<bool> {
+        let dn = match &self.mode {
+            Mode::BindAsUser { dn_template } => dn_template.replace("{}", &escape_dn(username)),
+            Mode::SearchAndBind {
+                service_dn,
+                service_password,
+                base_dn,
+                attribute,
+            } => {
+                if !connection
+                    .bind(service_dn, service_password.as_bytes())
+                    .await?
+                {
+                    return Err(io::Error::new(
+                        io::ErrorKind::PermissionDenied,
+                        "service bind refused",
+                    ));
+                }
+                match connection.search(base_dn, attribute, username).await? {
+                    Some(dn) => dn,
+                    None => return Ok(false),
+                }
+            }
+        };
+        connection.bind(&dn, password).await
+    }
+}
+
+impl Authenticator for LdapAuthenticator {
+    fn verify<'a>(
+        &'a self,
+        username: &'a str,
+        password: &'a [u8],
+        _peer: SocketAddr,
+    ) -> AuthFuture<'a> {
+        Box::pin(async move {
+            match self.check(username, password).await {
+                Ok(true) => Decision::Allow,
+                Ok(false) => Decision::Deny,
+                Err(e) => {
+                    log::warn!("LDAP check of user {:?}: {}", username, e);
+                    Decision::Deny
+                }
+            }
+        })
+    }
+}
+
+/// Escape `value` for use as an attribute value in a DN (RFC 4514).
+fn escape_dn(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    let last = value.chars().count().saturating_sub(1);
+    for (i, c) in value.chars().enumerate() {
+        match c {
+            '"' | '+' | ',' | ';' | '<' | '>' | '\\' | '=' => {
+                escaped.push('\\');
+                escaped.push(c);
+            }
+            '#' if i == 0 => escaped.push_str("\\#"),
+            ' ' if i == 0 || i == last => escaped.push_str("\\ "),
+            '\0' => escaped.push_str("\\00"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// A connection to the directory, used for one check at a time.
+struct Connection {
+    stream: TcpStream,
+    next_id: i64,
+}
+
+impl Connection {
+    async fn connect(server: &str) -> io::Result<Self> {
+        let stream = TcpStream::connect(server).await?;
+        stream.set_nodelay(true)?;
+        Ok(Connection { stream, next_id: 1 })
+    }
+
+    /// Whether binding as `dn` with `password` succeeded.
+    async fn bind(&mut self, dn: &str, password: &[u8]) -> io::Result<bool> {
+        let mut request = Vec::new();
+        put_integer(&mut request, TAG_INTEGER, LDAP_VERSION);
+        put(&mut request, TAG_OCTET_STRING, dn.as_bytes());
+        put(&mut request, TAG_AUTH_SIMPLE, password);
+        self.send(TAG_BIND_REQUEST, &request).await?;
+
+        let (tag, response) = self.receive().await?;
+        if tag != TAG_BIND_RESPONSE {
+            return Err(invalid("unexpected response to bind"));
+        }
+        match result_code(&response)? {
+            RESULT_SUCCESS => Ok(true),
+            RESULT_INVALID_CREDENTIALS => Ok(false),
+            code => Err(io::Error::other(format!(
+                "bind failed with result {}",
+                code
+            ))),
+        }
+    }
+
+    /// The DN of the only entry below `base` with `attribute` equal to
+    /// `value`.
+    async fn search(
+        &mut self,
+        base: &str,
+        attribute: &str,
+        value: &str,
+    ) -> io::Result<Option<String>> {
+        let mut filter = Vec::new();
+        put(&mut filter, TAG_OCTET_STRING, attribute.as_bytes());
+        put(&mut filter, TAG_OCTET_STRING, value.as_bytes());
+        // No attributes wanted, only the DN.
+        let mut attributes = Vec::new();
+        put(&mut attributes, TAG_OCTET_STRING, b"1.1");
+
+        let mut request = Vec::new();
+        put(&mut request, TAG_OCTET_STRING, base.as_bytes());
+        put_integer(&mut request, TAG_ENUMERATED, SCOPE_SUBTREE);
+        put_integer(&mut request, TAG_ENUMERATED, DEREF_NEVER);
+        // A second entry is enough to know the username is ambiguous.
+        put_integer(&mut request, TAG_INTEGER, 2);
+        put_integer(&mut request, TAG_INTEGER, 0);
+        put(&mut request, TAG_BOOLEAN, &[0]);
+        put(&mut request, TAG_FILTER_EQUALITY, &filter);
+        put(&mut request, TAG_SEQUENCE, &attributes);
+        self.send(TAG_SEARCH_REQUEST, &request).await?;
+
+        let mut found = Vec::new();
+        loop {
+            let (tag, response) = self.receive().await?;
+            match tag {
+                TAG_SEARCH_ENTRY => {
+                    let dn = Reader(&response).next(TAG_OCTET_STRING)?;
+                    found.push(String::from_utf8_lossy(dn).into_owned());
+                }
+                TAG_SEARCH_REFERENCE => {}
+                TAG_SEARCH_DONE => break,
+                _ => return Err(invalid("unexpected response to search")),
+            }
+        }
+        if found.len() == 1 {
+            Ok(found.pop())
+        } else {
+            // Exceeding the size limit ends the search with an error, which
+            // is fine here: the username isn't unique.
+            Ok(None)
+        }
+    }
+
+    async fn send(&mut self, tag: u8, operation: &[u8]) -> io::Result<()> {
+        let mut message = Vec::new();
+        put_integer(&mut message, TAG_INTEGER, self.next_id);
+        put(&mut message, tag, operation);
+        self.next_id += 1;
+        let mut frame = Vec::with_capacity(message.len() + 6);
+        put(&mut frame, TAG_SEQUENCE, &message);
+        self.stream.write_all(&frame).await
+    }
+
+    /// The next message for the last request sent: its operation's tag and
+    /// contents.
+    async fn receive(&mut self) -> io::Result<(u8, Vec<u8>)> {
+        let tag = self.stream.read_u8().await?;
+        if tag != TAG_SEQUENCE {
+            return Err(invalid("not an LDAP message"));
+        }
+        let first = self.stream.read_u8().await?;
+        let len = if first < 0x80 {
+            first as usize
+        } else {
+            let n = (first & 0x7f) as usize;
+            if n == 0 || n > 4 {
+                return Err(invalid("bad message length"));
+            }
+            let mut len = 0;
+            for _ in 0..n {
+                len = len << 8 | self.stream.read_u8().await? as usize;
+            }
+            len
+        };
+        if len > MAX_MESSAGE {
+            return Err(invalid("message too long"));
+        }
+        let mut message = vec![0; len];
+        self.stream.read_exact(&mut message).await?;
+
+        let mut reader = Reader(&message);
+        let id = reader.next(TAG_INTEGER)?;
+        if integer(id)? != self.next_id - 1 {
+            return Err(invalid("response to another request"));
+        }
+        let (tag, operation) = reader.any()?;
+        Ok((tag, operation.to_vec()))
+    }
+}
+
+/// The result code of an `LDAPResult`.
+fn result_code(result: &[u8]) -> io::Result<i64> {
+    let code = Reader(result).next(TAG_ENUMERATED)?;
+    integer(code)
+}
+
+fn invalid(reason: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, reason.to_owned())
+}
+
+/// Append a BER element.
+fn put(out: &mut Vec<u8>, tag: u8, contents: &[u8]) {
+    out.push(tag);
+    let len = contents.len();
+    if len < 0x80 {
+        out.push(len as u8);
+    } else {
+        let bytes = (len as u32).to_be_bytes();
+        let skip = bytes.iter().take_while(|&&b| b == 0).count();
+        out.push(0x80 | (4 - skip) as u8);
+        out.extend_from_slice(&bytes[skip..]);
+    }
+    out.extend_from_slice(contents);
+}
+
+/// Append a BER integer in its shortest form.
+fn put_integer(out: &mut Vec<u8>, tag: u8, value: i64) {
+    let bytes = value.to_be_bytes();
+    let mut start = 0;
+    while start < 7
+        && ((bytes[start] == 0 && bytes[start + 1] & 0x80 == 0)
+            || (bytes[start] == 0xff && bytes[start + 1] & 0x80 != 0))
+    {
+        start += 1;
+    }
+    put(out, tag, &bytes[start..]);
+}
+
+fn integer(bytes: &[u8]) -> io::Result<i64> {
+    if bytes.is_empty() || bytes.len() > 8 {
+        return Err(invalid("bad integer"));
+    }
+    let negative = bytes[0] & 0x80 != 0;
+    let mut value = if negative { -1 } else { 0 };
+    for &b in bytes {
+        value = value << 8 | b as i64;
+    }
+    Ok(value)
+}
+
+/// Reads the BER elements of a constructed element's contents.
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn any(&mut self) -> io::Result<(u8, &'a [u8])> {
+        let (&tag, rest) = self.0.split_first().ok_or_else(|| invalid("truncated"))?;
+        let (&first, mut rest) = rest.split_first().ok_or_else(|| invalid("truncated"))?;
+        let len = if first < 0x80 {
+            first as usize
+        } else {
+            let n = (first & 0x7f) as usize;
+            if n == 0 || n > 4 || rest.len() < n {
+                return Err(invalid("bad length"));
+            }
+            let len = rest[..n].iter().fold(0, |len, &b| len << 8 | b as usize);
+            rest = &rest[n..];
+            len
+        };
+        if rest.len() < len {
+            return Err(invalid("truncated"));
+        }
+        let (contents, rest) = rest.split_at(len);
+        self.0 = rest;
+        Ok((tag, contents))
+    }
+
+    /// The contents of the next element, which must be tagged `tag`.
+    fn next(&mut self, tag: u8) -> io::Result<&'a [u8]> {
+        match self.any()? {
+            (found, contents) if found == tag => Ok(contents),
+            (found, _) => Err(invalid(&format!(
+                "expected tag {:#04x}, got {:#04x}",
+                tag, found
+            ))),
+        }
+    }
+}
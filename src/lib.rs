@@ -1,246 +1,2281 @@
-use std::{io, net::ToSocketAddrs, usize};
-use std::convert::TryFrom;
-use bytes::Buf;
-use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+use std::any::Any;
+use std::future::{self, Future};
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4};
+use std::panic::{self, AssertUnwindSafe};
+#[cfg(unix)]
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::thread;
+use std::time::Duration;
 use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
     net::{TcpListener, TcpStream},
+    sync::{mpsc, Notify},
+    time::{self, Instant},
 };
 
-const SOCKS_VERSION: u8 = 0x05;
-const RESERVED: u8 = 0x00;
+mod acl;
+mod audit;
+mod auth;
+#[cfg(target_os = "linux")]
+mod ban;
+mod blocklist;
+mod bloom;
+pub mod codec;
+mod config;
+mod dialcache;
+mod dialer;
+mod dns;
+mod dnsbl;
+mod error;
+mod eyeballs;
+mod feed;
+#[cfg(feature = "geoip")]
+mod geoip;
+#[cfg(feature = "gssapi")]
+mod gssapi;
+mod htpasswd;
+mod idna;
+mod isolation;
+#[cfg(feature = "ldap")]
+mod ldap;
+mod listener;
+mod lockout;
+mod nat64;
+#[cfg(all(unix, feature = "pam"))]
+mod pam;
+mod passwd;
+#[cfg(unix)]
+mod peercred;
+mod policy;
+pub mod protocol;
+#[cfg(feature = "radius")]
+mod radius;
+mod regex;
+mod resolver;
+mod retry;
+mod rules;
+mod schedule;
+mod script;
+mod session;
+mod sniff;
+#[cfg(unix)]
+mod sockopt;
+mod socks4;
+#[cfg(feature = "sqlite")]
+mod sqlite;
+#[cfg(all(unix, feature = "systemd"))]
+mod systemd;
+#[cfg(feature = "tls")]
+mod tls;
+mod totp;
+mod trie;
+mod udp;
+mod watch;
 
-enum AuthMethod {
-    /// No Authentication
-    NoAuth = 0x00,
-    // GssApi = 0x01,
-    /// Authenticate with a username / password
-    UserPass = 0x02,
+pub use acl::{
+    BlockedResponse, ClientAcl, DestinationAcl, DomainPattern, DomainPatternParseError,
+    DomainRegex, DomainRegexError, IpNet, IpNetParseError, PortAcl,
+};
+pub use audit::{AuditSink, AuthEvent, AuthFailure, AuthOutcome};
+pub use auth::{AuthFuture, Authenticator, Decision};
+#[cfg(target_os = "linux")]
+pub use ban::KernelBan;
+pub use blocklist::{BlocklistFormat, DomainBlocklist};
+pub use config::{BoxTask, MethodFilter, ServerBuilder, ServerConfig, Spawner};
+pub use dialer::{DialFuture, DialOptions, DialedStream, Dialer, Keepalive, TcpDialer};
+pub use dns::{DnsCache, DnsResolver, DnsStats, StaticHosts};
+pub use dnsbl::{Dnsbl, DnsblAction};
+pub use error::Socks5Error;
+pub use feed::IpFeed;
+#[cfg(feature = "geoip")]
+pub use geoip::{GeoIpDb, GeoIpFilter};
+#[cfg(feature = "gssapi")]
+pub use gssapi::{GssapiContext, GssapiProvider, GssapiStep};
+pub use htpasswd::HtpasswdFile;
+pub use isolation::StreamIsolation;
+#[cfg(feature = "ldap")]
+pub use ldap::LdapAuthenticator;
+pub use lockout::{Lockout, LockoutKey, LockoutPolicy};
+pub use nat64::Nat64;
+#[cfg(all(unix, feature = "pam"))]
+pub use pam::PamAuthenticator;
+#[cfg(unix)]
+pub use peercred::PeerCredPolicy;
+pub use policy::{DestinationFilter, UserPolicy};
+#[cfg(feature = "radius")]
+pub use radius::RadiusAuthenticator;
+pub use resolver::{
+    AddressFamilyPolicy, ResolveFuture, ResolveWithTtlFuture, Resolver, SystemResolver,
+};
+pub use retry::RetryPolicy;
+pub use rules::{Rule, RuleAction};
+pub use schedule::{Schedule, Weekday};
+pub use script::{AccessRequest, AccessScript};
+pub use session::Session;
+#[cfg(feature = "sqlite")]
+pub use sqlite::SqliteUsers;
+#[cfg(feature = "tls")]
+pub use tls::{ClientCertAuth, TlsAcceptor, TlsConnector, TlsFuture, TlsStream};
+pub use tokio_util::sync::CancellationToken;
+pub use totp::TotpAuthenticator;
+pub use udp::{FragPolicy, UdpAssociation, UdpStats};
+
+#[cfg(unix)]
+use tokio::net::UnixListener;
+
+use config::Requester;
+use listener::{Accepted, Listener};
+use protocol::{
+    unmap_socket_addr, Address, Command, Greeting, Method, MethodSelection, Parse, ProtocolError,
+    Reply, Request, Response, TargetAddr, UserPassRequest, UserPassResponse,
+};
+use session::{Counted, SessionState};
+#[cfg(unix)]
+use sockopt::ClientSocket;
+
+impl From<&io::Error> for Reply {
+    /// The reply to send when connecting to the target failed with `e`.
+    fn from(e: &io::Error) -> Reply {
+        match e.kind() {
+            io::ErrorKind::NetworkUnreachable => Reply::NetworkUnreachable,
+            io::ErrorKind::HostUnreachable => Reply::HostUnreachable,
+            io::ErrorKind::ConnectionRefused => Reply::ConnectionRefused,
+            io::ErrorKind::TimedOut => Reply::TtlExpired,
+            _ => Reply::GeneralFailure,
+        }
+    }
+}
+
+pub struct Server {
+    listeners: Vec<Arc<Listener>>,
+    config: Arc<ServerConfig>,
+    udp_associations: Arc<udp::Associations>,
+    connections: Arc<Connections>,
+    sessions: Arc<session::Registry>,
+    lockouts: Arc<lockout::Lockouts>,
+    /// Stops [`Server::serve`] when cancelled.
+    shutdown: CancellationToken,
 }
 
-impl From<AuthMethod> for u8 {
-    fn from(auth_method: AuthMethod) -> u8 {
-        auth_method as u8
+impl Server {
+    /// A server listening on `127.0.0.1:1080`.
+    ///
+    /// Panics if that address can't be bound; use [`Server::bind`] to handle
+    /// the error instead.
+    pub async fn new() -> Self {
+        Server::bind("127.0.0.1:1080").await.unwrap()
+    }
+
+    /// A server listening on `addr`.
+    pub async fn bind<A: tokio::net::ToSocketAddrs>(addr: A) -> io::Result<Self> {
+        Ok(Server::from_listener(TcpListener::bind(addr).await?))
+    }
+
+    /// A server accepting connections on an already bound `listener`.
+    pub fn from_listener(listener: TcpListener) -> Self {
+        Server::from_listeners(vec![listener.into()])
+    }
+
+    /// A server listening on the Unix socket at `path`. Must be called from
+    /// within a Tokio runtime.
+    #[cfg(unix)]
+    pub fn bind_unix<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Ok(Server::from_listeners(vec![
+            UnixListener::bind(path)?.into()
+        ]))
+    }
+
+    /// A server listening on the sockets systemd passed to this process
+    /// through socket activation. Must be called from within a Tokio
+    /// runtime.
+    #[cfg(all(unix, feature = "systemd"))]
+    pub fn from_systemd() -> io::Result<Self> {
+        Ok(Server::from_listeners(systemd::listeners()?))
+    }
+
+    pub(crate) fn from_listeners(listeners: Vec<Listener>) -> Self {
+        Server {
+            listeners: listeners.into_iter().map(Arc::new).collect(),
+            config: Arc::new(ServerConfig::default()),
+            udp_associations: Arc::default(),
+            connections: Arc::default(),
+            sessions: Arc::default(),
+            lockouts: Arc::default(),
+            shutdown: CancellationToken::new(),
+        }
+    }
+
+    /// Build a server from a [`ServerConfig`].
+    pub fn builder() -> ServerBuilder {
+        ServerBuilder::new()
+    }
+
+    /// Accept connections on `listener` as well, e.g. to serve both IPv4 and
+    /// IPv6 clients.
+    pub fn with_listener(mut self, listener: TcpListener) -> Self {
+        self.listeners.push(Arc::new(listener.into()));
+        self
+    }
+
+    /// Accept connections on the Unix socket `listener` as well. These
+    /// clients are served as if connecting from `127.0.0.1`.
+    #[cfg(unix)]
+    pub fn with_unix_listener(mut self, listener: UnixListener) -> Self {
+        self.listeners.push(Arc::new(listener.into()));
+        self
+    }
+
+    /// Replace all settings with `config`. Its listen addresses are ignored,
+    /// the server is already listening.
+    pub fn with_config(mut self, config: ServerConfig) -> Self {
+        self.config = Arc::new(config);
+        self
+    }
+
+    /// The current settings.
+    pub fn config(&self) -> &ServerConfig {
+        &self.config
+    }
+
+    /// Like [`Server::from_listener`], taking a listener from the standard
+    /// library. It is switched to non-blocking mode. Must be called from
+    /// within a Tokio runtime.
+    pub fn from_std(listener: std::net::TcpListener) -> io::Result<Self> {
+        listener.set_nonblocking(true)?;
+        Ok(Server::from_listener(TcpListener::from_std(listener)?))
+    }
+
+    /// Require RFC 1929 username/password authentication, checked against
+    /// `users`.
+    pub fn with_users<I, U, P>(mut self, users: I) -> Self
+    where
+        I: IntoIterator<Item = (U, P)>,
+        U: Into<String>,
+        P: Into<String>,
+    {
+        Arc::make_mut(&mut self.config).users = users
+            .into_iter()
+            .map(|(user, pass)| (user.into(), pass.into()))
+            .collect();
+        self
+    }
+
+    /// Restrict `username` by `policy`, once authenticated with
+    /// username/password.
+    pub fn with_user_policy(mut self, username: impl Into<String>, policy: UserPolicy) -> Self {
+        Arc::make_mut(&mut self.config)
+            .user_policies
+            .insert(username.into(), policy);
+        self
+    }
+
+    /// Restrict each of `usernames` by `policy`, as a group. They share the
+    /// bandwidth budget, while sessions are still counted per user.
+    pub fn with_group_policy<I, U>(mut self, usernames: I, policy: UserPolicy) -> Self
+    where
+        I: IntoIterator<Item = U>,
+        U: Into<String>,
+    {
+        let config = Arc::make_mut(&mut self.config);
+        for username in usernames {
+            config.user_policies.insert(username.into(), policy.clone());
+        }
+        self
+    }
+
+    /// Require username/password authentication, checked by
+    /// `authenticator` instead of against the users set with
+    /// [`Server::with_users`].
+    pub fn with_authenticator<A: Authenticator + 'static>(mut self, authenticator: A) -> Self {
+        Arc::make_mut(&mut self.config).authenticator = Some(Arc::new(authenticator));
+        self
+    }
+
+    /// Require username/password authentication, checked by the async
+    /// closure `verify`. It gets the username and password, and resolves to
+    /// whether they are valid. Passwords that aren't UTF-8 are refused
+    /// without asking.
+    ///
+    /// ```no_run
+    /// # use socks5_rs::Server;
+    /// # async fn example() -> Server {
+    /// Server::new()
+    ///     .await
+    ///     .with_user_pass(|user, pass| async move { user == "alice" && pass == "secret" })
+    /// # }
+    /// ```
+    pub fn with_user_pass<F, Fut>(self, verify: F) -> Self
+    where
+        F: Fn(String, String) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = bool> + Send + 'static,
+    {
+        self.with_authenticator(auth::UserPassFn(verify))
+    }
+
+    /// Accept GSSAPI authentication, with security contexts created by
+    /// `provider`.
+    #[cfg(feature = "gssapi")]
+    pub fn with_gssapi<P: GssapiProvider + 'static>(mut self, provider: P) -> Self {
+        Arc::make_mut(&mut self.config).gssapi = Some(Arc::new(provider));
+        self
+    }
+
+    /// Connect to targets from one of `addrs` per user, never the same for
+    /// two users at once, so that destinations can't tell which connections
+    /// come from the same user. See [`StreamIsolation`].
+    ///
+    /// Users whose [`UserPolicy`] has an egress address use that instead.
+    pub fn with_stream_isolation(mut self, addrs: impl IntoIterator<Item = IpAddr>) -> Self {
+        Arc::make_mut(&mut self.config).stream_isolation = Some(StreamIsolation::new(addrs));
+        self
+    }
+
+    /// Connect to targets in the address family of `addr` from `addr`,
+    /// rather than from the address the system picks, e.g. to choose the
+    /// egress IP of a multi-homed host. Call once with an IPv4 address and
+    /// once with an IPv6 one to choose both. Upstream proxies are connected
+    /// to from it too; UDP datagrams are still relayed from the address
+    /// clients reached the server on.
+    ///
+    /// Stream isolation, the egress address of a user's [`UserPolicy`] and
+    /// that of a rule, see [`Rule::with_egress_addr`], take precedence.
+    pub fn with_egress_addr(mut self, addr: IpAddr) -> Self {
+        let config = Arc::make_mut(&mut self.config);
+        match addr {
+            IpAddr::V4(addr) => config.egress_v4 = Some(addr),
+            IpAddr::V6(addr) => config.egress_v6 = Some(addr),
+        }
+        self
+    }
+
+    /// Connect to targets, and upstream proxies, out of the network
+    /// interface named `name` (`SO_BINDTODEVICE`), e.g. a WireGuard
+    /// interface, whatever the routing table says. Rules may choose another
+    /// with [`Rule::with_interface`]. UDP datagrams are still relayed
+    /// as routed.
+    ///
+    /// Needs the `CAP_NET_RAW` capability before Linux 5.7. Connecting
+    /// fails if the interface doesn't exist.
+    #[cfg(target_os = "linux")]
+    pub fn with_interface(mut self, name: &str) -> Self {
+        Arc::make_mut(&mut self.config).interface = Some(name.to_owned());
+        self
+    }
+
+    /// Tag connections to targets, and upstream proxies, with the firewall
+    /// mark `mark` (`SO_MARK`), so that policy routing and nftables can
+    /// tell proxied traffic from the host's own. Rules may choose another
+    /// with [`Rule::with_mark`].
+    ///
+    /// Needs the `CAP_NET_ADMIN` capability, or connecting fails.
+    #[cfg(target_os = "linux")]
+    pub fn with_mark(mut self, mark: u32) -> Self {
+        Arc::make_mut(&mut self.config).mark = Some(mark);
+        self
+    }
+
+    /// Probe idle connections to clients and targets with TCP keepalive as
+    /// `keepalive` says, so that sessions whose peer went away, e.g. behind
+    /// a NAT that dropped them, are noticed and ended rather than held
+    /// open for good.
+    #[cfg(unix)]
+    pub fn with_keepalive(mut self, keepalive: Keepalive) -> Self {
+        Arc::make_mut(&mut self.config).keepalive = Some(keepalive);
+        self
+    }
+
+    /// Whether connections to clients and targets set `TCP_NODELAY`,
+    /// sending small writes at once rather than waiting to coalesce them
+    /// with Nagle's algorithm. On by default, which suits the interactive
+    /// protocols proxied; turning it off trades their latency for fewer
+    /// packets.
+    pub fn with_nodelay(mut self, nodelay: bool) -> Self {
+        Arc::make_mut(&mut self.config).nodelay = nodelay;
+        self
+    }
+
+    /// Connect to targets, and upstream proxies, with TCP Fast Open
+    /// (`TCP_FASTOPEN_CONNECT`): to those that handed out a Fast Open
+    /// cookie before, the first data the client sends goes in the SYN,
+    /// sparing a round trip on each connection to frequent destinations.
+    ///
+    /// The connect then completes before the target answered, so the
+    /// client is told it succeeded even if the target turns out to refuse,
+    /// and finds out from the connection closing instead. Needs client
+    /// Fast Open enabled in `net.ipv4.tcp_fastopen`, as it is by default.
+    #[cfg(target_os = "linux")]
+    pub fn with_fast_open(mut self) -> Self {
+        Arc::make_mut(&mut self.config).fast_open = true;
+        self
+    }
+
+    /// Retry connecting to targets that refused or didn't answer as
+    /// `retry` says before giving up and failing the request, e.g. to ride
+    /// out a target restarting. Rules may retry otherwise with
+    /// [`Rule::with_connect_retry`]. Each attempt tries every address of
+    /// the target, within the connect timeout.
+    pub fn with_connect_retry(mut self, retry: RetryPolicy) -> Self {
+        Arc::make_mut(&mut self.config).retry = Some(retry);
+        self
+    }
+
+    /// Connect to targets, and upstream proxies, with Multipath TCP
+    /// (`IPPROTO_MPTCP`), so that relayed sessions can use several paths
+    /// at once, e.g. both Wi-Fi and cellular, to targets that support it.
+    /// Targets that don't are spoken to over plain TCP by the kernel, and
+    /// where the kernel lacks MPTCP, or has it disabled, connections are
+    /// made over plain TCP.
+    #[cfg(target_os = "linux")]
+    pub fn with_mptcp(mut self) -> Self {
+        Arc::make_mut(&mut self.config).mptcp = true;
+        self
+    }
+
+    /// Mark the packets of sessions with the DSCP `dscp`, of which the low
+    /// six bits count, both on connections to targets and to clients and
+    /// on UDP relays, so that network gear can prioritize proxied traffic,
+    /// or deprioritize it. Users and rules may choose another with
+    /// [`UserPolicy::with_dscp`] and [`Rule::with_dscp`].
+    #[cfg(unix)]
+    pub fn with_dscp(mut self, dscp: u8) -> Self {
+        Arc::make_mut(&mut self.config).dscp = Some(dscp);
+        self
+    }
+
+    /// Refuse requests for blocked targets as `response` says, rather than
+    /// replying with the code of the ACL or rule blocking them.
+    pub fn with_blocked_response(mut self, response: BlockedResponse) -> Self {
+        Arc::make_mut(&mut self.config).blocked_response = response;
+        self
+    }
+
+    /// Sniff the first bytes clients send on CONNECTs to IPs, for up to
+    /// `timeout`, for the server name of a TLS ClientHello or the host of an
+    /// HTTP request. The name is checked against the domain rules and ACLs,
+    /// and the connection closed if blocked, so that clients resolving
+    /// names themselves can't get around them.
+    ///
+    /// Clients of protocols where the server speaks first are relayed
+    /// once `timeout` is up.
+    pub fn with_sniffing(mut self, timeout: Duration) -> Self {
+        Arc::make_mut(&mut self.config).sniff_timeout = Some(timeout);
+        self
+    }
+
+    /// Ask `script` about CONNECT requests and UDP datagrams before the
+    /// rules, which decide on those it leaves to them.
+    pub fn with_access_script<A: AccessScript + 'static>(mut self, script: A) -> Self {
+        Arc::make_mut(&mut self.config).access_script = Some(Arc::new(script));
+        self
+    }
+
+    /// Add `rule` to the end of the rules deciding what becomes of CONNECT
+    /// requests and UDP datagrams. The first rule matching a request does,
+    /// before the ACLs are checked; requests matching none are let through
+    /// to them.
+    pub fn with_rule(mut self, rule: Rule) -> Self {
+        Arc::make_mut(&mut self.config).rules.push(rule);
+        self
+    }
+
+    /// Never resolve the domain names of targets to evaluate rules, so that
+    /// names routed through an upstream proxy reach it unresolved, and it
+    /// alone looks them up: no query for them leaks from this host, and the
+    /// proxy's view of DNS is the one used. Rules matching networks then
+    /// only match IP targets. Names connected to directly are still
+    /// resolved here, and checked against the ACLs, when connecting.
+    pub fn with_remote_dns(mut self, enabled: bool) -> Self {
+        Arc::make_mut(&mut self.config).remote_dns = enabled;
+        self
+    }
+
+    /// Only reach targets that pass `acl`: domain names before resolving
+    /// them, IPs after, for CONNECT and UDP alike. With several ACLs,
+    /// targets must pass all of them.
+    pub fn with_destination_acl(mut self, acl: DestinationAcl) -> Self {
+        Arc::make_mut(&mut self.config).destination_acls.push(acl);
+        self
+    }
+
+    /// Only serve TCP clients connecting from IPs that pass `acl`. Others
+    /// are disconnected before the handshake.
+    pub fn with_client_acl(mut self, acl: ClientAcl) -> Self {
+        Arc::make_mut(&mut self.config).client_acl = Some(acl);
+        self
+    }
+
+    /// Only reach targets whose IPs pass `filter`, checked after resolving
+    /// them, for CONNECT and UDP alike.
+    #[cfg(feature = "geoip")]
+    pub fn with_geoip_filter(mut self, filter: GeoIpFilter) -> Self {
+        Arc::make_mut(&mut self.config).geoip_filter = Some(filter);
+        self
+    }
+
+    /// Look up the IPs of targets in DNS blocklists, after resolving them,
+    /// for CONNECT and UDP alike.
+    pub fn with_dnsbl(mut self, dnsbl: Dnsbl) -> Self {
+        Arc::make_mut(&mut self.config).dnsbl = Some(dnsbl);
+        self
+    }
+
+    /// Only reach target ports that pass `acl`, for CONNECT and UDP alike.
+    /// Per-user port rules go in a [`UserPolicy`].
+    pub fn with_port_acl(mut self, acl: PortAcl) -> Self {
+        Arc::make_mut(&mut self.config).port_acl = Some(acl);
+        self
+    }
+
+    /// Hand a record of every authentication attempt to `sink`.
+    pub fn with_audit_sink<A: AuditSink + 'static>(mut self, sink: A) -> Self {
+        Arc::make_mut(&mut self.config).audit_sink = Some(Arc::new(sink));
+        self
+    }
+
+    /// Lock out client IPs and usernames that fail username/password
+    /// authentication too often, as set by `policy`. While locked out,
+    /// their credentials are refused without being checked.
+    ///
+    /// Note that anyone can lock out a username by failing with it on
+    /// purpose.
+    pub fn with_auth_lockout(mut self, policy: LockoutPolicy) -> Self {
+        Arc::make_mut(&mut self.config).auth_lockout = Some(policy);
+        self
+    }
+
+    /// Add client IPs to the kernel set `ban` while they are locked out,
+    /// so that the firewall drops their packets before they reach the
+    /// server. Has no effect without [`Server::with_auth_lockout`].
+    #[cfg(target_os = "linux")]
+    pub fn with_kernel_ban(mut self, ban: KernelBan) -> Self {
+        Arc::make_mut(&mut self.config).kernel_ban = Some(ban);
+        self
+    }
+
+    /// Offer `methods` in this order of preference, instead of the default
+    /// of GSSAPI, then username/password, or else no authentication. Methods
+    /// that aren't configured (e.g. username/password without users) are
+    /// left out.
+    pub fn with_methods<I: IntoIterator<Item = Method>>(mut self, methods: I) -> Self {
+        Arc::make_mut(&mut self.config).methods = Some(methods.into_iter().collect());
+        self
+    }
+
+    /// Let clients use only the methods `filter` accepts for their IP. For
+    /// example, to allow unauthenticated access from localhost only:
+    ///
+    /// ```no_run
+    /// # use socks5_rs::{protocol::Method, Server};
+    /// # async fn example() -> Server {
+    /// Server::new()
+    ///     .await
+    ///     .with_users(vec![("user", "pass")])
+    ///     .with_methods(vec![Method::UserPass, Method::NoAuth])
+    ///     .with_method_filter(|method, ip| method != Method::NoAuth || ip.is_loopback())
+    /// # }
+    /// ```
+    pub fn with_method_filter<F>(mut self, filter: F) -> Self
+    where
+        F: Fn(Method, IpAddr) -> bool + Send + Sync + 'static,
+    {
+        Arc::make_mut(&mut self.config).method_filter = Some(Arc::new(filter));
+        self
+    }
+
+    /// Speak SOCKS over TLS to TCP clients, with the handshake run by
+    /// `acceptor`. Unix socket clients are served as before.
+    #[cfg(feature = "tls")]
+    pub fn with_tls<A: TlsAcceptor + 'static>(mut self, acceptor: A) -> Self {
+        Arc::make_mut(&mut self.config).tls = Some(Arc::new(acceptor));
+        self
+    }
+
+    /// Identify TLS clients as users by their certificates, as set by
+    /// `auth`. Only applies along with [`Server::with_tls`].
+    #[cfg(feature = "tls")]
+    pub fn with_client_cert_auth(mut self, auth: ClientCertAuth) -> Self {
+        Arc::make_mut(&mut self.config).client_cert_auth = Some(auth);
+        self
+    }
+
+    /// Serve Unix socket clients only if the credentials of their process
+    /// pass `policy`. Others are disconnected right away.
+    #[cfg(unix)]
+    pub fn with_unix_peer_cred(mut self, policy: PeerCredPolicy) -> Self {
+        Arc::make_mut(&mut self.config).unix_peer_cred = Some(policy);
+        self
+    }
+
+    /// Also accept SOCKS4 and SOCKS4a clients on the same port. They are
+    /// refused while authentication is required.
+    pub fn with_socks4(mut self, enabled: bool) -> Self {
+        Arc::make_mut(&mut self.config).socks4 = enabled;
+        self
+    }
+
+    /// Resolve the domain names of targets with `resolver`, instead of the
+    /// system's resolver.
+    pub fn with_resolver<R: Resolver + 'static>(mut self, resolver: R) -> Self {
+        Arc::make_mut(&mut self.config).resolver = Some(Arc::new(resolver));
+        self
+    }
+
+    /// Connect to targets and upstream proxies with `dialer`, instead of
+    /// over TCP.
+    pub fn with_dialer<D: Dialer + 'static>(mut self, dialer: D) -> Self {
+        Arc::make_mut(&mut self.config).dialer = Some(Arc::new(dialer));
+        self
+    }
+
+    /// Resolve the names listed in `hosts` to their addresses there,
+    /// without asking the resolver or cache.
+    pub fn with_static_hosts(mut self, hosts: StaticHosts) -> Self {
+        Arc::make_mut(&mut self.config).static_hosts = Some(hosts);
+        self
+    }
+
+    /// Cache the addresses target names resolve to in `cache`.
+    pub fn with_dns_cache(mut self, cache: DnsCache) -> Self {
+        Arc::make_mut(&mut self.config).dns_cache = Some(cache);
+        self
+    }
+
+    /// Filter and order the addresses target names resolve to by `policy`,
+    /// e.g. to prefer IPv4 on hosts with unreliable IPv6 routes.
+    pub fn with_address_family_policy(mut self, policy: AddressFamilyPolicy) -> Self {
+        Arc::make_mut(&mut self.config).address_family_policy = policy;
+        self
+    }
+
+    /// Connect to targets with several addresses by Happy Eyeballs (RFC
+    /// 8305): start connecting to the next address every `delay` until one
+    /// connects, rather than only once the previous attempt fails,
+    /// alternating between IPv6 and IPv4. RFC 8305 recommends 250
+    /// milliseconds. Cuts connecting through broken dual-stack paths from a
+    /// connect timeout to about `delay`.
+    pub fn with_happy_eyeballs(mut self, delay: Duration) -> Self {
+        Arc::make_mut(&mut self.config).happy_eyeballs_delay = Some(delay);
+        self
+    }
+
+    /// Reach IPv4 targets through the NAT64 gateway at `nat64`, for
+    /// servers on IPv6-only networks: at the IPv6 addresses embedding
+    /// theirs, whether given as addresses or resolved from A records.
+    /// Addresses a DNS64 resolver answers with under the prefix are taken
+    /// for the IPv4 addresses they embed, so that the ACLs check them as
+    /// such. IPv4 sources of UDP replies are reported to clients as such.
+    pub fn with_nat64(mut self, nat64: Nat64) -> Self {
+        Arc::make_mut(&mut self.config).nat64 = Some(nat64);
+        self
+    }
+
+    /// Give up each attempt to connect to an address of a target, or to an
+    /// upstream proxy, after `timeout`, moving on to the next address. By
+    /// default attempts last as long as the system lets them, which can
+    /// be minutes for an address that doesn't answer. An upstream proxy
+    /// is given as long again to answer the request made through it.
+    ///
+    /// When the last attempt timed out, the client is replied "TTL
+    /// expired" (`0x06`) and the connection closed.
+    pub fn with_connect_timeout(mut self, timeout: Duration) -> Self {
+        Arc::make_mut(&mut self.config).connect_timeout = Some(timeout);
+        self
+    }
+
+    /// Remember for `ttl` the target addresses that refused connections,
+    /// were unreachable or timed out, and refuse requests for them in the
+    /// meantime with the same reply, without connecting. Spares the server,
+    /// and the target, clients retrying a dead target over and over. Other
+    /// addresses of a target are still tried.
+    pub fn with_dial_failure_cache(mut self, ttl: Duration) -> Self {
+        Arc::make_mut(&mut self.config).dial_failure_ttl = Some(ttl);
+        self
+    }
+
+    /// Give up resolving the domain names of targets after `timeout`,
+    /// replying "host unreachable". Defaults to five seconds.
+    pub fn with_resolve_timeout(mut self, timeout: Duration) -> Self {
+        Arc::make_mut(&mut self.config).resolve_timeout = timeout;
+        self
+    }
+
+    /// Reach link-local IPv6 targets (`fe80::/10`) that come without a
+    /// scope through the interface with index `scope_id`.
+    pub fn with_link_local_scope_id(mut self, scope_id: u32) -> Self {
+        Arc::make_mut(&mut self.config).link_local_scope_id = Some(scope_id);
+        self
+    }
+
+    /// Like [`Server::with_link_local_scope_id`], naming the interface
+    /// (e.g. `eth0`) instead. Fails if there is no such interface.
+    #[cfg(unix)]
+    pub fn with_link_local_interface(self, interface: &str) -> io::Result<Self> {
+        let name = std::ffi::CString::new(interface)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        // SAFETY: `name` is a valid NUL terminated string.
+        let index = unsafe { libc::if_nametoindex(name.as_ptr()) };
+        if index == 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(self.with_link_local_scope_id(index))
+    }
+
+    /// How UDP relays treat fragmented datagrams. Defaults to
+    /// [`FragPolicy::Drop`].
+    pub fn with_udp_frag_policy(mut self, policy: FragPolicy) -> Self {
+        Arc::make_mut(&mut self.config).udp_frag_policy = policy;
+        self
+    }
+
+    /// Close UDP associations that relay nothing for `timeout`. Defaults to
+    /// two minutes.
+    pub fn with_udp_idle_timeout(mut self, timeout: Duration) -> Self {
+        Arc::make_mut(&mut self.config).udp_idle_timeout = timeout;
+        self
+    }
+
+    /// Refuse UDP ASSOCIATE requests while `max` associations are open.
+    pub fn with_udp_max_associations(mut self, max: usize) -> Self {
+        Arc::make_mut(&mut self.config).udp_max_associations = Some(max);
+        self
+    }
+
+    /// Close connections that haven't sent their greeting within `timeout`
+    /// of being accepted. Defaults to ten seconds.
+    pub fn with_greeting_timeout(mut self, timeout: Duration) -> Self {
+        Arc::make_mut(&mut self.config).greeting_timeout = timeout;
+        self
+    }
+
+    /// Close connections that don't finish authenticating within `timeout`
+    /// of the method being selected. Defaults to ten seconds.
+    pub fn with_auth_timeout(mut self, timeout: Duration) -> Self {
+        Arc::make_mut(&mut self.config).auth_timeout = timeout;
+        self
+    }
+
+    /// Close connections that don't send their request within `timeout` of
+    /// authenticating. Defaults to ten seconds.
+    pub fn with_request_timeout(mut self, timeout: Duration) -> Self {
+        Arc::make_mut(&mut self.config).request_timeout = timeout;
+        self
+    }
+
+    /// Stop accepting connections once `token` is cancelled, for example
+    /// a child of a token shared by the whole application.
+    pub fn with_shutdown(mut self, token: CancellationToken) -> Self {
+        self.shutdown = token;
+        self
+    }
+
+    /// A token that stops the server when cancelled: [`Server::serve`]
+    /// stops accepting connections and returns once the open ones are
+    /// drained.
+    pub fn shutdown_token(&self) -> CancellationToken {
+        self.shutdown.clone()
+    }
+
+    /// How long shutting down waits for open connections to finish before
+    /// aborting them. Defaults to 30 seconds.
+    pub fn with_drain_timeout(mut self, timeout: Duration) -> Self {
+        Arc::make_mut(&mut self.config).drain_timeout = timeout;
+        self
+    }
+
+    /// Run the accept loops and connections with `spawner` rather than
+    /// [`tokio::spawn`], e.g. on another runtime's `Handle` or a `LocalSet`.
+    ///
+    /// ```no_run
+    /// # async fn example() -> std::io::Result<()> {
+    /// use socks5_rs::Server;
+    ///
+    /// let local = tokio::task::LocalSet::new();
+    /// let server = Server::bind("127.0.0.1:1080")
+    ///     .await?
+    ///     .with_spawner(|task| drop(tokio::task::spawn_local(task)));
+    /// local.run_until(server.serve()).await?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn with_spawner<F>(mut self, spawner: F) -> Self
+    where
+        F: Fn(BoxTask) + Send + Sync + 'static,
+    {
+        Arc::make_mut(&mut self.config).spawner = Some(Arc::new(spawner));
+        self
+    }
+
+    /// The address the server is listening on, e.g. to learn the port
+    /// picked when binding port 0. With several TCP listeners, that of the
+    /// first.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        match self
+            .listeners
+            .iter()
+            .find_map(|listener| listener.local_addr())
+        {
+            Some(addr) => addr,
+            None => Err(io::Error::new(io::ErrorKind::NotFound, "no TCP listeners")),
+        }
+    }
+
+    /// The addresses of all TCP listeners.
+    pub fn local_addrs(&self) -> io::Result<Vec<SocketAddr>> {
+        self.listeners
+            .iter()
+            .filter_map(|listener| listener.local_addr())
+            .collect()
+    }
+
+    /// The config connections are served with: [`Server::config`] plus the
+    /// listeners' addresses, so that targets looping back to the server
+    /// are refused.
+    fn serving_config(&self) -> Arc<ServerConfig> {
+        let mut config = self.config.clone();
+        let bound_addrs = self
+            .listeners
+            .iter()
+            .filter_map(|listener| listener.local_addr()?.ok())
+            .collect();
+        Arc::make_mut(&mut config).bound_addrs = bound_addrs;
+        config
+    }
+
+    /// Connection counts, across all listeners.
+    pub fn stats(&self) -> ServerStats {
+        ServerStats {
+            accepted: self.connections.accepted.load(Ordering::Relaxed),
+            active: self.connections.active.load(Ordering::Relaxed),
+            failed: self.connections.failed.load(Ordering::Relaxed),
+            panicked: self.connections.panicked.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Counts of how target names were resolved, and how long the resolver
+    /// took.
+    pub fn dns_stats(&self) -> DnsStats {
+        let (hits, misses) = self
+            .config
+            .dns_cache
+            .as_ref()
+            .map_or((0, 0), DnsCache::counts);
+        self.config.dns_counters.stats(hits, misses)
+    }
+
+    /// The connections being served, oldest first.
+    pub fn sessions(&self) -> Vec<Session> {
+        self.sessions.list()
+    }
+
+    /// End the session `id`, closing its client connection and whatever it
+    /// relays to. Returns whether there was such a session.
+    pub fn kill(&self, id: u64) -> bool {
+        self.sessions.kill(id)
+    }
+
+    /// The lockouts in force, see [`Server::with_auth_lockout`].
+    pub fn lockouts(&self) -> Vec<Lockout> {
+        self.lockouts.list()
+    }
+
+    /// Lift the lockout of `key` and forget its failures. Returns whether it
+    /// was locked out.
+    pub fn clear_lockout(&self, key: &LockoutKey) -> bool {
+        let cleared = self.lockouts.clear(key);
+        #[cfg(target_os = "linux")]
+        if let (true, LockoutKey::Ip(ip), Some(ban)) = (cleared, key, &self.config.kernel_ban) {
+            ban.unban(*ip);
+        }
+        cleared
+    }
+
+    /// Lift all lockouts and forget all failures.
+    pub fn clear_lockouts(&self) {
+        #[cfg(target_os = "linux")]
+        if let Some(ban) = &self.config.kernel_ban {
+            for lockout in self.lockouts.list() {
+                if let LockoutKey::Ip(ip) = lockout.key {
+                    ban.unban(ip);
+                }
+            }
+        }
+        self.lockouts.clear_all()
+    }
+
+    /// Occupancy of the UDP association table.
+    pub fn udp_stats(&self) -> UdpStats {
+        self.udp_associations.stats()
+    }
+
+    /// The UDP associations currently open.
+    pub fn udp_associations(&self) -> Vec<UdpAssociation> {
+        self.udp_associations.list()
+    }
+
+    /// Accept and serve clients until shut down (see
+    /// [`Server::shutdown_token`]). Fails if accepting fails for a reason
+    /// other than a connection going away before it was accepted, or
+    /// running out of file descriptors, after which accepting is retried
+    /// with backoff.
+    ///
+    /// Each listener is accepted on by a task of its own, so they can accept
+    /// in parallel on a multi-threaded runtime.
+    ///
+    /// On shutdown, connections being served get
+    /// [`ServerConfig::drain_timeout`] to finish before they are aborted.
+    pub async fn serve(&self) -> io::Result<ShutdownReport> {
+        // A child token, so a listener failing stops the others without
+        // cancelling the caller's token.
+        let stop = self.shutdown.child_token();
+        let tasks = Arc::new(Tasks::default());
+        let (done, mut results) = mpsc::channel(self.listeners.len().max(1));
+        let config = self.serving_config();
+        for listener in &self.listeners {
+            let acceptor = Acceptor {
+                listener: listener.clone(),
+                config: config.clone(),
+                udp_associations: self.udp_associations.clone(),
+                connections: self.connections.clone(),
+                sessions: self.sessions.clone(),
+                lockouts: self.lockouts.clone(),
+                tasks: tasks.clone(),
+                stop: stop.clone(),
+            };
+            let done = done.clone();
+            self.config.spawn(async move {
+                let _ = done.send(acceptor.run().await).await;
+            });
+        }
+        drop(done);
+
+        let mut error = None;
+        while let Some(res) = results.recv().await {
+            if let Err(e) = res {
+                stop.cancel();
+                error.get_or_insert(e);
+            }
+        }
+        if let Some(e) = error {
+            tasks.abort.cancel();
+            return Err(e);
+        }
+
+        Ok(tasks.drain(self.config.drain_timeout).await)
+    }
+
+    /// Accept and serve clients like [`Server::serve`], but on one
+    /// current-thread runtime per listener, each on a thread of its own.
+    /// Connections stay on the runtime that accepted them, so there is no
+    /// handoff between threads.
+    ///
+    /// Pair it with [`ServerBuilder::reuse_port_listeners`] to get a shard
+    /// per core. This blocks until all shards have stopped, and must not be
+    /// called from within a runtime. [`ServerConfig::spawner`] is not used,
+    /// tasks are spawned on the shard's runtime.
+    ///
+    /// ```no_run
+    /// # fn main() -> std::io::Result<()> {
+    /// use socks5_rs::Server;
+    ///
+    /// let shards = std::thread::available_parallelism()?.get();
+    /// let server = tokio::runtime::Runtime::new()?.block_on(
+    ///     Server::builder()
+    ///         .listen_addr("0.0.0.0:1080".parse().unwrap())
+    ///         .reuse_port_listeners(shards)
+    ///         .build(),
+    /// )?;
+    /// server.serve_sharded()?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn serve_sharded(self) -> io::Result<ShutdownReport> {
+        let stop = self.shutdown.child_token();
+        let mut shards = Vec::with_capacity(self.listeners.len());
+        let serving_config = self.serving_config();
+        for (i, listener) in self.listeners.into_iter().enumerate() {
+            let listener = Arc::try_unwrap(listener)
+                .map_err(|_| io::Error::other("listener still in use"))?
+                .into_std()?;
+            let mut config = serving_config.clone();
+            Arc::make_mut(&mut config).spawner = None;
+            let udp_associations = self.udp_associations.clone();
+            let connections = self.connections.clone();
+            let sessions = self.sessions.clone();
+            let lockouts = self.lockouts.clone();
+            let shard_stop = stop.clone();
+            let shard = thread::Builder::new()
+                .name(format!("socks5-shard-{}", i))
+                .spawn(move || {
+                    let runtime = tokio::runtime::Builder::new_current_thread()
+                        .enable_all()
+                        .build()?;
+                    runtime.block_on(async move {
+                        let drain_timeout = config.drain_timeout;
+                        let tasks = Arc::new(Tasks::default());
+                        let acceptor = Acceptor {
+                            listener: Arc::new(listener.into_tokio()?),
+                            config,
+                            udp_associations,
+                            connections,
+                            sessions,
+                            lockouts,
+                            tasks: tasks.clone(),
+                            stop: shard_stop.clone(),
+                        };
+                        match acceptor.run().await {
+                            Ok(()) => Ok(tasks.drain(drain_timeout).await),
+                            Err(e) => {
+                                shard_stop.cancel();
+                                tasks.abort.cancel();
+                                Err(e)
+                            }
+                        }
+                    })
+                });
+            match shard {
+                Ok(shard) => shards.push(shard),
+                Err(e) => {
+                    stop.cancel();
+                    shards.into_iter().for_each(|shard| drop(shard.join()));
+                    return Err(e);
+                }
+            }
+        }
+
+        let mut report = ShutdownReport::default();
+        let mut error = None;
+        for shard in shards {
+            match shard.join() {
+                Ok(Ok(shard)) => {
+                    report.completed += shard.completed;
+                    report.aborted += shard.aborted;
+                }
+                Ok(Err(e)) => {
+                    stop.cancel();
+                    error.get_or_insert(e);
+                }
+                Err(_) => {
+                    stop.cancel();
+                    error.get_or_insert(io::Error::other("shard panicked"));
+                }
+            }
+        }
+        match error {
+            Some(e) => Err(e),
+            None => Ok(report),
+        }
     }
 }
 
-enum Rep {
-    Success = 0x00,
+/// The connection tasks spawned by one [`Server::serve`] call, or one shard.
+#[derive(Default)]
+struct Tasks {
+    open: AtomicUsize,
+    /// Notified when the last open task finishes.
+    idle: Notify,
+    /// Stops the tasks still open.
+    abort: CancellationToken,
 }
 
-impl From<Rep> for u8 {
-    fn from(rep: Rep) -> u8 {
-        rep as u8
+impl Tasks {
+    fn spawn<F>(self: &Arc<Self>, config: &ServerConfig, task: F)
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        self.open.fetch_add(1, Ordering::SeqCst);
+        let guard = TaskGuard(self.clone());
+        config.spawn(async move {
+            let abort = guard.0.abort.clone();
+            tokio::select! {
+                _ = abort.cancelled() => {}
+                _ = task => {}
+            }
+            drop(guard);
+        });
+    }
+
+    /// Wait up to `timeout` for the open tasks to finish, then abort the
+    /// rest.
+    async fn drain(&self, timeout: Duration) -> ShutdownReport {
+        let open = self.open.load(Ordering::SeqCst);
+        let mut report = ShutdownReport::default();
+        if time::timeout(timeout, self.wait_idle()).await.is_err() {
+            report.aborted = self.open.load(Ordering::SeqCst);
+            self.abort.cancel();
+            self.wait_idle().await;
+            log::info!(
+                "aborted {} connections still open at shutdown",
+                report.aborted
+            );
+        }
+        report.completed = open.saturating_sub(report.aborted);
+        report
+    }
+
+    async fn wait_idle(&self) {
+        loop {
+            // Created before checking, so a notification in between isn't
+            // missed.
+            let idle = self.idle.notified();
+            if self.open.load(Ordering::SeqCst) == 0 {
+                return;
+            }
+            idle.await;
+        }
     }
 }
 
+/// Counts a task as open until it finishes, or is dropped by its spawner.
+struct TaskGuard(Arc<Tasks>);
 
+impl Drop for TaskGuard {
+    fn drop(&mut self) {
+        if self.0.open.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.0.idle.notify_waiters();
+        }
+    }
+}
 
-#[derive(thiserror::Error, Debug)]
-enum Socks5Error {
-    #[error("IO error: {0}")]
-    Io(#[from] io::Error),
-    #[error("Address type not supported")]
-    AddressTypeNotSupported,
-    // #[error("unknown error")]
-    // Unknown,
+/// How long accepting pauses after running out of resources, doubling each
+/// time it fails again.
+const ACCEPT_BACKOFF_MIN: Duration = Duration::from_millis(5);
+const ACCEPT_BACKOFF_MAX: Duration = Duration::from_secs(1);
+
+/// Accepts clients on one listener, in a task of its own so that several
+/// listeners accept in parallel.
+struct Acceptor {
+    listener: Arc<Listener>,
+    config: Arc<ServerConfig>,
+    udp_associations: Arc<udp::Associations>,
+    connections: Arc<Connections>,
+    sessions: Arc<session::Registry>,
+    lockouts: Arc<lockout::Lockouts>,
+    tasks: Arc<Tasks>,
+    stop: CancellationToken,
 }
 
-pub struct Server {
-    listener: TcpListener,
+impl Acceptor {
+    async fn run(self) -> io::Result<()> {
+        let mut backoff = ACCEPT_BACKOFF_MIN;
+        loop {
+            let accept = future::poll_fn(|cx| self.listener.poll_accept(cx));
+            let accepted = tokio::select! {
+                _ = self.stop.cancelled() => return Ok(()),
+                accepted = accept => accepted,
+            };
+            let accepted = match accepted {
+                Ok(accepted) => {
+                    backoff = ACCEPT_BACKOFF_MIN;
+                    accepted
+                }
+                Err(e) if is_connection_error(&e) => {
+                    log::debug!("accept failed: {}", e);
+                    continue;
+                }
+                Err(e) if is_resource_exhaustion(&e) => {
+                    // Retrying right away would spin until connections
+                    // close and free up descriptors.
+                    log::warn!("accept failed, retrying in {:?}: {}", backoff, e);
+                    tokio::select! {
+                        _ = self.stop.cancelled() => return Ok(()),
+                        _ = time::sleep(backoff) => {}
+                    }
+                    backoff = (backoff * 2).min(ACCEPT_BACKOFF_MAX);
+                    continue;
+                }
+                Err(e) => return Err(e),
+            };
+            let (peer, local) = match accepted.addrs() {
+                Ok(addrs) => addrs,
+                Err(e) => {
+                    log::debug!("dropping connection: {}", e);
+                    continue;
+                }
+            };
+            if let (Accepted::Tcp(..), Some(acl)) = (&accepted, &self.config.client_acl) {
+                if !acl.allows(peer.ip()) {
+                    log::info!("dropping connection from {}: not an allowed client", peer);
+                    continue;
+                }
+            }
+            let config = self.config.clone();
+            let udp_associations = self.udp_associations.clone();
+            let lockouts = self.lockouts.clone();
+            let registration = self.sessions.register(unmap_socket_addr(peer));
+            let session = registration.state.clone();
+            if let Accepted::Tcp(stream, _) = &accepted {
+                set_client_options(stream, &self.config, &session);
+            }
+            let guard = ConnectionGuard::new(&self.connections);
+            self.tasks.spawn(&self.config, async move {
+                let _registration = registration;
+                let serve = {
+                    let session = session.clone();
+                    async move {
+                        match accepted {
+                            #[cfg(feature = "tls")]
+                            Accepted::Tcp(stream, _) if config.tls.is_some() => {
+                                let stream = accept_tls(stream, peer, &config, &session).await?;
+                                serve_connection(
+                                    stream,
+                                    peer,
+                                    local,
+                                    config,
+                                    udp_associations,
+                                    lockouts,
+                                    session,
+                                )
+                                .await
+                            }
+                            Accepted::Tcp(stream, _) => {
+                                serve_connection(
+                                    stream,
+                                    peer,
+                                    local,
+                                    config,
+                                    udp_associations,
+                                    lockouts,
+                                    session,
+                                )
+                                .await
+                            }
+                            #[cfg(unix)]
+                            Accepted::Unix(stream) => {
+                                if let Some(policy) = &config.unix_peer_cred {
+                                    let cred = stream.peer_cred()?;
+                                    if !policy.allows(cred.uid(), cred.gid()) {
+                                        log::info!(
+                                            "refusing Unix socket client with uid {}, gid {}",
+                                            cred.uid(),
+                                            cred.gid()
+                                        );
+                                        let outcome =
+                                            AuthOutcome::Failure(AuthFailure::PeerCredentials);
+                                        audit::record(&config, peer, None, None, outcome);
+                                        return Err(Socks5Error::NotAllowed);
+                                    }
+                                    session.set_peer_uid(cred.uid());
+                                }
+                                serve_connection(
+                                    stream,
+                                    peer,
+                                    local,
+                                    config,
+                                    udp_associations,
+                                    lockouts,
+                                    session,
+                                )
+                                .await
+                            }
+                        }
+                    }
+                };
+                let res = tokio::select! {
+                    res = CatchUnwind(Box::pin(serve)) => res,
+                    _ = session.kill.cancelled() => {
+                        log::info!("killed session {} from {}", session.id(), peer);
+                        return;
+                    }
+                };
+                match res {
+                    Ok(Ok(())) => {}
+                    Ok(Err(e)) => {
+                        guard.0.failed.fetch_add(1, Ordering::Relaxed);
+                        log::debug!("closing connection from {}: {}", peer, e);
+                    }
+                    Err(panic) => {
+                        guard.0.panicked.fetch_add(1, Ordering::Relaxed);
+                        let target = match session.target() {
+                            Some(target) => target.to_string(),
+                            None => "no target yet".to_string(),
+                        };
+                        log::error!(
+                            "connection from {} ({}) panicked: {}",
+                            peer,
+                            target,
+                            panic_message(&*panic)
+                        );
+                    }
+                }
+            });
+        }
+    }
 }
 
-impl Server {
-    pub async fn new() -> Self {
-        Server {
-            listener: TcpListener::bind("127.0.0.1:1080").await.unwrap(),
+/// Polls a connection's future, catching a panic so that it only ends that
+/// connection, and can be reported.
+struct CatchUnwind<F>(Pin<Box<F>>);
+
+impl<F: Future> Future for CatchUnwind<F> {
+    type Output = thread::Result<F::Output>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let future = self.0.as_mut();
+        match panic::catch_unwind(AssertUnwindSafe(|| future.poll(cx))) {
+            Ok(poll) => poll.map(Ok),
+            Err(panic) => Poll::Ready(Err(panic)),
         }
     }
-    pub async fn serve(&self) {
-        while let Ok((stream, _)) = self.listener.accept().await {
-            tokio::spawn(async move {
-                Socks5Handler::init(stream).await;
-            });
+}
+
+fn panic_message(panic: &(dyn Any + Send)) -> &str {
+    if let Some(message) = panic.downcast_ref::<&str>() {
+        message
+    } else if let Some(message) = panic.downcast_ref::<String>() {
+        message
+    } else {
+        "unknown panic"
+    }
+}
+
+/// Connection counts of a [`Server`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ServerStats {
+    /// Connections accepted since the server started.
+    pub accepted: u64,
+    /// Connections being served.
+    pub active: usize,
+    /// Connections that ended with an error, e.g. a failed handshake or an
+    /// unreachable target.
+    pub failed: u64,
+    /// Connections whose task panicked. The panic only ends that
+    /// connection.
+    pub panicked: u64,
+}
+
+#[derive(Default)]
+struct Connections {
+    accepted: AtomicU64,
+    active: AtomicUsize,
+    failed: AtomicU64,
+    panicked: AtomicU64,
+}
+
+/// Counts a connection as active for as long as it lives.
+struct ConnectionGuard(Arc<Connections>);
+
+impl ConnectionGuard {
+    fn new(connections: &Arc<Connections>) -> Self {
+        connections.accepted.fetch_add(1, Ordering::Relaxed);
+        connections.active.fetch_add(1, Ordering::Relaxed);
+        ConnectionGuard(connections.clone())
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.0.active.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// What became of the open connections when a [`Server`] shut down.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ShutdownReport {
+    /// Connections that finished within the drain timeout.
+    pub completed: usize,
+    /// Connections aborted when the drain timeout ran out.
+    pub aborted: usize,
+}
+
+/// Errors concerning one incoming connection only, after which accepting
+/// can go on.
+fn is_connection_error(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::Interrupted
+    )
+}
+
+/// Errors from running out of file descriptors or memory, which pass once
+/// connections close.
+fn is_resource_exhaustion(e: &io::Error) -> bool {
+    #[cfg(unix)]
+    if let Some(errno) = e.raw_os_error() {
+        return matches!(
+            errno,
+            libc::EMFILE | libc::ENFILE | libc::ENOBUFS | libc::ENOMEM
+        );
+    }
+    e.kind() == io::ErrorKind::OutOfMemory
+}
+
+/// Set the socket options of the client's TCP connection `stream` that the
+/// server configures.
+fn set_client_options(stream: &TcpStream, config: &ServerConfig, session: &SessionState) {
+    if let Err(e) = stream.set_nodelay(config.nodelay) {
+        log::debug!(
+            "setting TCP_NODELAY on session {} failed: {}",
+            session.id(),
+            e
+        );
+    }
+    #[cfg(unix)]
+    {
+        session.set_client_socket(ClientSocket::new(stream));
+        if let Some(keepalive) = &config.keepalive {
+            if let Err(e) = sockopt::set_keepalive(stream, keepalive) {
+                log::debug!(
+                    "setting keepalive on session {} failed: {}",
+                    session.id(),
+                    e
+                );
+            }
         }
     }
+    if let Some(dscp) = config.dscp {
+        session.set_client_dscp(dscp);
+    }
 }
 
-struct Socks5Handler {
+/// Run the TLS handshake with the client on `stream`, within the greeting
+/// timeout, and identify it by its certificate.
+#[cfg(feature = "tls")]
+async fn accept_tls(
     stream: TcpStream,
-    socks_version: u8,
-    auth_nmethods: u8,
+    peer: SocketAddr,
+    config: &ServerConfig,
+    session: &SessionState,
+) -> Result<TlsStream, Socks5Error> {
+    let acceptor = config
+        .tls
+        .as_ref()
+        .expect("TLS accepted without an acceptor");
+    let deadline = Instant::now() + config.greeting_timeout;
+    let stream = within(deadline, async {
+        Ok::<_, Socks5Error>(acceptor.accept(stream).await?)
+    })
+    .await?;
+    if let Some(auth) = &config.client_cert_auth {
+        match stream.peer_certificate().and_then(|cert| auth.user(cert)) {
+            // Served as the user from here on, see `select_method`.
+            Some(user) => session.set_user(user),
+            None if auth.required() => {
+                log::info!("refusing {}: no known client certificate", peer);
+                let outcome = AuthOutcome::Failure(AuthFailure::ClientCertificate);
+                audit::record(config, peer, None, None, outcome);
+                return Err(Socks5Error::AuthFailed);
+            }
+            None => {}
+        }
+    }
+    Ok(stream)
 }
 
-impl Socks5Handler {
-    async fn init(stream: TcpStream) {
-        let mut handler = Socks5Handler {
-            stream,
-            socks_version: 0,
-            auth_nmethods: 0,
-        };
+/// Serve one client connection from `peer_addr`: the SOCKS5 (or, when
+/// enabled, SOCKS4) handshake followed by the relay, for use in custom
+/// accept loops. Fails with what ended the connection early.
+///
+/// Each call has UDP associations and lockouts of its own, so
+/// [`ServerConfig::udp_max_associations`] and [`ServerConfig::auth_lockout`]
+/// only apply to connections accepted by a [`Server`].
+pub async fn handle_connection(
+    stream: TcpStream,
+    peer_addr: SocketAddr,
+    config: Arc<ServerConfig>,
+) -> Result<(), Socks5Error> {
+    let local_addr = stream.local_addr()?;
+    let session = SessionState::new(0, peer_addr);
+    set_client_options(&stream, &config, &session);
+    serve_connection(
+        stream,
+        peer_addr,
+        local_addr,
+        config,
+        Arc::default(),
+        Arc::default(),
+        Arc::new(session),
+    )
+    .await
+}
+
+/// Like [`handle_connection`], over any byte stream, e.g. TLS or an
+/// in-memory pipe.
+///
+/// `local_addr` stands for the address the client reached: UDP relays are
+/// bound on its IP, and failure replies use its address family.
+pub async fn handle_stream<S>(
+    stream: S,
+    peer_addr: SocketAddr,
+    local_addr: SocketAddr,
+    config: Arc<ServerConfig>,
+) -> Result<(), Socks5Error>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    serve_connection(
+        stream,
+        peer_addr,
+        local_addr,
+        config,
+        Arc::default(),
+        Arc::default(),
+        Arc::new(SessionState::new(0, peer_addr)),
+    )
+    .await
+}
+
+async fn serve_connection<S>(
+    stream: S,
+    peer: SocketAddr,
+    local: SocketAddr,
+    config: Arc<ServerConfig>,
+    udp_associations: Arc<udp::Associations>,
+    lockouts: Arc<lockout::Lockouts>,
+    session: Arc<SessionState>,
+) -> Result<(), Socks5Error>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut handler = Socks5Handler {
+        stream: Counted::new(stream, session.clone()),
+        peer: unmap_socket_addr(peer),
+        local,
+        config,
+        udp_associations,
+        lockouts,
+        session,
+        policy: None,
+        user_session: None,
+    };
+
+    let res = handler.handle_req().await;
+    if res.is_err() {
+        // The peer may be gone already, in which case there is nothing
+        // left to shut down.
+        let _ = handler.stream.shutdown().await;
+    }
+    res
+}
+
+struct Socks5Handler<S> {
+    stream: S,
+    peer: SocketAddr,
+    /// Where the client reached us.
+    local: SocketAddr,
+    config: Arc<ServerConfig>,
+    udp_associations: Arc<udp::Associations>,
+    lockouts: Arc<lockout::Lockouts>,
+    session: Arc<SessionState>,
+    /// Restrictions on the authenticated user.
+    policy: Option<UserPolicy>,
+    /// Counts this session against the user's limit.
+    user_session: Option<policy::SessionGuard>,
+}
 
+impl<S: AsyncRead + AsyncWrite + Unpin> Socks5Handler<S> {
+    async fn handle_req(&mut self) -> Result<(), Socks5Error> {
+        // `VER | NMETHODS` of the greeting, or `VN | CD` for SOCKS4.
+        let deadline = Instant::now() + self.config.greeting_timeout;
         let mut header = [0u8; 2];
+        within(deadline, self.stream.read_exact(&mut header)).await?;
 
-        handler.stream.read_exact(&mut header).await.unwrap();
+        if self.config.socks4 && header[0] == socks4::SOCKS4_VERSION {
+            let (stream, peer) = (&mut self.stream, self.peer);
+            return socks4::handle(stream, peer, &self.config, &self.session, header[1]).await;
+        }
 
-        handler.socks_version = header[0];
-        handler.auth_nmethods = header[1];
+        let greeting = read_message(&mut self.stream, header.to_vec(), Greeting::parse);
+        let greeting = within(deadline, greeting).await;
+        if let Err(Socks5Error::Protocol(ProtocolError::InvalidVersion(version))) = greeting {
+            log::warn!(
+                "rejecting {}: not a SOCKS5 greeting (version {:#04x})",
+                self.peer,
+                version
+            );
+        }
+        let method = self.auth(&greeting?.methods).await?;
 
-        if handler.handle_req().await.is_err() {
-            handler.stream.shutdown().await.unwrap();
-        };
-    }
+        #[cfg(feature = "gssapi")]
+        if method == Method::GssApi {
+            return self.handle_gssapi_req().await;
+        }
+        #[cfg(not(feature = "gssapi"))]
+        let _ = method;
 
-    async fn handle_req(&mut self) -> Result<(), io::Error> {
-        self.auth().await?;
+        let deadline = Instant::now() + self.config.request_timeout;
+        let req = read_message(&mut self.stream, Vec::new(), Request::parse);
+        let req = match within(deadline, req).await {
+            Ok(req) => req,
+            Err(e) => {
+                if let Socks5Error::Protocol(ProtocolError::InvalidVersion(version)) = e {
+                    log::warn!("rejecting request with SOCKS version {:#04x}", version);
+                }
+                if let Some(rep) = e.reply() {
+                    self.stream.write_all(&self.error_reply(rep)).await?;
+                }
+                return Err(e);
+            }
+        };
 
-        let req = Socks5Req::from_stream(&mut self.stream).await.unwrap();
+        self.session.set_target(&req.target);
+        match req.command {
+            Command::Connect => {
+                if let Some(policy) = &self.policy {
+                    if let Err(e) = policy.check(&req.target) {
+                        if self.config.refuse_blocked().await {
+                            let rep = e.reply().unwrap_or(Reply::NotAllowed);
+                            self.stream.write_all(&self.error_reply(rep)).await?;
+                        }
+                        return Err(e);
+                    }
+                }
+                self.connect(&req.target).await
+            }
+            Command::UdpAssociate => {
+                let table = &self.udp_associations;
+                let (stream, local) = (&mut self.stream, self.local);
+                let user = self.session.user();
+                let requester = Requester {
+                    peer: self.peer,
+                    user: user.as_deref(),
+                    policy: self.policy.as_ref(),
+                };
+                Ok(
+                    udp::associate(stream, local, &self.config, table, &req.target, &requester)
+                        .await?,
+                )
+            }
+            Command::Bind => {
+                let reply = self.error_reply(Reply::CommandNotSupported);
+                self.stream.write_all(&reply).await?;
+                Err(Socks5Error::CommandNotSupported(req.command))
+            }
+        }
+    }
 
-        let socket_addr = req.as_socket_addr().unwrap();
-        let mut target = TcpStream::connect(&socket_addr[..]).await?;
+    async fn connect(&mut self, target_addr: &TargetAddr) -> Result<(), Socks5Error> {
+        let mut egress = self.policy.as_ref().and_then(UserPolicy::egress_addr);
+        // Holds the isolation address until the relay ends.
+        let mut _isolation = None;
+        if let (None, Some(isolation)) = (egress, &self.config.stream_isolation) {
+            let user = self.session.user();
+            match isolation.acquire(user.as_deref()) {
+                Some(guard) => {
+                    egress = Some(guard.addr());
+                    _isolation = Some(guard);
+                }
+                None => {
+                    log::warn!(
+                        "refusing CONNECT from {}: no egress address free",
+                        self.peer
+                    );
+                    let reply = self.error_reply(Reply::GeneralFailure);
+                    self.stream.write_all(&reply).await?;
+                    return Err(Socks5Error::Io(io::Error::other(
+                        "no egress address free for stream isolation",
+                    )));
+                }
+            }
+        }
+        let user = self.session.user();
+        let requester = Requester {
+            peer: self.peer,
+            user: user.as_deref(),
+            policy: self.policy.as_ref(),
+        };
+        let dialed = dial(target_addr, &self.config, &requester, &self.session, egress).await;
+        let target = match dialed {
+            Ok(target) => target,
+            Err(e) => {
+                if !e.is_blocked() || self.config.refuse_blocked().await {
+                    let rep = e.reply().unwrap_or(Reply::GeneralFailure);
+                    self.stream.write_all(&self.error_reply(rep)).await?;
+                }
+                return Err(e);
+            }
+        };
 
+        self.session.set_connected(target.peer_addr());
         self.stream
-            .write_all(&[
-                SOCKS_VERSION,
-                Rep::Success.into(),
-                RESERVED,
-                0x01,
-                0,
-                0,
-                0,
-                0,
-                0,
-                0,
-            ])
+            .write_all(&reply(Reply::Succeeded, target.local_addr()))
             .await?;
 
-        tokio::io::copy_bidirectional(&mut self.stream, &mut target).await?;
+        let mut target = target;
+        let sniff_timeout = self.config.sniff_timeout;
+        if let (Some(timeout), Some(_)) = (sniff_timeout, target_addr.socket_addr()) {
+            let (first, name) = sniff::read_name(&mut self.stream, timeout)
+                .await
+                .map_err(Socks5Error::Relay)?;
+            let sniffed = name
+                .and_then(|name| Address::domain(name).ok())
+                .map(|domain| TargetAddr::new(domain, target_addr.port));
+            if let Some(sniffed) = sniffed {
+                log::debug!("CONNECT to {} is for {}", target_addr, sniffed);
+                let connected = target.peer_addr();
+                if let Err(e) = self
+                    .config
+                    .check_sniffed(&sniffed, connected, &requester)
+                    .await
+                {
+                    // Replied already, so closing is all that is left.
+                    self.config.refuse_blocked().await;
+                    return Err(e);
+                }
+            }
+            target.write_all(&first).await.map_err(Socks5Error::Relay)?;
+        }
+
+        let bandwidth = self.policy.as_ref().and_then(UserPolicy::bandwidth);
+        let mut target = policy::Limited::new(target, bandwidth);
+        tokio::io::copy_bidirectional(&mut self.stream, &mut target)
+            .await
+            .map_err(Socks5Error::Relay)?;
 
         Ok(())
     }
 
-    async fn auth(&mut self) -> Result<(), io::Error> {
-        let mut methods = vec!(0u8; self.auth_nmethods as usize);
-        self.stream.read_exact(&mut methods).await?;
+    /// Request phase and relay for a GSSAPI client, with every message
+    /// encapsulated in the negotiated security context.
+    #[cfg(feature = "gssapi")]
+    async fn handle_gssapi_req(&mut self) -> Result<(), Socks5Error> {
+        let provider = self
+            .config
+            .gssapi
+            .clone()
+            .expect("GSSAPI selected without a provider");
+        let deadline = Instant::now() + self.config.auth_timeout;
+        let session = within(deadline, gssapi::negotiate(&mut self.stream, &*provider)).await;
+        let outcome = match &session {
+            Ok(_) => AuthOutcome::Success,
+            Err(e) if e.kind() == io::ErrorKind::TimedOut => {
+                AuthOutcome::Failure(AuthFailure::Timeout)
+            }
+            Err(_) => AuthOutcome::Failure(AuthFailure::Gssapi),
+        };
+        self.audit(None, Some(Method::GssApi), outcome);
+        let session = session?;
+
+        let deadline = Instant::now() + self.config.request_timeout;
+        let req = within(deadline, session.read(&mut self.stream)).await?;
+        let req = match Request::parse(&req) {
+            Ok(Parse::Complete(req, _)) => req,
+            Ok(Parse::Incomplete(_)) => {
+                return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into())
+            }
+            Err(e) => {
+                let e = Socks5Error::from(e);
+                if let Some(rep) = e.reply() {
+                    let reply = self.error_reply(rep);
+                    session.write(&mut self.stream, &reply).await?;
+                }
+                return Err(e);
+            }
+        };
+
+        // UDP datagrams would need encapsulating too, which we don't do.
+        self.session.set_target(&req.target);
+        let target_addr = match req.command {
+            Command::Connect => req.target,
+            command @ (Command::UdpAssociate | Command::Bind) => {
+                let reply = self.error_reply(Reply::CommandNotSupported);
+                session.write(&mut self.stream, &reply).await?;
+                return Err(Socks5Error::CommandNotSupported(command));
+            }
+        };
+
+        let requester = Requester {
+            peer: self.peer,
+            user: None,
+            policy: None,
+        };
+        let dialed = dial(&target_addr, &self.config, &requester, &self.session, None).await;
+        let mut target = match dialed {
+            Ok(target) => target,
+            Err(e) => {
+                if !e.is_blocked() || self.config.refuse_blocked().await {
+                    let reply = self.error_reply(e.reply().unwrap_or(Reply::GeneralFailure));
+                    session.write(&mut self.stream, &reply).await?;
+                }
+                return Err(e);
+            }
+        };
+
+        self.session.set_connected(target.peer_addr());
+        session
+            .write(
+                &mut self.stream,
+                &reply(Reply::Succeeded, target.local_addr()),
+            )
+            .await?;
+
+        session
+            .relay(&mut self.stream, &mut target)
+            .await
+            .map_err(Socks5Error::Relay)
+    }
+
+    /// A failure reply. BND.ADDR is left unspecified, but in the address
+    /// family the client is connected over.
+    fn error_reply(&self, rep: Reply) -> Vec<u8> {
+        let bnd = match unmap_socket_addr(self.local) {
+            SocketAddr::V6(_) => SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)),
+            _ => UNSPECIFIED_ADDR,
+        };
+        reply(rep, bnd)
+    }
+
+    /// Method negotiation, followed by the username/password
+    /// sub-negotiation when selected. Returns the selected method.
+    async fn auth(&mut self, offered: &[Method]) -> Result<Method, Socks5Error> {
+        #[allow(unused_mut)]
+        let mut method = self.select_method(offered)?;
+        #[allow(unused_mut)]
+        let mut failure = AuthFailure::NoAcceptableMethod;
+        #[cfg(feature = "tls")]
+        if method == Some(Method::NoAuth) {
+            if let Some(user) = self.session.user() {
+                let policy = self.user_policy(&user);
+                if !self.open_user_session(&user, policy) {
+                    method = None;
+                    failure = AuthFailure::TooManySessions;
+                }
+            }
+        }
 
-        let mut response = [0u8; 2];
-        response[0] = SOCKS_VERSION;
-        response[1] = AuthMethod::NoAuth.into();
+        let mut response = Vec::new();
+        MethodSelection {
+            method: method.unwrap_or(Method::NoAcceptable),
+        }
+        .encode(&mut response);
         self.stream.write_all(&response).await?;
 
-        Ok(())
+        let method = match method {
+            Some(method) => method,
+            None => {
+                let user = self.session.user();
+                self.audit(user.as_deref(), None, AuthOutcome::Failure(failure));
+                return Err(Socks5Error::NoAcceptableMethod);
+            }
+        };
+
+        match method {
+            Method::UserPass => {
+                let deadline = Instant::now() + self.config.auth_timeout;
+                let res = within(deadline, self.user_pass_auth()).await;
+                if let Err(Socks5Error::Timeout) = res {
+                    let outcome = AuthOutcome::Failure(AuthFailure::Timeout);
+                    self.audit(None, Some(method), outcome);
+                }
+                res?;
+            }
+            Method::NoAuth => {
+                let user = self.session.user();
+                self.audit(user.as_deref(), Some(method), AuthOutcome::Success);
+            }
+            // GSSAPI is recorded once the context is established.
+            _ => {}
+        }
+
+        Ok(method)
     }
-}
 
-enum Atyp {
-    V4 = 0x01,
-    Domain = 0x03,
-    V6 = 0x04,
-}
+    /// Pick the most preferred configured method that the client offers.
+    fn select_method(&self, offered: &[Method]) -> io::Result<Option<Method>> {
+        #[cfg(unix)]
+        if self
+            .config
+            .unix_peer_cred
+            .as_ref()
+            .is_some_and(|policy| policy.skip_socks_auth)
+            && self.session.peer_uid().is_some()
+            && offered.contains(&Method::NoAuth)
+        {
+            return Ok(Some(Method::NoAuth));
+        }
+        // Identified by a TLS client certificate, see `accept_tls`.
+        #[cfg(feature = "tls")]
+        if self.session.user().is_some() && offered.contains(&Method::NoAuth) {
+            return Ok(Some(Method::NoAuth));
+        }
+        Ok(self
+            .config
+            .methods(self.peer.ip())
+            .into_iter()
+            .find(|method| offered.contains(method)))
+    }
+
+    /// RFC 1929 username/password sub-negotiation.
+    async fn user_pass_auth(&mut self) -> Result<(), Socks5Error> {
+        let req = read_message(&mut self.stream, Vec::new(), UserPassRequest::parse).await?;
+
+        let authenticator: &dyn Authenticator = match &self.config.authenticator {
+            Some(authenticator) => &**authenticator,
+            None => &self.config.users,
+        };
+        let username = String::from_utf8_lossy(&req.username);
+        let ip = self.peer.ip();
+        let policy = self.config.auth_lockout;
+        let mut failure = AuthFailure::BadCredentials;
+        let decision = if policy.is_some() && self.lockouts.is_locked(ip, &username) {
+            log::info!("refusing {:?} from {}: locked out", username, self.peer);
+            failure = AuthFailure::LockedOut;
+            Decision::Deny
+        } else {
+            match std::str::from_utf8(&req.username) {
+                Ok(username) => {
+                    authenticator
+                        .verify(username, &req.password, self.peer)
+                        .await
+                }
+                Err(_) => Decision::Deny,
+            }
+        };
+        let mut success = decision == Decision::Allow;
+        if success {
+            // Allowed implies a UTF-8 username.
+            let user_policy = self.user_policy(&username);
+            success = self.open_user_session(&username, user_policy);
+            failure = AuthFailure::TooManySessions;
+        }
+        let outcome = if success {
+            AuthOutcome::Success
+        } else {
+            AuthOutcome::Failure(failure)
+        };
+        self.audit(Some(&username), Some(Method::UserPass), outcome);
+        // Users refused for too many sessions gave the right password, so
+        // that neither counts as a failure nor clears earlier ones.
+        if let Some(policy) = &policy {
+            if success {
+                self.lockouts.succeed(ip, &username);
+            } else if decision == Decision::Deny && self.lockouts.fail(policy, ip, &username) {
+                #[cfg(target_os = "linux")]
+                if let Some(ban) = &self.config.kernel_ban {
+                    ban.ban(ip, policy.lockout);
+                }
+            }
+        }
 
-impl TryFrom<u8> for Atyp {
-    type Error = Socks5Error;
+        let mut response = Vec::new();
+        UserPassResponse { success }.encode(&mut response);
+        self.stream.write_all(&response).await?;
 
-    fn try_from(n: u8) -> Result<Self, Self::Error> {
-        match n {
-            0x01 => Ok(Atyp::V4),
-            0x03 => Ok(Atyp::Domain),
-            0x04 => Ok(Atyp::V6),
-            _ => Err(Socks5Error::AddressTypeNotSupported),
+        if success {
+            Ok(())
+        } else {
+            Err(Socks5Error::AuthFailed)
         }
     }
 }
 
-enum Command {
-    // Connect = 0x01,
+impl<S> Socks5Handler<S> {
+    fn audit(&self, username: Option<&str>, method: Option<Method>, outcome: AuthOutcome) {
+        audit::record(&self.config, self.peer, username, method, outcome);
+    }
+
+    /// The policy of `username`, from the authenticator or else the config.
+    fn user_policy(&self, username: &str) -> Option<UserPolicy> {
+        let policy = match &self.config.authenticator {
+            Some(authenticator) => authenticator.policy(username),
+            None => self.config.users.policy(username),
+        };
+        policy.or_else(|| self.config.user_policies.get(username).cloned())
+    }
+
+    /// Record `username` as authenticated, under its policy. Fails if the
+    /// user has as many sessions open as their policy allows.
+    fn open_user_session(&mut self, username: &str, policy: Option<UserPolicy>) -> bool {
+        if let Some(policy) = &policy {
+            match policy.open_session(username) {
+                Some(guard) => self.user_session = Some(guard),
+                None => {
+                    log::info!(
+                        "refusing {:?} from {}: too many sessions",
+                        username,
+                        self.peer
+                    );
+                    return false;
+                }
+            }
+        }
+        self.session.set_user(username);
+        self.policy = policy;
+        true
+    }
 }
-impl From<Command> for u8 {
-    fn from(command: Command) -> u8 {
-        command as u8
+
+/// Read one message from `stream`, delimited by `parse`. `buf` holds bytes of
+/// it that were read already.
+///
+/// Only as many bytes as `parse` asks for are read, so nothing following the
+/// message is consumed.
+async fn read_message<R, T>(
+    stream: &mut R,
+    mut buf: Vec<u8>,
+    parse: fn(&[u8]) -> Result<Parse<T>, ProtocolError>,
+) -> Result<T, Socks5Error>
+where
+    R: AsyncRead + Unpin,
+{
+    loop {
+        match parse(&buf)? {
+            Parse::Complete(message, _) => return Ok(message),
+            Parse::Incomplete(needed) => {
+                let len = buf.len();
+                buf.resize(len + needed, 0);
+                stream.read_exact(&mut buf[len..]).await?;
+            }
+        }
     }
 }
 
-struct Socks5Req {
-    // version: u8,
-    // command: u8,
-    atyp: Atyp,
-    addr: Vec<u8>,
-    port: u16,
+/// Run one handshake phase, failing with [`Socks5Error::Timeout`] if it
+/// isn't over by `deadline`.
+async fn within<F, T, E>(deadline: Instant, phase: F) -> Result<T, E>
+where
+    F: Future<Output = Result<T, E>>,
+    E: From<Socks5Error>,
+{
+    match time::timeout_at(deadline, phase).await {
+        Ok(res) => res,
+        Err(_) => Err(Socks5Error::Timeout.into()),
+    }
 }
-impl Socks5Req {
-    async fn from_stream(stream: &mut TcpStream) -> Result<Self, Socks5Error> {
-        let mut first4 = [0u8; 4];
-        stream.read_exact(&mut first4).await?;
 
-        let atyp = Atyp::try_from(first4[3]).unwrap();
+/// Resolve and connect to `target_addr` for `requester`, from `egress` when
+/// set, else from the server's egress address in the family of each address,
+/// or through the upstream proxy the rules route it through.
+async fn dial(
+    target_addr: &TargetAddr,
+    config: &ServerConfig,
+    requester: &Requester<'_>,
+    session: &SessionState,
+    egress: Option<IpAddr>,
+) -> Result<DialedStream, Socks5Error> {
+    let routed = config
+        .route(target_addr, Command::Connect, requester)
+        .await?;
+    let (target_addr, upstream) = (&routed.target, routed.upstream);
+    let options = config.dial_options(routed.rule, requester.policy);
+    // The server's DSCP was set when the client connected.
+    if let Some(dscp) = options.dscp.filter(|&dscp| Some(dscp) != config.dscp) {
+        session.set_client_dscp(dscp);
+    }
+    let rule = routed.rule;
+    let egress_v4 = rule.and_then(Rule::egress_v4).or(config.egress_v4);
+    let egress_v6 = rule.and_then(Rule::egress_v6).or(config.egress_v6);
+    let (local_v4, local_v6) = (
+        egress.or(egress_v4.map(IpAddr::V4)),
+        egress.or(egress_v6.map(IpAddr::V6)),
+    );
+    let options_for = move |addr: SocketAddr| DialOptions {
+        local: if addr.is_ipv4() { local_v4 } else { local_v6 },
+        ..options.clone()
+    };
+    if let Some(proxy) = upstream {
+        config.check_routed(target_addr, requester.policy)?;
+        if let Some(addr) = target_addr.socket_addr() {
+            config.check_dnsbl(target_addr, vec![addr]).await?;
+        }
+        let dialer = config.dialer();
+        let timeout = config.connect_timeout;
+        let options = options_for(proxy);
+        return connect_upstream(&*dialer, proxy, target_addr, &options, timeout).await;
+    }
+    let socket_addr = config
+        .resolve_permitted(target_addr, requester.policy)
+        .await?;
+    let socket_addr = config.check_dnsbl(target_addr, socket_addr).await?;
 
-        let addr = match atyp {
-            Atyp::V4 => {
-                let mut addr = [0u8; 4];
-                stream.read_exact(&mut addr).await?;
-                addr.to_vec()
+    let mut addrs: Vec<SocketAddr> = socket_addr
+        .into_iter()
+        .map(|addr| config.nat64.map_or(addr, |nat64| nat64.translate(addr)))
+        .filter(|addr| egress.is_none_or(|egress| egress.is_ipv4() == addr.is_ipv4()))
+        .collect();
+    let failures = config
+        .dial_failure_ttl
+        .map(|ttl| (ttl, Arc::clone(&config.dial_failures)));
+    if let Some((_, failures)) = &failures {
+        let mut failed = None;
+        addrs.retain(|&addr| match failures.recent(addr) {
+            Some(kind) => {
+                failed = Some(kind);
+                false
             }
-            Atyp::Domain => {
-                let mut addr_len_buf = [0u8; 1];
-                stream.read_exact(&mut addr_len_buf).await?;
-                let mut addr = vec![0u8; addr_len_buf[0] as usize];
-                stream.read_exact(&mut addr).await?;
-                addr.to_vec()
-            }
-            Atyp::V6 => {
-                let mut addr = [0u8; 16];
-                stream.read_exact(&mut addr).await?;
-                addr.to_vec()
+            None => true,
+        });
+        if let Some(kind) = failed.filter(|_| addrs.is_empty()) {
+            log::debug!("not connecting to {}: it failed recently", target_addr);
+            return Err(Socks5Error::Connect {
+                target: target_addr.clone(),
+                source: io::Error::new(kind, "connecting failed recently"),
+            });
+        }
+    }
+    let timeout = config.connect_timeout;
+    let dialer = config.dialer();
+    let connect = move |addr| {
+        let failures = failures.clone();
+        let dialer = Arc::clone(&dialer);
+        let options = options_for(addr);
+        async move {
+            let connected = connect_bound(&*dialer, &options, addr, timeout).await;
+            if let (Some((ttl, failures)), Err(e)) = (failures, &connected) {
+                failures.fail(addr, e, ttl);
             }
+            connected
+        }
+    };
+    let retry = routed.rule.and_then(Rule::retry).or(config.retry);
+    let mut attempt = 1;
+    let connected = loop {
+        let connected = match config.happy_eyeballs_delay {
+            Some(delay) => eyeballs::connect(&addrs, delay, &connect).await,
+            None => connect_in_turn(egress, &addrs, &connect).await,
+        };
+        let e = match connected {
+            Ok(stream) => break Ok(stream),
+            Err(e) => e,
         };
+        match retry {
+            Some(retry) if attempt < retry.attempts && RetryPolicy::is_transient(&e) => {
+                let delay = retry.delay(attempt);
+                log::debug!(
+                    "connecting to {} failed: {}, retrying in {:?}",
+                    target_addr,
+                    e,
+                    delay
+                );
+                time::sleep(delay).await;
+                attempt += 1;
+            }
+            _ => break Err(e),
+        }
+    };
+    let stream = connected.map_err(|source| Socks5Error::Connect {
+        target: target_addr.clone(),
+        source,
+    })?;
+    log::debug!("connected to {} at {}", target_addr, stream.peer_addr());
+    Ok(stream)
+}
 
-        let mut port = [0u8; 2];
-        stream.read_exact(&mut port).await?;
-        let port = (&port[..]).get_u16();
+/// Connect to `target_addr` through the SOCKS5 proxy at `proxy` with
+/// `dialer` as `options` say, giving up connecting to the proxy after
+/// `timeout`.
+async fn connect_upstream(
+    dialer: &dyn Dialer,
+    proxy: SocketAddr,
+    target_addr: &TargetAddr,
+    options: &DialOptions,
+    timeout: Option<Duration>,
+) -> Result<DialedStream, Socks5Error> {
+    let connect_error = |source| Socks5Error::Connect {
+        target: target_addr.clone(),
+        source,
+    };
+    let mut stream = connect_bound(dialer, options, proxy, timeout)
+        .await
+        .map_err(connect_error)?;
 
-        Ok(Socks5Req {
-            // version: SOCKS_VERSION,
-            // command: Command::Connect.into(),
-            atyp,
-            addr,
-            port,
-        })
+    let handshake = async {
+        let mut buf = Vec::new();
+        Greeting {
+            methods: vec![Method::NoAuth],
+        }
+        .encode(&mut buf);
+        stream.write_all(&buf).await?;
+        let selection = read_message(&mut stream, Vec::new(), MethodSelection::parse).await?;
+        if selection.method != Method::NoAuth {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "upstream proxy requires authentication",
+            )
+            .into());
+        }
+        buf.clear();
+        Request {
+            command: Command::Connect,
+            target: target_addr.clone(),
+        }
+        .encode(&mut buf);
+        stream.write_all(&buf).await?;
+        let response = read_message(&mut stream, Vec::new(), Response::parse).await?;
+        Ok::<_, Socks5Error>(response.reply)
+    };
+    // A proxy that accepts but doesn't answer is given up like one that
+    // doesn't accept.
+    let handshake = match timeout {
+        Some(timeout) => time::timeout(timeout, handshake).await.unwrap_or_else(|_| {
+            let e = io::Error::new(io::ErrorKind::TimedOut, "upstream proxy timed out");
+            Err(e.into())
+        }),
+        None => handshake.await,
+    };
+    match handshake {
+        Ok(Reply::Succeeded) => Ok(stream),
+        Ok(reply) => Err(Socks5Error::Upstream {
+            target: target_addr.clone(),
+            reply,
+        }),
+        Err(e) => Err(connect_error(e.into())),
     }
+}
 
-    fn as_socket_addr(&self) -> Result<Vec<SocketAddr>, Socks5Error> {
-        let addr = &self.addr;
-        let port = self.port;
-
-        match self.atyp {
-            Atyp::V4 => Ok(vec![SocketAddr::from(SocketAddrV4::new(
-                Ipv4Addr::new(addr[0], addr[1], addr[2], addr[3]),
-                port,
-            ))]),
-            Atyp::Domain => {
-                let mut domain = String::from_utf8(addr.clone()).unwrap();
-                domain.push(':');
-                domain.push_str(&port.to_string());
-
-                Ok(domain.to_socket_addrs()?.collect())
-            }
-            Atyp::V6 => {
-                let mut addr = &addr[..];
-                Ok(vec![SocketAddr::from(SocketAddrV6::new(
-                    Ipv6Addr::new(
-                        addr.get_u16(),
-                        addr.get_u16(),
-                        addr.get_u16(),
-                        addr.get_u16(),
-                        addr.get_u16(),
-                        addr.get_u16(),
-                        addr.get_u16(),
-                        addr.get_u16(),
-                    ),
-                    port,
-                    0,
-                    0,
-                ))])
+/// Connect to the first of `addrs` that accepts with `connect`, trying them
+/// in turn. `local` is the address the attempts are made from, if set.
+async fn connect_in_turn<F, Fut>(
+    local: Option<IpAddr>,
+    addrs: &[SocketAddr],
+    connect: F,
+) -> io::Result<DialedStream>
+where
+    F: Fn(SocketAddr) -> Fut,
+    Fut: Future<Output = io::Result<DialedStream>>,
+{
+    let mut last_error = None;
+    for &addr in addrs {
+        match connect(addr).await {
+            Ok(stream) => return Ok(stream),
+            Err(e) => {
+                log::debug!("connecting to {} failed: {}", addr, e);
+                last_error = Some(e);
             }
         }
     }
+    Err(last_error.unwrap_or_else(|| match local {
+        Some(_) => io::Error::new(
+            io::ErrorKind::AddrNotAvailable,
+            "no target address in the egress address's family",
+        ),
+        None => io::Error::new(io::ErrorKind::AddrNotAvailable, "no target address"),
+    }))
+}
+
+/// Connect to `addr` with `dialer` as `options` say, giving up after
+/// `timeout`.
+async fn connect_bound(
+    dialer: &dyn Dialer,
+    options: &DialOptions,
+    addr: SocketAddr,
+    timeout: Option<Duration>,
+) -> io::Result<DialedStream> {
+    let connect = dialer.dial(addr, options);
+    match timeout {
+        Some(timeout) => time::timeout(timeout, connect)
+            .await
+            .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "connecting timed out"))?,
+        None => connect.await,
+    }
+}
+
+/// BND.ADDR/BND.PORT used when there is nothing meaningful to report.
+const UNSPECIFIED_ADDR: SocketAddr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0));
+
+/// Encode a `VER | REP | RSV | ATYP | BND.ADDR | BND.PORT` reply.
+fn reply(rep: Reply, bnd: SocketAddr) -> Vec<u8> {
+    let mut buf = Vec::new();
+    Response {
+        reply: rep,
+        bnd: bnd.into(),
+    }
+    .encode(&mut buf);
+    buf
 }
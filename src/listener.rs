@@ -0,0 +1,189 @@
+//! The kinds of sockets a [`Server`] accepts clients on.
+//!
+//! [`Server`]: crate::Server
+
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+#[cfg(unix)]
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddrV4};
+#[cfg(unix)]
+use std::ptr;
+use std::task::{Context, Poll};
+
+#[cfg(unix)]
+use tokio::net::TcpSocket;
+use tokio::net::{TcpListener, TcpStream};
+#[cfg(unix)]
+use tokio::net::{UnixListener, UnixStream};
+
+pub(crate) enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener),
+}
+
+/// A [`Listener`] taken out of its runtime, to be moved to another one.
+pub(crate) enum StdListener {
+    Tcp(std::net::TcpListener),
+    #[cfg(unix)]
+    Unix(std::os::unix::net::UnixListener),
+}
+
+pub(crate) enum Accepted {
+    Tcp(TcpStream, SocketAddr),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+/// Peer and local address of Unix socket clients. They are local, and are
+/// treated like clients from localhost, e.g. by method filters.
+#[cfg(unix)]
+const UNIX_ADDR: SocketAddr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0));
+
+impl Listener {
+    pub(crate) fn poll_accept(&self, cx: &mut Context<'_>) -> Poll<io::Result<Accepted>> {
+        match self {
+            Listener::Tcp(listener) => listener
+                .poll_accept(cx)
+                .map_ok(|(stream, peer)| Accepted::Tcp(stream, peer)),
+            #[cfg(unix)]
+            Listener::Unix(listener) => listener
+                .poll_accept(cx)
+                .map_ok(|(stream, _)| Accepted::Unix(stream)),
+        }
+    }
+
+    /// The address of a TCP listener.
+    pub(crate) fn local_addr(&self) -> Option<io::Result<SocketAddr>> {
+        match self {
+            Listener::Tcp(listener) => Some(listener.local_addr()),
+            #[cfg(unix)]
+            Listener::Unix(_) => None,
+        }
+    }
+
+    /// Take the listener out of its runtime.
+    pub(crate) fn into_std(self) -> io::Result<StdListener> {
+        match self {
+            Listener::Tcp(listener) => listener.into_std().map(StdListener::Tcp),
+            #[cfg(unix)]
+            Listener::Unix(listener) => listener.into_std().map(StdListener::Unix),
+        }
+    }
+}
+
+impl StdListener {
+    /// Register the listener with the runtime this is called from.
+    pub(crate) fn into_tokio(self) -> io::Result<Listener> {
+        match self {
+            StdListener::Tcp(listener) => TcpListener::from_std(listener).map(Listener::Tcp),
+            #[cfg(unix)]
+            StdListener::Unix(listener) => UnixListener::from_std(listener).map(Listener::Unix),
+        }
+    }
+}
+
+impl Accepted {
+    /// The peer and local address the connection is served as.
+    pub(crate) fn addrs(&self) -> io::Result<(SocketAddr, SocketAddr)> {
+        match self {
+            Accepted::Tcp(stream, peer) => Ok((*peer, stream.local_addr()?)),
+            #[cfg(unix)]
+            Accepted::Unix(_) => Ok((UNIX_ADDR, UNIX_ADDR)),
+        }
+    }
+}
+
+/// Backlog of the sockets bound by [`bind_reuse_port`], as used by
+/// [`TcpListener::bind`].
+#[cfg(unix)]
+const BACKLOG: u32 = 1024;
+
+/// Bind `n` sockets to `addr` with `SO_REUSEPORT`. If `addr` has port 0,
+/// they all share the port picked for the first.
+#[cfg(unix)]
+pub(crate) fn bind_reuse_port(mut addr: SocketAddr, n: usize) -> io::Result<Vec<TcpListener>> {
+    let mut listeners = Vec::with_capacity(n);
+    for _ in 0..n {
+        let socket = match addr {
+            SocketAddr::V4(_) => TcpSocket::new_v4()?,
+            SocketAddr::V6(_) => TcpSocket::new_v6()?,
+        };
+        socket.set_reuseaddr(true)?;
+        socket.set_reuseport(true)?;
+        socket.bind(addr)?;
+        let listener = socket.listen(BACKLOG)?;
+        addr = listener.local_addr()?;
+        listeners.push(listener);
+    }
+    Ok(listeners)
+}
+
+#[cfg(not(unix))]
+pub(crate) fn bind_reuse_port(_addr: SocketAddr, _n: usize) -> io::Result<Vec<TcpListener>> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "SO_REUSEPORT is only supported on Unix",
+    ))
+}
+
+impl From<TcpListener> for Listener {
+    fn from(listener: TcpListener) -> Self {
+        Listener::Tcp(listener)
+    }
+}
+
+#[cfg(unix)]
+impl From<UnixListener> for Listener {
+    fn from(listener: UnixListener) -> Self {
+        Listener::Unix(listener)
+    }
+}
+
+/// Whether `ip` is one of this host's: loopback, unspecified, or an address
+/// of one of its interfaces.
+pub(crate) fn is_local_ip(ip: IpAddr) -> bool {
+    ip.is_loopback() || ip.is_unspecified() || interface_ips().contains(&ip)
+}
+
+#[cfg(unix)]
+fn interface_ips() -> Vec<IpAddr> {
+    let mut ifaddrs = ptr::null_mut();
+    // SAFETY: on success `ifaddrs` is a list we own until `freeifaddrs`.
+    if unsafe { libc::getifaddrs(&mut ifaddrs) } == -1 {
+        return Vec::new();
+    }
+    let mut ips = Vec::new();
+    let mut node = ifaddrs;
+    while !node.is_null() {
+        // SAFETY: `node` is in the list, which lives until `freeifaddrs`,
+        // and `ifa_addr`, when set, points to a socket address of the
+        // family it gives.
+        unsafe {
+            let addr = (*node).ifa_addr;
+            if !addr.is_null() {
+                match i32::from((*addr).sa_family) {
+                    libc::AF_INET => {
+                        let addr = &*(addr as *const libc::sockaddr_in);
+                        let ip = Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr));
+                        ips.push(IpAddr::V4(ip));
+                    }
+                    libc::AF_INET6 => {
+                        let addr = &*(addr as *const libc::sockaddr_in6);
+                        ips.push(IpAddr::V6(Ipv6Addr::from(addr.sin6_addr.s6_addr)));
+                    }
+                    _ => {}
+                }
+            }
+            node = (*node).ifa_next;
+        }
+    }
+    // SAFETY: `ifaddrs` came from `getifaddrs` and isn't used afterwards.
+    unsafe { libc::freeifaddrs(ifaddrs) };
+    ips
+}
+
+#[cfg(not(unix))]
+fn interface_ips() -> Vec<IpAddr> {
+    Vec::new()
+}
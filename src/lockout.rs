@@ -0,0 +1,172 @@
+//! Locking out clients and usernames after repeated authentication
+//! failures, to slow down password guessing.
+
+use std::collections::{HashMap, VecDeque};
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// When to lock out, see [`Server::with_auth_lockout`].
+///
+/// [`Server::with_auth_lockout`]: crate::Server::with_auth_lockout
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LockoutPolicy {
+    /// Failures that lock out a client IP or username...
+    pub max_failures: u32,
+    /// ...when they happen within this long.
+    pub window: Duration,
+    /// How long a lockout lasts.
+    pub lockout: Duration,
+}
+
+impl Default for LockoutPolicy {
+    /// 5 failures within 5 minutes lock out for 15 minutes.
+    fn default() -> Self {
+        LockoutPolicy {
+            max_failures: 5,
+            window: Duration::from_secs(5 * 60),
+            lockout: Duration::from_secs(15 * 60),
+        }
+    }
+}
+
+/// What a lockout applies to.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum LockoutKey {
+    /// Clients connecting from this IP.
+    Ip(IpAddr),
+    /// Clients authenticating with this username, from anywhere.
+    User(String),
+}
+
+/// A lockout in force, as listed by [`Server::lockouts`].
+///
+/// [`Server::lockouts`]: crate::Server::lockouts
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub struct Lockout {
+    pub key: LockoutKey,
+    /// Time until the lockout ends.
+    pub remaining: Duration,
+}
+
+/// Failures and lockouts by client IP and username.
+#[derive(Default)]
+pub(crate) struct Lockouts {
+    entries: Mutex<Entries>,
+}
+
+#[derive(Default)]
+struct Entries {
+    map: HashMap<LockoutKey, Entry>,
+    /// Size of the map at which stale entries are next swept out.
+    sweep_at: usize,
+}
+
+#[derive(Default)]
+struct Entry {
+    /// Times of the failures within the window, oldest first.
+    failures: VecDeque<Instant>,
+    locked_until: Option<Instant>,
+}
+
+impl Entry {
+    fn is_locked(&self, now: Instant) -> bool {
+        self.locked_until.is_some_and(|until| until > now)
+    }
+}
+
+/// Entries kept before stale ones are first swept out.
+const MIN_SWEEP: usize = 1024;
+
+impl Lockouts {
+    /// Whether a client from `ip` authenticating as `username` is locked
+    /// out.
+    pub(crate) fn is_locked(&self, ip: IpAddr, username: &str) -> bool {
+        let now = Instant::now();
+        let entries = self.entries.lock().unwrap();
+        [LockoutKey::Ip(ip), LockoutKey::User(username.to_owned())]
+            .iter()
+            .any(|key| entries.map.get(key).is_some_and(|e| e.is_locked(now)))
+    }
+
+    /// Count a failure of `username` from `ip`, locking either out once
+    /// `policy` says so. Returns whether `ip` was locked out.
+    pub(crate) fn fail(&self, policy: &LockoutPolicy, ip: IpAddr, username: &str) -> bool {
+        let now = Instant::now();
+        let mut locked_ip = false;
+        let mut entries = self.entries.lock().unwrap();
+        for key in [LockoutKey::Ip(ip), LockoutKey::User(username.to_owned())] {
+            let entry = entries.map.entry(key.clone()).or_default();
+            if entry.is_locked(now) {
+                continue;
+            }
+            while entry
+                .failures
+                .front()
+                .is_some_and(|&at| now.duration_since(at) > policy.window)
+            {
+                entry.failures.pop_front();
+            }
+            entry.failures.push_back(now);
+            if entry.failures.len() >= policy.max_failures as usize {
+                entry.failures.clear();
+                entry.locked_until = Some(now + policy.lockout);
+                locked_ip |= key == LockoutKey::Ip(ip);
+                log::warn!("locking out {:?} for {:?}", key, policy.lockout);
+            }
+        }
+        if entries.map.len() >= entries.sweep_at {
+            entries.map.retain(|_, entry| {
+                entry.is_locked(now)
+                    || entry
+                        .failures
+                        .back()
+                        .is_some_and(|&at| now.duration_since(at) <= policy.window)
+            });
+            entries.sweep_at = (entries.map.len() * 2).max(MIN_SWEEP);
+        }
+        locked_ip
+    }
+
+    /// Forget the failures of `username` from `ip`, after it authenticated.
+    pub(crate) fn succeed(&self, ip: IpAddr, username: &str) {
+        let mut entries = self.entries.lock().unwrap();
+        for key in [LockoutKey::Ip(ip), LockoutKey::User(username.to_owned())] {
+            if let Some(entry) = entries.map.get_mut(&key) {
+                entry.failures.clear();
+            }
+        }
+    }
+
+    pub(crate) fn list(&self) -> Vec<Lockout> {
+        let now = Instant::now();
+        let entries = self.entries.lock().unwrap();
+        entries
+            .map
+            .iter()
+            .filter_map(|(key, entry)| match entry.locked_until {
+                Some(until) if until > now => Some(Lockout {
+                    key: key.clone(),
+                    remaining: until - now,
+                }),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Lift the lockout of `key`, and forget its failures. Returns whether
+    /// it was locked out.
+    pub(crate) fn clear(&self, key: &LockoutKey) -> bool {
+        let now = Instant::now();
+        let mut entries = self.entries.lock().unwrap();
+        entries
+            .map
+            .remove(key)
+            .is_some_and(|entry| entry.is_locked(now))
+    }
+
+    pub(crate) fn clear_all(&self) {
+        self.entries.lock().unwrap().map.clear();
+    }
+}
@@ -1,6 +1,6 @@
 #[tokio::main]
-async fn main() {
-
-    let server = socks5_rs::Server::new().await;
-    server.serve().await;
+async fn main() -> std::io::Result<()> {
+    let server = socks5_rs::Server::bind("127.0.0.1:1080").await?;
+    server.serve().await?;
+    Ok(())
 }
@@ -0,0 +1,115 @@
+//! NAT64 (RFC 6146): reaching IPv4 targets from an IPv6-only network,
+//! through a gateway translating IPv6 addresses that embed IPv4 ones
+//! (RFC 6052).
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use crate::IpNet;
+
+/// The prefix of a NAT64 gateway that IPv4 targets are reached through, see
+/// [`Server::with_nat64`].
+///
+/// ```
+/// use socks5_rs::Nat64;
+///
+/// let nat64 = Nat64::new("2001:db8:64::/96".parse().unwrap()).unwrap();
+/// assert_eq!(
+///     nat64.synthesize("192.0.2.33".parse().unwrap()),
+///     "2001:db8:64::c000:221".parse::<std::net::Ipv6Addr>().unwrap(),
+/// );
+/// ```
+///
+/// [`Server::with_nat64`]: crate::Server::with_nat64
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Nat64 {
+    prefix: IpNet,
+    octets: [u8; 16],
+}
+
+impl Nat64 {
+    /// The Well-Known Prefix, `64:ff9b::/96`, which most gateways and DNS64
+    /// servers use.
+    pub fn well_known() -> Self {
+        Nat64::new("64:ff9b::/96".parse().unwrap()).unwrap()
+    }
+
+    /// The gateway's `prefix`. `None` unless it is an IPv6 network with a
+    /// prefix length RFC 6052 allows: 32, 40, 48, 56, 64 or 96 bits.
+    pub fn new(prefix: IpNet) -> Option<Self> {
+        match prefix.addr() {
+            IpAddr::V6(addr) if [32, 40, 48, 56, 64, 96].contains(&prefix.prefix_len()) => {
+                Some(Nat64 {
+                    prefix,
+                    octets: addr.octets(),
+                })
+            }
+            _ => None,
+        }
+    }
+
+    pub fn prefix(&self) -> IpNet {
+        self.prefix
+    }
+
+    /// The IPv6 address `ip` is reached at through the gateway.
+    pub fn synthesize(&self, ip: Ipv4Addr) -> Ipv6Addr {
+        let mut octets = self.octets;
+        for (position, octet) in self.positions().zip(ip.octets()) {
+            octets[position] = octet;
+        }
+        Ipv6Addr::from(octets)
+    }
+
+    /// The IPv4 address embedded in `ip`, if it is under the prefix.
+    pub fn extract(&self, ip: Ipv6Addr) -> Option<Ipv4Addr> {
+        if !self.prefix.contains(ip.into()) {
+            return None;
+        }
+        let octets = ip.octets();
+        let mut embedded = [0; 4];
+        for (octet, position) in embedded.iter_mut().zip(self.positions()) {
+            *octet = octets[position];
+        }
+        Some(embedded.into())
+    }
+
+    /// Where the IPv4 address goes: right after the prefix, skipping bits
+    /// 64 to 71, which are reserved.
+    fn positions(&self) -> impl Iterator<Item = usize> {
+        let start = usize::from(self.prefix.prefix_len() / 8);
+        (start..16).filter(|&position| position != 8).take(4)
+    }
+
+    /// `addrs`, with the addresses under the prefix, such as DNS64 answers,
+    /// replaced by the IPv4 addresses they embed, so that they are checked
+    /// as what they reach.
+    pub(crate) fn unmap(&self, addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+        let mut unmapped: Vec<SocketAddr> = Vec::with_capacity(addrs.len());
+        for addr in addrs {
+            let addr = self.unmap_addr(addr);
+            if !unmapped.contains(&addr) {
+                unmapped.push(addr);
+            }
+        }
+        unmapped
+    }
+
+    /// `addr`, if under the prefix, at the IPv4 address it embeds.
+    pub(crate) fn unmap_addr(&self, addr: SocketAddr) -> SocketAddr {
+        match addr {
+            SocketAddr::V6(v6) => match self.extract(*v6.ip()) {
+                Some(ip) => SocketAddr::new(ip.into(), v6.port()),
+                None => addr,
+            },
+            addr => addr,
+        }
+    }
+
+    /// `addr`, if IPv4, at its address through the gateway.
+    pub(crate) fn translate(&self, addr: SocketAddr) -> SocketAddr {
+        match addr {
+            SocketAddr::V4(v4) => SocketAddr::new(self.synthesize(*v4.ip()).into(), v4.port()),
+            addr => addr,
+        }
+    }
+}
@@ -0,0 +1,202 @@
+//! Username/password authentication through the host's PAM stack, so that
+//! system accounts can use the proxy.
+
+use std::ffi::{CStr, CString};
+use std::net::SocketAddr;
+use std::os::raw::{c_char, c_int, c_void};
+use std::ptr;
+use std::sync::Arc;
+
+use crate::auth::{AuthFuture, Authenticator, Decision};
+
+const PAM_SUCCESS: c_int = 0;
+const PAM_BUF_ERR: c_int = 5;
+const PAM_CONV_ERR: c_int = 19;
+
+const PAM_PROMPT_ECHO_OFF: c_int = 1;
+const PAM_ERROR_MSG: c_int = 3;
+const PAM_TEXT_INFO: c_int = 4;
+
+/// `pam_set_item` type of the remote host.
+const PAM_RHOST: c_int = 4;
+const PAM_SILENT: c_int = 0x8000;
+const PAM_DISALLOW_NULL_AUTHTOK: c_int = 0x0001;
+
+#[allow(non_camel_case_types)]
+enum pam_handle_t {}
+
+#[repr(C)]
+struct PamMessage {
+    msg_style: c_int,
+    msg: *const c_char,
+}
+
+#[repr(C)]
+struct PamResponse {
+    resp: *mut c_char,
+    resp_retcode: c_int,
+}
+
+#[repr(C)]
+struct PamConv {
+    conv: extern "C" fn(
+        num_msg: c_int,
+        msg: *mut *const PamMessage,
+        resp: *mut *mut PamResponse,
+        appdata_ptr: *mut c_void,
+    ) -> c_int,
+    appdata_ptr: *mut c_void,
+}
+
+#[link(name = "pam")]
+extern "C" {
+    fn pam_start(
+        service_name: *const c_char,
+        user: *const c_char,
+        pam_conversation: *const PamConv,
+        pamh: *mut *mut pam_handle_t,
+    ) -> c_int;
+    fn pam_set_item(pamh: *mut pam_handle_t, item_type: c_int, item: *const c_void) -> c_int;
+    fn pam_authenticate(pamh: *mut pam_handle_t, flags: c_int) -> c_int;
+    fn pam_acct_mgmt(pamh: *mut pam_handle_t, flags: c_int) -> c_int;
+    fn pam_end(pamh: *mut pam_handle_t, pam_status: c_int) -> c_int;
+}
+
+/// An [`Authenticator`] handing credentials to PAM, under a service name
+/// whose configuration lives in `/etc/pam.d/`. Accounts must also pass
+/// PAM's account checks, e.g. not be expired. PAM runs on Tokio's blocking
+/// threads.
+///
+/// Checking passwords of system accounts usually needs the privileges to
+/// read `/etc/shadow`, or a PAM module with a helper that has them.
+///
+/// ```no_run
+/// # async fn run() -> std::io::Result<()> {
+/// use socks5_rs::{PamAuthenticator, Server};
+///
+/// Server::bind("0.0.0.0:1080")
+///     .await?
+///     .with_authenticator(PamAuthenticator::new("socks5"))
+///     .serve()
+///     .await?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct PamAuthenticator {
+    service: Arc<CString>,
+}
+
+impl PamAuthenticator {
+    /// Authenticate as PAM service `service`.
+    ///
+    /// Panics if `service` contains a NUL byte.
+    pub fn new(service: &str) -> Self {
+        PamAuthenticator {
+            service: Arc::new(CString::new(service).expect("NUL in PAM service name")),
+        }
+    }
+}
+
+impl Authenticator for PamAuthenticator {
+    fn verify<'a>(
+        &'a self,
+        username: &'a str,
+        password: &'a [u8],
+        peer: SocketAddr,
+    ) -> AuthFuture<'a> {
+        let service = self.service.clone();
+        let credentials = (CString::new(username), CString::new(password));
+        let (username, password) = match credentials {
+            (Ok(username), Ok(password)) => (username, password),
+            _ => return Box::pin(std::future::ready(Decision::Deny)),
+        };
+        let rhost = CString::new(peer.ip().to_string()).unwrap();
+        Box::pin(async move {
+            let allowed = tokio::task::spawn_blocking(move || {
+                authenticate(&service, &username, &password, &rhost)
+            })
+            .await
+            .unwrap_or(false);
+            if allowed {
+                Decision::Allow
+            } else {
+                Decision::Deny
+            }
+        })
+    }
+}
+
+/// Run a PAM transaction for `username`.
+fn authenticate(service: &CStr, username: &CStr, password: &CStr, rhost: &CStr) -> bool {
+    let conv = PamConv {
+        conv: converse,
+        appdata_ptr: password.as_ptr() as *mut c_void,
+    };
+    let mut handle = ptr::null_mut();
+    let rc = unsafe { pam_start(service.as_ptr(), username.as_ptr(), &conv, &mut handle) };
+    if rc != PAM_SUCCESS {
+        log::warn!("pam_start failed with {}", rc);
+        return false;
+    }
+    let mut rc = unsafe { pam_set_item(handle, PAM_RHOST, rhost.as_ptr() as *const c_void) };
+    if rc == PAM_SUCCESS {
+        rc = unsafe { pam_authenticate(handle, PAM_SILENT | PAM_DISALLOW_NULL_AUTHTOK) };
+    }
+    if rc == PAM_SUCCESS {
+        rc = unsafe { pam_acct_mgmt(handle, PAM_SILENT | PAM_DISALLOW_NULL_AUTHTOK) };
+    }
+    unsafe { pam_end(handle, rc) };
+    rc == PAM_SUCCESS
+}
+
+/// The conversation function: answers password prompts with the password
+/// in `appdata_ptr`, and every other prompt with nothing.
+extern "C" fn converse(
+    num_msg: c_int,
+    msg: *mut *const PamMessage,
+    resp: *mut *mut PamResponse,
+    appdata_ptr: *mut c_void,
+) -> c_int {
+    if num_msg <= 0 || msg.is_null() || resp.is_null() {
+        return PAM_CONV_ERR;
+    }
+    let n = num_msg as usize;
+    // PAM frees the responses, so they come from its allocator.
+    let responses =
+        unsafe { libc::calloc(n, std::mem::size_of::<PamResponse>()) } as *mut PamResponse;
+    if responses.is_null() {
+        return PAM_BUF_ERR;
+    }
+    for i in 0..n {
+        // Linux-PAM passes an array of pointers to the messages.
+        let message = unsafe { &**msg.add(i) };
+        let response = unsafe { &mut *responses.add(i) };
+        match message.msg_style {
+            PAM_PROMPT_ECHO_OFF => {
+                response.resp = unsafe { libc::strdup(appdata_ptr as *const c_char) };
+                if response.resp.is_null() {
+                    free_responses(responses, i);
+                    return PAM_BUF_ERR;
+                }
+            }
+            // Nothing to show them to.
+            PAM_ERROR_MSG | PAM_TEXT_INFO => {}
+            // No other answers to give, e.g. to a one-time code prompt.
+            _ => {
+                free_responses(responses, i);
+                return PAM_CONV_ERR;
+            }
+        }
+    }
+    unsafe { *resp = responses };
+    PAM_SUCCESS
+}
+
+/// Free the first `n` responses and the array holding them, after a failed
+/// conversation.
+fn free_responses(responses: *mut PamResponse, n: usize) {
+    for i in 0..n {
+        unsafe { libc::free((*responses.add(i)).resp as *mut c_void) };
+    }
+    unsafe { libc::free(responses as *mut c_void) };
+}
@@ -0,0 +1,482 @@
+//! Argon2 (RFC 9106), in the PHC string format
+//! `$argon2id$v=19$m=<KiB>,t=<passes>,p=<lanes>$<salt>$<hash>`.
+
+use std::convert::TryInto;
+
+use super::blake2b::Blake2b;
+use super::{base64_decode, constant_time_eq, STANDARD_ALPHABET};
+
+/// The version this implements, 1.3. Hashes of earlier versions are
+/// refused.
+const VERSION: u32 = 0x13;
+
+/// u64 words per 1 KiB memory block.
+const BLOCK_WORDS: usize = 128;
+const SYNC_POINTS: usize = 4;
+
+type Block = [u64; BLOCK_WORDS];
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Variant {
+    D = 0,
+    I = 1,
+    Id = 2,
+}
+
+#[derive(Clone)]
+pub(crate) struct Hash {
+    variant: Variant,
+    memory_kib: u32,
+    passes: u32,
+    lanes: u32,
+    salt: Vec<u8>,
+    hash: Vec<u8>,
+}
+
+impl Hash {
+    pub(crate) fn parse(s: &str) -> Result<Self, &'static str> {
+        let mut fields = s.split('$');
+        if fields.next() != Some("") {
+            return Err("not an Argon2 hash");
+        }
+        let variant = match fields.next() {
+            Some("argon2d") => Variant::D,
+            Some("argon2i") => Variant::I,
+            Some("argon2id") => Variant::Id,
+            _ => return Err("not an Argon2 hash"),
+        };
+        if fields.next() != Some("v=19") {
+            return Err("unsupported Argon2 version");
+        }
+
+        let (mut memory_kib, mut passes, mut lanes) = (None, None, None);
+        for param in fields.next().ok_or("missing Argon2 parameters")?.split(',') {
+            let (name, value) = param.split_once('=').ok_or("bad Argon2 parameter")?;
+            let value: u32 = value.parse().map_err(|_| "bad Argon2 parameter")?;
+            match name {
+                "m" => memory_kib = Some(value),
+                "t" => passes = Some(value),
+                "p" => lanes = Some(value),
+                _ => return Err("unknown Argon2 parameter"),
+            }
+        }
+        let (memory_kib, passes, lanes) = match (memory_kib, passes, lanes) {
+            (Some(m), Some(t), Some(p)) => (m, t, p),
+            _ => return Err("missing Argon2 parameters"),
+        };
+        if lanes == 0 || lanes > 0x00ff_ffff || passes == 0 || memory_kib < 8 * lanes {
+            return Err("bad Argon2 parameters");
+        }
+
+        let salt = fields.next().ok_or("missing Argon2 salt")?;
+        let salt = base64_decode(salt, STANDARD_ALPHABET).ok_or("bad Argon2 salt")?;
+        let hash = fields.next().ok_or("missing Argon2 hash")?;
+        let hash = base64_decode(hash, STANDARD_ALPHABET).ok_or("bad Argon2 hash")?;
+        if fields.next().is_some() || salt.len() < 8 || hash.len() < 4 {
+            return Err("bad Argon2 hash");
+        }
+
+        Ok(Hash {
+            variant,
+            memory_kib,
+            passes,
+            lanes,
+            salt,
+            hash,
+        })
+    }
+
+    pub(crate) fn verify(&self, password: &[u8]) -> bool {
+        let tag = argon2(
+            self.variant,
+            password,
+            &self.salt,
+            &[],
+            &[],
+            self.memory_kib,
+            self.passes,
+            self.lanes,
+            self.hash.len(),
+        );
+        constant_time_eq(&tag, &self.hash)
+    }
+}
+
+/// The tag of `password` with `salt`, and optionally a `secret` key and
+/// associated `data`, which PHC strings don't carry.
+#[allow(clippy::too_many_arguments)]
+fn argon2(
+    variant: Variant,
+    password: &[u8],
+    salt: &[u8],
+    secret: &[u8],
+    data: &[u8],
+    memory_kib: u32,
+    passes: u32,
+    lanes: u32,
+    tag_len: usize,
+) -> Vec<u8> {
+    let mut h0 = Blake2b::new(64);
+    for value in [
+        lanes,
+        tag_len as u32,
+        memory_kib,
+        passes,
+        VERSION,
+        variant as u32,
+    ] {
+        h0.update(&value.to_le_bytes());
+    }
+    h0.update(&(password.len() as u32).to_le_bytes());
+    h0.update(password);
+    h0.update(&(salt.len() as u32).to_le_bytes());
+    h0.update(salt);
+    h0.update(&(secret.len() as u32).to_le_bytes());
+    h0.update(secret);
+    h0.update(&(data.len() as u32).to_le_bytes());
+    h0.update(data);
+    let mut seed = [0u8; 72];
+    h0.finalize(&mut seed[..64]);
+
+    let lanes = lanes as usize;
+    let segment_len = memory_kib as usize / (SYNC_POINTS * lanes);
+    let lane_len = segment_len * SYNC_POINTS;
+    let mut memory = vec![[0u64; BLOCK_WORDS]; lane_len * lanes];
+
+    let mut bytes = [0u8; 1024];
+    for lane in 0..lanes {
+        seed[68..].copy_from_slice(&(lane as u32).to_le_bytes());
+        for i in 0..2 {
+            seed[64..68].copy_from_slice(&(i as u32).to_le_bytes());
+            hash_long(&seed, &mut bytes);
+            memory[lane * lane_len + i] = block_from_bytes(&bytes);
+        }
+    }
+
+    let fill = Fill {
+        variant,
+        passes,
+        lanes,
+        segment_len,
+        lane_len,
+        blocks: (lane_len * lanes) as u64,
+    };
+    for pass in 0..passes {
+        for slice in 0..SYNC_POINTS {
+            for lane in 0..lanes {
+                fill.segment(&mut memory, pass, slice, lane);
+            }
+        }
+    }
+
+    let mut last = memory[lane_len - 1];
+    for lane in 1..lanes {
+        xor(&mut last, &memory[lane * lane_len + lane_len - 1]);
+    }
+    for (chunk, word) in bytes.chunks_exact_mut(8).zip(&last) {
+        chunk.copy_from_slice(&word.to_le_bytes());
+    }
+    let mut tag = vec![0u8; tag_len];
+    hash_long(&bytes, &mut tag);
+    tag
+}
+
+struct Fill {
+    variant: Variant,
+    passes: u32,
+    lanes: usize,
+    segment_len: usize,
+    lane_len: usize,
+    blocks: u64,
+}
+
+impl Fill {
+    fn segment(&self, memory: &mut [Block], pass: u32, slice: usize, lane: usize) {
+        let data_independent = self.variant == Variant::I
+            || (self.variant == Variant::Id && pass == 0 && slice < SYNC_POINTS / 2);
+
+        let zero = [0u64; BLOCK_WORDS];
+        let mut input = [0u64; BLOCK_WORDS];
+        let mut addresses = [0u64; BLOCK_WORDS];
+        if data_independent {
+            input[0] = pass as u64;
+            input[1] = lane as u64;
+            input[2] = slice as u64;
+            input[3] = self.blocks;
+            input[4] = self.passes as u64;
+            input[5] = self.variant as u64;
+        }
+        let next_addresses = |input: &mut Block, addresses: &mut Block| {
+            input[6] += 1;
+            compress(&zero, input, addresses, false);
+            let first = *addresses;
+            compress(&zero, &first, addresses, false);
+        };
+
+        // The first two blocks of each lane are filled already.
+        let start = if pass == 0 && slice == 0 {
+            if data_independent {
+                next_addresses(&mut input, &mut addresses);
+            }
+            2
+        } else {
+            0
+        };
+
+        let offset = lane * self.lane_len + slice * self.segment_len;
+        for index in start..self.segment_len {
+            let current = offset + index;
+            let previous = if current.is_multiple_of(self.lane_len) {
+                current + self.lane_len - 1
+            } else {
+                current - 1
+            };
+            let pseudo_rand = if data_independent {
+                if index % BLOCK_WORDS == 0 {
+                    next_addresses(&mut input, &mut addresses);
+                }
+                addresses[index % BLOCK_WORDS]
+            } else {
+                memory[previous][0]
+            };
+
+            let ref_lane = if pass == 0 && slice == 0 {
+                lane
+            } else {
+                ((pseudo_rand >> 32) % self.lanes as u64) as usize
+            };
+            let ref_index =
+                self.ref_index(pass, slice, index, pseudo_rand as u32, ref_lane == lane);
+            let reference = memory[ref_lane * self.lane_len + ref_index];
+            let prev = memory[previous];
+            compress(&prev, &reference, &mut memory[current], pass != 0);
+        }
+    }
+
+    /// The index within its lane of the block to mix into block `index` of
+    /// the segment.
+    fn ref_index(
+        &self,
+        pass: u32,
+        slice: usize,
+        index: usize,
+        rand: u32,
+        same_lane: bool,
+    ) -> usize {
+        // Blocks that may be referenced: those finished, less the one
+        // just before when in the same lane.
+        let area = if pass == 0 {
+            if slice == 0 || same_lane {
+                slice * self.segment_len + index - 1
+            } else if index == 0 {
+                slice * self.segment_len - 1
+            } else {
+                slice * self.segment_len
+            }
+        } else if same_lane {
+            self.lane_len - self.segment_len + index - 1
+        } else if index == 0 {
+            self.lane_len - self.segment_len - 1
+        } else {
+            self.lane_len - self.segment_len
+        } as u64;
+
+        let x = (rand as u64 * rand as u64) >> 32;
+        let relative = area - 1 - ((area * x) >> 32);
+        let start = if pass == 0 || slice == SYNC_POINTS - 1 {
+            0
+        } else {
+            (slice + 1) * self.segment_len
+        };
+        (start + relative as usize) % self.lane_len
+    }
+}
+
+/// The compression function G, written into `next`, or XORed into it for
+/// passes after the first.
+fn compress(prev: &Block, reference: &Block, next: &mut Block, with_xor: bool) {
+    let mut r = *prev;
+    xor(&mut r, reference);
+    let mut z = r;
+    for row in 0..8 {
+        let mut v = [0u64; 16];
+        v.copy_from_slice(&z[row * 16..row * 16 + 16]);
+        permute(&mut v);
+        z[row * 16..row * 16 + 16].copy_from_slice(&v);
+    }
+    for column in 0..8 {
+        let mut v = [0u64; 16];
+        for i in 0..8 {
+            v[2 * i] = z[2 * column + 16 * i];
+            v[2 * i + 1] = z[2 * column + 16 * i + 1];
+        }
+        permute(&mut v);
+        for i in 0..8 {
+            z[2 * column + 16 * i] = v[2 * i];
+            z[2 * column + 16 * i + 1] = v[2 * i + 1];
+        }
+    }
+    xor(&mut z, &r);
+    if with_xor {
+        xor(next, &z);
+    } else {
+        *next = z;
+    }
+}
+
+/// The BLAKE2b round without message words, with multiplications added.
+fn permute(v: &mut [u64; 16]) {
+    gb(v, 0, 4, 8, 12);
+    gb(v, 1, 5, 9, 13);
+    gb(v, 2, 6, 10, 14);
+    gb(v, 3, 7, 11, 15);
+    gb(v, 0, 5, 10, 15);
+    gb(v, 1, 6, 11, 12);
+    gb(v, 2, 7, 8, 13);
+    gb(v, 3, 4, 9, 14);
+}
+
+fn gb(v: &mut [u64; 16], a: usize, b: usize, c: usize, d: usize) {
+    let mul = |x: u64, y: u64| {
+        2u64.wrapping_mul(x & 0xffff_ffff)
+            .wrapping_mul(y & 0xffff_ffff)
+    };
+    v[a] = v[a].wrapping_add(v[b]).wrapping_add(mul(v[a], v[b]));
+    v[d] = (v[d] ^ v[a]).rotate_right(32);
+    v[c] = v[c].wrapping_add(v[d]).wrapping_add(mul(v[c], v[d]));
+    v[b] = (v[b] ^ v[c]).rotate_right(24);
+    v[a] = v[a].wrapping_add(v[b]).wrapping_add(mul(v[a], v[b]));
+    v[d] = (v[d] ^ v[a]).rotate_right(16);
+    v[c] = v[c].wrapping_add(v[d]).wrapping_add(mul(v[c], v[d]));
+    v[b] = (v[b] ^ v[c]).rotate_right(63);
+}
+
+/// The variable-length hash H' of the specification.
+fn hash_long(input: &[u8], out: &mut [u8]) {
+    let len = (out.len() as u32).to_le_bytes();
+    if out.len() <= 64 {
+        let mut h = Blake2b::new(out.len());
+        h.update(&len);
+        h.update(input);
+        h.finalize(out);
+        return;
+    }
+
+    // A chain of 64-byte hashes, each contributing its first half, and a
+    // last one of whatever length remains.
+    let mut v = [0u8; 64];
+    let mut h = Blake2b::new(64);
+    h.update(&len);
+    h.update(input);
+    h.finalize(&mut v);
+    let mut written = 0;
+    while out.len() - written > 64 {
+        out[written..written + 32].copy_from_slice(&v[..32]);
+        written += 32;
+        let next_len = (out.len() - written).min(64);
+        let mut h = Blake2b::new(next_len);
+        h.update(&v);
+        h.finalize(&mut v[..next_len]);
+    }
+    let rest = out.len() - written;
+    out[written..].copy_from_slice(&v[..rest]);
+}
+
+fn block_from_bytes(bytes: &[u8; 1024]) -> Block {
+    let mut block = [0u64; BLOCK_WORDS];
+    for (word, chunk) in block.iter_mut().zip(bytes.chunks_exact(8)) {
+        *word = u64::from_le_bytes(chunk.try_into().unwrap());
+    }
+    block
+}
+
+fn xor(a: &mut Block, b: &Block) {
+    for (a, b) in a.iter_mut().zip(b) {
+        *a ^= b;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    /// The test vectors of RFC 9106, section 5.
+    fn rfc9106(variant: Variant) -> String {
+        let tag = argon2(
+            variant,
+            &[0x01; 32],
+            &[0x02; 16],
+            &[0x03; 8],
+            &[0x04; 12],
+            32,
+            3,
+            4,
+            32,
+        );
+        hex(&tag)
+    }
+
+    #[test]
+    fn rfc9106_argon2d() {
+        assert_eq!(
+            rfc9106(Variant::D),
+            "512b391b6f1162975371d30919734294f868e3be3984f3c1a13a4db9fabe4acb"
+        );
+    }
+
+    #[test]
+    fn rfc9106_argon2i() {
+        assert_eq!(
+            rfc9106(Variant::I),
+            "c814d9d1dc7f37aa13f0d77f2494bda1c8de6b016dd388d29952a4c4672b6ce8"
+        );
+    }
+
+    #[test]
+    fn rfc9106_argon2id() {
+        assert_eq!(
+            rfc9106(Variant::Id),
+            "0d640df58d78766c08c037a34a8b53c9d01ef0452d75b65eb52520e96b01e659"
+        );
+    }
+
+    #[test]
+    fn phc_string() {
+        let hash = Hash::parse(
+            "$argon2i$v=19$m=256,t=2,p=1$c29tZXNhbHQ$iekCn0Y3spW+sCcFanM2xBT63UP2sghkUoHLIUpWRS8",
+        )
+        .unwrap();
+        assert!(hash.verify(b"password"));
+        assert!(!hash.verify(b"passwore"));
+        assert!(!hash.verify(b""));
+    }
+
+    #[test]
+    fn malformed() {
+        for s in [
+            "",
+            "argon2id$v=19$m=64,t=1,p=1$c29tZXNhbHQ$AAAAAAAA",
+            "$argon2x$v=19$m=64,t=1,p=1$c29tZXNhbHQ$AAAAAAAA",
+            "$argon2id$v=16$m=64,t=1,p=1$c29tZXNhbHQ$AAAAAAAA",
+            "$argon2id$m=64,t=1,p=1$c29tZXNhbHQ$AAAAAAAA",
+            "$argon2id$v=19$m=64,t=1$c29tZXNhbHQ$AAAAAAAA",
+            "$argon2id$v=19$m=64,t=1,p=1,x=1$c29tZXNhbHQ$AAAAAAAA",
+            "$argon2id$v=19$m=64,t=one,p=1$c29tZXNhbHQ$AAAAAAAA",
+            "$argon2id$v=19$m=64,t=0,p=1$c29tZXNhbHQ$AAAAAAAA",
+            "$argon2id$v=19$m=64,t=1,p=0$c29tZXNhbHQ$AAAAAAAA",
+            "$argon2id$v=19$m=7,t=1,p=1$c29tZXNhbHQ$AAAAAAAA",
+            "$argon2id$v=19$m=64,t=1,p=1$c29tZXNhbHQ",
+            "$argon2id$v=19$m=64,t=1,p=1$c2*tZXNhbHQ$AAAAAAAA",
+            "$argon2id$v=19$m=64,t=1,p=1$c29tZXNhbHQ$AAAAAAAA=",
+            "$argon2id$v=19$m=64,t=1,p=1$c29tZQ$AAAAAAAA",
+            "$argon2id$v=19$m=64,t=1,p=1$c29tZXNhbHQ$AAA",
+            "$argon2id$v=19$m=64,t=1,p=1$c29tZXNhbHQ$AAAAAAAA$",
+        ] {
+            assert!(Hash::parse(s).is_err(), "{:?}", s);
+        }
+    }
+}
@@ -0,0 +1,190 @@
+//! BLAKE2b (RFC 7693), unkeyed, as Argon2 builds on it.
+
+use std::convert::TryInto;
+
+const IV: [u64; 8] = [
+    0x6a09e667f3bcc908,
+    0xbb67ae8584caa73b,
+    0x3c6ef372fe94f82b,
+    0xa54ff53a5f1d36f1,
+    0x510e527fade682d1,
+    0x9b05688c2b3e6c1f,
+    0x1f83d9abfb41bd6b,
+    0x5be0cd19137e2179,
+];
+
+const SIGMA: [[usize; 16]; 12] = [
+    [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15],
+    [14, 10, 4, 8, 9, 15, 13, 6, 1, 12, 0, 2, 11, 7, 5, 3],
+    [11, 8, 12, 0, 5, 2, 15, 13, 10, 14, 3, 6, 7, 1, 9, 4],
+    [7, 9, 3, 1, 13, 12, 11, 14, 2, 6, 5, 10, 4, 0, 15, 8],
+    [9, 0, 5, 7, 2, 4, 10, 15, 14, 1, 11, 12, 6, 8, 3, 13],
+    [2, 12, 6, 10, 0, 11, 8, 3, 4, 13, 7, 5, 15, 14, 1, 9],
+    [12, 5, 1, 15, 14, 13, 4, 10, 0, 7, 6, 3, 9, 2, 8, 11],
+    [13, 11, 7, 14, 12, 1, 3, 9, 5, 0, 15, 4, 8, 6, 2, 10],
+    [6, 15, 14, 9, 11, 3, 0, 8, 12, 2, 13, 7, 1, 4, 10, 5],
+    [10, 2, 8, 4, 7, 6, 1, 5, 15, 11, 9, 14, 3, 12, 13, 0],
+    [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15],
+    [14, 10, 4, 8, 9, 15, 13, 6, 1, 12, 0, 2, 11, 7, 5, 3],
+];
+
+const BLOCK_LEN: usize = 128;
+
+pub(crate) struct Blake2b {
+    h: [u64; 8],
+    /// Bytes compressed so far.
+    t: u128,
+    buf: [u8; BLOCK_LEN],
+    buf_len: usize,
+    out_len: usize,
+}
+
+impl Blake2b {
+    /// A hasher with `out_len` bytes of output, 1 to 64.
+    pub(crate) fn new(out_len: usize) -> Self {
+        debug_assert!((1..=64).contains(&out_len));
+        let mut h = IV;
+        h[0] ^= 0x0101_0000 ^ out_len as u64;
+        Blake2b {
+            h,
+            t: 0,
+            buf: [0; BLOCK_LEN],
+            buf_len: 0,
+            out_len,
+        }
+    }
+
+    pub(crate) fn update(&mut self, mut data: &[u8]) {
+        while !data.is_empty() {
+            // The last block is compressed by `finalize`, so a full buffer
+            // is only compressed once more data follows.
+            if self.buf_len == BLOCK_LEN {
+                self.t += BLOCK_LEN as u128;
+                let block = self.buf;
+                self.compress(&block, false);
+                self.buf_len = 0;
+            }
+            let n = (BLOCK_LEN - self.buf_len).min(data.len());
+            self.buf[self.buf_len..self.buf_len + n].copy_from_slice(&data[..n]);
+            self.buf_len += n;
+            data = &data[n..];
+        }
+    }
+
+    pub(crate) fn finalize(mut self, out: &mut [u8]) {
+        self.t += self.buf_len as u128;
+        self.buf[self.buf_len..].fill(0);
+        let block = self.buf;
+        self.compress(&block, true);
+        let mut bytes = [0u8; 64];
+        for (chunk, word) in bytes.chunks_exact_mut(8).zip(&self.h) {
+            chunk.copy_from_slice(&word.to_le_bytes());
+        }
+        out.copy_from_slice(&bytes[..self.out_len]);
+    }
+
+    fn compress(&mut self, block: &[u8; BLOCK_LEN], last: bool) {
+        let mut m = [0u64; 16];
+        for (word, chunk) in m.iter_mut().zip(block.chunks_exact(8)) {
+            *word = u64::from_le_bytes(chunk.try_into().unwrap());
+        }
+        let mut v = [0u64; 16];
+        v[..8].copy_from_slice(&self.h);
+        v[8..].copy_from_slice(&IV);
+        v[12] ^= self.t as u64;
+        v[13] ^= (self.t >> 64) as u64;
+        if last {
+            v[14] = !v[14];
+        }
+        for s in &SIGMA {
+            g(&mut v, 0, 4, 8, 12, m[s[0]], m[s[1]]);
+            g(&mut v, 1, 5, 9, 13, m[s[2]], m[s[3]]);
+            g(&mut v, 2, 6, 10, 14, m[s[4]], m[s[5]]);
+            g(&mut v, 3, 7, 11, 15, m[s[6]], m[s[7]]);
+            g(&mut v, 0, 5, 10, 15, m[s[8]], m[s[9]]);
+            g(&mut v, 1, 6, 11, 12, m[s[10]], m[s[11]]);
+            g(&mut v, 2, 7, 8, 13, m[s[12]], m[s[13]]);
+            g(&mut v, 3, 4, 9, 14, m[s[14]], m[s[15]]);
+        }
+        for i in 0..8 {
+            self.h[i] ^= v[i] ^ v[i + 8];
+        }
+    }
+}
+
+fn g(v: &mut [u64; 16], a: usize, b: usize, c: usize, d: usize, x: u64, y: u64) {
+    v[a] = v[a].wrapping_add(v[b]).wrapping_add(x);
+    v[d] = (v[d] ^ v[a]).rotate_right(32);
+    v[c] = v[c].wrapping_add(v[d]);
+    v[b] = (v[b] ^ v[c]).rotate_right(24);
+    v[a] = v[a].wrapping_add(v[b]).wrapping_add(y);
+    v[d] = (v[d] ^ v[a]).rotate_right(16);
+    v[c] = v[c].wrapping_add(v[d]);
+    v[b] = (v[b] ^ v[c]).rotate_right(63);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    fn blake2b(out_len: usize, chunks: &[&[u8]]) -> String {
+        let mut h = Blake2b::new(out_len);
+        for chunk in chunks {
+            h.update(chunk);
+        }
+        let mut out = vec![0; out_len];
+        h.finalize(&mut out);
+        hex(&out)
+    }
+
+    #[test]
+    fn rfc7693_abc() {
+        assert_eq!(
+            blake2b(64, &[b"abc"]),
+            "ba80a53f981c4d0d6a2797b69f12f6e94c212f14685ac4b74b12bb6fdbffa2d1\
+             7d87c5392aab792dc252d5de4533cc9518d38aa8dbf1925ab92386edd4009923"
+        );
+    }
+
+    #[test]
+    fn empty() {
+        assert_eq!(
+            blake2b(64, &[]),
+            "786a02f742015903c6c6fd852552d272912f4740e15847618a86e217f71f5419\
+             d25e1031afee585313896444934eb04b903a685b1448b755d56f701afe9be2ce"
+        );
+        assert_eq!(
+            blake2b(32, &[]),
+            "0e5751c026e543b2e8ab2eb06099daa1d1e5df47778f7787faab45cdf12fe3a8"
+        );
+    }
+
+    #[test]
+    fn block_boundaries() {
+        let data: Vec<u8> = (0..=255).collect();
+        assert_eq!(
+            blake2b(64, &[&data[..128]]),
+            "2319e3789c47e2daa5fe807f61bec2a1a6537fa03f19ff32e87eecbfd64b7e0e\
+             8ccff439ac333b040f19b0c4ddd11a61e24ac1fe0f10a039806c5dcc0da3d115"
+        );
+        assert_eq!(
+            blake2b(64, &[&data[..100], &data[100..129]]),
+            "f59711d44a031d5f97a9413c065d1e614c417ede998590325f49bad2fd444d3e\
+             4418be19aec4e11449ac1a57207898bc57d76a1bcf3566292c20c683a5c4648f"
+        );
+    }
+
+    #[test]
+    fn multi_block() {
+        let data: Vec<u8> = (0..=255).chain(0..=255).collect();
+        let (a, b) = data.split_at(300);
+        assert_eq!(
+            blake2b(32, &[a, b]),
+            "540b20132d8aeae54057cb69c24f95d26a1c472cc700dd450defe9bb796d4f14"
+        );
+    }
+}
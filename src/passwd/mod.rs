@@ -0,0 +1,71 @@
+//! Password hashes, as stored in credentials files instead of passwords.
+
+mod argon2;
+mod bcrypt;
+mod blake2b;
+
+pub(crate) const BCRYPT_ALPHABET: &[u8; 64] =
+    b"./ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789";
+pub(crate) const STANDARD_ALPHABET: &[u8; 64] =
+    b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+/// A password hash in one of the schemes understood.
+#[derive(Clone)]
+pub(crate) enum PasswordHash {
+    Bcrypt(bcrypt::Hash),
+    Argon2(argon2::Hash),
+}
+
+impl PasswordHash {
+    /// Parse a hash in its usual string form: `$2b$...` for bcrypt, PHC
+    /// strings (`$argon2id$...`) for Argon2.
+    pub(crate) fn parse(s: &str) -> Result<Self, &'static str> {
+        if s.starts_with("$2") {
+            bcrypt::Hash::parse(s).map(PasswordHash::Bcrypt)
+        } else if s.starts_with("$argon2") {
+            argon2::Hash::parse(s).map(PasswordHash::Argon2)
+        } else {
+            Err("unsupported password hash scheme")
+        }
+    }
+
+    /// Whether `password` hashes to this. Slow by design.
+    pub(crate) fn verify(&self, password: &[u8]) -> bool {
+        match self {
+            PasswordHash::Bcrypt(hash) => hash.verify(password),
+            PasswordHash::Argon2(hash) => hash.verify(password),
+        }
+    }
+}
+
+/// Compare in time depending only on the lengths.
+pub(crate) fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    let diff = a.iter().zip(b).fold(0, |diff, (a, b)| diff | (a ^ b));
+    // Keep the compiler from short-circuiting the fold.
+    std::hint::black_box(diff) == 0
+}
+
+/// Decode unpadded base64 in `alphabet`. Bits left over past the last
+/// whole byte are ignored.
+pub(crate) fn base64_decode(s: &str, alphabet: &[u8; 64]) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(s.len() * 3 / 4);
+    let (mut bits, mut n) = (0u32, 0);
+    for c in s.bytes() {
+        let value = alphabet.iter().position(|&a| a == c)? as u32;
+        bits = bits << 6 | value;
+        n += 6;
+        if n >= 8 {
+            n -= 8;
+            out.push((bits >> n) as u8);
+            bits &= (1 << n) - 1;
+        }
+    }
+    // A single character left over can't hold a byte.
+    if n == 6 {
+        return None;
+    }
+    Some(out)
+}
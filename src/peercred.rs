@@ -0,0 +1,35 @@
+//! Authenticating Unix socket clients by the credentials of the process on
+//! the other end (`SO_PEERCRED`), which the kernel vouches for.
+
+use std::collections::HashSet;
+
+/// Which Unix socket clients are served, see
+/// [`Server::with_unix_peer_cred`].
+///
+/// ```
+/// use socks5_rs::PeerCredPolicy;
+///
+/// let policy = PeerCredPolicy {
+///     uids: [1000, 1001].into(),
+///     skip_socks_auth: true,
+///     ..PeerCredPolicy::default()
+/// };
+/// ```
+///
+/// [`Server::with_unix_peer_cred`]: crate::Server::with_unix_peer_cred
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct PeerCredPolicy {
+    /// Users whose processes are served.
+    pub uids: HashSet<u32>,
+    /// Groups whose processes are served, by the process's effective group.
+    pub gids: HashSet<u32>,
+    /// Whether clients that pass skip SOCKS authentication, if they offer
+    /// to. Otherwise they authenticate like any other client.
+    pub skip_socks_auth: bool,
+}
+
+impl PeerCredPolicy {
+    pub(crate) fn allows(&self, uid: u32, gid: u32) -> bool {
+        self.uids.contains(&uid) || self.gids.contains(&gid)
+    }
+}
@@ -0,0 +1,346 @@
+//! Per-user policies: what an authenticated user may reach, how fast, from
+//! which address, and with how many sessions at once.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::future::Future;
+use std::io;
+use std::net::IpAddr;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{ready, Context, Poll};
+use std::time::Duration;
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::time::{self, Instant, Sleep};
+
+use crate::acl::{DestinationAcl, PortAcl};
+use crate::error::Socks5Error;
+use crate::protocol::TargetAddr;
+use crate::Schedule;
+
+/// Decides whether a user may reach a target.
+pub type DestinationFilter = dyn Fn(&TargetAddr) -> bool + Send + Sync;
+
+/// Restrictions on a user, applied once they authenticated with
+/// username/password. Users without a policy are unrestricted.
+///
+/// Policies come from [`Authenticator::policy`], or else from
+/// [`ServerConfig::user_policies`]. Clones share the bandwidth budget and
+/// session counts.
+///
+/// ```
+/// use socks5_rs::UserPolicy;
+///
+/// let policy = UserPolicy::new()
+///     .with_destinations(|target| target.port == 443)
+///     .with_bandwidth_limit(1 << 20)
+///     .with_max_sessions(4);
+/// ```
+///
+/// [`Authenticator::policy`]: crate::Authenticator::policy
+/// [`ServerConfig::user_policies`]: crate::ServerConfig::user_policies
+#[derive(Clone, Default)]
+pub struct UserPolicy {
+    destinations: Option<Arc<DestinationFilter>>,
+    ports: Option<PortAcl>,
+    destination_acls: Vec<DestinationAcl>,
+    schedule: Option<Schedule>,
+    bandwidth: Option<Arc<RateLimit>>,
+    egress_addr: Option<IpAddr>,
+    dscp: Option<u8>,
+    max_sessions: Option<usize>,
+    /// Open sessions by username.
+    sessions: Arc<Mutex<HashMap<String, usize>>>,
+}
+
+impl UserPolicy {
+    /// A policy restricting nothing.
+    pub fn new() -> Self {
+        UserPolicy::default()
+    }
+
+    /// Let the user reach only the targets `filter` accepts, by CONNECT or
+    /// over UDP. Others are refused with "connection not allowed by
+    /// ruleset", or dropped for UDP.
+    pub fn with_destinations<F>(mut self, filter: F) -> Self
+    where
+        F: Fn(&TargetAddr) -> bool + Send + Sync + 'static,
+    {
+        self.destinations = Some(Arc::new(filter));
+        self
+    }
+
+    /// Let the user reach only the targets that pass `acl`, in addition to
+    /// the server's [`DestinationAcl`]s. Can be called more than once.
+    pub fn with_destination_acl(mut self, acl: DestinationAcl) -> Self {
+        self.destination_acls.push(acl);
+        self
+    }
+
+    /// Let the user reach only the target ports `acl` allows, in addition to
+    /// the server's [`PortAcl`], if any.
+    pub fn with_port_acl(mut self, acl: PortAcl) -> Self {
+        self.ports = Some(acl);
+        self
+    }
+
+    /// Let the user's requests through only while `schedule` is active.
+    /// Others are refused with "connection not allowed by ruleset", or
+    /// dropped for UDP.
+    pub fn with_schedule(mut self, schedule: Schedule) -> Self {
+        self.schedule = Some(schedule);
+        self
+    }
+
+    /// Relay at most `bytes_per_sec` over the user's CONNECT sessions, both
+    /// directions and all sessions together.
+    pub fn with_bandwidth_limit(mut self, bytes_per_sec: u64) -> Self {
+        self.bandwidth = Some(Arc::new(RateLimit::new(bytes_per_sec)));
+        self
+    }
+
+    /// Connect to targets from `addr`. Targets only reachable over the other
+    /// address family fail to connect.
+    pub fn with_egress_addr(mut self, addr: IpAddr) -> Self {
+        self.egress_addr = Some(addr);
+        self
+    }
+
+    /// Mark the packets of the user's sessions with the DSCP `dscp`, both to
+    /// targets and to the client. Takes precedence over
+    /// [`Server::with_dscp`].
+    ///
+    /// [`Server::with_dscp`]: crate::Server::with_dscp
+    #[cfg(unix)]
+    pub fn with_dscp(mut self, dscp: u8) -> Self {
+        self.dscp = Some(dscp);
+        self
+    }
+
+    /// Refuse authenticating the user while `max` of their sessions are
+    /// open.
+    pub fn with_max_sessions(mut self, max: usize) -> Self {
+        self.max_sessions = Some(max);
+        self
+    }
+
+    /// Refuse `target` if the user may not reach it.
+    pub(crate) fn check(&self, target: &TargetAddr) -> Result<(), Socks5Error> {
+        if !self.schedule.as_ref().is_none_or(Schedule::is_active) {
+            return Err(Socks5Error::NotAllowed);
+        }
+        if let Some(ports) = &self.ports {
+            ports.check(target)?;
+        }
+        match &self.destinations {
+            Some(destinations) if !destinations(target) => Err(Socks5Error::NotAllowed),
+            _ => Ok(()),
+        }
+    }
+
+    pub(crate) fn destination_acls(&self) -> &[DestinationAcl] {
+        &self.destination_acls
+    }
+
+    pub(crate) fn egress_addr(&self) -> Option<IpAddr> {
+        self.egress_addr
+    }
+
+    pub(crate) fn dscp(&self) -> Option<u8> {
+        self.dscp
+    }
+
+    pub(crate) fn bandwidth(&self) -> Option<Arc<RateLimit>> {
+        self.bandwidth.clone()
+    }
+
+    /// Count a session of `username`, until the returned guard is dropped.
+    /// Fails if the user has as many sessions as allowed.
+    pub(crate) fn open_session(&self, username: &str) -> Option<SessionGuard> {
+        let mut sessions = self.sessions.lock().unwrap();
+        let open = sessions.entry(username.to_owned()).or_default();
+        if self.max_sessions.is_some_and(|max| *open >= max) {
+            return None;
+        }
+        *open += 1;
+        Some(SessionGuard {
+            sessions: self.sessions.clone(),
+            username: username.to_owned(),
+        })
+    }
+}
+
+impl fmt::Debug for UserPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("UserPolicy")
+            .field("destinations", &self.destinations.as_ref().map(|_| ".."))
+            .field("ports", &self.ports)
+            .field("destination_acls", &self.destination_acls)
+            .field("schedule", &self.schedule)
+            .field(
+                "bandwidth",
+                &self.bandwidth.as_ref().map(|limit| limit.rate),
+            )
+            .field("egress_addr", &self.egress_addr)
+            .field("dscp", &self.dscp)
+            .field("max_sessions", &self.max_sessions)
+            .finish()
+    }
+}
+
+/// Counts a session of a user as open while alive.
+pub(crate) struct SessionGuard {
+    sessions: Arc<Mutex<HashMap<String, usize>>>,
+    username: String,
+}
+
+impl Drop for SessionGuard {
+    fn drop(&mut self) {
+        let mut sessions = self.sessions.lock().unwrap();
+        if let Some(open) = sessions.get_mut(&self.username) {
+            *open -= 1;
+            if *open == 0 {
+                sessions.remove(&self.username);
+            }
+        }
+    }
+}
+
+/// A token bucket of bytes, holding up to a second's worth.
+pub(crate) struct RateLimit {
+    rate: u64,
+    bucket: Mutex<Bucket>,
+}
+
+struct Bucket {
+    /// Bytes that may be relayed now. Goes negative when several streams
+    /// relay at once, which the next ones wait off.
+    tokens: f64,
+    refilled: Instant,
+}
+
+/// Smallest amount worth waiting for, so that slow limits don't relay a
+/// byte at a time.
+const MIN_GRANT: f64 = 1024.0;
+
+impl RateLimit {
+    fn new(rate: u64) -> Self {
+        RateLimit {
+            rate: rate.max(1),
+            bucket: Mutex::new(Bucket {
+                tokens: rate as f64,
+                refilled: Instant::now(),
+            }),
+        }
+    }
+
+    /// How many bytes may be relayed now, or else how long until some may.
+    fn available(&self) -> Result<usize, Duration> {
+        let rate = self.rate as f64;
+        let mut bucket = self.bucket.lock().unwrap();
+        let now = Instant::now();
+        let elapsed = now.duration_since(bucket.refilled).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * rate).min(rate);
+        bucket.refilled = now;
+        if bucket.tokens >= MIN_GRANT.min(rate) {
+            Ok(bucket.tokens as usize)
+        } else {
+            Err(Duration::from_secs_f64(
+                (MIN_GRANT.min(rate) - bucket.tokens) / rate,
+            ))
+        }
+    }
+
+    fn consume(&self, bytes: usize) {
+        self.bucket.lock().unwrap().tokens -= bytes as f64;
+    }
+}
+
+/// A stream relaying no faster than a [`RateLimit`] allows.
+pub(crate) struct Limited<S> {
+    inner: S,
+    limit: Option<Arc<RateLimit>>,
+    read_wait: Option<Pin<Box<Sleep>>>,
+    write_wait: Option<Pin<Box<Sleep>>>,
+}
+
+impl<S> Limited<S> {
+    pub(crate) fn new(inner: S, limit: Option<Arc<RateLimit>>) -> Self {
+        Limited {
+            inner,
+            limit,
+            read_wait: None,
+            write_wait: None,
+        }
+    }
+}
+
+/// How many bytes `limit` allows now, waiting on `wait` until some are.
+fn poll_allowance(
+    limit: &RateLimit,
+    wait: &mut Option<Pin<Box<Sleep>>>,
+    cx: &mut Context<'_>,
+) -> Poll<usize> {
+    loop {
+        if let Some(sleep) = wait {
+            ready!(sleep.as_mut().poll(cx));
+            *wait = None;
+        }
+        match limit.available() {
+            Ok(allowed) => return Poll::Ready(allowed),
+            Err(delay) => *wait = Some(Box::pin(time::sleep(delay))),
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Limited<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        let limit = match &this.limit {
+            Some(limit) => limit.clone(),
+            None => return Pin::new(&mut this.inner).poll_read(cx, buf),
+        };
+        let allowed = ready!(poll_allowance(&limit, &mut this.read_wait, cx));
+        let mut limited = buf.take(allowed.min(buf.remaining()));
+        ready!(Pin::new(&mut this.inner).poll_read(cx, &mut limited))?;
+        let read = limited.filled().len();
+        // SAFETY: `limited` is over `buf`'s unfilled part, and the inner
+        // stream initialized and filled `read` bytes of it.
+        unsafe { buf.assume_init(read) };
+        buf.advance(read);
+        limit.consume(read);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Limited<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        let limit = match &this.limit {
+            Some(limit) => limit.clone(),
+            None => return Pin::new(&mut this.inner).poll_write(cx, buf),
+        };
+        let allowed = ready!(poll_allowance(&limit, &mut this.write_wait, cx));
+        let written =
+            ready!(Pin::new(&mut this.inner).poll_write(cx, &buf[..allowed.min(buf.len())]))?;
+        limit.consume(written);
+        Poll::Ready(Ok(written))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
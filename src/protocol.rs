@@ -0,0 +1,557 @@
+//! The SOCKS5 wire format (RFC 1928 and RFC 1929), free of any I/O.
+//!
+//! Parsers take the bytes received so far and return either the message and
+//! the number of bytes it spans, or how many more bytes are needed at least.
+//! Encoders append to any `BufMut`. See [`crate::codec`] for `Framed` streams.
+
+use std::convert::TryFrom;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use bytes::BufMut;
+
+use crate::idna;
+
+pub const SOCKS_VERSION: u8 = 0x05;
+pub const RESERVED: u8 = 0x00;
+pub const ATYP_IPV4: u8 = 0x01;
+pub const ATYP_DOMAIN: u8 = 0x03;
+pub const ATYP_IPV6: u8 = 0x04;
+/// Longest domain name the one-byte length prefix can describe.
+pub const MAX_DOMAIN_LEN: usize = 255;
+/// Version of the RFC 1929 username/password sub-negotiation.
+pub const USER_PASS_VERSION: u8 = 0x01;
+
+/// A message that can't be parsed.
+#[derive(thiserror::Error, Debug)]
+pub enum ProtocolError {
+    #[error("Unsupported SOCKS version {0:#04x}")]
+    InvalidVersion(u8),
+    #[error("Unsupported sub-negotiation version {0:#04x}")]
+    InvalidSubnegotiationVersion(u8),
+    #[error("Address type {0:#04x} not supported")]
+    AddressTypeNotSupported(u8),
+    #[error("Command {0:#04x} not supported")]
+    CommandNotSupported(u8),
+    #[error("Reserved field is {0:#04x}, not zero")]
+    InvalidReserved(u8),
+    #[error("Domain name is empty, too long or not valid")]
+    InvalidDomain,
+    #[error("Unknown reply code {0:#04x}")]
+    UnknownReply(u8),
+}
+
+/// Outcome of parsing a message.
+#[derive(Debug)]
+pub enum Parse<T> {
+    /// The message, and how many bytes of the input it took.
+    Complete(T, usize),
+    /// The input ends early; at least this many more bytes are needed.
+    Incomplete(usize),
+}
+
+impl<T> Parse<T> {
+    pub fn map<U>(self, f: impl FnOnce(T) -> U) -> Parse<U> {
+        match self {
+            Parse::Complete(value, len) => Parse::Complete(f(value), len),
+            Parse::Incomplete(needed) => Parse::Incomplete(needed),
+        }
+    }
+
+    /// Account for `offset` bytes preceding the part that was parsed.
+    pub fn shift(self, offset: usize) -> Parse<T> {
+        match self {
+            Parse::Complete(value, len) => Parse::Complete(value, offset + len),
+            incomplete => incomplete,
+        }
+    }
+}
+
+/// `Incomplete` for the bytes missing from `buf` up to `len`, if any.
+macro_rules! need {
+    ($buf:expr, $len:expr) => {
+        if $buf.len() < $len {
+            return Ok(Parse::Incomplete($len - $buf.len()));
+        }
+    };
+}
+
+/// An authentication METHOD, as offered in a greeting and selected by the
+/// server.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Method {
+    /// No authentication required
+    NoAuth,
+    /// GSSAPI (RFC 1961)
+    GssApi,
+    /// Username / password (RFC 1929)
+    UserPass,
+    /// Any other method, IANA assigned (0x03 to 0x7f) or private (0x80 to
+    /// 0xfe).
+    Other(u8),
+    /// NO ACCEPTABLE METHODS, only ever sent by servers.
+    NoAcceptable,
+}
+
+impl From<u8> for Method {
+    fn from(n: u8) -> Self {
+        match n {
+            0x00 => Method::NoAuth,
+            0x01 => Method::GssApi,
+            0x02 => Method::UserPass,
+            0xff => Method::NoAcceptable,
+            n => Method::Other(n),
+        }
+    }
+}
+
+impl From<Method> for u8 {
+    fn from(method: Method) -> u8 {
+        match method {
+            Method::NoAuth => 0x00,
+            Method::GssApi => 0x01,
+            Method::UserPass => 0x02,
+            Method::Other(n) => n,
+            Method::NoAcceptable => 0xff,
+        }
+    }
+}
+
+/// The REP field of a reply.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Reply {
+    Succeeded = 0x00,
+    GeneralFailure = 0x01,
+    /// Connection not allowed by ruleset
+    NotAllowed = 0x02,
+    NetworkUnreachable = 0x03,
+    HostUnreachable = 0x04,
+    ConnectionRefused = 0x05,
+    TtlExpired = 0x06,
+    CommandNotSupported = 0x07,
+    AddressTypeNotSupported = 0x08,
+}
+
+impl From<Reply> for u8 {
+    fn from(reply: Reply) -> u8 {
+        reply as u8
+    }
+}
+
+impl TryFrom<u8> for Reply {
+    type Error = ProtocolError;
+
+    fn try_from(n: u8) -> Result<Self, Self::Error> {
+        match n {
+            0x00 => Ok(Reply::Succeeded),
+            0x01 => Ok(Reply::GeneralFailure),
+            0x02 => Ok(Reply::NotAllowed),
+            0x03 => Ok(Reply::NetworkUnreachable),
+            0x04 => Ok(Reply::HostUnreachable),
+            0x05 => Ok(Reply::ConnectionRefused),
+            0x06 => Ok(Reply::TtlExpired),
+            0x07 => Ok(Reply::CommandNotSupported),
+            0x08 => Ok(Reply::AddressTypeNotSupported),
+            _ => Err(ProtocolError::UnknownReply(n)),
+        }
+    }
+}
+
+/// The CMD field of a request.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Command {
+    Connect = 0x01,
+    Bind = 0x02,
+    UdpAssociate = 0x03,
+}
+
+impl From<Command> for u8 {
+    fn from(command: Command) -> u8 {
+        command as u8
+    }
+}
+
+impl TryFrom<u8> for Command {
+    type Error = ProtocolError;
+
+    fn try_from(n: u8) -> Result<Self, Self::Error> {
+        match n {
+            0x01 => Ok(Command::Connect),
+            0x02 => Ok(Command::Bind),
+            0x03 => Ok(Command::UdpAssociate),
+            _ => Err(ProtocolError::CommandNotSupported(n)),
+        }
+    }
+}
+
+/// DST.ADDR or BND.ADDR.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Address {
+    Ipv4(Ipv4Addr),
+    Ipv6(Ipv6Addr),
+    /// A domain name, resolved by the server. At most [`MAX_DOMAIN_LEN`]
+    /// bytes go on the wire; use [`Address::domain`] to have that checked,
+    /// and the name normalized.
+    Domain(String),
+}
+
+impl Address {
+    /// A domain name address, provided it is a valid name and fits the
+    /// one-byte length prefix.
+    ///
+    /// The name is normalized, so that each name has one spelling: it is
+    /// lowercased, loses a trailing dot, and labels outside ASCII are
+    /// encoded in Punycode (RFC 3492).
+    ///
+    /// ```
+    /// use socks5_rs::protocol::Address;
+    ///
+    /// let domain = Address::domain("Bücher.Example.")?;
+    /// assert_eq!(domain, Address::Domain("xn--bcher-kva.example".into()));
+    /// assert_eq!(Address::domain("XN--BCHER-KVA.example")?, domain);
+    /// # Ok::<(), socks5_rs::protocol::ProtocolError>(())
+    /// ```
+    pub fn domain(name: impl AsRef<str>) -> Result<Self, ProtocolError> {
+        let name = idna::normalize(name.as_ref()).ok_or(ProtocolError::InvalidDomain)?;
+        if name.len() > MAX_DOMAIN_LEN {
+            return Err(ProtocolError::InvalidDomain);
+        }
+        Ok(Address::Domain(name))
+    }
+
+    /// The ATYP byte announcing this address.
+    pub fn atyp(&self) -> u8 {
+        match self {
+            Address::Ipv4(_) => ATYP_IPV4,
+            Address::Domain(_) => ATYP_DOMAIN,
+            Address::Ipv6(_) => ATYP_IPV6,
+        }
+    }
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Address::Ipv4(ip) => ip.fmt(f),
+            Address::Ipv6(ip) => write!(f, "[{}]", ip),
+            Address::Domain(domain) => f.write_str(domain),
+        }
+    }
+}
+
+impl From<IpAddr> for Address {
+    fn from(ip: IpAddr) -> Self {
+        match ip {
+            IpAddr::V4(ip) => Address::Ipv4(ip),
+            IpAddr::V6(ip) => Address::Ipv6(ip),
+        }
+    }
+}
+
+impl From<Ipv4Addr> for Address {
+    fn from(ip: Ipv4Addr) -> Self {
+        Address::Ipv4(ip)
+    }
+}
+
+impl From<Ipv6Addr> for Address {
+    fn from(ip: Ipv6Addr) -> Self {
+        Address::Ipv6(ip)
+    }
+}
+
+/// `ATYP | ADDR | PORT`, as found in requests, replies and UDP headers.
+///
+/// Displays as `host:port`, with IPv6 addresses in brackets.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct TargetAddr {
+    pub address: Address,
+    pub port: u16,
+}
+
+impl fmt::Display for TargetAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.address, self.port)
+    }
+}
+
+impl TargetAddr {
+    pub fn new(address: impl Into<Address>, port: u16) -> Self {
+        TargetAddr {
+            address: address.into(),
+            port,
+        }
+    }
+
+    /// The socket address, unless this names a domain.
+    pub fn socket_addr(&self) -> Option<SocketAddr> {
+        match self.address {
+            Address::Ipv4(ip) => Some(SocketAddr::from((ip, self.port))),
+            Address::Ipv6(ip) => Some(SocketAddr::from((ip, self.port))),
+            Address::Domain(_) => None,
+        }
+    }
+
+    pub fn parse(buf: &[u8]) -> Result<Parse<Self>, ProtocolError> {
+        need!(buf, 1);
+        let (start, addr_len) = match buf[0] {
+            ATYP_IPV4 => (1, 4),
+            ATYP_IPV6 => (1, 16),
+            ATYP_DOMAIN => {
+                need!(buf, 2);
+                (2, buf[1] as usize)
+            }
+            atyp => return Err(ProtocolError::AddressTypeNotSupported(atyp)),
+        };
+        let len = start + addr_len + 2;
+        need!(buf, len);
+
+        let addr = &buf[start..start + addr_len];
+        let address = match buf[0] {
+            ATYP_IPV4 => {
+                let mut octets = [0u8; 4];
+                octets.copy_from_slice(addr);
+                Address::Ipv4(octets.into())
+            }
+            ATYP_IPV6 => {
+                let mut octets = [0u8; 16];
+                octets.copy_from_slice(addr);
+                Address::Ipv6(octets.into())
+            }
+            _ => Address::domain(
+                String::from_utf8(addr.to_vec()).map_err(|_| ProtocolError::InvalidDomain)?,
+            )?,
+        };
+        let port = u16::from_be_bytes([buf[len - 2], buf[len - 1]]);
+
+        Ok(Parse::Complete(TargetAddr { address, port }, len))
+    }
+
+    pub fn encode<B: BufMut>(&self, buf: &mut B) {
+        buf.put_u8(self.address.atyp());
+        match &self.address {
+            Address::Ipv4(ip) => buf.put_slice(&ip.octets()),
+            Address::Ipv6(ip) => buf.put_slice(&ip.octets()),
+            Address::Domain(domain) => {
+                buf.put_u8(domain.len() as u8);
+                buf.put_slice(domain.as_bytes());
+            }
+        }
+        buf.put_u16(self.port);
+    }
+}
+
+impl From<SocketAddr> for TargetAddr {
+    /// IPv4-mapped addresses become ATYP IPv4 so IPv4-only peers can use them.
+    fn from(addr: SocketAddr) -> Self {
+        let addr = unmap_socket_addr(addr);
+        TargetAddr::new(addr.ip(), addr.port())
+    }
+}
+
+/// Turn an IPv4-mapped IPv6 address, as seen on dual-stack sockets, back
+/// into the IPv4 address it stands for.
+pub(crate) fn unmap_socket_addr(addr: SocketAddr) -> SocketAddr {
+    match addr {
+        SocketAddr::V6(v6) => match v6.ip().to_ipv4_mapped() {
+            Some(ip) => SocketAddr::from((ip, v6.port())),
+            None => addr,
+        },
+        SocketAddr::V4(_) => addr,
+    }
+}
+
+/// `VER | NMETHODS | METHODS`
+#[derive(Clone, Debug)]
+pub struct Greeting {
+    pub methods: Vec<Method>,
+}
+
+impl Greeting {
+    pub fn parse(buf: &[u8]) -> Result<Parse<Self>, ProtocolError> {
+        need!(buf, 2);
+        if buf[0] != SOCKS_VERSION {
+            return Err(ProtocolError::InvalidVersion(buf[0]));
+        }
+        let len = 2 + buf[1] as usize;
+        need!(buf, len);
+
+        let methods = buf[2..len].iter().map(|&n| Method::from(n)).collect();
+        Ok(Parse::Complete(Greeting { methods }, len))
+    }
+
+    pub fn encode<B: BufMut>(&self, buf: &mut B) {
+        buf.put_slice(&[SOCKS_VERSION, self.methods.len() as u8]);
+        for &method in &self.methods {
+            buf.put_u8(method.into());
+        }
+    }
+}
+
+/// `VER | METHOD`
+#[derive(Clone, Debug)]
+pub struct MethodSelection {
+    pub method: Method,
+}
+
+impl MethodSelection {
+    pub fn parse(buf: &[u8]) -> Result<Parse<Self>, ProtocolError> {
+        need!(buf, 2);
+        if buf[0] != SOCKS_VERSION {
+            return Err(ProtocolError::InvalidVersion(buf[0]));
+        }
+        Ok(Parse::Complete(
+            MethodSelection {
+                method: buf[1].into(),
+            },
+            2,
+        ))
+    }
+
+    pub fn encode<B: BufMut>(&self, buf: &mut B) {
+        buf.put_slice(&[SOCKS_VERSION, self.method.into()]);
+    }
+}
+
+/// RFC 1929 `VER | ULEN | UNAME | PLEN | PASSWD`
+#[derive(Clone, Debug)]
+pub struct UserPassRequest {
+    pub username: Vec<u8>,
+    pub password: Vec<u8>,
+}
+
+impl UserPassRequest {
+    pub fn parse(buf: &[u8]) -> Result<Parse<Self>, ProtocolError> {
+        need!(buf, 2);
+        if buf[0] != USER_PASS_VERSION {
+            return Err(ProtocolError::InvalidSubnegotiationVersion(buf[0]));
+        }
+        let ulen = buf[1] as usize;
+        need!(buf, 2 + ulen + 1);
+        let plen = buf[2 + ulen] as usize;
+        let len = 2 + ulen + 1 + plen;
+        need!(buf, len);
+
+        Ok(Parse::Complete(
+            UserPassRequest {
+                username: buf[2..2 + ulen].to_vec(),
+                password: buf[3 + ulen..len].to_vec(),
+            },
+            len,
+        ))
+    }
+
+    pub fn encode<B: BufMut>(&self, buf: &mut B) {
+        buf.put_slice(&[USER_PASS_VERSION, self.username.len() as u8]);
+        buf.put_slice(&self.username);
+        buf.put_u8(self.password.len() as u8);
+        buf.put_slice(&self.password);
+    }
+}
+
+/// RFC 1929 `VER | STATUS`
+#[derive(Clone, Debug)]
+pub struct UserPassResponse {
+    pub success: bool,
+}
+
+impl UserPassResponse {
+    pub fn parse(buf: &[u8]) -> Result<Parse<Self>, ProtocolError> {
+        need!(buf, 2);
+        if buf[0] != USER_PASS_VERSION {
+            return Err(ProtocolError::InvalidSubnegotiationVersion(buf[0]));
+        }
+        Ok(Parse::Complete(
+            UserPassResponse {
+                success: buf[1] == 0x00,
+            },
+            2,
+        ))
+    }
+
+    pub fn encode<B: BufMut>(&self, buf: &mut B) {
+        let status = if self.success { 0x00 } else { 0x01 };
+        buf.put_slice(&[USER_PASS_VERSION, status]);
+    }
+}
+
+/// `VER | CMD | RSV | ATYP | DST.ADDR | DST.PORT`
+#[derive(Clone, Debug)]
+pub struct Request {
+    pub command: Command,
+    pub target: TargetAddr,
+}
+
+impl Request {
+    pub fn parse(buf: &[u8]) -> Result<Parse<Self>, ProtocolError> {
+        need!(buf, 3);
+        if buf[0] != SOCKS_VERSION {
+            return Err(ProtocolError::InvalidVersion(buf[0]));
+        }
+        let command = Command::try_from(buf[1])?;
+        if buf[2] != RESERVED {
+            return Err(ProtocolError::InvalidReserved(buf[2]));
+        }
+
+        Ok(TargetAddr::parse(&buf[3..])?
+            .map(|target| Request { command, target })
+            .shift(3))
+    }
+
+    pub fn encode<B: BufMut>(&self, buf: &mut B) {
+        buf.put_slice(&[SOCKS_VERSION, self.command.into(), RESERVED]);
+        self.target.encode(buf);
+    }
+}
+
+/// The reply to a request: `VER | REP | RSV | ATYP | BND.ADDR | BND.PORT`
+#[derive(Clone, Debug)]
+pub struct Response {
+    pub reply: Reply,
+    pub bnd: TargetAddr,
+}
+
+impl Response {
+    pub fn parse(buf: &[u8]) -> Result<Parse<Self>, ProtocolError> {
+        need!(buf, 3);
+        if buf[0] != SOCKS_VERSION {
+            return Err(ProtocolError::InvalidVersion(buf[0]));
+        }
+        let reply = Reply::try_from(buf[1])?;
+
+        Ok(TargetAddr::parse(&buf[3..])?
+            .map(|bnd| Response { reply, bnd })
+            .shift(3))
+    }
+
+    pub fn encode<B: BufMut>(&self, buf: &mut B) {
+        buf.put_slice(&[SOCKS_VERSION, self.reply.into(), RESERVED]);
+        self.bnd.encode(buf);
+    }
+}
+
+/// `RSV | FRAG | ATYP | DST.ADDR | DST.PORT`, preceding every UDP datagram.
+#[derive(Clone, Debug)]
+pub struct UdpHeader {
+    pub frag: u8,
+    pub dst: TargetAddr,
+}
+
+impl UdpHeader {
+    pub fn parse(buf: &[u8]) -> Result<Parse<Self>, ProtocolError> {
+        need!(buf, 3);
+        if let Some(&rsv) = buf[..2].iter().find(|&&b| b != RESERVED) {
+            return Err(ProtocolError::InvalidReserved(rsv));
+        }
+        let frag = buf[2];
+
+        Ok(TargetAddr::parse(&buf[3..])?
+            .map(|dst| UdpHeader { frag, dst })
+            .shift(3))
+    }
+
+    pub fn encode<B: BufMut>(&self, buf: &mut B) {
+        buf.put_slice(&[RESERVED, RESERVED, self.frag]);
+        self.dst.encode(buf);
+    }
+}
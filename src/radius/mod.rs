@@ -0,0 +1,527 @@
+//! Username/password authentication against a RADIUS server (RFC 2865),
+//! sending PAP Access-Requests over UDP.
+
+mod md5;
+
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::io;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::time::{Duration, SystemTime};
+
+use tokio::net::UdpSocket;
+use tokio::time::{self, Instant};
+
+use crate::auth::{AuthFuture, Authenticator, Decision};
+use crate::passwd::constant_time_eq;
+use md5::{hmac_md5, md5};
+
+const ACCESS_REQUEST: u8 = 1;
+const ACCESS_ACCEPT: u8 = 2;
+const ACCESS_REJECT: u8 = 3;
+const ACCESS_CHALLENGE: u8 = 11;
+
+const ATTR_USER_NAME: u8 = 1;
+const ATTR_USER_PASSWORD: u8 = 2;
+const ATTR_CALLING_STATION_ID: u8 = 31;
+const ATTR_NAS_IDENTIFIER: u8 = 32;
+const ATTR_MESSAGE_AUTHENTICATOR: u8 = 80;
+
+const HEADER_LEN: usize = 20;
+/// Longest password PAP can carry.
+const MAX_PASSWORD: usize = 128;
+const MAX_PACKET: usize = 4096;
+
+/// An [`Authenticator`] asking a RADIUS server about credentials.
+///
+/// ```no_run
+/// # async fn run() -> std::io::Result<()> {
+/// use std::time::Duration;
+/// use socks5_rs::{RadiusAuthenticator, Server};
+///
+/// let radius = RadiusAuthenticator::new("10.0.0.2:1812".parse().unwrap(), "shared secret")
+///     .with_timeout(Duration::from_secs(2))
+///     .with_retries(3);
+/// Server::bind("0.0.0.0:1080")
+///     .await?
+///     .with_authenticator(radius)
+///     .serve()
+///     .await?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct RadiusAuthenticator {
+    server: SocketAddr,
+    secret: Vec<u8>,
+    nas_identifier: String,
+    timeout: Duration,
+    retries: u32,
+    next_id: AtomicU8,
+}
+
+impl RadiusAuthenticator {
+    /// Ask `server`, sharing `secret` with it.
+    pub fn new(server: SocketAddr, secret: impl Into<Vec<u8>>) -> Self {
+        RadiusAuthenticator {
+            server,
+            secret: secret.into(),
+            nas_identifier: "socks5_rs".to_owned(),
+            timeout: Duration::from_secs(3),
+            retries: 2,
+            next_id: AtomicU8::new(0),
+        }
+    }
+
+    /// How long to wait for an answer before sending the request again, 3
+    /// seconds by default.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// How many times to send the request again when unanswered, 2 by
+    /// default. Credentials are denied once they are used up.
+    pub fn with_retries(mut self, retries: u32) -> Self {
+        self.retries = retries;
+        self
+    }
+
+    /// The NAS-Identifier sent, by which the server tells this proxy from
+    /// its other clients. Defaults to `socks5_rs`.
+    pub fn with_nas_identifier(mut self, nas_identifier: impl Into<String>) -> Self {
+        self.nas_identifier = nas_identifier.into();
+        self
+    }
+
+    async fn check(&self, username: &str, password: &[u8], peer: SocketAddr) -> io::Result<bool> {
+        if password.len() > MAX_PASSWORD || username.len() > 253 {
+            return Ok(false);
+        }
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let authenticator = random_authenticator();
+        let request = self.request(id, &authenticator, username, password, peer);
+
+        let local: SocketAddr = if self.server.is_ipv4() {
+            (Ipv4Addr::UNSPECIFIED, 0).into()
+        } else {
+            (Ipv6Addr::UNSPECIFIED, 0).into()
+        };
+        let socket = UdpSocket::bind(local).await?;
+        socket.connect(self.server).await?;
+        let mut buf = vec![0; MAX_PACKET];
+        for _ in 0..=self.retries {
+            // Retransmissions are the same packet, so that the server can
+            // recognize them.
+            socket.send(&request).await?;
+            let deadline = Instant::now() + self.timeout;
+            while let Ok(received) = time::timeout_at(deadline, socket.recv(&mut buf)).await {
+                let response = &buf[..received?];
+                match self.response_code(response, id, &authenticator) {
+                    Some(ACCESS_ACCEPT) => return Ok(true),
+                    Some(ACCESS_REJECT) => return Ok(false),
+                    // Further rounds aren't supported, e.g. asking for a
+                    // one-time password.
+                    Some(ACCESS_CHALLENGE) => return Ok(false),
+                    _ => log::debug!("ignoring a bad RADIUS response"),
+                }
+            }
+        }
+        Err(io::ErrorKind::TimedOut.into())
+    }
+
+    /// An Access-Request packet.
+    fn request(
+        &self,
+        id: u8,
+        authenticator: &[u8; 16],
+        username: &str,
+        password: &[u8],
+        peer: SocketAddr,
+    ) -> Vec<u8> {
+        let mut packet = vec![ACCESS_REQUEST, id, 0, 0];
+        packet.extend_from_slice(authenticator);
+        put_attribute(&mut packet, ATTR_USER_NAME, username.as_bytes());
+        put_attribute(
+            &mut packet,
+            ATTR_USER_PASSWORD,
+            &self.hide(password, authenticator),
+        );
+        put_attribute(
+            &mut packet,
+            ATTR_NAS_IDENTIFIER,
+            &self.nas_identifier.as_bytes()[..self.nas_identifier.len().min(253)],
+        );
+        put_attribute(
+            &mut packet,
+            ATTR_CALLING_STATION_ID,
+            peer.ip().to_string().as_bytes(),
+        );
+        // Signed as a whole, as servers expect since the BlastRADIUS attack.
+        let signature_at = packet.len() + 2;
+        put_attribute(&mut packet, ATTR_MESSAGE_AUTHENTICATOR, &[0; 16]);
+        let len = packet.len() as u16;
+        packet[2..4].copy_from_slice(&len.to_be_bytes());
+        let signature = hmac_md5(&self.secret, &packet);
+        packet[signature_at..signature_at + 16].copy_from_slice(&signature);
+        packet
+    }
+
+    /// The User-Password attribute for `password`.
+    fn hide(&self, password: &[u8], authenticator: &[u8; 16]) -> Vec<u8> {
+        let len = password.len().max(1).div_ceil(16) * 16;
+        let mut hidden = password.to_vec();
+        hidden.resize(len, 0);
+        let mut previous = *authenticator;
+        for chunk in hidden.chunks_exact_mut(16) {
+            let pad = md5(&[&self.secret, &previous]);
+            for (b, p) in chunk.iter_mut().zip(&pad) {
+                *b ^= p;
+            }
+            previous.copy_from_slice(chunk);
+        }
+        hidden
+    }
+
+    /// The code of `response`, if it answers request `id` and is signed
+    /// with the shared secret.
+    fn response_code(&self, response: &[u8], id: u8, authenticator: &[u8; 16]) -> Option<u8> {
+        if response.len() < HEADER_LEN || response[1] != id {
+            return None;
+        }
+        let len = u16::from_be_bytes([response[2], response[3]]) as usize;
+        if len < HEADER_LEN || len > response.len() {
+            return None;
+        }
+        let response = &response[..len];
+        let attributes = &response[HEADER_LEN..];
+        let expected = md5(&[&response[..4], authenticator, attributes, &self.secret]);
+        if !constant_time_eq(&expected, &response[4..HEADER_LEN]) {
+            return None;
+        }
+
+        // Check the Message-Authenticator too, when there is one.
+        let mut at = 0;
+        while at + 2 <= attributes.len() {
+            let (kind, attr_len) = (attributes[at], attributes[at + 1] as usize);
+            if attr_len < 2 || at + attr_len > attributes.len() {
+                return None;
+            }
+            if kind == ATTR_MESSAGE_AUTHENTICATOR {
+                if attr_len != 18 {
+                    return None;
+                }
+                let start = HEADER_LEN + at + 2;
+                let mut signed = response.to_vec();
+                signed[4..HEADER_LEN].copy_from_slice(authenticator);
+                signed[start..start + 16].fill(0);
+                let signature = hmac_md5(&self.secret, &signed);
+                if !constant_time_eq(&signature, &response[start..start + 16]) {
+                    return None;
+                }
+            }
+            at += attr_len;
+        }
+        Some(response[0])
+    }
+}
+
+impl Authenticator for RadiusAuthenticator {
+    fn verify<'a>(
+        &'a self,
+        username: &'a str,
+        password: &'a [u8],
+        peer: SocketAddr,
+    ) -> AuthFuture<'a> {
+        Box::pin(async move {
+            match self.check(username, password, peer).await {
+                Ok(true) => Decision::Allow,
+                Ok(false) => Decision::Deny,
+                Err(e) => {
+                    log::warn!("RADIUS check of user {:?}: {}", username, e);
+                    Decision::Deny
+                }
+            }
+        })
+    }
+}
+
+fn put_attribute(packet: &mut Vec<u8>, kind: u8, value: &[u8]) {
+    packet.push(kind);
+    packet.push(value.len() as u8 + 2);
+    packet.extend_from_slice(value);
+}
+
+/// A Request Authenticator, which must be unpredictable. The standard
+/// library's hasher keys are drawn from the OS's random source.
+fn random_authenticator() -> [u8; 16] {
+    let mut authenticator = [0; 16];
+    for chunk in authenticator.chunks_exact_mut(8) {
+        let mut hasher = RandomState::new().build_hasher();
+        if let Ok(now) = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH) {
+            hasher.write_u128(now.as_nanos());
+        }
+        chunk.copy_from_slice(&hasher.finish().to_le_bytes());
+    }
+    authenticator
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The shared secret of the examples of RFC 2865 section 7.
+    const SECRET: &[u8] = b"xyzzy5461";
+    /// The Request Authenticator of the example of section 7.1.
+    const AUTHENTICATOR: [u8; 16] = [
+        0x0f, 0x40, 0x3f, 0x94, 0x73, 0x97, 0x80, 0x57, 0xbd, 0x83, 0xd5, 0xcb, 0x98, 0xf4, 0x22,
+        0x7a,
+    ];
+    /// Its Access-Accept.
+    const ACCEPT: [u8; 38] = [
+        0x02, 0x00, 0x00, 0x26, 0x86, 0xfe, 0x22, 0x0e, 0x76, 0x24, 0xba, 0x2a, 0x10, 0x05, 0xf6,
+        0xbf, 0x9b, 0x55, 0xe0, 0xb2, 0x06, 0x06, 0x00, 0x00, 0x00, 0x01, 0x0f, 0x06, 0x00, 0x00,
+        0x00, 0x00, 0x0e, 0x06, 0xc0, 0xa8, 0x01, 0x03,
+    ];
+
+    fn unhex(hex: &str) -> Vec<u8> {
+        (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    fn radius(server: SocketAddr) -> RadiusAuthenticator {
+        RadiusAuthenticator::new(server, SECRET)
+    }
+
+    /// A response to `request`, signed with `secret`.
+    fn response(code: u8, request: &[u8], secret: &[u8]) -> Vec<u8> {
+        let mut response = vec![code, request[1], 0, 0];
+        response.extend_from_slice(&request[4..HEADER_LEN]);
+        put_attribute(&mut response, 18, b"Hello");
+        put_attribute(&mut response, ATTR_MESSAGE_AUTHENTICATOR, &[0; 16]);
+        let len = response.len() as u16;
+        response[2..4].copy_from_slice(&len.to_be_bytes());
+        let signature = hmac_md5(secret, &response);
+        let at = response.len() - 16;
+        response[at..].copy_from_slice(&signature);
+        let digest = md5(&[&response, secret]);
+        response[4..HEADER_LEN].copy_from_slice(&digest);
+        response
+    }
+
+    #[test]
+    fn hides_passwords() {
+        let radius = radius("127.0.0.1:1812".parse().unwrap());
+        // RFC 2865 section 7.1.
+        assert_eq!(
+            radius.hide(b"arctangent", &AUTHENTICATOR),
+            unhex("0dbe708d93d413ce3196e43f782a0aee")
+        );
+        // Empty, padded to a chunk.
+        assert_eq!(
+            radius.hide(b"", &AUTHENTICATOR),
+            unhex("6ccc13f9f2ba74ab5fe2e43f782a0aee")
+        );
+        // Each chunk chained to the one before.
+        assert_eq!(
+            radius.hide(b"a much longer password, of 40 characters", &AUTHENTICATOR),
+            unhex(concat!(
+                "0dec7e8c91d254c7308c835a0a0a7a8f",
+                "e8544284c3f56995c5f50b51c681e4b3",
+                "3f3550ec607ef56610f1d4fceed8c5c2",
+            ))
+        );
+    }
+
+    #[test]
+    fn verifies_responses() {
+        let radius = radius("127.0.0.1:1812".parse().unwrap());
+        // RFC 2865 section 7.1.
+        assert_eq!(
+            radius.response_code(&ACCEPT, 0, &AUTHENTICATOR),
+            Some(ACCESS_ACCEPT)
+        );
+        // Padding past the length is ignored.
+        let mut padded = ACCEPT.to_vec();
+        padded.extend_from_slice(&[0; 4]);
+        assert_eq!(
+            radius.response_code(&padded, 0, &AUTHENTICATOR),
+            Some(ACCESS_ACCEPT)
+        );
+
+        // Of another request, or another secret.
+        assert_eq!(radius.response_code(&ACCEPT, 1, &AUTHENTICATOR), None);
+        let mut other = AUTHENTICATOR;
+        other[0] ^= 1;
+        assert_eq!(radius.response_code(&ACCEPT, 0, &other), None);
+        let impostor = RadiusAuthenticator::new("127.0.0.1:1812".parse().unwrap(), "xyzzy5462");
+        assert_eq!(impostor.response_code(&ACCEPT, 0, &AUTHENTICATOR), None);
+        // Tampered with, or cut short.
+        for i in 0..ACCEPT.len() {
+            let mut tampered = ACCEPT;
+            tampered[i] ^= 0x10;
+            assert_eq!(radius.response_code(&tampered, 0, &AUTHENTICATOR), None);
+        }
+        for len in 0..ACCEPT.len() {
+            assert_eq!(
+                radius.response_code(&ACCEPT[..len], 0, &AUTHENTICATOR),
+                None
+            );
+        }
+    }
+
+    #[test]
+    fn verifies_message_authenticators() {
+        let radius = radius("127.0.0.1:1812".parse().unwrap());
+        let request = radius.request(7, &AUTHENTICATOR, "nemo", b"arctangent", peer());
+        let signed = response(ACCESS_REJECT, &request, SECRET);
+        assert_eq!(
+            radius.response_code(&signed, 7, &AUTHENTICATOR),
+            Some(ACCESS_REJECT)
+        );
+
+        // A Message-Authenticator signed with another secret, under a
+        // Response Authenticator signed with the right one.
+        let mut forged = response(ACCESS_ACCEPT, &request, b"other");
+        let digest = md5(&[&forged[..4], &AUTHENTICATOR, &forged[HEADER_LEN..], SECRET]);
+        forged[4..HEADER_LEN].copy_from_slice(&digest);
+        assert_eq!(radius.response_code(&forged, 7, &AUTHENTICATOR), None);
+
+        // Of the wrong length.
+        let mut short = vec![ACCESS_ACCEPT, 7, 0, 0];
+        short.extend_from_slice(&AUTHENTICATOR);
+        put_attribute(&mut short, ATTR_MESSAGE_AUTHENTICATOR, &[0; 15]);
+        let len = short.len() as u16;
+        short[2..4].copy_from_slice(&len.to_be_bytes());
+        let digest = md5(&[&short, SECRET]);
+        short[4..HEADER_LEN].copy_from_slice(&digest);
+        assert_eq!(radius.response_code(&short, 7, &AUTHENTICATOR), None);
+    }
+
+    fn peer() -> SocketAddr {
+        "192.0.2.1:4000".parse().unwrap()
+    }
+
+    #[test]
+    fn requests() {
+        let radius = radius("127.0.0.1:1812".parse().unwrap()).with_nas_identifier("proxy");
+        let request = radius.request(9, &AUTHENTICATOR, "nemo", b"arctangent", peer());
+        assert_eq!(request[..2], [ACCESS_REQUEST, 9]);
+        assert_eq!(
+            usize::from(u16::from_be_bytes([request[2], request[3]])),
+            request.len()
+        );
+        assert_eq!(request[4..HEADER_LEN], AUTHENTICATOR);
+
+        let mut attributes = Vec::new();
+        let mut at = HEADER_LEN;
+        while at < request.len() {
+            let len = usize::from(request[at + 1]);
+            attributes.push((request[at], &request[at + 2..at + len]));
+            at += len;
+        }
+        assert_eq!(at, request.len());
+        let hidden = unhex("0dbe708d93d413ce3196e43f782a0aee");
+        assert_eq!(
+            attributes[..4],
+            [
+                (ATTR_USER_NAME, &b"nemo"[..]),
+                (ATTR_USER_PASSWORD, &hidden[..]),
+                (ATTR_NAS_IDENTIFIER, b"proxy"),
+                (ATTR_CALLING_STATION_ID, b"192.0.2.1"),
+            ]
+        );
+        let (kind, signature) = attributes[4];
+        assert_eq!(kind, ATTR_MESSAGE_AUTHENTICATOR);
+        let mut unsigned = request.clone();
+        let at = request.len() - 16;
+        unsigned[at..].fill(0);
+        assert_eq!(signature, hmac_md5(SECRET, &unsigned));
+    }
+
+    /// The password in `request`, as the server finds it.
+    fn reveal(request: &[u8]) -> Vec<u8> {
+        let mut at = HEADER_LEN;
+        while request[at] != ATTR_USER_PASSWORD {
+            at += usize::from(request[at + 1]);
+        }
+        let hidden = &request[at + 2..at + usize::from(request[at + 1])];
+        let mut previous = &request[4..HEADER_LEN];
+        let mut password = Vec::new();
+        for chunk in hidden.chunks_exact(16) {
+            let pad = md5(&[SECRET, previous]);
+            password.extend(chunk.iter().zip(&pad).map(|(b, p)| b ^ p));
+            previous = chunk;
+        }
+        while password.last() == Some(&0) {
+            password.pop();
+        }
+        password
+    }
+
+    /// A server taking one request for each of `answers`: accepting the
+    /// password "arctangent" with the code and secret of the answer, and
+    /// rejecting others, or else dropping the request.
+    async fn server(answers: Vec<Option<(u8, &'static [u8])>>) -> SocketAddr {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0; MAX_PACKET];
+            for answer in answers {
+                let (len, client) = socket.recv_from(&mut buf).await.unwrap();
+                let request = &buf[..len];
+                if let Some((code, secret)) = answer {
+                    let code = match reveal(request) {
+                        password if password == b"arctangent" => code,
+                        _ => ACCESS_REJECT,
+                    };
+                    let response = response(code, request, secret);
+                    socket.send_to(&response, client).await.unwrap();
+                }
+            }
+        });
+        addr
+    }
+
+    async fn verify(answers: Vec<Option<(u8, &'static [u8])>>, password: &[u8]) -> Decision {
+        let server = server(answers).await;
+        radius(server)
+            .with_timeout(Duration::from_millis(50))
+            .with_retries(1)
+            .verify("nemo", password, peer())
+            .await
+    }
+
+    #[tokio::test]
+    async fn accepts() {
+        let accept = Some((ACCESS_ACCEPT, SECRET));
+        assert_eq!(verify(vec![accept], b"arctangent").await, Decision::Allow);
+        assert_eq!(verify(vec![accept], b"arctangenT").await, Decision::Deny);
+        // Retried when unanswered.
+        assert_eq!(
+            verify(vec![None, accept], b"arctangent").await,
+            Decision::Allow
+        );
+    }
+
+    #[tokio::test]
+    async fn denies() {
+        let challenge = Some((ACCESS_CHALLENGE, SECRET));
+        assert_eq!(verify(vec![challenge], b"arctangent").await, Decision::Deny);
+        // Forged answers are ignored, until the retries are used up.
+        let forged = Some((ACCESS_ACCEPT, &b"other"[..]));
+        assert_eq!(
+            verify(vec![forged, forged], b"arctangent").await,
+            Decision::Deny
+        );
+        assert_eq!(
+            verify(vec![None, None], b"arctangent").await,
+            Decision::Deny
+        );
+        // Not even asked.
+        let long = [b'a'; MAX_PASSWORD + 1];
+        assert_eq!(verify(vec![], &long).await, Decision::Deny);
+    }
+}
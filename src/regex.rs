@@ -0,0 +1,706 @@
+//! A small regular expression engine for domain names: compiled to an NFA
+//! and run in lockstep (Pike's VM), so matching takes time linear in the
+//! input, and can be bounded further by a step budget.
+//!
+//! Supported: literals, `.`, classes (`[a-z0-9-]`, `[^.]`), `\d`, `\w`,
+//! `\s` and escapes, `^` and `$`, groups (`(...)`, `(?:...)`), `|`, and the
+//! quantifiers `*`, `+`, `?`, `{m}`, `{m,}`, `{m,n}` (lazy forms match the
+//! same). Matching ignores ASCII case.
+
+use std::fmt;
+
+/// Instructions a program may have at most, bounding how large `{m,n}`
+/// repetitions can get.
+const MAX_PROGRAM_LEN: usize = 10_000;
+
+/// Failure to compile a regular expression.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct CompileError {
+    pub(crate) position: usize,
+    pub(crate) reason: &'static str,
+}
+
+/// A compiled regular expression.
+#[derive(Debug)]
+pub(crate) struct Program {
+    insts: Vec<Inst>,
+}
+
+#[derive(Debug)]
+enum Inst {
+    Byte(ByteSet),
+    Split(usize, usize),
+    Jump(usize),
+    Start,
+    End,
+    Match,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+struct ByteSet([u64; 4]);
+
+impl ByteSet {
+    const EMPTY: ByteSet = ByteSet([0; 4]);
+
+    fn insert(&mut self, b: u8) {
+        self.0[usize::from(b >> 6)] |= 1 << (b & 63);
+    }
+
+    fn insert_range(&mut self, from: u8, to: u8) {
+        for b in from..=to {
+            self.insert(b);
+        }
+    }
+
+    fn contains(&self, b: u8) -> bool {
+        self.0[usize::from(b >> 6)] & (1 << (b & 63)) != 0
+    }
+
+    fn negate(&mut self) {
+        for word in &mut self.0 {
+            *word = !*word;
+        }
+    }
+
+    /// Add the other case of every ASCII letter in the set.
+    fn fold_case(&mut self) {
+        for b in b'a'..=b'z' {
+            let upper = b.to_ascii_uppercase();
+            if self.contains(b) || self.contains(upper) {
+                self.insert(b);
+                self.insert(upper);
+            }
+        }
+    }
+}
+
+impl fmt::Debug for ByteSet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let bytes = (0..=255u8).filter(|&b| self.contains(b));
+        f.debug_set().entries(bytes.map(char::from)).finish()
+    }
+}
+
+enum Node {
+    Set(ByteSet),
+    Start,
+    End,
+    Concat(Vec<Node>),
+    Alternate(Vec<Node>),
+    Repeat {
+        node: Box<Node>,
+        min: u32,
+        max: Option<u32>,
+    },
+}
+
+impl Program {
+    pub(crate) fn compile(pattern: &str) -> Result<Self, CompileError> {
+        let mut parser = Parser {
+            pattern: pattern.as_bytes(),
+            pos: 0,
+        };
+        let node = parser.alternation()?;
+        if parser.pos < parser.pattern.len() {
+            return Err(parser.error("unmatched ')'"));
+        }
+        let mut program = Program { insts: Vec::new() };
+        program.emit(&node)?;
+        program.push(Inst::Match)?;
+        Ok(program)
+    }
+
+    /// Whether `text` contains a match, or `None` if telling took more
+    /// than `step_limit` steps.
+    pub(crate) fn is_match(&self, text: &[u8], step_limit: usize) -> Option<bool> {
+        let mut steps = 0;
+        let mut current = Threads::new(self.insts.len());
+        let mut next = Threads::new(self.insts.len());
+        for pos in 0..=text.len() {
+            // Unanchored: a match may start anywhere.
+            self.add(&mut current, 0, pos, text.len(), &mut steps);
+            for i in 0..current.len() {
+                let pc = current.dense[i];
+                match &self.insts[pc] {
+                    Inst::Match => return Some(true),
+                    Inst::Byte(set) if pos < text.len() && set.contains(text[pos]) => {
+                        self.add(&mut next, pc + 1, pos + 1, text.len(), &mut steps);
+                    }
+                    _ => {}
+                }
+            }
+            if steps > step_limit {
+                return None;
+            }
+            std::mem::swap(&mut current, &mut next);
+            next.clear();
+        }
+        Some(false)
+    }
+
+    /// Add the thread at `pc`, following jumps, splits and assertions.
+    fn add(&self, threads: &mut Threads, pc: usize, pos: usize, len: usize, steps: &mut usize) {
+        let mut stack = vec![pc];
+        while let Some(pc) = stack.pop() {
+            if !threads.insert(pc) {
+                continue;
+            }
+            *steps += 1;
+            match self.insts[pc] {
+                Inst::Jump(to) => stack.push(to),
+                Inst::Split(first, second) => {
+                    stack.push(second);
+                    stack.push(first);
+                }
+                Inst::Start if pos == 0 => stack.push(pc + 1),
+                Inst::End if pos == len => stack.push(pc + 1),
+                _ => {}
+            }
+        }
+    }
+
+    fn push(&mut self, inst: Inst) -> Result<usize, CompileError> {
+        if self.insts.len() >= MAX_PROGRAM_LEN {
+            return Err(CompileError {
+                position: 0,
+                reason: "too large once compiled",
+            });
+        }
+        self.insts.push(inst);
+        Ok(self.insts.len() - 1)
+    }
+
+    fn emit(&mut self, node: &Node) -> Result<(), CompileError> {
+        match node {
+            Node::Set(set) => {
+                self.push(Inst::Byte(*set))?;
+            }
+            Node::Start => {
+                self.push(Inst::Start)?;
+            }
+            Node::End => {
+                self.push(Inst::End)?;
+            }
+            Node::Concat(nodes) => {
+                for node in nodes {
+                    self.emit(node)?;
+                }
+            }
+            Node::Alternate(nodes) => {
+                let mut jumps = Vec::new();
+                for (i, node) in nodes.iter().enumerate() {
+                    if i + 1 == nodes.len() {
+                        self.emit(node)?;
+                        break;
+                    }
+                    let split = self.push(Inst::Split(0, 0))?;
+                    self.emit(node)?;
+                    jumps.push(self.push(Inst::Jump(0))?);
+                    let next = self.insts.len();
+                    self.insts[split] = Inst::Split(split + 1, next);
+                }
+                let end = self.insts.len();
+                for jump in jumps {
+                    self.insts[jump] = Inst::Jump(end);
+                }
+            }
+            Node::Repeat { node, min, max } => {
+                for _ in 0..*min {
+                    self.emit(node)?;
+                }
+                match max {
+                    None => {
+                        let split = self.push(Inst::Split(0, 0))?;
+                        self.emit(node)?;
+                        self.push(Inst::Jump(split))?;
+                        let end = self.insts.len();
+                        self.insts[split] = Inst::Split(split + 1, end);
+                    }
+                    Some(max) => {
+                        let mut splits = Vec::new();
+                        for _ in *min..*max {
+                            splits.push(self.push(Inst::Split(0, 0))?);
+                            self.emit(node)?;
+                        }
+                        let end = self.insts.len();
+                        for split in splits {
+                            self.insts[split] = Inst::Split(split + 1, end);
+                        }
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A set of program counters, in the order added.
+struct Threads {
+    dense: Vec<usize>,
+    sparse: Vec<usize>,
+}
+
+impl Threads {
+    fn new(len: usize) -> Self {
+        Threads {
+            dense: Vec::with_capacity(len),
+            sparse: vec![0; len],
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.dense.len()
+    }
+
+    /// Add `pc`, returning whether it is new.
+    fn insert(&mut self, pc: usize) -> bool {
+        let i = self.sparse[pc];
+        if i < self.dense.len() && self.dense[i] == pc {
+            return false;
+        }
+        self.sparse[pc] = self.dense.len();
+        self.dense.push(pc);
+        true
+    }
+
+    fn clear(&mut self) {
+        self.dense.clear();
+    }
+}
+
+struct Parser<'a> {
+    pattern: &'a [u8],
+    pos: usize,
+}
+
+impl Parser<'_> {
+    fn error(&self, reason: &'static str) -> CompileError {
+        CompileError {
+            position: self.pos,
+            reason,
+        }
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.pattern.get(self.pos).copied()
+    }
+
+    fn eat(&mut self, b: u8) -> bool {
+        if self.peek() == Some(b) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn alternation(&mut self) -> Result<Node, CompileError> {
+        let mut alternatives = vec![self.concatenation()?];
+        while self.eat(b'|') {
+            alternatives.push(self.concatenation()?);
+        }
+        Ok(if alternatives.len() == 1 {
+            alternatives.pop().unwrap()
+        } else {
+            Node::Alternate(alternatives)
+        })
+    }
+
+    fn concatenation(&mut self) -> Result<Node, CompileError> {
+        let mut nodes = Vec::new();
+        while !matches!(self.peek(), None | Some(b'|') | Some(b')')) {
+            nodes.push(self.repetition()?);
+        }
+        Ok(Node::Concat(nodes))
+    }
+
+    fn repetition(&mut self) -> Result<Node, CompileError> {
+        let mut node = self.atom()?;
+        loop {
+            let (min, max) = if self.eat(b'*') {
+                (0, None)
+            } else if self.eat(b'+') {
+                (1, None)
+            } else if self.eat(b'?') {
+                (0, Some(1))
+            } else if self.eat(b'{') {
+                self.bounds()?
+            } else {
+                return Ok(node);
+            };
+            // Lazy quantifiers match the same, as only whether there is a
+            // match is asked.
+            self.eat(b'?');
+            node = Node::Repeat {
+                node: Box::new(node),
+                min,
+                max,
+            };
+        }
+    }
+
+    /// The rest of `{m}`, `{m,}` or `{m,n}`.
+    fn bounds(&mut self) -> Result<(u32, Option<u32>), CompileError> {
+        let min = self.number()?;
+        let max = if self.eat(b',') {
+            if self.peek() == Some(b'}') {
+                None
+            } else {
+                Some(self.number()?)
+            }
+        } else {
+            Some(min)
+        };
+        if !self.eat(b'}') {
+            return Err(self.error("expected '}'"));
+        }
+        if max.is_some_and(|max| max < min) {
+            return Err(self.error("repetition bounds out of order"));
+        }
+        Ok((min, max))
+    }
+
+    fn number(&mut self) -> Result<u32, CompileError> {
+        let start = self.pos;
+        while self.peek().is_some_and(|b| b.is_ascii_digit()) {
+            self.pos += 1;
+        }
+        std::str::from_utf8(&self.pattern[start..self.pos])
+            .ok()
+            .and_then(|digits| digits.parse().ok())
+            .filter(|&n: &u32| n <= 1000)
+            .ok_or_else(|| self.error("expected a repetition count up to 1000"))
+    }
+
+    fn atom(&mut self) -> Result<Node, CompileError> {
+        let b = match self.peek() {
+            Some(b) => b,
+            None => return Err(self.error("unexpected end")),
+        };
+        self.pos += 1;
+        let mut set = ByteSet::EMPTY;
+        match b {
+            b'(' => {
+                if self.eat(b'?') && !self.eat(b':') {
+                    return Err(self.error("unsupported group flag"));
+                }
+                let node = self.alternation()?;
+                if !self.eat(b')') {
+                    return Err(self.error("unclosed group"));
+                }
+                return Ok(node);
+            }
+            b'[' => set = self.class()?,
+            b'.' => set.negate(),
+            b'^' => return Ok(Node::Start),
+            b'$' => return Ok(Node::End),
+            b'\\' => set = self.escape()?,
+            b'*' | b'+' | b'?' | b'{' => {
+                self.pos -= 1;
+                return Err(self.error("nothing to repeat"));
+            }
+            b => set.insert(b),
+        }
+        set.fold_case();
+        Ok(Node::Set(set))
+    }
+
+    /// The rest of a `[...]` class.
+    fn class(&mut self) -> Result<ByteSet, CompileError> {
+        let negated = self.eat(b'^');
+        let mut set = ByteSet::EMPTY;
+        let mut first = true;
+        loop {
+            let b = match self.peek() {
+                Some(b']') if !first => {
+                    self.pos += 1;
+                    break;
+                }
+                Some(b) => b,
+                None => return Err(self.error("unclosed class")),
+            };
+            self.pos += 1;
+            first = false;
+            let from = match b {
+                b'\\' => {
+                    let escaped = self.escape()?;
+                    match single(&escaped) {
+                        Some(b) => b,
+                        None => {
+                            for (i, word) in escaped.0.iter().enumerate() {
+                                set.0[i] |= word;
+                            }
+                            continue;
+                        }
+                    }
+                }
+                b => b,
+            };
+            if self.peek() == Some(b'-')
+                && !matches!(self.pattern.get(self.pos + 1), Some(b']') | None)
+            {
+                self.pos += 1;
+                let to = match self.peek() {
+                    Some(b'\\') => {
+                        self.pos += 1;
+                        single(&self.escape()?).ok_or_else(|| self.error("invalid range"))?
+                    }
+                    Some(b) => {
+                        self.pos += 1;
+                        b
+                    }
+                    None => return Err(self.error("unclosed class")),
+                };
+                if to < from {
+                    return Err(self.error("range out of order"));
+                }
+                set.insert_range(from, to);
+            } else {
+                set.insert(from);
+            }
+        }
+        set.fold_case();
+        if negated {
+            set.negate();
+        }
+        Ok(set)
+    }
+
+    /// The rest of an escape, after the `\`.
+    fn escape(&mut self) -> Result<ByteSet, CompileError> {
+        let b = match self.peek() {
+            Some(b) => b,
+            None => return Err(self.error("trailing '\\'")),
+        };
+        self.pos += 1;
+        if let Some(set) = perl_class(b) {
+            return Ok(set);
+        }
+        if let Some(mut set) = perl_class(b.to_ascii_lowercase()).filter(|_| b.is_ascii_uppercase())
+        {
+            set.negate();
+            return Ok(set);
+        }
+        if b.is_ascii_alphanumeric() {
+            return Err(self.error("unsupported escape"));
+        }
+        let mut set = ByteSet::EMPTY;
+        set.insert(b);
+        Ok(set)
+    }
+}
+
+/// The class of `\d`, `\w` or `\s`.
+fn perl_class(b: u8) -> Option<ByteSet> {
+    let mut set = ByteSet::EMPTY;
+    match b {
+        b'd' => set.insert_range(b'0', b'9'),
+        b'w' => {
+            set.insert_range(b'a', b'z');
+            set.insert_range(b'A', b'Z');
+            set.insert_range(b'0', b'9');
+            set.insert(b'_');
+        }
+        b's' => {
+            for b in [b' ', b'\t', b'\n', b'\r', 0x0b, 0x0c] {
+                set.insert(b);
+            }
+        }
+        _ => return None,
+    }
+    Some(set)
+}
+
+/// The only byte in `set`, if it has just one.
+fn single(set: &ByteSet) -> Option<u8> {
+    let mut bytes = (0..=255u8).filter(|&b| set.contains(b));
+    match (bytes.next(), bytes.next()) {
+        (Some(b), None) => Some(b),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, Instant};
+
+    const STEPS: usize = 1_000_000;
+
+    fn matches(pattern: &str, text: &str) -> bool {
+        Program::compile(pattern)
+            .unwrap()
+            .is_match(text.as_bytes(), STEPS)
+            .unwrap()
+    }
+
+    fn check(pattern: &str, matching: &[&str], not_matching: &[&str]) {
+        for text in matching {
+            assert!(
+                matches(pattern, text),
+                "/{}/ should match {:?}",
+                pattern,
+                text
+            );
+        }
+        for text in not_matching {
+            assert!(
+                !matches(pattern, text),
+                "/{}/ shouldn't match {:?}",
+                pattern,
+                text
+            );
+        }
+    }
+
+    fn error(pattern: &str) -> CompileError {
+        Program::compile(pattern).unwrap_err()
+    }
+
+    #[test]
+    fn literals() {
+        check(
+            "ads",
+            &["ads", "ads.example.com", "myads"],
+            &["ad", "a.ds", ""],
+        );
+        check("a.c", &["abc", "a.c", "a-c"], &["ac"]);
+        check(r"a\.c", &["a.c"], &["abc"]);
+        check("", &["", "anything"], &[]);
+    }
+
+    #[test]
+    fn case_folding() {
+        check(
+            "Example",
+            &["example.com", "EXAMPLE.COM", "eXaMpLe"],
+            &["exampl"],
+        );
+        check("[A-C]x", &["ax", "Bx", "cX"], &["dx"]);
+        check("[^a]", &["b", "xa"], &["a", "A", "aA", ""]);
+        check(r"\W", &["-", "."], &["aZ09_"]);
+    }
+
+    #[test]
+    fn classes() {
+        check("^[a-c0-2]+$", &["abc", "a0c2", "CAB"], &["abd", "3", ""]);
+        check("^[^.]+$", &["example", "com"], &["example.com", ""]);
+        check("^[]a]+$", &["]a]", "a"], &["b"]);
+        check("^[a-]+$", &["a-a", "-"], &["b"]);
+        check(r"^[\d.]+$", &["1.2.3.4"], &["1.2.3.x"]);
+        check(r"^[\w-]+$", &["my_host-1"], &["my.host"]);
+        check(r"^\d{3}$", &["123"], &["12a", "1234"]);
+        check(r"^\D+$", &["abc"], &["a1c"]);
+        check(r"\s", &["a b", "\t"], &["ab"]);
+        check(r"^\S+$", &["ab"], &["a b"]);
+        check(r"^[\]\\]+$", &["]\\"], &["a"]);
+    }
+
+    #[test]
+    fn alternation_and_groups() {
+        check(
+            r"^(ads?|track(er|ing)?)\d*\.",
+            &["ad.x", "ads2.x", "track.x", "tracker10.x", "tracking.x"],
+            &["adds.x", "tracke.x", "news.x", "x.ads.x"],
+        );
+        check("^(?:ab)+$", &["ab", "abab"], &["aba", "", "ba"]);
+        check("^(a|b|)$", &["a", "b", ""], &["ab"]);
+        check("cat|dog", &["hotdog", "cats"], &["cow"]);
+    }
+
+    #[test]
+    fn quantifiers() {
+        check("^ab?c$", &["ac", "abc"], &["abbc"]);
+        check("^ab*c$", &["ac", "abc", "abbbc"], &["adc"]);
+        check("^ab+c$", &["abc", "abbbc"], &["ac"]);
+        check("^ab+?c$", &["abc", "abbbc"], &["ac"]);
+        check("^a{2}$", &["aa"], &["a", "aaa"]);
+        check("^a{2,}$", &["aa", "aaaaaa"], &["a"]);
+        check("^a{2,3}$", &["aa", "aaa"], &["a", "aaaa"]);
+        check("^a{0}b$", &["b"], &["ab"]);
+        check("^(ab){1,2}?$", &["ab", "abab"], &["ababab"]);
+        check("^a**$", &["", "aaa"], &["b"]);
+    }
+
+    #[test]
+    fn anchors() {
+        check("^example", &["example.com"], &["www.example.com"]);
+        check(r"\.com$", &["example.com"], &["example.com.au"]);
+        check("^$", &[""], &["a"]);
+        check("a^b", &[], &["ab", "a^b"]);
+        check("a$|^b", &["xa", "bx"], &["ax", "xb"]);
+    }
+
+    #[test]
+    fn compile_errors() {
+        for (pattern, position, reason) in [
+            ("(ab", 3, "unclosed group"),
+            ("ab)", 2, "unmatched ')'"),
+            ("[ab", 3, "unclosed class"),
+            ("[z-a]", 4, "range out of order"),
+            ("*a", 0, "nothing to repeat"),
+            ("a|+", 2, "nothing to repeat"),
+            ("a{3,2}", 6, "repetition bounds out of order"),
+            ("a{2", 3, "expected '}'"),
+            ("a{}", 2, "expected a repetition count up to 1000"),
+            ("a{1001}", 6, "expected a repetition count up to 1000"),
+            ("ab\\", 3, "trailing '\\'"),
+            (r"\q", 2, "unsupported escape"),
+            ("(?i)a", 2, "unsupported group flag"),
+        ] {
+            assert_eq!(
+                error(pattern),
+                CompileError { position, reason },
+                "{:?}",
+                pattern
+            );
+        }
+    }
+
+    #[test]
+    fn program_size() {
+        // A thousand a's, nine times, and the match.
+        assert!(Program::compile("(a{1000}){9}").is_ok());
+        for pattern in [
+            "(a{1000}){10}",
+            "((a{1000}){1000}){1000}",
+            "[a-z]{1000}{1000}",
+        ] {
+            assert_eq!(
+                error(pattern).reason,
+                "too large once compiled",
+                "{}",
+                pattern
+            );
+        }
+        let program = Program::compile("x{1000}|y{1000}|z{1000}").unwrap();
+        assert!(program.insts.len() <= MAX_PROGRAM_LEN);
+    }
+
+    #[test]
+    fn step_limit() {
+        let program = Program::compile("(a|a?)*(a*)*b").unwrap();
+        let text = "a".repeat(5000);
+        let started = Instant::now();
+        assert_eq!(program.is_match(text.as_bytes(), 10_000), None);
+        assert!(started.elapsed() < Duration::from_secs(1));
+
+        // Given the steps, the answer.
+        let program = Program::compile("a{100}b").unwrap();
+        let text = "a".repeat(200);
+        assert_eq!(program.is_match(text.as_bytes(), 10), None);
+        assert_eq!(program.is_match(text.as_bytes(), STEPS), Some(false));
+        let text = format!("{}b", text);
+        assert_eq!(program.is_match(text.as_bytes(), STEPS), Some(true));
+    }
+
+    #[test]
+    fn linear_time() {
+        // Backtracking engines take exponential time on this.
+        let program = Program::compile("^(a+)+$").unwrap();
+        let text = format!("{}!", "a".repeat(10_000));
+        let started = Instant::now();
+        assert_eq!(program.is_match(text.as_bytes(), usize::MAX), Some(false));
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+}
@@ -0,0 +1,122 @@
+//! Resolving the domain names of targets, by the system's resolver or by
+//! one the embedder supplies, e.g. for split-horizon DNS, service discovery
+//! or tests.
+
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::time::Duration;
+
+/// The future returned by [`Resolver::resolve`].
+pub type ResolveFuture<'a> = Pin<Box<dyn Future<Output = io::Result<Vec<SocketAddr>>> + Send + 'a>>;
+
+/// The future returned by [`Resolver::resolve_with_ttl`].
+pub type ResolveWithTtlFuture<'a> =
+    Pin<Box<dyn Future<Output = io::Result<(Vec<SocketAddr>, Option<Duration>)>> + Send + 'a>>;
+
+/// Resolves the domain names of targets to the addresses to connect or
+/// relay datagrams to, see [`Server::with_resolver`].
+///
+/// Addresses are tried in the order returned. The server's resolve timeout
+/// applies on top of any the resolver has.
+///
+/// ```
+/// use std::collections::HashMap;
+/// use std::io;
+/// use std::net::SocketAddr;
+/// use socks5_rs::{ResolveFuture, Resolver, SystemResolver};
+///
+/// /// Resolves services from a table, and other names through the system.
+/// struct Services(HashMap<String, SocketAddr>);
+///
+/// impl Resolver for Services {
+///     fn resolve<'a>(&'a self, domain: &'a str, port: u16) -> ResolveFuture<'a> {
+///         match self.0.get(domain) {
+///             Some(&addr) => Box::pin(async move { Ok(vec![addr]) }),
+///             None => SystemResolver.resolve(domain, port),
+///         }
+///     }
+/// }
+/// ```
+///
+/// [`Server::with_resolver`]: crate::Server::with_resolver
+pub trait Resolver: Send + Sync {
+    /// The addresses of `domain`, with `port`.
+    fn resolve<'a>(&'a self, domain: &'a str, port: u16) -> ResolveFuture<'a>;
+
+    /// The addresses of `domain`, with `port`, and how long they may be
+    /// cached for, if the resolver knows. A [`DnsCache`] asks this.
+    ///
+    /// [`DnsCache`]: crate::DnsCache
+    fn resolve_with_ttl<'a>(&'a self, domain: &'a str, port: u16) -> ResolveWithTtlFuture<'a> {
+        Box::pin(async move { Ok((self.resolve(domain, port).await?, None)) })
+    }
+}
+
+/// The system's resolver, `getaddrinfo`, run on Tokio's blocking pool. The
+/// default.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemResolver;
+
+impl Resolver for SystemResolver {
+    fn resolve<'a>(&'a self, domain: &'a str, port: u16) -> ResolveFuture<'a> {
+        Box::pin(async move { Ok(tokio::net::lookup_host((domain, port)).await?.collect()) })
+    }
+}
+
+/// Which of the addresses a target name resolves to are tried, and in which
+/// order, when it has both IPv4 and IPv6 ones, see
+/// [`Server::with_address_family_policy`].
+///
+/// Targets given as addresses are dialed as given.
+///
+/// [`Server::with_address_family_policy`]: crate::Server::with_address_family_policy
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum AddressFamilyPolicy {
+    /// In the order the resolver returned them.
+    #[default]
+    AsResolved,
+    /// IPv6 addresses first, then IPv4 ones.
+    PreferIpv6,
+    /// IPv4 addresses first, then IPv6 ones.
+    PreferIpv4,
+    /// Only IPv4 addresses. Names without any fail to resolve.
+    Ipv4Only,
+    /// Only IPv6 addresses. Names without any fail to resolve.
+    Ipv6Only,
+    /// IPv6 and IPv4 addresses in turn, starting with IPv6, so that a
+    /// broken family costs a single attempt at a time.
+    Interleave,
+}
+
+impl AddressFamilyPolicy {
+    /// `addrs`, filtered and ordered by the policy.
+    pub(crate) fn apply(self, mut addrs: Vec<SocketAddr>) -> io::Result<Vec<SocketAddr>> {
+        match self {
+            AddressFamilyPolicy::AsResolved => return Ok(addrs),
+            AddressFamilyPolicy::PreferIpv6 => addrs.sort_by_key(SocketAddr::is_ipv4),
+            AddressFamilyPolicy::PreferIpv4 => addrs.sort_by_key(SocketAddr::is_ipv6),
+            AddressFamilyPolicy::Ipv4Only => addrs.retain(SocketAddr::is_ipv4),
+            AddressFamilyPolicy::Ipv6Only => addrs.retain(SocketAddr::is_ipv6),
+            AddressFamilyPolicy::Interleave => {
+                let (v6, v4): (Vec<_>, Vec<_>) = addrs.into_iter().partition(SocketAddr::is_ipv6);
+                let (mut v6, mut v4) = (v6.into_iter(), v4.into_iter());
+                addrs = Vec::with_capacity(v6.len() + v4.len());
+                loop {
+                    match (v6.next(), v4.next()) {
+                        (None, None) => break,
+                        (a, b) => addrs.extend(a.into_iter().chain(b)),
+                    }
+                }
+            }
+        }
+        if addrs.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                "no addresses of the allowed family",
+            ));
+        }
+        Ok(addrs)
+    }
+}
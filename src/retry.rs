@@ -0,0 +1,74 @@
+//! Retrying connects to targets that failed in ways that may pass, backing
+//! off between attempts.
+
+use std::collections::hash_map::RandomState;
+use std::convert::TryFrom;
+use std::hash::{BuildHasher, Hasher};
+use std::io;
+use std::time::{Duration, SystemTime};
+
+/// How to retry connecting to a target that refused or didn't answer, see
+/// [`Server::with_connect_retry`].
+///
+/// The wait before the `n`th retry is `backoff` doubled `n - 1` times, at
+/// most `max_backoff`, plus up to `jitter` at random so that clients that
+/// failed together don't retry together.
+///
+/// [`Server::with_connect_retry`]: crate::Server::with_connect_retry
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// Attempts at connecting, the first included.
+    pub attempts: u32,
+    /// Wait before the first retry.
+    pub backoff: Duration,
+    /// Longest wait between attempts, jitter aside.
+    pub max_backoff: Duration,
+    /// Most time added to each wait at random.
+    pub jitter: Duration,
+}
+
+impl Default for RetryPolicy {
+    /// 3 attempts, 200 milliseconds apart and then 400, with up to 100
+    /// milliseconds of jitter.
+    fn default() -> Self {
+        RetryPolicy {
+            attempts: 3,
+            backoff: Duration::from_millis(200),
+            max_backoff: Duration::from_secs(2),
+            jitter: Duration::from_millis(100),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Whether connecting again may succeed where it failed with `error`.
+    pub(crate) fn is_transient(error: &io::Error) -> bool {
+        matches!(
+            error.kind(),
+            io::ErrorKind::ConnectionRefused | io::ErrorKind::TimedOut
+        )
+    }
+
+    /// The wait before retry number `retry`, counted from one.
+    pub(crate) fn delay(&self, retry: u32) -> Duration {
+        let factor = 1u32
+            .checked_shl(retry.saturating_sub(1))
+            .unwrap_or(u32::MAX);
+        let backoff = self.backoff.saturating_mul(factor).min(self.max_backoff);
+        backoff + random_below(self.jitter)
+    }
+}
+
+/// A duration up to `max`, drawn from the standard library's hasher keys,
+/// which come from the OS's random source.
+fn random_below(max: Duration) -> Duration {
+    if max.is_zero() {
+        return Duration::ZERO;
+    }
+    let mut hasher = RandomState::new().build_hasher();
+    if let Ok(now) = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH) {
+        hasher.write_u128(now.as_nanos());
+    }
+    let max_nanos = u64::try_from(max.as_nanos()).unwrap_or(u64::MAX);
+    Duration::from_nanos(hasher.finish() % max_nanos.saturating_add(1))
+}
@@ -0,0 +1,290 @@
+//! An ordered list of rules, each matching some requests and deciding what
+//! becomes of them.
+
+use std::future::Future;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::ops::RangeInclusive;
+
+use crate::protocol::{Address, Reply, TargetAddr};
+use crate::{DomainPattern, IpNet, RetryPolicy, Schedule};
+
+/// What becomes of a request matching a [`Rule`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RuleAction {
+    /// Let the request through to the checks configured besides the rules,
+    /// such as [`DestinationAcl`]s.
+    ///
+    /// [`DestinationAcl`]: crate::DestinationAcl
+    Allow,
+    /// Refuse the request with the reply.
+    Block(Reply),
+    /// Connect to the target through the SOCKS5 proxy at the address, which
+    /// must not require authentication. The proxy resolves domain names,
+    /// so only IP targets are checked against the networks of ACLs, though
+    /// rules before it matching networks resolve names here first, unless
+    /// [`Server::with_remote_dns`] is set. UDP datagrams can't be routed,
+    /// and are dropped.
+    ///
+    /// [`Server::with_remote_dns`]: crate::Server::with_remote_dns
+    Route(SocketAddr),
+    /// Reach the target instead, which is then checked like any other,
+    /// against the user's [`UserPolicy`] too.
+    ///
+    /// [`UserPolicy`]: crate::UserPolicy
+    Rewrite(TargetAddr),
+}
+
+/// Matches requests by their target and user, and decides what becomes of
+/// them, see [`Server::with_rule`].
+///
+/// A rule matches a request when it matches each of the domains, networks,
+/// ports, users and schedule given, by any one of them. A rule given none
+/// matches every request.
+///
+/// Networks match IP targets, and domain names by the addresses they
+/// resolve to. Users match clients authenticated with username/password,
+/// by whichever authenticator checks it, and clients identified by a TLS
+/// client certificate. Clients authenticated otherwise, e.g. by GSSAPI or
+/// Unix socket credentials, match no user.
+///
+/// ```
+/// use socks5_rs::{Rule, RuleAction, Schedule, Weekday};
+/// use socks5_rs::protocol::Reply;
+///
+/// let rules = [
+///     Rule::new(RuleAction::Route("10.0.0.1:1080".parse().unwrap()))
+///         .with_domain("*.corp.example".parse().unwrap()),
+///     Rule::new(RuleAction::Block(Reply::NotAllowed))
+///         .with_network("192.168.0.0/16".parse().unwrap())
+///         .with_ports(22..=22),
+///     Rule::new(RuleAction::Allow).with_user("admin"),
+///     Rule::new(RuleAction::Block(Reply::NotAllowed))
+///         .with_domain("*.video.example".parse().unwrap())
+///         .with_schedule(Schedule::new().with_window(Weekday::WORKDAYS, (9, 0), (17, 0))),
+/// ];
+/// ```
+///
+/// [`Server::with_rule`]: crate::Server::with_rule
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Rule {
+    domains: Vec<DomainPattern>,
+    networks: Vec<IpNet>,
+    ports: Vec<RangeInclusive<u16>>,
+    users: Vec<String>,
+    schedule: Option<Schedule>,
+    action: RuleAction,
+    egress_v4: Option<Ipv4Addr>,
+    egress_v6: Option<Ipv6Addr>,
+    interface: Option<String>,
+    mark: Option<u32>,
+    dscp: Option<u8>,
+    retry: Option<RetryPolicy>,
+}
+
+impl Rule {
+    /// A rule taking `action` on every request.
+    pub fn new(action: RuleAction) -> Self {
+        Rule {
+            domains: Vec::new(),
+            networks: Vec::new(),
+            ports: Vec::new(),
+            users: Vec::new(),
+            schedule: None,
+            action,
+            egress_v4: None,
+            egress_v6: None,
+            interface: None,
+            mark: None,
+            dscp: None,
+            retry: None,
+        }
+    }
+
+    /// Match targets with a domain name matching `pattern`.
+    pub fn with_domain(mut self, pattern: DomainPattern) -> Self {
+        self.domains.push(pattern);
+        self
+    }
+
+    /// Match targets in `net`.
+    pub fn with_network(mut self, net: IpNet) -> Self {
+        self.networks.push(net);
+        self
+    }
+
+    /// Match targets with a port in `ports`.
+    pub fn with_ports(mut self, ports: RangeInclusive<u16>) -> Self {
+        self.ports.push(ports);
+        self
+    }
+
+    /// Match requests of the user `username`.
+    pub fn with_user(mut self, username: &str) -> Self {
+        self.users.push(username.to_owned());
+        self
+    }
+
+    /// Match requests while `schedule` is active.
+    pub fn with_schedule(mut self, schedule: Schedule) -> Self {
+        self.schedule = Some(schedule);
+        self
+    }
+
+    /// Connect to the targets of the requests the rule lets through in the
+    /// address family of `addr` from `addr`, e.g. so that traffic to some
+    /// domains leaves from another IP than the rest. Call once with an IPv4
+    /// address and once with an IPv6 one to choose both. Takes precedence
+    /// over [`Server::with_egress_addr`], but not over stream isolation or
+    /// the egress address of a user's [`UserPolicy`].
+    ///
+    /// [`Server::with_egress_addr`]: crate::Server::with_egress_addr
+    /// [`UserPolicy`]: crate::UserPolicy
+    pub fn with_egress_addr(mut self, addr: IpAddr) -> Self {
+        match addr {
+            IpAddr::V4(addr) => self.egress_v4 = Some(addr),
+            IpAddr::V6(addr) => self.egress_v6 = Some(addr),
+        }
+        self
+    }
+
+    /// Connect to the targets of the requests the rule lets through out of
+    /// the network interface named `name` (`SO_BINDTODEVICE`), e.g. a
+    /// WireGuard interface, whatever the routing table says. Takes
+    /// precedence over [`Server::with_interface`].
+    ///
+    /// [`Server::with_interface`]: crate::Server::with_interface
+    #[cfg(target_os = "linux")]
+    pub fn with_interface(mut self, name: &str) -> Self {
+        self.interface = Some(name.to_owned());
+        self
+    }
+
+    /// Tag the connections to the targets of the requests the rule lets
+    /// through with the firewall mark `mark` (`SO_MARK`), for policy
+    /// routing and nftables. Takes precedence over [`Server::with_mark`].
+    ///
+    /// [`Server::with_mark`]: crate::Server::with_mark
+    #[cfg(target_os = "linux")]
+    pub fn with_mark(mut self, mark: u32) -> Self {
+        self.mark = Some(mark);
+        self
+    }
+
+    /// Mark the packets of the requests the rule lets through with the
+    /// DSCP `dscp`, both to the target and back to the client, e.g. to
+    /// give a class of traffic its own priority. Takes precedence over
+    /// [`UserPolicy::with_dscp`] and [`Server::with_dscp`].
+    ///
+    /// [`UserPolicy::with_dscp`]: crate::UserPolicy::with_dscp
+    /// [`Server::with_dscp`]: crate::Server::with_dscp
+    #[cfg(unix)]
+    pub fn with_dscp(mut self, dscp: u8) -> Self {
+        self.dscp = Some(dscp);
+        self
+    }
+
+    /// Retry connecting to the targets of the requests the rule lets
+    /// through as `retry` says. Takes precedence over
+    /// [`Server::with_connect_retry`].
+    ///
+    /// [`Server::with_connect_retry`]: crate::Server::with_connect_retry
+    pub fn with_connect_retry(mut self, retry: RetryPolicy) -> Self {
+        self.retry = Some(retry);
+        self
+    }
+
+    /// What becomes of the requests the rule matches.
+    pub fn action(&self) -> &RuleAction {
+        &self.action
+    }
+
+    /// The network interface to connect out of, if set.
+    pub(crate) fn interface(&self) -> Option<&str> {
+        self.interface.as_deref()
+    }
+
+    /// The firewall mark to tag connections with, if set.
+    pub(crate) fn mark(&self) -> Option<u32> {
+        self.mark
+    }
+
+    /// The IPv4 address to connect from, if set.
+    pub(crate) fn egress_v4(&self) -> Option<Ipv4Addr> {
+        self.egress_v4
+    }
+
+    /// The IPv6 address to connect from, if set.
+    pub(crate) fn egress_v6(&self) -> Option<Ipv6Addr> {
+        self.egress_v6
+    }
+
+    /// The DSCP to mark packets with, if set.
+    pub(crate) fn dscp(&self) -> Option<u8> {
+        self.dscp
+    }
+
+    /// How to retry connecting, if set.
+    pub(crate) fn retry(&self) -> Option<RetryPolicy> {
+        self.retry
+    }
+
+    /// Whether the rule matches a request of `user` for `target`, leaving
+    /// out the networks.
+    fn matches_request(&self, target: &TargetAddr, user: Option<&str>) -> bool {
+        let domain = match &target.address {
+            Address::Domain(domain) => Some(domain.as_str()),
+            _ => None,
+        };
+        if !self.domains.is_empty()
+            && !domain.is_some_and(|domain| self.domains.iter().any(|p| p.matches(domain)))
+        {
+            return false;
+        }
+        if !self.ports.is_empty() && !self.ports.iter().any(|ports| ports.contains(&target.port)) {
+            return false;
+        }
+        if !self.users.is_empty() && !user.is_some_and(|user| self.users.iter().any(|u| u == user))
+        {
+            return false;
+        }
+        self.schedule.as_ref().is_none_or(Schedule::is_active)
+    }
+
+    /// Whether the rule matches a target at any of `ips`.
+    fn matches_ips(&self, ips: &[IpAddr]) -> bool {
+        self.networks.is_empty()
+            || ips
+                .iter()
+                .any(|ip| self.networks.iter().any(|net| net.contains(*ip)))
+    }
+}
+
+/// The first of `rules` matching a request of `user` for `target`. Domain
+/// names are only resolved, by `resolve`, for rules matching networks, and
+/// at most once.
+pub(crate) async fn evaluate<'a, F>(
+    rules: &'a [Rule],
+    target: &TargetAddr,
+    user: Option<&str>,
+    resolve: impl FnOnce() -> F,
+) -> Option<&'a Rule>
+where
+    F: Future<Output = Vec<IpAddr>>,
+{
+    let mut resolve = Some(resolve);
+    let mut ips = target.socket_addr().map(|addr| vec![addr.ip()]);
+    for rule in rules {
+        if !rule.matches_request(target, user) {
+            continue;
+        }
+        if !rule.networks.is_empty() && ips.is_none() {
+            if let Some(resolve) = resolve.take() {
+                ips = Some(resolve().await);
+            }
+        }
+        if rule.matches_ips(ips.as_deref().unwrap_or_default()) {
+            return Some(rule);
+        }
+    }
+    None
+}
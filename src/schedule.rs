@@ -0,0 +1,150 @@
+//! Weekly time windows, for rules that only apply part of the time.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// A day of the week.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Weekday {
+    Monday,
+    Tuesday,
+    Wednesday,
+    Thursday,
+    Friday,
+    Saturday,
+    Sunday,
+}
+
+impl Weekday {
+    /// Monday to Friday.
+    pub const WORKDAYS: [Weekday; 5] = [
+        Weekday::Monday,
+        Weekday::Tuesday,
+        Weekday::Wednesday,
+        Weekday::Thursday,
+        Weekday::Friday,
+    ];
+    /// Saturday and Sunday.
+    pub const WEEKEND: [Weekday; 2] = [Weekday::Saturday, Weekday::Sunday];
+
+    /// Monday is 0.
+    fn index(self) -> usize {
+        self as usize
+    }
+}
+
+/// When a rule applies: windows of the day on some days of the week, in
+/// the system's local time unless a UTC offset is given.
+///
+/// ```
+/// use socks5_rs::{Schedule, Weekday};
+///
+/// let work_hours = Schedule::new().with_window(Weekday::WORKDAYS, (9, 0), (17, 30));
+/// let nights = Schedule::new()
+///     .with_window([Weekday::Friday, Weekday::Saturday], (22, 0), (6, 0))
+///     .with_utc_offset(3600);
+/// ```
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Schedule {
+    windows: Vec<Window>,
+    /// Seconds east of UTC; local time when unset.
+    utc_offset: Option<i32>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct Window {
+    days: [bool; 7],
+    /// Minutes since midnight.
+    start: u16,
+    end: u16,
+}
+
+impl Schedule {
+    /// A schedule with no windows, so never active.
+    pub fn new() -> Self {
+        Schedule::default()
+    }
+
+    /// Be active on `days` from `start` until `end`, as `(hour, minute)`.
+    /// A window ending before it starts runs past midnight into the next
+    /// day, and one ending when it starts lasts the whole day.
+    ///
+    /// Panics if a time isn't within a day.
+    pub fn with_window(
+        mut self,
+        days: impl IntoIterator<Item = Weekday>,
+        start: (u8, u8),
+        end: (u8, u8),
+    ) -> Self {
+        let minutes = |(hour, minute): (u8, u8)| {
+            assert!(hour < 24 && minute < 60, "not a time of day");
+            u16::from(hour) * 60 + u16::from(minute)
+        };
+        let mut window = Window {
+            days: [false; 7],
+            start: minutes(start),
+            end: minutes(end),
+        };
+        for day in days {
+            window.days[day.index()] = true;
+        }
+        self.windows.push(window);
+        self
+    }
+
+    /// Tell the time at a fixed `seconds` east of UTC, rather than in the
+    /// system's time zone.
+    pub fn with_utc_offset(mut self, seconds: i32) -> Self {
+        self.utc_offset = Some(seconds);
+        self
+    }
+
+    /// Whether the schedule is active now.
+    pub fn is_active(&self) -> bool {
+        let (day, minute) = self.now();
+        let previous = (day + 6) % 7;
+        self.windows.iter().any(|window| {
+            if window.start < window.end {
+                window.days[day] && (window.start..window.end).contains(&minute)
+            } else {
+                // Past midnight, or all day.
+                (window.days[day] && minute >= window.start)
+                    || (window.days[previous] && minute < window.end)
+            }
+        })
+    }
+
+    /// The day of the week (Monday is 0) and minute of the day now.
+    fn now(&self) -> (usize, u16) {
+        let utc = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since_epoch| since_epoch.as_secs() as i64);
+        let offset = match self.utc_offset {
+            Some(offset) => i64::from(offset),
+            None => local_utc_offset(utc),
+        };
+        let secs = utc + offset;
+        let days = secs.div_euclid(86400);
+        // The epoch was a Thursday.
+        let day = (days + 3).rem_euclid(7) as usize;
+        let minute = (secs.rem_euclid(86400) / 60) as u16;
+        (day, minute)
+    }
+}
+
+/// The system time zone's offset from UTC at `utc`, in seconds.
+#[cfg(unix)]
+fn local_utc_offset(utc: i64) -> i64 {
+    let time = utc as libc::time_t;
+    // SAFETY: all-zero is a valid `tm`, which `localtime_r` fills in.
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    // SAFETY: both pointers are valid for the duration of the call.
+    if unsafe { libc::localtime_r(&time, &mut tm) }.is_null() {
+        return 0;
+    }
+    tm.tm_gmtoff as i64
+}
+
+#[cfg(not(unix))]
+fn local_utc_offset(_utc: i64) -> i64 {
+    0
+}
@@ -0,0 +1,57 @@
+//! Scripted access decisions, for policies [`Rule`]s can't express.
+//!
+//! The crate does not embed a script engine itself. Instead an
+//! [`AccessScript`] is asked about every request, which lets deployments
+//! plug in Rhai (or any other engine) while the server applies the
+//! decisions like those of rules.
+//!
+//! [`Rule`]: crate::Rule
+
+use std::net::SocketAddr;
+
+use crate::protocol::{Command, TargetAddr};
+use crate::RuleAction;
+
+/// Decides what becomes of requests, see [`Server::with_access_script`].
+///
+/// Scripts are run on the connection's task, for every CONNECT request and
+/// UDP datagram, so they must be quick: engines should be sandboxed and
+/// limited, e.g. by Rhai's `Engine::set_max_operations`, and a script
+/// failing or running out of its limits should block the request rather
+/// than let it through.
+///
+/// ```
+/// use socks5_rs::{AccessRequest, AccessScript, RuleAction};
+/// use socks5_rs::protocol::Reply;
+///
+/// // With Rhai, evaluate a compiled AST with the request in scope instead.
+/// let script = |request: &AccessRequest<'_>| match request.user {
+///     Some("guest") if request.target.port != 443 => Some(RuleAction::Block(Reply::NotAllowed)),
+///     _ => None,
+/// };
+/// # let _: &dyn AccessScript = &script;
+/// ```
+///
+/// [`Server::with_access_script`]: crate::Server::with_access_script
+pub trait AccessScript: Send + Sync {
+    /// What becomes of `request`, or `None` to leave it to the rules.
+    fn decide(&self, request: &AccessRequest<'_>) -> Option<RuleAction>;
+}
+
+impl<F: Fn(&AccessRequest<'_>) -> Option<RuleAction> + Send + Sync> AccessScript for F {
+    fn decide(&self, request: &AccessRequest<'_>) -> Option<RuleAction> {
+        self(request)
+    }
+}
+
+/// A request, as an [`AccessScript`] sees it.
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub struct AccessRequest<'a> {
+    pub client: SocketAddr,
+    /// The username the client authenticated with, if any.
+    pub user: Option<&'a str>,
+    /// [`Command::Connect`], or [`Command::UdpAssociate`] for a datagram.
+    pub command: Command,
+    pub target: &'a TargetAddr,
+}
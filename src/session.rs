@@ -0,0 +1,268 @@
+//! The registry of connections being served, listed by
+//! [`Server::sessions`] and ended by [`Server::kill`].
+//!
+//! [`Server::sessions`]: crate::Server::sessions
+//! [`Server::kill`]: crate::Server::kill
+
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::SystemTime;
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio_util::sync::CancellationToken;
+
+use crate::protocol::TargetAddr;
+#[cfg(unix)]
+use crate::sockopt::ClientSocket;
+
+/// A connection being served, as listed by [`Server::sessions`].
+///
+/// [`Server::sessions`]: crate::Server::sessions
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub struct Session {
+    /// Identifies the session to [`Server::kill`].
+    ///
+    /// [`Server::kill`]: crate::Server::kill
+    pub id: u64,
+    /// Where the client connects from.
+    pub peer: SocketAddr,
+    /// What the client asked to reach, once its request is read.
+    pub target: Option<TargetAddr>,
+    /// The address connected to for the target once connected: the one of
+    /// its addresses that accepted, or the upstream proxy it is routed
+    /// through.
+    pub connected: Option<SocketAddr>,
+    /// The username the client authenticated with, if any.
+    pub user: Option<String>,
+    /// UID of the process on the other end of a Unix socket, when checked
+    /// against a [`PeerCredPolicy`].
+    ///
+    /// [`PeerCredPolicy`]: crate::PeerCredPolicy
+    pub peer_uid: Option<u32>,
+    pub started: SystemTime,
+    /// Bytes received from the client, handshake included.
+    pub bytes_received: u64,
+    /// Bytes sent to the client, handshake included.
+    pub bytes_sent: u64,
+}
+
+/// A session's state, updated by its task.
+pub(crate) struct SessionState {
+    id: u64,
+    peer: SocketAddr,
+    started: SystemTime,
+    target: Mutex<Option<TargetAddr>>,
+    connected: Mutex<Option<SocketAddr>>,
+    user: Mutex<Option<String>>,
+    peer_uid: Mutex<Option<u32>>,
+    /// The client's socket, if it is connected over TCP.
+    #[cfg(unix)]
+    client_socket: Mutex<Option<ClientSocket>>,
+    received: AtomicU64,
+    sent: AtomicU64,
+    /// Ends the session when cancelled.
+    pub(crate) kill: CancellationToken,
+}
+
+impl SessionState {
+    /// State for a session that isn't registered anywhere.
+    pub(crate) fn new(id: u64, peer: SocketAddr) -> Self {
+        SessionState {
+            id,
+            peer,
+            started: SystemTime::now(),
+            target: Mutex::new(None),
+            connected: Mutex::new(None),
+            user: Mutex::new(None),
+            peer_uid: Mutex::new(None),
+            #[cfg(unix)]
+            client_socket: Mutex::new(None),
+            received: AtomicU64::new(0),
+            sent: AtomicU64::new(0),
+            kill: CancellationToken::new(),
+        }
+    }
+
+    pub(crate) fn id(&self) -> u64 {
+        self.id
+    }
+
+    pub(crate) fn set_target(&self, target: &TargetAddr) {
+        *self.target.lock().unwrap() = Some(target.clone());
+    }
+
+    pub(crate) fn target(&self) -> Option<TargetAddr> {
+        self.target.lock().unwrap().clone()
+    }
+
+    pub(crate) fn set_connected(&self, addr: SocketAddr) {
+        *self.connected.lock().unwrap() = Some(addr);
+    }
+
+    pub(crate) fn connected(&self) -> Option<SocketAddr> {
+        *self.connected.lock().unwrap()
+    }
+
+    pub(crate) fn user(&self) -> Option<String> {
+        self.user.lock().unwrap().clone()
+    }
+
+    pub(crate) fn set_user(&self, user: &str) {
+        *self.user.lock().unwrap() = Some(user.to_owned());
+    }
+
+    pub(crate) fn peer_uid(&self) -> Option<u32> {
+        *self.peer_uid.lock().unwrap()
+    }
+
+    pub(crate) fn set_peer_uid(&self, uid: u32) {
+        *self.peer_uid.lock().unwrap() = Some(uid);
+    }
+
+    #[cfg(unix)]
+    pub(crate) fn set_client_socket(&self, socket: ClientSocket) {
+        *self.client_socket.lock().unwrap() = Some(socket);
+    }
+
+    /// Mark the packets sent to the client with `dscp`, if it is connected
+    /// over TCP.
+    pub(crate) fn set_client_dscp(&self, dscp: u8) {
+        #[cfg(unix)]
+        if let Some(socket) = *self.client_socket.lock().unwrap() {
+            if let Err(e) = socket.set_dscp(dscp) {
+                log::debug!("setting the DSCP of session {} failed: {}", self.id, e);
+            }
+        }
+        #[cfg(not(unix))]
+        let _ = dscp;
+    }
+
+    fn snapshot(&self) -> Session {
+        Session {
+            id: self.id,
+            peer: self.peer,
+            target: self.target(),
+            connected: self.connected(),
+            user: self.user(),
+            peer_uid: self.peer_uid(),
+            started: self.started,
+            bytes_received: self.received.load(Ordering::Relaxed),
+            bytes_sent: self.sent.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[derive(Default)]
+pub(crate) struct Registry {
+    last_id: AtomicU64,
+    sessions: Mutex<HashMap<u64, Arc<SessionState>>>,
+}
+
+impl Registry {
+    /// Register a session for a client from `peer`, until the returned
+    /// guard is dropped.
+    pub(crate) fn register(self: &Arc<Self>, peer: SocketAddr) -> Registration {
+        let id = self.last_id.fetch_add(1, Ordering::Relaxed) + 1;
+        let state = Arc::new(SessionState::new(id, peer));
+        self.sessions.lock().unwrap().insert(id, state.clone());
+        Registration {
+            registry: self.clone(),
+            state,
+        }
+    }
+
+    /// The sessions, oldest first.
+    pub(crate) fn list(&self) -> Vec<Session> {
+        let mut sessions: Vec<_> = self
+            .sessions
+            .lock()
+            .unwrap()
+            .values()
+            .map(|state| state.snapshot())
+            .collect();
+        sessions.sort_by_key(|session| session.id);
+        sessions
+    }
+
+    pub(crate) fn kill(&self, id: u64) -> bool {
+        match self.sessions.lock().unwrap().get(&id) {
+            Some(state) => {
+                state.kill.cancel();
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// Keeps a session registered while alive.
+pub(crate) struct Registration {
+    registry: Arc<Registry>,
+    pub(crate) state: Arc<SessionState>,
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        self.registry
+            .sessions
+            .lock()
+            .unwrap()
+            .remove(&self.state.id);
+    }
+}
+
+/// A client stream counting the bytes through it into its session.
+pub(crate) struct Counted<S> {
+    inner: S,
+    state: Arc<SessionState>,
+}
+
+impl<S> Counted<S> {
+    pub(crate) fn new(inner: S, state: Arc<SessionState>) -> Self {
+        Counted { inner, state }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Counted<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let filled = buf.filled().len();
+        let poll = Pin::new(&mut self.inner).poll_read(cx, buf);
+        let read = buf.filled().len() - filled;
+        self.state
+            .received
+            .fetch_add(read as u64, Ordering::Relaxed);
+        poll
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Counted<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let poll = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(written)) = poll {
+            self.state.sent.fetch_add(written as u64, Ordering::Relaxed);
+        }
+        poll
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
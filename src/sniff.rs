@@ -0,0 +1,207 @@
+//! The domain name a client means to reach, sniffed from the first bytes it
+//! sends: the server name of a TLS ClientHello, or the host of an HTTP
+//! request.
+
+use std::io;
+use std::time::Duration;
+
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::time::{self, Instant};
+
+/// Most bytes read while sniffing: a full TLS record and its header.
+const MAX_SNIFF_LEN: usize = 5 + (1 << 14);
+
+/// Longest HTTP request head looked through for the host.
+const MAX_HTTP_HEAD: usize = 8192;
+
+const CONTENT_HANDSHAKE: u8 = 0x16;
+const HANDSHAKE_CLIENT_HELLO: u8 = 0x01;
+const EXTENSION_SERVER_NAME: u16 = 0x0000;
+const NAME_TYPE_HOST_NAME: u8 = 0x00;
+
+const HTTP_METHODS: &[&[u8]] = &[
+    b"GET ",
+    b"POST ",
+    b"PUT ",
+    b"HEAD ",
+    b"DELETE ",
+    b"OPTIONS ",
+    b"PATCH ",
+    b"CONNECT ",
+    b"TRACE ",
+];
+
+/// What sniffing the bytes so far found.
+#[derive(Debug, PartialEq, Eq)]
+enum Sniffed {
+    /// The name, lowercase.
+    Name(String),
+    /// The bytes name no host, or aren't TLS or HTTP.
+    Nothing,
+    /// The bytes so far are the start of something that may name a host.
+    NeedMore,
+}
+
+/// Read the first bytes a client sends on `stream`, for up to `timeout`,
+/// and the domain name they are for, if any. Clients of protocols where the
+/// server speaks first send nothing, and are given up on after `timeout`.
+pub(crate) async fn read_name<R: AsyncRead + Unpin>(
+    stream: &mut R,
+    timeout: Duration,
+) -> io::Result<(Vec<u8>, Option<String>)> {
+    let deadline = Instant::now() + timeout;
+    let mut buf = Vec::new();
+    loop {
+        match sniff(&buf) {
+            Sniffed::Name(name) => return Ok((buf, Some(name))),
+            Sniffed::NeedMore if buf.len() < MAX_SNIFF_LEN => {}
+            Sniffed::NeedMore | Sniffed::Nothing => return Ok((buf, None)),
+        }
+        let len = buf.len();
+        buf.resize(MAX_SNIFF_LEN, 0);
+        let read = time::timeout_at(deadline, stream.read(&mut buf[len..])).await;
+        match read {
+            Ok(Ok(n)) if n > 0 => buf.truncate(len + n),
+            Ok(Err(e)) => return Err(e),
+            // Closed, or out of time.
+            _ => {
+                buf.truncate(len);
+                return Ok((buf, None));
+            }
+        }
+    }
+}
+
+/// Sniff `data`, the first bytes a client sent.
+fn sniff(data: &[u8]) -> Sniffed {
+    match data.first() {
+        None => Sniffed::NeedMore,
+        Some(&CONTENT_HANDSHAKE) => tls_server_name(data),
+        Some(_) => http_host(data),
+    }
+}
+
+/// The server name extension of a ClientHello in the first TLS record.
+fn tls_server_name(data: &[u8]) -> Sniffed {
+    if data.len() < 5 {
+        return Sniffed::NeedMore;
+    }
+    let record_len = usize::from(u16::from_be_bytes([data[3], data[4]]));
+    if data[1] != 0x03 || record_len > MAX_SNIFF_LEN - 5 {
+        return Sniffed::Nothing;
+    }
+    let record = match data.get(5..5 + record_len) {
+        Some(record) => record,
+        None => return Sniffed::NeedMore,
+    };
+    match client_hello_server_name(&mut Reader(record)) {
+        Some(name) => Sniffed::Name(name),
+        None => Sniffed::Nothing,
+    }
+}
+
+fn client_hello_server_name(record: &mut Reader<'_>) -> Option<String> {
+    if record.u8()? != HANDSHAKE_CLIENT_HELLO {
+        return None;
+    }
+    // A ClientHello longer than the record is cut short, but the server
+    // name is usually early enough to be in it anyway.
+    let len = record.u24()?;
+    let mut hello = Reader(record.take(len.min(record.0.len()))?);
+    hello.take(2 + 32)?; // version, random
+    let session_id = usize::from(hello.u8()?);
+    hello.take(session_id)?;
+    let cipher_suites = usize::from(hello.u16()?);
+    hello.take(cipher_suites)?;
+    let compression = usize::from(hello.u8()?);
+    hello.take(compression)?;
+    let extensions = usize::from(hello.u16()?);
+    let mut extensions = Reader(hello.take(extensions.min(hello.0.len()))?);
+    while !extensions.0.is_empty() {
+        let ty = extensions.u16()?;
+        let len = usize::from(extensions.u16()?);
+        let mut body = Reader(extensions.take(len)?);
+        if ty != EXTENSION_SERVER_NAME {
+            continue;
+        }
+        let list = usize::from(body.u16()?);
+        let mut list = Reader(body.take(list)?);
+        while !list.0.is_empty() {
+            let name_type = list.u8()?;
+            let len = usize::from(list.u16()?);
+            let name = list.take(len)?;
+            if name_type == NAME_TYPE_HOST_NAME {
+                return std::str::from_utf8(name).ok().map(str::to_ascii_lowercase);
+            }
+        }
+        return None;
+    }
+    None
+}
+
+/// The `Host` header of an HTTP/1 request head, without its port.
+fn http_host(data: &[u8]) -> Sniffed {
+    let is_method = |method: &&[u8]| {
+        let len = method.len().min(data.len());
+        data[..len] == method[..len]
+    };
+    if !HTTP_METHODS.iter().any(is_method) {
+        return Sniffed::Nothing;
+    }
+    let head_end = data.windows(4).position(|window| window == b"\r\n\r\n");
+    let head = match head_end {
+        Some(end) => &data[..end],
+        None if data.len() < MAX_HTTP_HEAD => return Sniffed::NeedMore,
+        None => &data[..MAX_HTTP_HEAD],
+    };
+    let head = String::from_utf8_lossy(head);
+    let host = head.split("\r\n").skip(1).find_map(|line| {
+        let (name, value) = line.split_once(':')?;
+        if name.eq_ignore_ascii_case("host") {
+            Some(value.trim())
+        } else {
+            None
+        }
+    });
+    let host = match host {
+        Some(host) => host,
+        None => return Sniffed::Nothing,
+    };
+    // Bracketed IPv6 addresses aren't names.
+    if host.starts_with('[') {
+        return Sniffed::Nothing;
+    }
+    let name = match host.rsplit_once(':') {
+        Some((name, port)) if port.parse::<u16>().is_ok() => name,
+        _ => host,
+    };
+    Sniffed::Name(name.to_ascii_lowercase())
+}
+
+/// Reads big-endian fields off the front of a byte slice.
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Option<&'a [u8]> {
+        if self.0.len() < n {
+            return None;
+        }
+        let (taken, rest) = self.0.split_at(n);
+        self.0 = rest;
+        Some(taken)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        Some(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        let bytes = self.take(2)?;
+        Some(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn u24(&mut self) -> Option<usize> {
+        let bytes = self.take(3)?;
+        Some(bytes.iter().fold(0, |n, &b| (n << 8) | usize::from(b)))
+    }
+}
@@ -0,0 +1,101 @@
+//! Socket options Tokio doesn't expose, set with `setsockopt`.
+
+use std::convert::TryFrom;
+use std::io;
+use std::mem;
+use std::os::unix::io::{AsRawFd, RawFd};
+
+use tokio::net::TcpStream;
+
+use crate::dialer::Keepalive;
+
+/// Set the integer option `name` at `level` of `socket` to `value`.
+pub(crate) fn set_int<S: AsRawFd>(
+    socket: &S,
+    level: libc::c_int,
+    name: libc::c_int,
+    value: libc::c_int,
+) -> io::Result<()> {
+    let value_ptr = &value as *const libc::c_int as *const libc::c_void;
+    let len = mem::size_of_val(&value) as libc::socklen_t;
+    // SAFETY: the option value is an integer, borrowed for the call.
+    if unsafe { libc::setsockopt(socket.as_raw_fd(), level, name, value_ptr, len) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Mark the packets `socket` sends with `dscp`, of which the low six bits
+/// count. `ipv6` tells the family of the socket.
+pub(crate) fn set_dscp<S: AsRawFd>(socket: &S, ipv6: bool, dscp: u8) -> io::Result<()> {
+    let tos = libc::c_int::from(dscp & 0x3f) << 2;
+    if !ipv6 {
+        return set_int(socket, libc::IPPROTO_IP, libc::IP_TOS, tos);
+    }
+    set_int(socket, libc::IPPROTO_IPV6, libc::IPV6_TCLASS, tos)?;
+    // Dual-stack sockets talking to IPv4 peers send IPv4 packets.
+    let _ = set_int(socket, libc::IPPROTO_IP, libc::IP_TOS, tos);
+    Ok(())
+}
+
+/// Probe whether the peer of `socket` is still there as `keepalive` says.
+/// Only the probing itself is turned on where its timing can't be set.
+pub(crate) fn set_keepalive<S: AsRawFd>(socket: &S, keepalive: &Keepalive) -> io::Result<()> {
+    set_int(socket, libc::SOL_SOCKET, libc::SO_KEEPALIVE, 1)?;
+    set_keepalive_timing(socket, keepalive)
+}
+
+#[cfg(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "macos",
+    target_os = "ios"
+))]
+fn set_keepalive_timing<S: AsRawFd>(socket: &S, keepalive: &Keepalive) -> io::Result<()> {
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    let idle = libc::TCP_KEEPIDLE;
+    #[cfg(any(target_os = "macos", target_os = "ios"))]
+    let idle = libc::TCP_KEEPALIVE;
+    // In whole seconds, at least one, as the kernel takes them.
+    let seconds = |duration: std::time::Duration| {
+        let seconds = libc::c_int::try_from(duration.as_secs()).unwrap_or(libc::c_int::MAX);
+        seconds.max(1)
+    };
+    let retries = libc::c_int::try_from(keepalive.retries).unwrap_or(libc::c_int::MAX);
+    set_int(socket, libc::IPPROTO_TCP, idle, seconds(keepalive.idle))?;
+    let interval = seconds(keepalive.interval);
+    set_int(socket, libc::IPPROTO_TCP, libc::TCP_KEEPINTVL, interval)?;
+    set_int(socket, libc::IPPROTO_TCP, libc::TCP_KEEPCNT, retries)
+}
+
+#[cfg(not(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "macos",
+    target_os = "ios"
+)))]
+fn set_keepalive_timing<S: AsRawFd>(_: &S, _: &Keepalive) -> io::Result<()> {
+    Ok(())
+}
+
+/// The socket of a client's TCP connection, for the options that depend on
+/// who the client turns out to be. Only valid while the connection is
+/// served.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct ClientSocket {
+    fd: RawFd,
+    ipv6: bool,
+}
+
+impl ClientSocket {
+    pub(crate) fn new(stream: &TcpStream) -> Self {
+        ClientSocket {
+            fd: stream.as_raw_fd(),
+            ipv6: stream.local_addr().is_ok_and(|addr| addr.is_ipv6()),
+        }
+    }
+
+    pub(crate) fn set_dscp(&self, dscp: u8) -> io::Result<()> {
+        set_dscp(&self.fd, self.ipv6, dscp)
+    }
+}
@@ -0,0 +1,137 @@
+//! SOCKS4 and SOCKS4a compatibility, enabled with [`Server::with_socks4`].
+//!
+//! [`Server::with_socks4`]: crate::Server::with_socks4
+
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr};
+
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    time::Instant,
+};
+
+use crate::config::Requester;
+use crate::protocol::{Address, Command, Method, ProtocolError, TargetAddr};
+use crate::session::SessionState;
+use crate::{dial, within, ServerConfig, Socks5Error};
+
+pub(crate) const SOCKS4_VERSION: u8 = 0x04;
+
+const CD_CONNECT: u8 = 0x01;
+const CD_BIND: u8 = 0x02;
+
+/// Version byte of SOCKS4 replies.
+const REPLY_VERSION: u8 = 0x00;
+const REQUEST_GRANTED: u8 = 90;
+const REQUEST_REJECTED: u8 = 91;
+
+/// Upper bound on the NUL terminated USERID and 4a domain fields.
+const MAX_FIELD_LEN: usize = 255;
+
+/// Serve a SOCKS4(a) client whose `VN | CD` has already been read.
+///
+/// `VN | CD | DSTPORT | DSTIP | USERID | NUL [| DOMAIN | NUL]`
+pub(crate) async fn handle<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    peer: SocketAddr,
+    config: &ServerConfig,
+    session: &SessionState,
+    command: u8,
+) -> Result<(), Socks5Error> {
+    let deadline = Instant::now() + config.request_timeout;
+    let (port_ip, domain) = within(deadline, async {
+        let mut port_ip = [0u8; 6];
+        stream.read_exact(&mut port_ip).await?;
+        let _user_id = read_nul_terminated(stream).await?;
+
+        // SOCKS4a: DSTIP 0.0.0.x with x != 0 means a domain name follows.
+        let domain = if port_ip[2..5] == [0, 0, 0] && port_ip[5] != 0 {
+            Some(read_nul_terminated(stream).await?)
+        } else {
+            None
+        };
+        Ok::<_, Socks5Error>((port_ip, domain))
+    })
+    .await?;
+    let port = u16::from_be_bytes([port_ip[0], port_ip[1]]);
+    let ip = &port_ip[2..];
+
+    let target_addr = match domain {
+        Some(domain) => {
+            let domain = String::from_utf8(domain)
+                .ok()
+                .and_then(|domain| Address::domain(domain).ok());
+            match domain {
+                Some(domain) => TargetAddr::new(domain, port),
+                None => {
+                    write_reply(stream, REQUEST_REJECTED).await?;
+                    return Err(ProtocolError::InvalidDomain.into());
+                }
+            }
+        }
+        None => TargetAddr::new(Ipv4Addr::new(ip[0], ip[1], ip[2], ip[3]), port),
+    };
+    session.set_target(&target_addr);
+
+    if command != CD_CONNECT {
+        write_reply(stream, REQUEST_REJECTED).await?;
+        return Err(match command {
+            CD_BIND => Socks5Error::CommandNotSupported(Command::Bind),
+            _ => ProtocolError::CommandNotSupported(command).into(),
+        });
+    }
+
+    // SOCKS4 has no way to authenticate, so it is only served when SOCKS5
+    // clients don't need to either.
+    if !config.methods(peer.ip()).contains(&Method::NoAuth) {
+        write_reply(stream, REQUEST_REJECTED).await?;
+        return Err(Socks5Error::NotAllowed);
+    }
+
+    let requester = Requester {
+        peer,
+        user: None,
+        policy: None,
+    };
+    let mut target = match dial(&target_addr, config, &requester, session, None).await {
+        Ok(target) => target,
+        Err(e) => {
+            if !e.is_blocked() || config.refuse_blocked().await {
+                write_reply(stream, REQUEST_REJECTED).await?;
+            }
+            return Err(e);
+        }
+    };
+
+    session.set_connected(target.peer_addr());
+    write_reply(stream, REQUEST_GRANTED).await?;
+
+    tokio::io::copy_bidirectional(stream, &mut target)
+        .await
+        .map_err(Socks5Error::Relay)?;
+
+    Ok(())
+}
+
+async fn read_nul_terminated<R: AsyncRead + Unpin>(stream: &mut R) -> io::Result<Vec<u8>> {
+    let mut field = Vec::new();
+    loop {
+        match stream.read_u8().await? {
+            0 => return Ok(field),
+            _ if field.len() == MAX_FIELD_LEN => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "SOCKS4 field too long",
+                ))
+            }
+            b => field.push(b),
+        }
+    }
+}
+
+/// `VN | CD | DSTPORT | DSTIP`, with DSTPORT and DSTIP left zero.
+async fn write_reply<W: AsyncWrite + Unpin>(stream: &mut W, cd: u8) -> io::Result<()> {
+    stream
+        .write_all(&[REPLY_VERSION, cd, 0, 0, 0, 0, 0, 0])
+        .await
+}
@@ -0,0 +1,320 @@
+//! Users from a SQLite database, for deployments with more users than a
+//! credentials file is comfortable for.
+//!
+//! The database holds one table:
+//!
+//! ```sql
+//! CREATE TABLE users (
+//!     username      TEXT PRIMARY KEY NOT NULL,
+//!     password_hash TEXT NOT NULL,
+//!     enabled       INTEGER NOT NULL DEFAULT 1
+//! );
+//! ```
+//!
+//! with hashes in the forms understood by [`HtpasswdFile`]. It is created
+//! when missing, rows are managed with any SQLite client.
+//!
+//! [`HtpasswdFile`]: crate::HtpasswdFile
+
+use std::ffi::{CStr, CString};
+use std::io;
+use std::net::SocketAddr;
+use std::os::raw::{c_char, c_int, c_void};
+use std::path::{Path, PathBuf};
+use std::ptr;
+use std::sync::{Arc, Mutex};
+
+use crate::auth::{AuthFuture, Authenticator, Decision};
+use crate::passwd::PasswordHash;
+
+const SQLITE_OK: c_int = 0;
+const SQLITE_ROW: c_int = 100;
+const SQLITE_DONE: c_int = 101;
+const SQLITE_OPEN_READWRITE: c_int = 0x0000_0002;
+const SQLITE_OPEN_CREATE: c_int = 0x0000_0004;
+const SQLITE_OPEN_NOMUTEX: c_int = 0x0000_8000;
+/// `SQLITE_TRANSIENT`: sqlite copies bound values.
+const SQLITE_TRANSIENT: isize = -1;
+
+/// Milliseconds to wait for a lock held by another writer, e.g. an admin
+/// adding users, before failing the lookup.
+const BUSY_TIMEOUT_MS: c_int = 5_000;
+
+/// Connections kept open for reuse.
+const MAX_IDLE: usize = 4;
+
+const CREATE_TABLE: &str = "CREATE TABLE IF NOT EXISTS users (\
+    username TEXT PRIMARY KEY NOT NULL, \
+    password_hash TEXT NOT NULL, \
+    enabled INTEGER NOT NULL DEFAULT 1)";
+const SELECT_USER: &str = "SELECT password_hash, enabled FROM users WHERE username = ?1";
+
+/// Checked instead when the user is unknown or disabled, so as not to tell
+/// them apart by how quickly they are denied.
+const DUMMY_HASH: &str = "$2b$10$......................0123456789012345678901234567890";
+
+#[allow(non_camel_case_types)]
+enum sqlite3 {}
+#[allow(non_camel_case_types)]
+enum sqlite3_stmt {}
+
+#[link(name = "sqlite3")]
+extern "C" {
+    fn sqlite3_open_v2(
+        filename: *const c_char,
+        db: *mut *mut sqlite3,
+        flags: c_int,
+        vfs: *const c_char,
+    ) -> c_int;
+    fn sqlite3_close(db: *mut sqlite3) -> c_int;
+    fn sqlite3_errmsg(db: *mut sqlite3) -> *const c_char;
+    fn sqlite3_busy_timeout(db: *mut sqlite3, ms: c_int) -> c_int;
+    fn sqlite3_prepare_v2(
+        db: *mut sqlite3,
+        sql: *const c_char,
+        len: c_int,
+        stmt: *mut *mut sqlite3_stmt,
+        tail: *mut *const c_char,
+    ) -> c_int;
+    fn sqlite3_bind_text(
+        stmt: *mut sqlite3_stmt,
+        index: c_int,
+        value: *const c_char,
+        len: c_int,
+        destructor: isize,
+    ) -> c_int;
+    fn sqlite3_step(stmt: *mut sqlite3_stmt) -> c_int;
+    fn sqlite3_column_text(stmt: *mut sqlite3_stmt, column: c_int) -> *const c_void;
+    fn sqlite3_column_bytes(stmt: *mut sqlite3_stmt, column: c_int) -> c_int;
+    fn sqlite3_column_int64(stmt: *mut sqlite3_stmt, column: c_int) -> i64;
+    fn sqlite3_finalize(stmt: *mut sqlite3_stmt) -> c_int;
+}
+
+/// An [`Authenticator`] looking users up in a SQLite database. Lookups and
+/// hashing run on Tokio's blocking threads.
+///
+/// Users are looked up on every authentication, so users added, changed or
+/// disabled in the database take effect right away, without a reload.
+/// Clients already authenticated stay connected.
+///
+/// ```no_run
+/// # async fn run() -> std::io::Result<()> {
+/// use socks5_rs::{Server, SqliteUsers};
+///
+/// let users = SqliteUsers::open("/var/lib/socks5/users.db")?;
+/// Server::bind("0.0.0.0:1080")
+///     .await?
+///     .with_authenticator(users)
+///     .serve()
+///     .await?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct SqliteUsers {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    path: PathBuf,
+    idle: Mutex<Vec<Connection>>,
+    dummy: PasswordHash,
+}
+
+impl SqliteUsers {
+    /// Open the database at `path`, creating it and the `users` table if
+    /// needed.
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref().to_owned();
+        let connection = Connection::open(&path)?;
+        connection.execute(CREATE_TABLE)?;
+        Ok(SqliteUsers {
+            inner: Arc::new(Inner {
+                path,
+                idle: Mutex::new(vec![connection]),
+                dummy: PasswordHash::parse(DUMMY_HASH).unwrap(),
+            }),
+        })
+    }
+}
+
+impl Inner {
+    /// The hash of `username`, if the user exists and is enabled.
+    fn lookup(&self, username: &str) -> io::Result<Option<String>> {
+        let idle = self.idle.lock().unwrap().pop();
+        let connection = match idle {
+            Some(connection) => connection,
+            None => Connection::open(&self.path)?,
+        };
+        let found = connection.user(username)?;
+        let mut idle = self.idle.lock().unwrap();
+        if idle.len() < MAX_IDLE {
+            idle.push(connection);
+        }
+        Ok(found.and_then(|(hash, enabled)| if enabled { Some(hash) } else { None }))
+    }
+
+    fn verify(&self, username: &str, password: &[u8]) -> bool {
+        let hash = match self.lookup(username) {
+            Ok(hash) => hash,
+            Err(e) => {
+                log::warn!("looking up user {:?}: {}", username, e);
+                None
+            }
+        };
+        match hash.as_deref().map(PasswordHash::parse) {
+            Some(Ok(hash)) => hash.verify(password),
+            Some(Err(e)) => {
+                log::warn!("password hash of user {:?}: {}", username, e);
+                false
+            }
+            None => {
+                self.dummy.verify(password);
+                false
+            }
+        }
+    }
+}
+
+impl Authenticator for SqliteUsers {
+    fn verify<'a>(
+        &'a self,
+        username: &'a str,
+        password: &'a [u8],
+        _peer: SocketAddr,
+    ) -> AuthFuture<'a> {
+        let inner = self.inner.clone();
+        let username = username.to_owned();
+        let password = password.to_vec();
+        Box::pin(async move {
+            let allowed = tokio::task::spawn_blocking(move || inner.verify(&username, &password))
+                .await
+                .unwrap_or(false);
+            if allowed {
+                Decision::Allow
+            } else {
+                Decision::Deny
+            }
+        })
+    }
+}
+
+/// An open database handle, used by one thread at a time.
+struct Connection(*mut sqlite3);
+
+// Opened with SQLITE_OPEN_NOMUTEX, which allows moving between threads as
+// long as there is no concurrent use.
+unsafe impl Send for Connection {}
+
+impl Connection {
+    fn open(path: &Path) -> io::Result<Self> {
+        let filename = CString::new(path.to_string_lossy().as_bytes())
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "NUL in database path"))?;
+        let mut db = ptr::null_mut();
+        let flags = SQLITE_OPEN_READWRITE | SQLITE_OPEN_CREATE | SQLITE_OPEN_NOMUTEX;
+        let rc = unsafe { sqlite3_open_v2(filename.as_ptr(), &mut db, flags, ptr::null()) };
+        // A handle is returned even on failure, to read the error from.
+        let connection = Connection(db);
+        if db.is_null() {
+            return Err(io::Error::new(
+                io::ErrorKind::OutOfMemory,
+                "sqlite3_open_v2",
+            ));
+        }
+        connection.check(rc)?;
+        unsafe { sqlite3_busy_timeout(db, BUSY_TIMEOUT_MS) };
+        Ok(connection)
+    }
+
+    fn execute(&self, sql: &str) -> io::Result<()> {
+        self.prepare(sql)?.step().map(drop)
+    }
+
+    /// The password hash and enabled flag of `username`.
+    fn user(&self, username: &str) -> io::Result<Option<(String, bool)>> {
+        let statement = self.prepare(SELECT_USER)?;
+        let rc = unsafe {
+            sqlite3_bind_text(
+                statement.raw,
+                1,
+                username.as_ptr() as *const c_char,
+                username.len() as c_int,
+                SQLITE_TRANSIENT,
+            )
+        };
+        self.check(rc)?;
+        if statement.step()? == SQLITE_DONE {
+            return Ok(None);
+        }
+        let hash = unsafe {
+            let text = sqlite3_column_text(statement.raw, 0) as *const u8;
+            let len = sqlite3_column_bytes(statement.raw, 0) as usize;
+            if text.is_null() {
+                String::new()
+            } else {
+                String::from_utf8_lossy(std::slice::from_raw_parts(text, len)).into_owned()
+            }
+        };
+        let enabled = unsafe { sqlite3_column_int64(statement.raw, 1) } != 0;
+        Ok(Some((hash, enabled)))
+    }
+
+    fn prepare(&self, sql: &str) -> io::Result<Statement<'_>> {
+        let mut raw = ptr::null_mut();
+        let rc = unsafe {
+            sqlite3_prepare_v2(
+                self.0,
+                sql.as_ptr() as *const c_char,
+                sql.len() as c_int,
+                &mut raw,
+                ptr::null_mut(),
+            )
+        };
+        self.check(rc)?;
+        Ok(Statement {
+            connection: self,
+            raw,
+        })
+    }
+
+    fn check(&self, rc: c_int) -> io::Result<()> {
+        if rc == SQLITE_OK {
+            Ok(())
+        } else {
+            Err(self.error())
+        }
+    }
+
+    /// The error of the last failed call.
+    fn error(&self) -> io::Error {
+        let message = unsafe { CStr::from_ptr(sqlite3_errmsg(self.0)) };
+        io::Error::other(format!("sqlite: {}", message.to_string_lossy()))
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        unsafe { sqlite3_close(self.0) };
+    }
+}
+
+struct Statement<'a> {
+    connection: &'a Connection,
+    raw: *mut sqlite3_stmt,
+}
+
+impl Statement<'_> {
+    /// SQLITE_ROW or SQLITE_DONE.
+    fn step(&self) -> io::Result<c_int> {
+        match unsafe { sqlite3_step(self.raw) } {
+            rc @ (SQLITE_ROW | SQLITE_DONE) => Ok(rc),
+            _ => Err(self.connection.error()),
+        }
+    }
+}
+
+impl Drop for Statement<'_> {
+    fn drop(&mut self) {
+        unsafe { sqlite3_finalize(self.raw) };
+    }
+}
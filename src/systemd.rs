@@ -0,0 +1,83 @@
+//! systemd socket activation, the `sd_listen_fds` convention: listening
+//! sockets are passed as the file descriptors from 3 on, their number in
+//! `LISTEN_FDS` and the PID they are meant for in `LISTEN_PID`.
+
+use std::env;
+use std::io;
+use std::mem;
+use std::os::unix::io::{FromRawFd, RawFd};
+
+use tokio::net::{TcpListener, UnixListener};
+
+use crate::listener::Listener;
+
+const SD_LISTEN_FDS_START: RawFd = 3;
+
+/// Take over the sockets systemd passed to this process.
+///
+/// The environment is left as is: `LISTEN_PID` already keeps child
+/// processes from picking the sockets up too.
+pub(crate) fn listeners() -> io::Result<Vec<Listener>> {
+    let pid = env::var("LISTEN_PID").ok().and_then(|pid| pid.parse().ok());
+    if pid != Some(std::process::id()) {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            "no sockets passed by systemd",
+        ));
+    }
+    let fds: RawFd = env::var("LISTEN_FDS")
+        .ok()
+        .and_then(|fds| fds.parse().ok())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "invalid LISTEN_FDS"))?;
+
+    (SD_LISTEN_FDS_START..SD_LISTEN_FDS_START + fds)
+        .map(listener)
+        .collect()
+}
+
+fn listener(fd: RawFd) -> io::Result<Listener> {
+    // SAFETY: `fd` was handed to us by systemd and isn't used elsewhere.
+    // Every call only reads into the buffers passed along.
+    unsafe {
+        if libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) == -1 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let mut ty: libc::c_int = 0;
+        let mut len = mem::size_of_val(&ty) as libc::socklen_t;
+        let ty_ptr = &mut ty as *mut libc::c_int as *mut libc::c_void;
+        if libc::getsockopt(fd, libc::SOL_SOCKET, libc::SO_TYPE, ty_ptr, &mut len) == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        if ty != libc::SOCK_STREAM {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "systemd passed a socket that isn't SOCK_STREAM",
+            ));
+        }
+
+        let mut addr: libc::sockaddr_storage = mem::zeroed();
+        let mut len = mem::size_of_val(&addr) as libc::socklen_t;
+        let addr_ptr = &mut addr as *mut libc::sockaddr_storage as *mut libc::sockaddr;
+        if libc::getsockname(fd, addr_ptr, &mut len) == -1 {
+            return Err(io::Error::last_os_error());
+        }
+
+        match addr.ss_family as libc::c_int {
+            libc::AF_INET | libc::AF_INET6 => {
+                let listener = std::net::TcpListener::from_raw_fd(fd);
+                listener.set_nonblocking(true)?;
+                Ok(TcpListener::from_std(listener)?.into())
+            }
+            libc::AF_UNIX => {
+                let listener = std::os::unix::net::UnixListener::from_raw_fd(fd);
+                listener.set_nonblocking(true)?;
+                Ok(UnixListener::from_std(listener)?.into())
+            }
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "systemd passed a socket of an unsupported family",
+            )),
+        }
+    }
+}
@@ -0,0 +1,205 @@
+//! SOCKS over TLS, with client certificates identifying users.
+//!
+//! The crate does not link against a TLS implementation itself. Instead a
+//! [`TlsAcceptor`] runs the handshake on every TCP connection, which lets
+//! deployments plug in rustls, OpenSSL or anything else, while the server
+//! maps the client certificate the acceptor verified to a user. Likewise a
+//! [`TlsConnector`] runs the handshake of the server's own connections,
+//! such as to DNS over TLS or HTTPS servers.
+
+mod sha256;
+mod x509;
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::TcpStream;
+
+/// The future returned by [`TlsAcceptor::accept`] and
+/// [`TlsConnector::connect`].
+pub type TlsFuture = Pin<Box<dyn Future<Output = io::Result<TlsStream>> + Send>>;
+
+/// Runs the server side of the TLS handshake on client connections.
+///
+/// Whether clients are asked for a certificate, and which ones are trusted,
+/// is up to the acceptor: certificates it hands on are taken as verified.
+pub trait TlsAcceptor: Send + Sync {
+    fn accept(&self, stream: TcpStream) -> TlsFuture;
+}
+
+/// Runs the client side of the TLS handshake on connections the server
+/// makes.
+///
+/// The connector must verify that the server's certificate is trusted and
+/// valid for `server_name`: connections it hands on are taken as being to
+/// that server.
+pub trait TlsConnector: Send + Sync {
+    fn connect(&self, server_name: &str, stream: TcpStream) -> TlsFuture;
+}
+
+/// A connection over TLS, as returned by a [`TlsAcceptor`] or a
+/// [`TlsConnector`].
+pub struct TlsStream {
+    stream: Box<dyn Stream>,
+    peer_certificate: Option<Vec<u8>>,
+}
+
+trait Stream: AsyncRead + AsyncWrite + Send + Unpin {}
+
+impl<S: AsyncRead + AsyncWrite + Send + Unpin> Stream for S {}
+
+impl TlsStream {
+    /// Wrap the TLS stream `stream`, whose peer presented the DER-encoded
+    /// certificate `peer_certificate`, if any.
+    pub fn new<S>(stream: S, peer_certificate: Option<Vec<u8>>) -> Self
+    where
+        S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+    {
+        TlsStream {
+            stream: Box::new(stream),
+            peer_certificate,
+        }
+    }
+
+    /// The peer's DER-encoded certificate.
+    pub fn peer_certificate(&self) -> Option<&[u8]> {
+        self.peer_certificate.as_deref()
+    }
+}
+
+impl AsyncRead for TlsStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for TlsStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_shutdown(cx)
+    }
+}
+
+/// Client certificates trusted to identify users, see
+/// [`Server::with_client_cert_auth`].
+///
+/// Clients whose certificate identifies a user skip SOCKS authentication,
+/// if they offer to, and are served as that user. Other clients
+/// authenticate like over plain TCP, unless certificates are required.
+///
+/// ```
+/// use socks5_rs::ClientCertAuth;
+///
+/// # fn run() -> std::io::Result<()> {
+/// let auth = ClientCertAuth::new()
+///     .with_subject("CN=alice,O=Example", "alice")
+///     .with_fingerprint(
+///         "5D:8A:0C:4F:3B:27:9E:61:A0:C2:7F:13:E4:88:B9:06:\
+///          D1:52:6A:F0:3C:97:2B:E5:18:4D:A6:70:C3:F9:8E:21",
+///         "bob",
+///     )?
+///     .with_required(true);
+/// # Ok(())
+/// # }
+/// ```
+///
+/// [`Server::with_client_cert_auth`]: crate::Server::with_client_cert_auth
+#[derive(Clone, Debug, Default)]
+pub struct ClientCertAuth {
+    required: bool,
+    fingerprints: HashMap<[u8; 32], String>,
+    subjects: HashMap<String, String>,
+}
+
+impl ClientCertAuth {
+    /// Trust no certificates yet, and don't require them.
+    pub fn new() -> Self {
+        ClientCertAuth::default()
+    }
+
+    /// Refuse clients without a certificate identifying a user.
+    pub fn with_required(mut self, required: bool) -> Self {
+        self.required = required;
+        self
+    }
+
+    /// Identify the certificate with SHA-256 fingerprint `fingerprint` as
+    /// `user`. The fingerprint is in hex, bytes optionally separated by
+    /// colons, as `openssl x509 -fingerprint -sha256` prints it.
+    ///
+    /// Fails with [`io::ErrorKind::InvalidData`] if `fingerprint` isn't
+    /// 32 bytes of hex.
+    pub fn with_fingerprint(
+        mut self,
+        fingerprint: &str,
+        user: impl Into<String>,
+    ) -> io::Result<Self> {
+        let parsed = parse_fingerprint(fingerprint).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("invalid SHA-256 fingerprint {:?}", fingerprint),
+            )
+        })?;
+        self.fingerprints.insert(parsed, user.into());
+        Ok(self)
+    }
+
+    /// Identify certificates with subject `subject` as `user`. The subject
+    /// is an RFC 4514 string, most specific RDN first, as `openssl x509
+    /// -subject -nameopt RFC2253` prints it.
+    pub fn with_subject(mut self, subject: impl Into<String>, user: impl Into<String>) -> Self {
+        self.subjects.insert(subject.into(), user.into());
+        self
+    }
+
+    pub(crate) fn required(&self) -> bool {
+        self.required
+    }
+
+    /// The user the DER-encoded certificate `certificate` identifies.
+    pub(crate) fn user(&self, certificate: &[u8]) -> Option<&str> {
+        if let Some(user) = self.fingerprints.get(&sha256::sha256(certificate)) {
+            return Some(user);
+        }
+        if self.subjects.is_empty() {
+            return None;
+        }
+        let subject = x509::subject(certificate)?;
+        self.subjects.get(&subject).map(String::as_str)
+    }
+}
+
+fn parse_fingerprint(fingerprint: &str) -> Option<[u8; 32]> {
+    let digits: Vec<u8> = fingerprint
+        .bytes()
+        .filter(|b| !matches!(b, b':' | b' ' | b'\n'))
+        .collect();
+    if digits.len() != 64 || !digits.iter().all(u8::is_ascii_hexdigit) {
+        return None;
+    }
+    let mut parsed = [0u8; 32];
+    for (byte, pair) in parsed.iter_mut().zip(digits.chunks_exact(2)) {
+        let pair = std::str::from_utf8(pair).ok()?;
+        *byte = u8::from_str_radix(pair, 16).ok()?;
+    }
+    Some(parsed)
+}
@@ -0,0 +1,321 @@
+//! Just enough of X.509 (RFC 5280) to read a certificate's subject.
+
+use std::fmt::Write;
+
+const SEQUENCE: u8 = 0x30;
+const SET: u8 = 0x31;
+const OID: u8 = 0x06;
+/// `[0] EXPLICIT Version` of a `TBSCertificate`.
+const VERSION: u8 = 0xa0;
+
+const UTF8_STRING: u8 = 0x0c;
+const PRINTABLE_STRING: u8 = 0x13;
+const TELETEX_STRING: u8 = 0x14;
+const IA5_STRING: u8 = 0x16;
+const BMP_STRING: u8 = 0x1e;
+
+/// Attribute types with a short name in RFC 4514, by DER-encoded OID.
+const NAMES: &[(&[u8], &str)] = &[
+    (&[0x55, 0x04, 0x03], "CN"),
+    (&[0x55, 0x04, 0x06], "C"),
+    (&[0x55, 0x04, 0x07], "L"),
+    (&[0x55, 0x04, 0x08], "ST"),
+    (&[0x55, 0x04, 0x09], "STREET"),
+    (&[0x55, 0x04, 0x0a], "O"),
+    (&[0x55, 0x04, 0x0b], "OU"),
+    (
+        &[0x09, 0x92, 0x26, 0x89, 0x93, 0xf2, 0x2c, 0x64, 0x01, 0x01],
+        "UID",
+    ),
+    (
+        &[0x09, 0x92, 0x26, 0x89, 0x93, 0xf2, 0x2c, 0x64, 0x01, 0x19],
+        "DC",
+    ),
+];
+
+/// The subject of the DER-encoded certificate `der` as an RFC 4514 string,
+/// e.g. `CN=alice,O=Example`. `None` if it can't be parsed.
+pub(crate) fn subject(der: &[u8]) -> Option<String> {
+    let mut certificate = Der(der).expect(SEQUENCE)?;
+    let mut tbs = certificate.expect(SEQUENCE)?;
+    if tbs.peek() == Some(VERSION) {
+        tbs.any()?;
+    }
+    // serialNumber, signature, issuer, validity.
+    for _ in 0..4 {
+        tbs.any()?;
+    }
+    let mut name = tbs.expect(SEQUENCE)?;
+
+    let mut rdns = Vec::new();
+    while !name.0.is_empty() {
+        let mut rdn = name.expect(SET)?;
+        let mut attributes = Vec::new();
+        while !rdn.0.is_empty() {
+            let mut attribute = rdn.expect(SEQUENCE)?;
+            let oid = attribute.expect(OID)?.0;
+            let (tag, value, encoded) = attribute.any()?;
+            attributes.push(format_attribute(oid, tag, value, encoded));
+        }
+        // Reversed too, as OpenSSL prints them. RFC 4514 leaves the order of
+        // a multi-valued RDN open.
+        attributes.reverse();
+        rdns.push(attributes.join("+"));
+    }
+    // RFC 4514 starts from the most specific RDN, the last one.
+    rdns.reverse();
+    Some(rdns.join(","))
+}
+
+fn format_attribute(oid: &[u8], tag: u8, value: &[u8], encoded: &[u8]) -> String {
+    let name = NAMES.iter().find(|(known, _)| *known == oid);
+    let string = match tag {
+        UTF8_STRING | PRINTABLE_STRING | IA5_STRING => String::from_utf8(value.to_vec()).ok(),
+        // Close enough to Latin-1 in practice.
+        TELETEX_STRING => Some(value.iter().map(|&b| b as char).collect()),
+        BMP_STRING if value.len().is_multiple_of(2) => {
+            let units = value
+                .chunks_exact(2)
+                .map(|c| u16::from_be_bytes([c[0], c[1]]));
+            char::decode_utf16(units)
+                .collect::<Result<String, _>>()
+                .ok()
+        }
+        _ => None,
+    };
+    match (name, string) {
+        (Some((_, name)), Some(string)) => format!("{}={}", name, escape(&string)),
+        // Anything else goes by dotted OID and the hex of its encoding.
+        _ => {
+            let mut formatted = format_oid(oid);
+            formatted.push_str("=#");
+            for byte in encoded {
+                let _ = write!(formatted, "{:02x}", byte);
+            }
+            formatted
+        }
+    }
+}
+
+fn format_oid(oid: &[u8]) -> String {
+    let mut arcs = Vec::new();
+    let mut arc = 0u64;
+    for &byte in oid {
+        arc = (arc << 7) | u64::from(byte & 0x7f);
+        if byte & 0x80 == 0 {
+            if arcs.is_empty() {
+                let first = (arc / 40).min(2);
+                arcs.push(first);
+                arcs.push(arc - first * 40);
+            } else {
+                arcs.push(arc);
+            }
+            arc = 0;
+        }
+    }
+    let arcs: Vec<String> = arcs.iter().map(u64::to_string).collect();
+    arcs.join(".")
+}
+
+/// Escape an attribute value (RFC 4514).
+fn escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    let last = value.chars().count().saturating_sub(1);
+    for (i, c) in value.chars().enumerate() {
+        match c {
+            '"' | '+' | ',' | ';' | '<' | '>' | '\\' | '=' => {
+                escaped.push('\\');
+                escaped.push(c);
+            }
+            '#' if i == 0 => escaped.push_str("\\#"),
+            ' ' if i == 0 || i == last => escaped.push_str("\\ "),
+            '\0' => escaped.push_str("\\00"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// DER-encoded values being read.
+struct Der<'a>(&'a [u8]);
+
+impl<'a> Der<'a> {
+    fn peek(&self) -> Option<u8> {
+        self.0.first().copied()
+    }
+
+    /// The next value's tag, contents, and whole encoding.
+    fn any(&mut self) -> Option<(u8, &'a [u8], &'a [u8])> {
+        let (&tag, rest) = self.0.split_first()?;
+        let (&first, mut rest) = rest.split_first()?;
+        let len = if first < 0x80 {
+            usize::from(first)
+        } else {
+            let n = usize::from(first & 0x7f);
+            if n == 0 || n > 4 || rest.len() < n {
+                return None;
+            }
+            let len = rest[..n]
+                .iter()
+                .fold(0usize, |len, &b| (len << 8) | usize::from(b));
+            rest = &rest[n..];
+            len
+        };
+        if rest.len() < len {
+            return None;
+        }
+        let header = self.0.len() - rest.len();
+        let encoded = &self.0[..header + len];
+        self.0 = &rest[len..];
+        Some((tag, &rest[..len], encoded))
+    }
+
+    /// The contents of the next value, which must have tag `tag`.
+    fn expect(&mut self, tag: u8) -> Option<Der<'a>> {
+        match self.any()? {
+            (found, contents, _) if found == tag => Some(Der(contents)),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tls::sha256::sha256;
+
+    /// A self-signed certificate, made by `openssl req -x509 -multivalue-rdn
+    /// -subj "/DC=org/DC=example/O=Example, Inc./OU=Eng+UID=alice/CN=Alice
+    /// Liddell"`.
+    const ALICE: &str = concat!(
+        "3082025a30820201a00302010202145b8d3033a31b039f3eea48eb002202c195",
+        "6dff3a300a06082a8648ce3d04030230818131133011060a0992268993f22c64",
+        "011916036f726731173015060a0992268993f22c64011916076578616d706c65",
+        "31163014060355040a0c0d4578616d706c652c20496e632e3121300a06035504",
+        "0b0c03456e673013060a0992268993f22c6401010c05616c6963653116301406",
+        "035504030c0d416c696365204c696464656c6c3020170d323631303135303431",
+        "3931335a180f32313236303932313034313931335a30818131133011060a0992",
+        "268993f22c64011916036f726731173015060a0992268993f22c640119160765",
+        "78616d706c6531163014060355040a0c0d4578616d706c652c20496e632e3121",
+        "300a060355040b0c03456e673013060a0992268993f22c6401010c05616c6963",
+        "653116301406035504030c0d416c696365204c696464656c6c3059301306072a",
+        "8648ce3d020106082a8648ce3d03010703420004ae08659f203bbc38bbef0cf5",
+        "10212d9acca8e783f121b4b6a65c6ed8f0daaa38c2060419a802b19111089622",
+        "0c79c4db4a9858a887aafddd6d0a710ddb6a3907a3533051301d0603551d0e04",
+        "160414cd44dc9f14a23a547225a7ff6c92881e10c78f6c301f0603551d230418",
+        "30168014cd44dc9f14a23a547225a7ff6c92881e10c78f6c300f0603551d1301",
+        "01ff040530030101ff300a06082a8648ce3d0403020347003044022069ec928f",
+        "88b4977381a22d7133bbf1aa4aaeb31f3995298172e45da03bbff27802206077",
+        "6a98615fd80df6bc368f65f5bab4818200569b3d1475636b870f6a4c55d5",
+    );
+
+    fn unhex(hex: &str) -> Vec<u8> {
+        (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn certificate() {
+        let der = unhex(ALICE);
+        // As `openssl x509 -subject -nameopt RFC2253` prints them.
+        assert_eq!(
+            subject(&der).as_deref(),
+            Some("CN=Alice Liddell,UID=alice+OU=Eng,O=Example\\, Inc.,DC=example,DC=org")
+        );
+        // And `openssl x509 -fingerprint -sha256`.
+        assert_eq!(
+            sha256(&der)[..],
+            unhex("e3b05a827191410854306ab80fe34e15dce75a893f2799b598ea4a50e6fe050e")[..]
+        );
+    }
+
+    #[test]
+    fn truncated() {
+        let der = unhex(ALICE);
+        for len in 0..der.len() {
+            assert_eq!(subject(&der[..len]), None, "{} bytes", len);
+        }
+    }
+
+    #[test]
+    fn corrupt() {
+        let der = unhex(ALICE);
+        for i in 0..der.len() {
+            for flip in [0x01, 0x80, 0xff] {
+                let mut corrupt = der.clone();
+                corrupt[i] ^= flip;
+                subject(&corrupt);
+            }
+        }
+    }
+
+    #[test]
+    fn malformed() {
+        for der in [
+            // Not a sequence.
+            &[0x31, 0x00][..],
+            // Empty.
+            &[0x30, 0x00],
+            // Longer than the input.
+            &[0x30, 0x03, 0x30, 0x00],
+            &[0x30, 0x81],
+            &[0x30, 0x82, 0xff],
+            // Indefinite, and longer than four bytes.
+            &[0x30, 0x80, 0x00, 0x00],
+            &[0x30, 0x85, 0x00, 0x00, 0x00, 0x00, 0x02, 0x30, 0x00],
+            &[0x30, 0x84, 0xff, 0xff, 0xff, 0xff, 0x30, 0x00],
+            // Fields missing from the TBSCertificate.
+            &[0x30, 0x04, 0x30, 0x02, 0x02, 0x00],
+            // A name that isn't a sequence of sets of sequences.
+            &[
+                0x30, 0x0c, 0x30, 0x0a, 0x02, 0x00, 0x30, 0x00, 0x30, 0x00, 0x30, 0x00, 0x31, 0x00,
+            ],
+            &[
+                0x30, 0x0e, 0x30, 0x0c, 0x02, 0x00, 0x30, 0x00, 0x30, 0x00, 0x30, 0x00, 0x30, 0x02,
+                0x30, 0x00,
+            ],
+            &[
+                0x30, 0x10, 0x30, 0x0e, 0x02, 0x00, 0x30, 0x00, 0x30, 0x00, 0x30, 0x00, 0x30, 0x04,
+                0x31, 0x02, 0x30, 0x00,
+            ],
+        ] {
+            assert_eq!(subject(der), None, "{:02x?}", der);
+        }
+    }
+
+    #[test]
+    fn attributes() {
+        // An empty name, with and without a version.
+        let empty = [
+            0x30, 0x0c, 0x30, 0x0a, 0x02, 0x00, 0x30, 0x00, 0x30, 0x00, 0x30, 0x00, 0x30, 0x00,
+        ];
+        assert_eq!(subject(&empty).as_deref(), Some(""));
+        let versioned = [
+            0x30, 0x11, 0x30, 0x0f, 0xa0, 0x03, 0x02, 0x01, 0x02, 0x02, 0x00, 0x30, 0x00, 0x30,
+            0x00, 0x30, 0x00, 0x30, 0x00,
+        ];
+        assert_eq!(subject(&versioned).as_deref(), Some(""));
+
+        let cn = &[0x55, 0x04, 0x03][..];
+        for (tag, value, formatted) in [
+            (UTF8_STRING, &b"#a,b+c "[..], "CN=\\#a\\,b\\+c\\ "),
+            (PRINTABLE_STRING, b" x", "CN=\\ x"),
+            (TELETEX_STRING, b"\xe9", "CN=\u{e9}"),
+            (BMP_STRING, b"\x00a\x00\xe9", "CN=a\u{e9}"),
+            // Odd-length BMP strings and unknown types go in hex.
+            (BMP_STRING, b"\x00", "2.5.4.3=#1e0100"),
+            (0x04, b"ab", "2.5.4.3=#04026162"),
+        ] {
+            let mut encoded = vec![tag, value.len() as u8];
+            encoded.extend_from_slice(value);
+            assert_eq!(format_attribute(cn, tag, value, &encoded), formatted);
+        }
+        let unknown = [0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x09, 0x01];
+        assert_eq!(
+            format_attribute(&unknown, IA5_STRING, b"a", &[0x16, 0x01, b'a']),
+            "1.2.840.113549.1.9.1=#160161"
+        );
+    }
+}
@@ -0,0 +1,378 @@
+//! Time-based one-time passwords (RFC 6238) in the RFC 1929 password field,
+//! so that a stolen static password alone doesn't get anyone in.
+
+mod sha1;
+
+use std::collections::HashMap;
+use std::future;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::auth::{AuthFuture, Authenticator, Decision};
+use crate::passwd::constant_time_eq;
+use crate::UserPolicy;
+use sha1::hmac_sha1;
+
+/// An [`Authenticator`] checking one-time codes from users' authenticator
+/// apps, HMAC-SHA1 based as most apps expect.
+///
+/// Clients send either just the code as the password, or, to keep a second
+/// factor, their password followed by the code, with the password checked
+/// by another authenticator. Each code is accepted once, and users without a
+/// secret are denied.
+///
+/// ```
+/// use socks5_rs::{HtpasswdFile, TotpAuthenticator};
+///
+/// # fn run() -> std::io::Result<()> {
+/// let users = HtpasswdFile::load("/etc/socks5/users")?;
+/// let totp = TotpAuthenticator::password_and_code(users)
+///     .with_secret("alice", "JBSWY3DPEHPK3PXP")?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct TotpAuthenticator {
+    /// Checks the password in front of the code, if there is one.
+    password: Option<Arc<dyn Authenticator>>,
+    secrets: HashMap<String, Vec<u8>>,
+    digits: u32,
+    step: Duration,
+    skew: u64,
+    /// Username -> time step of the last code accepted.
+    last_used: Mutex<HashMap<String, u64>>,
+}
+
+impl TotpAuthenticator {
+    /// Take the password to be just the code.
+    pub fn code_only() -> Self {
+        TotpAuthenticator {
+            password: None,
+            secrets: HashMap::new(),
+            digits: 6,
+            step: Duration::from_secs(30),
+            skew: 1,
+            last_used: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Take the password to be the user's password followed by the code,
+    /// with the password checked by `password`.
+    pub fn password_and_code<A: Authenticator + 'static>(password: A) -> Self {
+        TotpAuthenticator {
+            password: Some(Arc::new(password)),
+            ..Self::code_only()
+        }
+    }
+
+    /// Check the codes of `username` against `secret`, in base32 as shown
+    /// to users when enrolling them (`otpauth://` URIs carry it too).
+    ///
+    /// Fails with [`io::ErrorKind::InvalidData`] if `secret` isn't base32.
+    pub fn with_secret(mut self, username: impl Into<String>, secret: &str) -> io::Result<Self> {
+        let username = username.into();
+        let secret = base32_decode(secret).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("TOTP secret of {:?} isn't base32", username),
+            )
+        })?;
+        self.secrets.insert(username, secret);
+        Ok(self)
+    }
+
+    /// Digits of a code, 6 by default.
+    ///
+    /// Fails with [`io::ErrorKind::InvalidInput`] unless `digits` is from 6
+    /// to 9.
+    pub fn with_digits(mut self, digits: u32) -> io::Result<Self> {
+        if !(6..=9).contains(&digits) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("TOTP codes have 6 to 9 digits, not {}", digits),
+            ));
+        }
+        self.digits = digits;
+        Ok(self)
+    }
+
+    /// How long a code is current, 30 seconds by default.
+    pub fn with_step(mut self, step: Duration) -> Self {
+        self.step = step.max(Duration::from_secs(1));
+        self
+    }
+
+    /// Also accept codes up to `steps` time steps old or early, for clock
+    /// drift and typing time. Defaults to 1.
+    pub fn with_skew(mut self, steps: u64) -> Self {
+        self.skew = steps;
+        self
+    }
+
+    /// The time step of the earliest unused code in the window around
+    /// `now` that matches `code`.
+    fn matching_step(
+        &self,
+        username: &str,
+        secret: &[u8],
+        code: &[u8],
+        now: SystemTime,
+    ) -> Option<u64> {
+        let now = now.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        let current = now / self.step.as_secs();
+        let last_used = self.last_used.lock().unwrap().get(username).copied();
+        let first = current.saturating_sub(self.skew);
+        (first..=current + self.skew)
+            .filter(|&step| last_used.is_none_or(|last| step > last))
+            .find(|&step| constant_time_eq(self.code(secret, step).as_bytes(), code))
+    }
+
+    /// The code of `secret` for time step `step` (RFC 4226 HOTP).
+    fn code(&self, secret: &[u8], step: u64) -> String {
+        let mac = hmac_sha1(secret, &step.to_be_bytes());
+        let offset = usize::from(mac[19] & 0x0f);
+        let value = u32::from_be_bytes([
+            mac[offset],
+            mac[offset + 1],
+            mac[offset + 2],
+            mac[offset + 3],
+        ]) & 0x7fff_ffff;
+        format!(
+            "{:0width$}",
+            value % 10u32.pow(self.digits),
+            width = self.digits as usize
+        )
+    }
+
+    /// Mark the code of time step `step` used. Fails if it or a later one
+    /// was used meanwhile.
+    fn use_step(&self, username: &str, step: u64) -> bool {
+        let mut last_used = self.last_used.lock().unwrap();
+        match last_used.get(username) {
+            Some(&last) if last >= step => false,
+            _ => {
+                last_used.insert(username.to_owned(), step);
+                true
+            }
+        }
+    }
+}
+
+impl Authenticator for TotpAuthenticator {
+    fn verify<'a>(
+        &'a self,
+        username: &'a str,
+        password: &'a [u8],
+        peer: SocketAddr,
+    ) -> AuthFuture<'a> {
+        let deny = || -> AuthFuture<'a> { Box::pin(future::ready(Decision::Deny)) };
+        let digits = self.digits as usize;
+        let secret = match self.secrets.get(username) {
+            Some(secret) if password.len() >= digits => secret,
+            _ => return deny(),
+        };
+        let (static_password, code) = password.split_at(password.len() - digits);
+        if self.password.is_none() && !static_password.is_empty() {
+            return deny();
+        }
+        let step = match self.matching_step(username, secret, code, SystemTime::now()) {
+            Some(step) => step,
+            None => return deny(),
+        };
+        Box::pin(async move {
+            if let Some(authenticator) = &self.password {
+                let decision = authenticator.verify(username, static_password, peer).await;
+                if decision != Decision::Allow {
+                    return decision;
+                }
+            }
+            if self.use_step(username, step) {
+                Decision::Allow
+            } else {
+                log::info!("refusing {:?} from {}: code replayed", username, peer);
+                Decision::Deny
+            }
+        })
+    }
+
+    fn policy(&self, username: &str) -> Option<UserPolicy> {
+        self.password
+            .as_ref()
+            .and_then(|authenticator| authenticator.policy(username))
+    }
+}
+
+/// Decode base32 (RFC 4648), ignoring case, spaces and padding.
+fn base32_decode(encoded: &str) -> Option<Vec<u8>> {
+    const ALPHABET: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+    let mut decoded = Vec::new();
+    let (mut bits, mut pending) = (0u32, 0u32);
+    for c in encoded.bytes().filter(|&c| !matches!(c, b' ' | b'=')) {
+        let value = ALPHABET.iter().position(|&a| a == c.to_ascii_uppercase())?;
+        bits = (bits << 5) | value as u32;
+        pending += 5;
+        if pending >= 8 {
+            pending -= 8;
+            decoded.push((bits >> pending) as u8);
+        }
+    }
+    if decoded.is_empty() {
+        return None;
+    }
+    Some(decoded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The SHA-1 secret of RFC 6238's test vectors, "12345678901234567890".
+    const SECRET: &str = "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ";
+
+    fn totp() -> TotpAuthenticator {
+        TotpAuthenticator::code_only()
+            .with_secret("alice", SECRET)
+            .unwrap()
+    }
+
+    fn at(secs: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(secs)
+    }
+
+    fn code_at(totp: &TotpAuthenticator, secs: u64) -> String {
+        totp.code(&totp.secrets["alice"], secs / totp.step.as_secs())
+    }
+
+    fn matches(totp: &TotpAuthenticator, code: &str, secs: u64) -> Option<u64> {
+        totp.matching_step("alice", &totp.secrets["alice"], code.as_bytes(), at(secs))
+    }
+
+    fn peer() -> SocketAddr {
+        "127.0.0.1:1".parse().unwrap()
+    }
+
+    #[test]
+    fn rfc6238_sha1() {
+        let totp = totp().with_digits(8).unwrap();
+        for (secs, code) in [
+            (59, "94287082"),
+            (1_111_111_109, "07081804"),
+            (1_111_111_111, "14050471"),
+            (1_234_567_890, "89005924"),
+            (2_000_000_000, "69279037"),
+            (20_000_000_000, "65353130"),
+        ] {
+            assert_eq!(code_at(&totp, secs), code, "{}", secs);
+            assert_eq!(matches(&totp, code, secs), Some(secs / 30));
+        }
+    }
+
+    #[test]
+    fn six_digits() {
+        // The last 6 digits of the 8 digit codes.
+        assert_eq!(code_at(&totp(), 59), "287082");
+        assert_eq!(code_at(&totp(), 1_111_111_109), "081804");
+    }
+
+    #[test]
+    fn digits_out_of_range() {
+        for digits in [0, 5, 10] {
+            let err = totp().with_digits(digits).err().unwrap();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        }
+        assert!(totp().with_digits(9).is_ok());
+    }
+
+    #[test]
+    fn invalid_secret() {
+        let err = TotpAuthenticator::code_only()
+            .with_secret("alice", "not base32!")
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(TotpAuthenticator::code_only()
+            .with_secret("alice", "")
+            .is_err());
+        // Case, spaces and padding don't matter.
+        assert!(TotpAuthenticator::code_only()
+            .with_secret("alice", "gezd gnbv gy3t qojq====")
+            .is_ok());
+    }
+
+    #[test]
+    fn skew() {
+        let now = 1_234_567_890;
+        let step = now / 30;
+        let totp = totp();
+        for (offset, accepted) in [(-2, false), (-1, true), (0, true), (1, true), (2, false)] {
+            let secs = (now as i64 + offset * 30) as u64;
+            let code = code_at(&totp, secs);
+            let expected = if accepted {
+                Some((step as i64 + offset) as u64)
+            } else {
+                None
+            };
+            assert_eq!(matches(&totp, &code, now), expected, "{}", offset);
+        }
+
+        let strict = totp.with_skew(0);
+        assert_eq!(matches(&strict, &code_at(&strict, now), now), Some(step));
+        assert_eq!(matches(&strict, &code_at(&strict, now - 30), now), None);
+
+        let lenient = TotpAuthenticator::code_only()
+            .with_secret("alice", SECRET)
+            .unwrap()
+            .with_skew(3);
+        assert_eq!(
+            matches(&lenient, &code_at(&lenient, now - 90), now),
+            Some(step - 3)
+        );
+    }
+
+    #[test]
+    fn replay() {
+        let now = 1_234_567_890;
+        let step = now / 30;
+        let totp = totp();
+        let code = code_at(&totp, now);
+        assert_eq!(matches(&totp, &code, now), Some(step));
+        assert!(totp.use_step("alice", step));
+
+        // Neither the code used, nor an earlier one, is accepted again.
+        assert_eq!(matches(&totp, &code, now), None);
+        assert!(!totp.use_step("alice", step));
+        assert_eq!(matches(&totp, &code_at(&totp, now - 30), now), None);
+        assert!(!totp.use_step("alice", step - 1));
+        // A later one is.
+        let next = code_at(&totp, now + 30);
+        assert_eq!(matches(&totp, &next, now), Some(step + 1));
+        assert!(totp.use_step("alice", step + 1));
+        // Other users aren't affected.
+        assert!(totp.use_step("bob", step));
+    }
+
+    #[tokio::test]
+    async fn verify() {
+        let totp = totp();
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let code = code_at(&totp, now);
+        for (username, password) in [
+            ("bob", code.clone()),
+            ("alice", "00000x".to_owned()),
+            ("alice", "12345".to_owned()),
+            // There is no password in front of the code to check.
+            ("alice", format!("pw{}", code)),
+        ] {
+            let decision = totp.verify(username, password.as_bytes(), peer()).await;
+            assert_eq!(decision, Decision::Deny, "{} {}", username, password);
+        }
+        let decision = totp.verify("alice", code.as_bytes(), peer()).await;
+        assert_eq!(decision, Decision::Allow);
+        let decision = totp.verify("alice", code.as_bytes(), peer()).await;
+        assert_eq!(decision, Decision::Deny);
+    }
+}
@@ -0,0 +1,266 @@
+//! A set of domain names and domains, for matching names against lists of
+//! millions of them.
+//!
+//! Names are stored label by label, from the top-level domain down, so that
+//! a lookup walks the labels of the name once, whatever the size of the
+//! set. The nodes are kept in one array, their labels in one string, and the
+//! edges in an open-addressing hash table from a node and a label to the
+//! child, so that each label takes a single probe, and each node a dozen
+//! bytes plus its label.
+
+/// Flags of a node.
+pub(crate) type Flags = u8;
+
+/// The name itself is in the set.
+pub(crate) const EXACT: Flags = 1;
+/// The name and its subdomains are in the set.
+pub(crate) const SUBTREE: Flags = 2;
+/// The name and its subdomains are exempt from the set.
+pub(crate) const EXEMPT: Flags = 4;
+
+/// Marks an empty slot of the edge table.
+const EMPTY: u32 = u32::MAX;
+
+#[derive(Clone, Debug)]
+pub(crate) struct DomainTrie {
+    labels: String,
+    /// The root, which has no label, first.
+    nodes: Vec<Node>,
+    /// Indices of nodes, by the hash of their parent and label. A power of
+    /// two long, and at most three quarters full.
+    edges: Vec<u32>,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Node {
+    parent: u32,
+    label_start: u32,
+    /// Labels are at most 63 bytes long.
+    label_len: u8,
+    flags: Flags,
+}
+
+impl Default for DomainTrie {
+    fn default() -> Self {
+        DomainTrie {
+            labels: String::new(),
+            nodes: vec![Node {
+                parent: EMPTY,
+                label_start: 0,
+                label_len: 0,
+                flags: 0,
+            }],
+            edges: vec![EMPTY; 16],
+        }
+    }
+}
+
+impl DomainTrie {
+    /// Set `flags` on `name`, a lowercase domain name without a trailing
+    /// dot, with labels of up to 63 bytes. Returns whether any weren't set.
+    pub(crate) fn insert(&mut self, name: &str, flags: Flags) -> bool {
+        let mut node = 0;
+        for label in name.rsplit('.') {
+            node = match self.find(node, label) {
+                Ok(child) => child,
+                Err(slot) => self.add(node, label, slot),
+            };
+        }
+        let node = &mut self.nodes[node as usize];
+        let new = node.flags & flags != flags;
+        node.flags |= flags;
+        new
+    }
+
+    /// The flags of `name`, a lowercase domain name without a trailing dot,
+    /// and of each domain it is a subdomain of, ORed together. Of the flags
+    /// of the name's parents, only [`SUBTREE`] and [`EXEMPT`] are included.
+    pub(crate) fn lookup(&self, name: &str) -> Flags {
+        let mut found = 0;
+        let mut node = 0;
+        for label in name.rsplit('.') {
+            node = match self.find(node, label) {
+                Ok(child) => child,
+                Err(_) => return found,
+            };
+            found |= self.nodes[node as usize].flags & (SUBTREE | EXEMPT);
+        }
+        found | self.nodes[node as usize].flags
+    }
+
+    /// Release the memory reserved for more names.
+    pub(crate) fn shrink_to_fit(&mut self) {
+        self.labels.shrink_to_fit();
+        self.nodes.shrink_to_fit();
+    }
+
+    /// The child of `parent` labelled `label`, or else the empty slot of the
+    /// edge table for it.
+    fn find(&self, parent: u32, label: &str) -> Result<u32, usize> {
+        let mask = self.edges.len() - 1;
+        let mut slot = hash(parent, label) & mask;
+        loop {
+            let node = self.edges[slot];
+            if node == EMPTY {
+                return Err(slot);
+            }
+            let candidate = &self.nodes[node as usize];
+            if candidate.parent == parent && self.label(candidate) == label {
+                return Ok(node);
+            }
+            slot = (slot + 1) & mask;
+        }
+    }
+
+    /// Add a child of `parent` labelled `label`, at `slot` of the edge table.
+    fn add(&mut self, parent: u32, label: &str, slot: usize) -> u32 {
+        let node = self.nodes.len() as u32;
+        self.nodes.push(Node {
+            parent,
+            label_start: self.labels.len() as u32,
+            label_len: label.len() as u8,
+            flags: 0,
+        });
+        self.labels.push_str(label);
+        if self.nodes.len() * 4 > self.edges.len() * 3 {
+            self.grow();
+        } else {
+            self.edges[slot] = node;
+        }
+        node
+    }
+
+    /// Double the edge table, placing every node in it anew.
+    fn grow(&mut self) {
+        self.edges = vec![EMPTY; self.edges.len() * 2];
+        let mask = self.edges.len() - 1;
+        for (index, node) in self.nodes.iter().enumerate().skip(1) {
+            let mut slot = hash(node.parent, self.label(node)) & mask;
+            while self.edges[slot] != EMPTY {
+                slot = (slot + 1) & mask;
+            }
+            self.edges[slot] = index as u32;
+        }
+    }
+
+    fn label(&self, node: &Node) -> &str {
+        let start = node.label_start as usize;
+        &self.labels[start..start + usize::from(node.label_len)]
+    }
+}
+
+/// FNV-1a of a node index and a label, which are short enough for it to
+/// beat stronger hashes. The lists hashed are the operator's, so there is
+/// no one to flood it.
+fn hash(parent: u32, label: &str) -> usize {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for &byte in parent.to_le_bytes().iter().chain(label.as_bytes()) {
+        hash ^= u64::from(byte);
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    hash as usize
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exact() {
+        let mut trie = DomainTrie::default();
+        assert!(trie.insert("ads.example.com", EXACT));
+        assert!(!trie.insert("ads.example.com", EXACT));
+        assert_eq!(trie.lookup("ads.example.com"), EXACT);
+        // Neither its subdomains nor its parents, which are only nodes.
+        assert_eq!(trie.lookup("x.ads.example.com"), 0);
+        assert_eq!(trie.lookup("example.com"), 0);
+        assert_eq!(trie.lookup("com"), 0);
+        assert_eq!(trie.lookup("ads.example.org"), 0);
+        assert_eq!(trie.lookup("sads.example.com"), 0);
+    }
+
+    #[test]
+    fn subtree() {
+        let mut trie = DomainTrie::default();
+        assert!(trie.insert("example.com", SUBTREE));
+        assert_eq!(trie.lookup("example.com"), SUBTREE);
+        assert_eq!(trie.lookup("ads.example.com"), SUBTREE);
+        assert_eq!(trie.lookup("a.b.c.example.com"), SUBTREE);
+        assert_eq!(trie.lookup("com"), 0);
+        assert_eq!(trie.lookup("myexample.com"), 0);
+        assert_eq!(trie.lookup("example.com.au"), 0);
+
+        // Flags add up, on the name and along the way.
+        assert!(trie.insert("example.com", EXACT));
+        assert!(!trie.insert("example.com", EXACT | SUBTREE));
+        assert_eq!(trie.lookup("example.com"), EXACT | SUBTREE);
+        assert_eq!(trie.lookup("ads.example.com"), SUBTREE);
+        assert!(trie.insert("x.ads.example.com", EXACT));
+        assert_eq!(trie.lookup("x.ads.example.com"), EXACT | SUBTREE);
+    }
+
+    #[test]
+    fn exempt() {
+        let mut trie = DomainTrie::default();
+        trie.insert("example.com", SUBTREE);
+        trie.insert("cdn.example.com", EXEMPT);
+        trie.insert("ads.cdn.example.com", EXACT);
+        assert_eq!(trie.lookup("example.com"), SUBTREE);
+        assert_eq!(trie.lookup("www.example.com"), SUBTREE);
+        // Exemptions are passed down like blocks are, so that both are seen
+        // and the exemption can win over the parent's block.
+        assert_eq!(trie.lookup("cdn.example.com"), SUBTREE | EXEMPT);
+        assert_eq!(trie.lookup("img.cdn.example.com"), SUBTREE | EXEMPT);
+        assert_eq!(trie.lookup("ads.cdn.example.com"), EXACT | SUBTREE | EXEMPT);
+        assert_eq!(trie.lookup("cdn.example.org"), 0);
+    }
+
+    #[test]
+    fn single_labels_and_the_root() {
+        let mut trie = DomainTrie::default();
+        trie.insert("localhost", EXACT);
+        trie.insert("test", SUBTREE);
+        assert_eq!(trie.lookup("localhost"), EXACT);
+        assert_eq!(trie.lookup("a.localhost"), 0);
+        assert_eq!(trie.lookup("a.test"), SUBTREE);
+        assert_eq!(trie.lookup(""), 0);
+    }
+
+    #[test]
+    fn same_labels_under_different_parents() {
+        let mut trie = DomainTrie::default();
+        trie.insert("www.example.com", EXACT);
+        trie.insert("www.example.org", SUBTREE);
+        trie.insert("example.www", EXEMPT);
+        assert_eq!(trie.lookup("www.example.com"), EXACT);
+        assert_eq!(trie.lookup("www.example.org"), SUBTREE);
+        assert_eq!(trie.lookup("example.www"), EXEMPT);
+        assert_eq!(trie.lookup("www.example.net"), 0);
+        assert_eq!(trie.lookup("www"), 0);
+    }
+
+    #[test]
+    fn grows() {
+        let mut trie = DomainTrie::default();
+        let name = |i: usize| format!("host{}.zone{}.example{}", i, i % 97, i % 3);
+        for i in 0..20_000 {
+            let flags = [EXACT, SUBTREE, EXEMPT][i % 3];
+            assert!(trie.insert(&name(i), flags));
+            assert!(trie.edges.len().is_power_of_two());
+            assert!(trie.nodes.len() * 4 <= trie.edges.len() * 3);
+        }
+        assert!(trie.edges.len() > 16);
+        trie.shrink_to_fit();
+        // Every edge survived each rehash.
+        for i in 0..20_000 {
+            let flags = [EXACT, SUBTREE, EXEMPT][i % 3];
+            assert_eq!(trie.lookup(&name(i)), flags, "{}", name(i));
+            let sub = format!("www.{}", name(i));
+            assert_eq!(trie.lookup(&sub), flags & !EXACT, "{}", sub);
+        }
+        assert_eq!(trie.lookup("host20000.zone0.example0"), 0);
+        assert_eq!(trie.lookup("host1.zone2.example1"), 0);
+        // The root, three domains, 291 zones and the hosts.
+        assert_eq!(trie.nodes.len(), 1 + 3 + 291 + 20_000);
+    }
+}
@@ -0,0 +1,590 @@
+//! UDP ASSOCIATE relay (RFC 1928 section 7).
+
+use std::collections::{HashMap, HashSet};
+use std::future::{self, Future};
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::Poll;
+use std::time::{Duration, Instant};
+
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::UdpSocket,
+    time,
+};
+
+use crate::config::{Requester, Routed};
+use crate::protocol::{Command, Parse, Reply, TargetAddr, UdpHeader};
+#[cfg(unix)]
+use crate::sockopt;
+#[cfg(unix)]
+use crate::UserPolicy;
+use crate::{reply, ServerConfig};
+
+/// Largest datagram we relay in either direction.
+const MAX_DATAGRAM: usize = 65535;
+
+/// How long a partially reassembled datagram is kept. RFC 1928 asks for no
+/// less than 5 seconds.
+const REASSEMBLY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Marks the last fragment of a sequence in the FRAG field.
+const FRAG_END: u8 = 0x80;
+
+/// How long the check of a destination is relied on, so that changes to
+/// rules, ACLs and DNS catch up with long associations.
+const DESTINATION_TTL: Duration = Duration::from_secs(30);
+
+/// Destinations an association keeps the checks of.
+const MAX_DESTINATIONS: usize = 1024;
+
+/// Datagrams held for a destination while it is checked.
+const MAX_PENDING: usize = 16;
+
+/// What to do with client datagrams whose FRAG field is non-zero.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum FragPolicy {
+    /// Drop fragments, like most implementations do.
+    #[default]
+    Drop,
+    /// Reassemble fragments of up to `max_len` bytes in total before
+    /// relaying them as one datagram.
+    Reassemble { max_len: usize },
+}
+
+/// Occupancy counters of a server's UDP association table.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct UdpStats {
+    /// Associations currently open.
+    pub active: usize,
+    /// Highest number of associations open at once.
+    pub peak: usize,
+    /// Associations opened since the server started.
+    pub total: u64,
+    /// Associations refused because the table was full.
+    pub rejected: u64,
+    /// Associations closed for being idle.
+    pub expired: u64,
+}
+
+/// One open association, as listed by [`Server::udp_associations`].
+///
+/// [`Server::udp_associations`]: crate::Server::udp_associations
+#[derive(Clone, Debug)]
+pub struct UdpAssociation {
+    /// Address of the controlling TCP connection.
+    pub control: SocketAddr,
+    /// Address the client sends datagrams from, once known.
+    pub client: Option<SocketAddr>,
+    /// Our relay socket.
+    pub relay: SocketAddr,
+    /// Time since a datagram was last relayed.
+    pub idle: Duration,
+}
+
+/// Table of open associations, keyed by the address of their controlling
+/// connection.
+#[derive(Default)]
+pub(crate) struct Associations {
+    entries: Mutex<HashMap<SocketAddr, Entry>>,
+    peak: AtomicUsize,
+    total: AtomicU64,
+    rejected: AtomicU64,
+    expired: AtomicU64,
+}
+
+struct Entry {
+    client: Option<SocketAddr>,
+    relay: SocketAddr,
+    last_active: Instant,
+}
+
+impl Associations {
+    /// Add an association unless `max` are open already. It is removed again
+    /// when the returned guard is dropped.
+    fn insert(
+        self: &Arc<Self>,
+        control: SocketAddr,
+        relay: SocketAddr,
+        max: Option<usize>,
+    ) -> Option<AssociationGuard> {
+        let mut entries = self.entries.lock().unwrap();
+        if max.is_some_and(|max| entries.len() >= max) {
+            self.rejected.fetch_add(1, Ordering::Relaxed);
+            return None;
+        }
+
+        entries.insert(
+            control,
+            Entry {
+                client: None,
+                relay,
+                last_active: Instant::now(),
+            },
+        );
+        self.peak.fetch_max(entries.len(), Ordering::Relaxed);
+        self.total.fetch_add(1, Ordering::Relaxed);
+
+        Some(AssociationGuard {
+            table: self.clone(),
+            control,
+        })
+    }
+
+    pub(crate) fn stats(&self) -> UdpStats {
+        UdpStats {
+            active: self.entries.lock().unwrap().len(),
+            peak: self.peak.load(Ordering::Relaxed),
+            total: self.total.load(Ordering::Relaxed),
+            rejected: self.rejected.load(Ordering::Relaxed),
+            expired: self.expired.load(Ordering::Relaxed),
+        }
+    }
+
+    pub(crate) fn list(&self) -> Vec<UdpAssociation> {
+        self.entries
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(&control, entry)| UdpAssociation {
+                control,
+                client: entry.client,
+                relay: entry.relay,
+                idle: entry.last_active.elapsed(),
+            })
+            .collect()
+    }
+}
+
+struct AssociationGuard {
+    table: Arc<Associations>,
+    control: SocketAddr,
+}
+
+impl AssociationGuard {
+    /// Record traffic from or to `client`.
+    fn touch(&self, client: Option<SocketAddr>) {
+        if let Some(entry) = self.table.entries.lock().unwrap().get_mut(&self.control) {
+            entry.client = client;
+            entry.last_active = Instant::now();
+        }
+    }
+}
+
+impl Drop for AssociationGuard {
+    fn drop(&mut self) {
+        self.table.entries.lock().unwrap().remove(&self.control);
+    }
+}
+
+/// The reassembly queue of one association.
+struct Reassembly {
+    dst: TargetAddr,
+    /// FRAG position of the last fragment queued.
+    position: u8,
+    data: Vec<u8>,
+    started: Instant,
+}
+
+/// Run a UDP association for the client on `stream`.
+///
+/// A relay socket is bound on the same local IP the client reached us on and
+/// its address is returned in the reply. Datagrams are relayed until the
+/// controlling TCP connection is closed by the client, or nothing has been
+/// relayed for the configured idle timeout. Datagrams to destinations the
+/// user's policy, the rules or the destination ACL don't allow are dropped.
+/// So are datagrams from anywhere but the client and the destinations it
+/// sent to, and only relayed datagrams keep the association alive.
+///
+/// Destinations are checked and resolved alongside relaying, so that a slow
+/// lookup only holds up the datagrams waiting on it, and the outcome is
+/// reused for `DESTINATION_TTL`.
+pub(crate) async fn associate<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    local: SocketAddr,
+    config: &ServerConfig,
+    table: &Arc<Associations>,
+    req_addr: &TargetAddr,
+    requester: &Requester<'_>,
+) -> io::Result<()> {
+    let peer = requester.peer;
+    let socket = UdpSocket::bind(SocketAddr::new(local.ip(), 0)).await?;
+    let relay = socket.local_addr()?;
+    // Rules are matched per datagram, so only the user's DSCP or the
+    // server's applies to the relay.
+    #[cfg(unix)]
+    if let Some(dscp) = requester.policy.and_then(UserPolicy::dscp).or(config.dscp) {
+        if let Err(e) = sockopt::set_dscp(&socket, relay.is_ipv6(), dscp) {
+            log::debug!("setting the DSCP of the UDP relay {} failed: {}", relay, e);
+        }
+    }
+
+    let guard = match table.insert(peer, relay, config.udp_max_associations) {
+        Some(guard) => guard,
+        None => {
+            stream
+                .write_all(&reply(Reply::GeneralFailure, relay))
+                .await?;
+            return Err(io::Error::other("too many UDP associations"));
+        }
+    };
+
+    stream.write_all(&reply(Reply::Succeeded, relay)).await?;
+
+    // The client may announce the address it will send from; an all-zero
+    // address means "unknown", in which case we lock onto the first datagram
+    // coming from the client's IP.
+    let mut client = req_addr
+        .socket_addr()
+        .filter(|addr| addr.port() != 0 && !addr.ip().is_unspecified());
+
+    let mut reassembly = None;
+    let mut destinations: HashMap<TargetAddr, Destination> = HashMap::new();
+    // The addresses of `destinations`, which replies are relayed from.
+    let mut repliers: HashSet<SocketAddr> = HashSet::new();
+    let mut lookups: Vec<Lookup<'_>> = Vec::new();
+    let mut ctrl = [0u8; 64];
+    let mut buf = vec![0u8; MAX_DATAGRAM];
+    let idle = time::sleep(config.udp_idle_timeout);
+    tokio::pin!(idle);
+    loop {
+        tokio::select! {
+            _ = &mut idle => {
+                table.expired.fetch_add(1, Ordering::Relaxed);
+                break;
+            }
+            (dst, addr) = next_lookup(&mut lookups), if !lookups.is_empty() => {
+                let checked = Destination::Checked(addr, Instant::now() + DESTINATION_TTL);
+                let previous = destinations.insert(dst, checked);
+                repliers = repliers_of(&destinations);
+                if let (Some(Destination::Pending(queued, _)), Some(addr)) = (previous, addr) {
+                    for data in queued {
+                        socket.send_to(&data, addr).await?;
+                    }
+                }
+            }
+            n = stream.read(&mut ctrl) => {
+                match n {
+                    Ok(0) | Err(_) => break,
+                    // Anything else on the control connection is ignored.
+                    Ok(_) => continue,
+                }
+            }
+            res = socket.recv_from(&mut buf) => {
+                let (len, src) = res?;
+                let from_client = match client {
+                    Some(client) => client == src,
+                    None if src.ip() == peer.ip() => {
+                        client = Some(src);
+                        true
+                    }
+                    None => false,
+                };
+                if from_client {
+                    idle.as_mut().reset(time::Instant::now() + config.udp_idle_timeout);
+                    guard.touch(client);
+
+                    let datagram = match decapsulate(&buf[..len]) {
+                        Some((0, dst, data)) => {
+                            // An unfragmented datagram abandons reassembly.
+                            reassembly = None;
+                            Some((dst, data.to_vec()))
+                        }
+                        Some((frag, dst, data)) => match config.udp_frag_policy {
+                            FragPolicy::Drop => None,
+                            FragPolicy::Reassemble { max_len } => {
+                                reassemble(&mut reassembly, max_len, frag, dst, data)
+                            }
+                        },
+                        None => None,
+                    };
+                    if let Some((dst, data)) = datagram {
+                        let now = Instant::now();
+                        match destinations.get_mut(&dst) {
+                            Some(Destination::Checked(addr, expires)) if *expires > now => {
+                                if let Some(addr) = addr {
+                                    socket.send_to(&data, *addr).await?;
+                                }
+                            }
+                            Some(Destination::Pending(queued, _)) => {
+                                if queued.len() < MAX_PENDING {
+                                    queued.push(data);
+                                }
+                            }
+                            expired => {
+                                // Replies keep coming from where the last check led while
+                                // the destination is checked again.
+                                let previous = expired.and_then(|expired| expired.addr());
+                                if destinations.len() >= MAX_DESTINATIONS {
+                                    destinations.retain(|_, destination| match destination {
+                                        Destination::Pending(..) => true,
+                                        Destination::Checked(_, expires) => *expires > now,
+                                    });
+                                    repliers = repliers_of(&destinations);
+                                }
+                                // Past the limit, datagrams to new destinations are dropped.
+                                if destinations.len() < MAX_DESTINATIONS {
+                                    let pending = Destination::Pending(vec![data], previous);
+                                    destinations.insert(dst.clone(), pending);
+                                    lookups.push(Box::pin(async move {
+                                        let addr = destination(config, requester, &dst).await;
+                                        (dst, addr)
+                                    }));
+                                }
+                            }
+                        }
+                    }
+                } else if let (Some(client), true) = (client, repliers.contains(&src)) {
+                    idle.as_mut().reset(time::Instant::now() + config.udp_idle_timeout);
+                    guard.touch(Some(client));
+                    let mut packet = Vec::with_capacity(len + 22);
+                    let src = config.nat64.map_or(src, |nat64| nat64.unmap_addr(src));
+                    UdpHeader { frag: 0, dst: src.into() }.encode(&mut packet);
+                    packet.extend_from_slice(&buf[..len]);
+                    socket.send_to(&packet, client).await?;
+                }
+                // Anything else is dropped.
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Where datagrams to a destination go.
+enum Destination {
+    /// Being checked, with the datagrams waiting on it, and the address of
+    /// the previous check if it passed.
+    Pending(Vec<Vec<u8>>, Option<SocketAddr>),
+    /// Checked: the address to relay to, or none to drop datagrams, until
+    /// the check expires.
+    Checked(Option<SocketAddr>, Instant),
+}
+
+impl Destination {
+    /// The address datagrams were last relayed to, if any.
+    fn addr(&self) -> Option<SocketAddr> {
+        match *self {
+            Destination::Pending(_, addr) | Destination::Checked(addr, _) => addr,
+        }
+    }
+}
+
+/// The addresses replies are relayed from: those of the destinations whose
+/// check passed.
+fn repliers_of(destinations: &HashMap<TargetAddr, Destination>) -> HashSet<SocketAddr> {
+    destinations
+        .values()
+        .filter_map(Destination::addr)
+        .collect()
+}
+
+/// The check of a destination, and its outcome.
+type Lookup<'a> = Pin<Box<dyn Future<Output = (TargetAddr, Option<SocketAddr>)> + Send + 'a>>;
+
+/// Wait for the first of `lookups` to finish, and remove it.
+async fn next_lookup(lookups: &mut Vec<Lookup<'_>>) -> (TargetAddr, Option<SocketAddr>) {
+    future::poll_fn(|cx| {
+        for i in 0..lookups.len() {
+            if let Poll::Ready(output) = lookups[i].as_mut().poll(cx) {
+                drop(lookups.swap_remove(i));
+                return Poll::Ready(output);
+            }
+        }
+        Poll::Pending
+    })
+    .await
+}
+
+/// The address to relay datagrams for `dst` to, unless `requester` may not
+/// send there.
+async fn destination(
+    config: &ServerConfig,
+    requester: &Requester<'_>,
+    dst: &TargetAddr,
+) -> Option<SocketAddr> {
+    let policy = requester.policy;
+    if policy.is_some_and(|policy| policy.check(dst).is_err()) {
+        return None;
+    }
+    let target = match config.route(dst, Command::UdpAssociate, requester).await {
+        Ok(Routed {
+            target,
+            upstream: None,
+            ..
+        }) => target,
+        // Blocked, or routed through a proxy.
+        _ => return None,
+    };
+    let addrs = config.resolve_permitted(&target, policy).await.ok()?;
+    let addrs = config.check_dnsbl(&target, addrs).await.ok()?;
+    let addr = addrs.into_iter().next()?;
+    Some(config.nat64.map_or(addr, |nat64| nat64.translate(addr)))
+}
+
+/// Split a client datagram into `FRAG`, `DST.ADDR | DST.PORT` and the
+/// payload, dropping malformed ones.
+fn decapsulate(packet: &[u8]) -> Option<(u8, TargetAddr, &[u8])> {
+    match UdpHeader::parse(packet) {
+        Ok(Parse::Complete(header, len)) => Some((header.frag, header.dst, &packet[len..])),
+        _ => None,
+    }
+}
+
+/// Queue a fragment, returning the whole datagram once its last fragment
+/// arrives. Out of order, oversized and stale sequences are discarded.
+fn reassemble(
+    queue: &mut Option<Reassembly>,
+    max_len: usize,
+    frag: u8,
+    dst: TargetAddr,
+    data: &[u8],
+) -> Option<(TargetAddr, Vec<u8>)> {
+    let position = frag & !FRAG_END;
+
+    if position == 1 {
+        *queue = Some(Reassembly {
+            dst,
+            position,
+            data: data.to_vec(),
+            started: Instant::now(),
+        });
+    } else {
+        let current = queue.take().filter(|current| {
+            current.position + 1 == position
+                && current.dst == dst
+                && current.started.elapsed() < REASSEMBLY_TIMEOUT
+        });
+        let mut current = current?;
+        current.position = position;
+        current.data.extend_from_slice(data);
+        *queue = Some(current);
+    }
+
+    if queue.as_ref()?.data.len() > max_len {
+        *queue = None;
+        return None;
+    }
+
+    if frag & FRAG_END != 0 {
+        let done = queue.take()?;
+        return Some((done.dst, done.data));
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Server;
+    use tokio::net::TcpStream;
+
+    const IDLE_TIMEOUT: Duration = Duration::from_millis(400);
+
+    /// Open an association on a server of its own, returning the control
+    /// connection, the relay's address and the server.
+    async fn open() -> (TcpStream, SocketAddr, Arc<Server>) {
+        let server = Server::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .with_udp_idle_timeout(IDLE_TIMEOUT);
+        let addr = server.local_addr().unwrap();
+        let server = Arc::new(server);
+        let serving = server.clone();
+        tokio::spawn(async move { serving.serve().await });
+
+        let mut control = TcpStream::connect(addr).await.unwrap();
+        control.write_all(&[5, 1, 0]).await.unwrap();
+        let mut selection = [0; 2];
+        control.read_exact(&mut selection).await.unwrap();
+        assert_eq!(selection, [5, 0]);
+        control
+            .write_all(&[5, 3, 0, 1, 0, 0, 0, 0, 0, 0])
+            .await
+            .unwrap();
+        let mut response = [0; 10];
+        control.read_exact(&mut response).await.unwrap();
+        assert_eq!(response[1], 0);
+        let port = u16::from_be_bytes([response[8], response[9]]);
+        (control, SocketAddr::new(addr.ip(), port), server)
+    }
+
+    fn encapsulate(dst: SocketAddr, data: &[u8]) -> Vec<u8> {
+        let mut packet = Vec::new();
+        UdpHeader {
+            frag: 0,
+            dst: dst.into(),
+        }
+        .encode(&mut packet);
+        packet.extend_from_slice(data);
+        packet
+    }
+
+    async fn recv(socket: &UdpSocket) -> Option<(Vec<u8>, SocketAddr)> {
+        let mut buf = [0; 512];
+        let received = time::timeout(Duration::from_millis(200), socket.recv_from(&mut buf));
+        let (len, src) = received.await.ok()?.unwrap();
+        Some((buf[..len].to_vec(), src))
+    }
+
+    async fn bind() -> UdpSocket {
+        UdpSocket::bind("127.0.0.1:0").await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn relays_only_replies_of_destinations() {
+        let (_control, relay, _server) = open().await;
+        let (client, target, stranger) = (bind().await, bind().await, bind().await);
+        let target_addr = target.local_addr().unwrap();
+
+        client
+            .send_to(&encapsulate(target_addr, b"ping"), relay)
+            .await
+            .unwrap();
+        let (data, src) = recv(&target).await.unwrap();
+        assert_eq!((data.as_slice(), src), (&b"ping"[..], relay));
+        target.send_to(b"pong", relay).await.unwrap();
+        let (data, _) = recv(&client).await.unwrap();
+        assert_eq!(data, encapsulate(target_addr, b"pong"));
+
+        // Anyone else is dropped.
+        stranger.send_to(b"injected", relay).await.unwrap();
+        assert_eq!(recv(&client).await, None);
+    }
+
+    #[tokio::test]
+    async fn only_relayed_datagrams_keep_alive() {
+        let (mut control, relay, server) = open().await;
+        let (client, target, stranger) = (bind().await, bind().await, bind().await);
+        let target_addr = target.local_addr().unwrap();
+        client
+            .send_to(&encapsulate(target_addr, b"ping"), relay)
+            .await
+            .unwrap();
+        recv(&target).await.unwrap();
+
+        // Replies from the destination keep the association alive.
+        for _ in 0..8 {
+            time::sleep(IDLE_TIMEOUT / 4).await;
+            target.send_to(b"pong", relay).await.unwrap();
+            recv(&client).await.unwrap();
+        }
+        assert_eq!(server.udp_stats().expired, 0);
+
+        // Datagrams from anyone else don't.
+        let flooding = tokio::spawn(async move {
+            loop {
+                stranger.send_to(b"keepalive", relay).await.unwrap();
+                time::sleep(IDLE_TIMEOUT / 8).await;
+            }
+        });
+        let mut ctrl = [0; 1];
+        let closed = time::timeout(IDLE_TIMEOUT * 3, control.read(&mut ctrl)).await;
+        flooding.abort();
+        assert_eq!(closed.unwrap().unwrap(), 0);
+        assert_eq!(server.udp_stats().expired, 1);
+    }
+}
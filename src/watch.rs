@@ -0,0 +1,175 @@
+//! Reloading what was loaded from files, such as users and blocklists,
+//! whenever the files change.
+
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+use tokio::task::JoinHandle;
+
+/// Something loaded from a source that can change, and be loaded again.
+pub(crate) trait Reload: Send + Sync + 'static {
+    /// Whether the source may have changed since last loaded.
+    fn changed(&self) -> bool;
+
+    /// Load the source again. Blocks, so is run where blocking is fine.
+    fn reload(&self) -> io::Result<()>;
+
+    /// What a failed reload keeps, for the log, e.g. "the previous users".
+    fn kept(&self) -> String;
+}
+
+/// Reload `source` whenever it changes, checking every `interval`, until
+/// it is dropped. Reading and parsing a file can take a while for a large
+/// one, so reloads are run on a blocking thread. Failed ones are logged.
+pub(crate) fn spawn<T: Reload>(source: &Arc<T>, interval: Duration) -> JoinHandle<()> {
+    let source = Arc::downgrade(source);
+    tokio::spawn(async move {
+        let mut ticks = tokio::time::interval(interval);
+        ticks.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        // The source was just loaded.
+        ticks.tick().await;
+        loop {
+            ticks.tick().await;
+            let source = match source.upgrade() {
+                Some(source) => source,
+                None => return,
+            };
+            if !source.changed() {
+                continue;
+            }
+            let reloading = source.clone();
+            let reloaded = tokio::task::spawn_blocking(move || reloading.reload())
+                .await
+                .unwrap_or_else(|e| Err(io::Error::other(e)));
+            if let Err(e) = reloaded {
+                log::warn!("keeping {}: {}", source.kept(), e);
+            }
+        }
+    })
+}
+
+/// The modification time of a file when last loaded.
+pub(crate) struct Modified(Mutex<Option<SystemTime>>);
+
+impl Modified {
+    pub(crate) fn new(modified: Option<SystemTime>) -> Self {
+        Modified(Mutex::new(modified))
+    }
+
+    /// The modification time of the file at `path`, where the platform
+    /// has them.
+    pub(crate) fn of(path: &Path) -> io::Result<Option<SystemTime>> {
+        Ok(fs::metadata(path)?.modified().ok())
+    }
+
+    pub(crate) fn set(&self, modified: Option<SystemTime>) {
+        *self.0.lock().unwrap() = modified;
+    }
+
+    /// Whether the file at `path` was modified since last loaded.
+    pub(crate) fn changed(&self, path: &Path) -> bool {
+        let modified = fs::metadata(path).and_then(|metadata| metadata.modified());
+        match modified {
+            Ok(modified) => *self.0.lock().unwrap() != Some(modified),
+            // Likely being replaced, check again next time.
+            Err(_) => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+    #[derive(Default)]
+    struct Source {
+        changed: AtomicBool,
+        fail: AtomicBool,
+        reloads: AtomicUsize,
+    }
+
+    impl Reload for Source {
+        fn changed(&self) -> bool {
+            self.changed.load(Ordering::SeqCst)
+        }
+
+        fn reload(&self) -> io::Result<()> {
+            // Panics on the runtime's own threads.
+            tokio::runtime::Handle::current().block_on(async {});
+            self.reloads.fetch_add(1, Ordering::SeqCst);
+            self.changed.store(false, Ordering::SeqCst);
+            if self.fail.load(Ordering::SeqCst) {
+                return Err(io::Error::other("failed"));
+            }
+            Ok(())
+        }
+
+        fn kept(&self) -> String {
+            "the previous source".to_owned()
+        }
+    }
+
+    const INTERVAL: Duration = Duration::from_millis(10);
+
+    async fn ticks(n: u32) {
+        tokio::time::sleep(INTERVAL * n).await;
+    }
+
+    #[tokio::test]
+    async fn reloads_on_change_off_the_runtime() {
+        let source = Arc::new(Source::default());
+        let watching = spawn(&source, INTERVAL);
+        ticks(5).await;
+        assert_eq!(source.reloads.load(Ordering::SeqCst), 0);
+
+        source.changed.store(true, Ordering::SeqCst);
+        ticks(5).await;
+        assert_eq!(source.reloads.load(Ordering::SeqCst), 1);
+
+        // Failures are logged, and the watch goes on.
+        source.fail.store(true, Ordering::SeqCst);
+        source.changed.store(true, Ordering::SeqCst);
+        ticks(5).await;
+        source.changed.store(true, Ordering::SeqCst);
+        ticks(5).await;
+        assert_eq!(source.reloads.load(Ordering::SeqCst), 3);
+        assert!(!watching.is_finished());
+    }
+
+    #[tokio::test]
+    async fn stops_once_dropped() {
+        let source = Arc::new(Source::default());
+        let watching = spawn(&source, INTERVAL);
+        drop(source);
+        ticks(5).await;
+        assert!(watching.is_finished());
+    }
+
+    #[test]
+    fn modified() {
+        let path = std::env::temp_dir().join(format!("socks5_rs-watch-{}", std::process::id()));
+        fs::write(&path, "a").unwrap();
+        let modified = Modified::new(Modified::of(&path).unwrap());
+        assert!(!modified.changed(&path));
+
+        let later = SystemTime::now() + Duration::from_secs(60);
+        fs::File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_modified(later)
+            .unwrap();
+        assert!(modified.changed(&path));
+        modified.set(Modified::of(&path).unwrap());
+        assert!(!modified.changed(&path));
+
+        // Missing files, e.g. while being replaced, aren't changes.
+        fs::remove_file(&path).unwrap();
+        assert!(!modified.changed(&path));
+        assert!(Modified::of(&path).is_err());
+    }
+}